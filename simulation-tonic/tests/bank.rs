@@ -1,6 +1,6 @@
 //! Bank simulation showing how the simulation framework can detect a message
 //! reordering bug.
-use simulation::{deterministic::DeterministicRuntime, Environment, TcpListener};
+use simulation::{deterministic::DeterministicRuntime, Environment, SpawnEnv, TcpListener};
 pub mod bank {
     tonic::include_proto!("bank");
 }
@@ -165,16 +165,17 @@ where
 }
 
 fn run_bank_simulation(seed: u64) {
-    let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+    let (mut runtime, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
     let latency_fault = runtime.latency_fault();
-    let handle = runtime.localhost_handle();
-    runtime.block_on(async {
-        handle.spawn(latency_fault.run());
-        handle.spawn(start_server(handle.clone()));
-        let mut client = Client::new(handle.clone(), "127.0.0.1:9092".parse().unwrap()).await;
-        client.deposit(1, 100).await;
-        assert_eq!(client.query_balance(1).await, 100);
-    });
+    runtime
+        .block_on(async {
+            handle.spawn(latency_fault.run());
+            handle.spawn(start_server(handle.clone()));
+            let mut client = Client::new(handle.clone(), "127.0.0.1:9092".parse().unwrap()).await;
+            client.deposit(1, 100).await;
+            assert_eq!(client.query_balance(1).await, 100);
+        })
+        .unwrap();
 }
 
 #[test]
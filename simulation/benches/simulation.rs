@@ -0,0 +1,94 @@
+//! Benchmarks of fixed-seed simulations, reporting the real time taken per simulated second.
+//!
+//! Each iteration runs a workload for a fixed amount of simulated time, and the real time it
+//! took is divided by the simulated seconds which passed. Regressions in the cost of the
+//! simulator itself, or in the CPU used by the workload under simulation, show up as an
+//! increase in the time reported for each benchmark.
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::{Future, FutureExt};
+use simulation::{
+    deterministic::{DeterministicRuntime, DeterministicRuntimeHandle},
+    servers, NetEnv, SpawnEnv, TimeEnv,
+};
+use std::{
+    net,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SEED: u64 = 1;
+const SIMULATED: Duration = Duration::from_secs(60);
+const CLIENTS: u8 = 8;
+const MESSAGE: usize = 64;
+
+/// Run `workload` with a runtime seeded with `SEED` once per iteration, returning the real time
+/// taken for each second of simulated time.
+fn per_simulated_second<F, U>(iters: u64, workload: F) -> Duration
+where
+    F: Fn(&DeterministicRuntime) -> U,
+    U: Future<Output = ()>,
+{
+    let mut real = Duration::from_secs(0);
+    let mut simulated = Duration::from_secs(0);
+    for _ in 0..iters {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(SEED).build().unwrap();
+        let start = Instant::now();
+        let simulated_start = handle.now();
+        let workload = workload(&runtime);
+        runtime.block_on(workload).unwrap();
+        real += start.elapsed();
+        simulated += handle.now() - simulated_start;
+    }
+    // criterion divides the returned time by the number of iterations, so scale the real
+    // time by the simulated seconds which passed in each.
+    let seconds = simulated.as_secs_f64() / iters as f64;
+    real.div_f64(seconds.max(f64::EPSILON))
+}
+
+/// Send messages to the server at `addr` and wait for each to be echoed, until `SIMULATED`
+/// has passed.
+async fn echo_client(handle: DeterministicRuntimeHandle, addr: net::SocketAddr) {
+    let deadline = handle.now() + SIMULATED;
+    let mut socket = loop {
+        match handle.connect(addr).await {
+            Ok(socket) => break socket,
+            Err(_) => handle.delay_from(Duration::from_millis(100)).await,
+        }
+    };
+    let mut message = [0; MESSAGE];
+    while handle.now() < deadline {
+        socket.write_all(&message).await.unwrap();
+        socket.read_exact(&mut message).await.unwrap();
+        handle.delay_from(Duration::from_millis(10)).await;
+    }
+}
+
+fn echo(c: &mut Criterion) {
+    c.bench_function("echo", |b| {
+        b.iter_custom(|iters| {
+            per_simulated_second(iters, |runtime| {
+                let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let clients: Vec<_> = (0..CLIENTS)
+                    .map(|i| runtime.handle(net::Ipv4Addr::new(10, 0, 1, i).into()))
+                    .collect();
+                async move {
+                    server.spawn(servers::echo(server.clone(), addr).map(Result::unwrap));
+                    let clients: Vec<_> = clients
+                        .into_iter()
+                        .map(|client| {
+                            simulation::spawn_with_result(
+                                &client,
+                                echo_client(client.clone(), addr),
+                            )
+                        })
+                        .collect();
+                    futures::future::join_all(clients).await;
+                }
+            })
+        })
+    });
+}
+
+criterion_group!(benches, echo);
+criterion_main!(benches);
@@ -10,7 +10,7 @@
 
 use bytes::BytesMut;
 use futures::{FutureExt, SinkExt, StreamExt};
-use simulation::{deterministic::DeterministicRuntime, Environment, TcpListener};
+use simulation::{deterministic::DeterministicRuntime, Environment, SpawnEnv, TcpListener};
 use std::{io, net, sync, time};
 use tokio::codec::{Framed, LinesCodec};
 
@@ -190,39 +190,40 @@ where
 fn simulate(seed: u64) -> std::time::Duration {
     // A SingleThreaded runtime can be swapped in at will.
     // let mut runtime = SingleThreadedRuntime::new().unwrap();
-    let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
-    let handle = runtime.localhost_handle();
+    let (mut runtime, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
     let start_time = handle.now();
     let latency_fault = runtime.latency_fault();
-    runtime.block_on(async {
-        handle.spawn(latency_fault.run());
-        let server_handle = handle.clone();
-        let banking_server = BankingServer::new(server_handle.clone(), 100);
-        let mut server_fut = simulation::spawn_with_result(&handle, async move {
-            banking_server.serve(9092).await.unwrap()
+    runtime
+        .block_on(async {
+            handle.spawn(latency_fault.run());
+            let server_handle = handle.clone();
+            let banking_server = BankingServer::new(server_handle.clone(), 100);
+            let mut server_fut = simulation::spawn_with_result(&handle, async move {
+                banking_server.serve(9092).await.unwrap()
+            })
+            .fuse();
+
+            let r1 = simulation::spawn_with_result(
+                &handle,
+                atm(handle.clone(), time::Duration::from_millis(200), 2),
+            );
+
+            let r2 = simulation::spawn_with_result(
+                &handle,
+                atm(handle.clone(), time::Duration::from_millis(500), 1),
+            );
+
+            let mut fut = futures::future::join_all(vec![r1, r2]).fuse();
+            futures::select!(
+                _ = fut => {
+                    println!("clients finished")
+                }
+                _ = server_fut => {
+                    println!("bank overdrafted on seed {}", seed)
+                }
+            );
         })
-        .fuse();
-
-        let r1 = simulation::spawn_with_result(
-            &handle,
-            atm(handle.clone(), time::Duration::from_millis(200), 2),
-        );
-
-        let r2 = simulation::spawn_with_result(
-            &handle,
-            atm(handle.clone(), time::Duration::from_millis(500), 1),
-        );
-
-        let mut fut = futures::future::join_all(vec![r1, r2]).fuse();
-        futures::select!(
-            _ = fut => {
-                println!("clients finished")
-            }
-            _ = server_fut => {
-                println!("bank overdrafted on seed {}", seed)
-            }
-        );
-    });
+        .unwrap();
     let end_time = handle.now();
     end_time - start_time
 }
@@ -1,6 +1,9 @@
 use futures::{SinkExt, StreamExt};
-use simulation::{deterministic::DeterministicRuntime, Environment, TcpListener};
-use std::{net::{self, IpAddr, Ipv4Addr, SocketAddr}, time};
+use simulation::{deterministic::DeterministicRuntime, Environment, SpawnEnv, TcpListener};
+use std::{
+    net::{self, SocketAddr},
+    time,
+};
 use tokio::codec::{Framed, LinesCodec};
 
 type Err = Box<dyn std::error::Error + Send + Sync + 'static>;
@@ -14,7 +17,7 @@ async fn handle_request<E>(
     E: Environment,
 {
     // delay the response, in deterministic mode this will immediately progress time.
-    env.delay_from(time::Duration::from_secs(1));
+    env.delay_from(time::Duration::from_secs(1)).await;
     println!("handling connection from {:?}", addr);
     let mut transport = Framed::new(socket, LinesCodec::new());
     if let Err(e) = transport.send(String::from("Hello World!")).await {
@@ -51,7 +54,7 @@ where
                 let mut transport = Framed::new(conn, LinesCodec::new());
                 let result = match transport.next().await {
                     Some(res) => res,
-                    None => panic!("Missing next frame in transport, this is a bug")
+                    None => panic!("Missing next frame in transport, this is a bug"),
                 };
                 assert_eq!(result.unwrap(), "Hello World!");
                 println!("Success!");
@@ -62,19 +65,18 @@ where
 }
 
 fn main() -> Result<(), Err> {
-    let mut runtime = DeterministicRuntime::new_with_seed(1)?;
-    let handle = runtime.handle(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let (mut runtime, handle) = DeterministicRuntime::builder().seed(1).build()?;
     let latency_fault = runtime.latency_fault();
 
     let addr: SocketAddr = "127.0.0.1:8080".parse()?;
-    runtime.block_on(async {
-        handle.spawn(latency_fault.run());
-        let server = server(handle.clone(), addr);
-        handle.spawn(async move {
-            server.await.unwrap()
-        });
-        client(handle, addr).await.unwrap();
-    });
+    runtime
+        .block_on(async {
+            handle.spawn(latency_fault.run());
+            let server = server(handle.clone(), addr);
+            handle.spawn(async move { server.await.unwrap() });
+            client(handle, addr).await.unwrap();
+        })
+        .unwrap();
 
     Ok(())
 }
@@ -0,0 +1,302 @@
+//! Drives a sequence of network operations decoded from the fuzzer's input against the
+//! deterministic network, checking that the network never loses, reorders or invents bytes
+//! unless a host was killed, and never refuses a connection to a bound address.
+//!
+//! The first bytes of the input choose the seed, the base latency and jitter, and whether the
+//! latency fault injector runs. The rest is a sequence of operations, each an opcode byte
+//! followed by its arguments. Running out of input ends the sequence, after which every
+//! connection is drained and compared against what was written to it.
+#![no_main]
+use futures::future;
+use libfuzzer_sys::fuzz_target;
+use simulation::{
+    deterministic::{DeterministicRuntime, DeterministicRuntimeHandle, Listener, Socket},
+    NetEnv, SpawnEnv, TcpListener, TimeEnv,
+};
+use std::{io, net, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const HOSTS: u8 = 3;
+const PORT: u16 = 7000;
+/// How long a single operation may block before it is abandoned.
+const PATIENCE: Duration = Duration::from_secs(300);
+
+/// Reads arguments from the fuzzer's input, yielding zeros once it runs out.
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((byte, rest)) => {
+                self.data = rest;
+                *byte
+            }
+            None => 0,
+        }
+    }
+
+    fn u64(&mut self) -> u64 {
+        (0..8).fold(0, |value, _| value << 8 | u64::from(self.byte()))
+    }
+
+    fn host(&mut self) -> net::IpAddr {
+        net::Ipv4Addr::new(10, 0, 0, 1 + self.byte() % HOSTS).into()
+    }
+}
+
+enum Op {
+    Bind(net::IpAddr),
+    Connect(net::IpAddr, net::IpAddr),
+    Write(usize, usize, usize),
+    Read(usize, usize, usize),
+    Shutdown(usize, usize),
+    Kill(net::IpAddr),
+    Delay(Duration),
+}
+
+impl Op {
+    fn decode(input: &mut Input<'_>) -> Self {
+        match input.byte() % 7 {
+            0 => Op::Bind(input.host()),
+            1 => Op::Connect(input.host(), input.host()),
+            2 => Op::Write(
+                input.byte().into(),
+                input.byte().into(),
+                usize::from(input.byte()) * 64,
+            ),
+            3 => Op::Read(
+                input.byte().into(),
+                input.byte().into(),
+                usize::from(input.byte()) * 64 + 1,
+            ),
+            4 => Op::Shutdown(input.byte().into(), input.byte().into()),
+            5 => Op::Kill(input.host()),
+            _ => Op::Delay(Duration::from_millis(u64::from(input.byte()) * 10)),
+        }
+    }
+}
+
+/// A connection and what has passed through it. End 0 is the client and end 1 the server;
+/// bytes written by one end are read by the other.
+struct Connection {
+    hosts: [net::IpAddr; 2],
+    ends: [Option<Socket>; 2],
+    written: [Vec<u8>; 2],
+    read: [Vec<u8>; 2],
+    shutdown: [bool; 2],
+    /// Set once either host was killed, after which bytes may be lost.
+    broken: bool,
+}
+
+impl Connection {
+    /// Record `bytes` read by `end`, checking they continue what its peer wrote.
+    fn received(&mut self, end: usize, bytes: &[u8]) {
+        self.read[end].extend_from_slice(bytes);
+        let read = &self.read[end];
+        let written = &self.written[1 - end];
+        assert!(
+            written.starts_with(read),
+            "end {} read {} bytes which were not written by its peer, which wrote {}",
+            end,
+            read.len(),
+            written.len()
+        );
+    }
+}
+
+struct Driver {
+    handle: DeterministicRuntimeHandle,
+    listeners: Vec<(net::IpAddr, Listener)>,
+    connections: Vec<Connection>,
+    /// Counter from which written bytes are generated, so each write is distinguishable.
+    next_byte: u8,
+}
+
+impl Driver {
+    async fn run(&mut self, op: Op) {
+        match op {
+            Op::Bind(host) => {
+                if self.listeners.iter().all(|(bound, _)| *bound != host) {
+                    let addr = net::SocketAddr::new(host, PORT);
+                    let listener = self.handle.scoped(host).bind(addr).await;
+                    let listener = listener.expect("binding an unbound address failed");
+                    self.listeners.push((host, listener));
+                }
+            }
+            Op::Connect(client, server) => self.connect(client, server).await,
+            Op::Write(index, end, len) => self.write(index, end, len).await,
+            Op::Read(index, end, len) => self.read(index, end, len).await,
+            Op::Shutdown(index, end) => {
+                if let Some((connection, end)) = self.end(index, end) {
+                    let socket = connection.ends[end].as_mut().unwrap();
+                    if socket.shutdown().await.is_ok() {
+                        connection.shutdown[end] = true;
+                    }
+                }
+            }
+            Op::Kill(host) => {
+                self.handle.kill(host);
+                self.listeners.retain(|(bound, _)| *bound != host);
+                for connection in &mut self.connections {
+                    for end in 0..2 {
+                        if connection.hosts[end] == host {
+                            connection.broken = true;
+                            connection.ends[end] = None;
+                        }
+                    }
+                }
+            }
+            Op::Delay(duration) => self.handle.delay_from(duration).await,
+        }
+    }
+
+    async fn connect(&mut self, client: net::IpAddr, server: net::IpAddr) {
+        let handle = self.handle.scoped(client);
+        let addr = net::SocketAddr::new(server, PORT);
+        let listener = match self.listeners.iter_mut().find(|(bound, _)| *bound == server) {
+            Some((_, listener)) => listener,
+            None => {
+                let refused = handle.timeout(handle.connect(addr), PATIENCE).await;
+                match refused {
+                    Ok(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused),
+                    Ok(Ok(_)) => panic!("connected to {}, which nothing listens on", addr),
+                    Err(_) => panic!("connecting to {}, which nothing listens on, hung", addr),
+                }
+                return;
+            }
+        };
+        let connect = handle.timeout(handle.connect(addr), PATIENCE);
+        let accept = handle.timeout(listener.accept(), PATIENCE);
+        match future::join(connect, accept).await {
+            (Ok(Ok(client_socket)), Ok(Ok((server_socket, _)))) => {
+                self.connections.push(Connection {
+                    hosts: [client, server],
+                    ends: [Some(client_socket), Some(server_socket)],
+                    written: Default::default(),
+                    read: Default::default(),
+                    shutdown: [false; 2],
+                    broken: false,
+                });
+            }
+            (Ok(Err(e)), _) => panic!("connecting to {}, which is bound, failed: {}", addr, e),
+            _ => {}
+        }
+    }
+
+    async fn write(&mut self, index: usize, end: usize, len: usize) {
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                self.next_byte = self.next_byte.wrapping_add(1);
+                self.next_byte
+            })
+            .collect();
+        let handle = self.handle.clone();
+        let (connection, end) = match self.end(index, end) {
+            Some(end) if !end.0.shutdown[end.1] => end,
+            _ => return,
+        };
+        let socket = connection.ends[end].as_mut().unwrap();
+        let mut sent = 0;
+        while sent < data.len() {
+            match handle.timeout(socket.write(&data[sent..]), PATIENCE).await {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+                Ok(Ok(n)) => sent += n,
+            }
+        }
+        connection.written[end].extend_from_slice(&data[..sent]);
+    }
+
+    async fn read(&mut self, index: usize, end: usize, len: usize) {
+        let handle = self.handle.clone();
+        let (connection, end) = match self.end(index, end) {
+            Some(end) => end,
+            None => return,
+        };
+        let mut buf = vec![0; len];
+        let socket = connection.ends[end].as_mut().unwrap();
+        let read = handle.timeout(socket.read(&mut buf), PATIENCE).await;
+        match read {
+            Ok(Ok(0)) => {
+                let complete = connection.read[end].len() == connection.written[1 - end].len();
+                assert!(
+                    connection.broken || (connection.shutdown[1 - end] && complete),
+                    "end {} read the end of the stream before its peer finished writing",
+                    end
+                );
+            }
+            Ok(Ok(n)) => connection.received(end, &buf[..n]),
+            Ok(Err(e)) => assert!(connection.broken, "read failed without a fault: {}", e),
+            Err(_) => {}
+        }
+    }
+
+    /// Returns the connection at `index` and the end at `end`, wrapping around, if it is open.
+    fn end(&mut self, index: usize, end: usize) -> Option<(&mut Connection, usize)> {
+        if self.connections.is_empty() {
+            return None;
+        }
+        let len = self.connections.len();
+        let connection = &mut self.connections[index % len];
+        let end = end % 2;
+        connection.ends[end].as_ref()?;
+        Some((connection, end))
+    }
+
+    /// Read everything left on each connection no host was killed on, checking every byte
+    /// written arrived.
+    async fn drain(&mut self) {
+        let handle = self.handle.clone();
+        for connection in self.connections.iter_mut().filter(|c| !c.broken) {
+            for end in 0..2 {
+                let mut buf = vec![0; 4096];
+                while connection.read[end].len() < connection.written[1 - end].len() {
+                    let socket = connection.ends[end].as_mut().unwrap();
+                    match handle.timeout(socket.read(&mut buf), PATIENCE).await {
+                        Ok(Ok(n)) if n > 0 => connection.received(end, &buf[..n]),
+                        _ => break,
+                    }
+                }
+                assert_eq!(
+                    connection.read[end].len(),
+                    connection.written[1 - end].len(),
+                    "bytes were lost without a loss fault"
+                );
+            }
+        }
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input { data };
+    let seed = input.u64();
+    let latency = Duration::from_millis(u64::from(input.byte()));
+    let jitter = Duration::from_millis(u64::from(input.byte()));
+    let latency_faults = input.byte() % 2 == 1;
+    let (mut runtime, handle) = DeterministicRuntime::builder()
+        .seed(seed)
+        .latency(latency)
+        .jitter(jitter)
+        .build()
+        .unwrap();
+    if latency_faults {
+        handle.spawn(runtime.latency_fault().run());
+    }
+    let mut driver = Driver {
+        handle,
+        listeners: vec![],
+        connections: vec![],
+        next_byte: 0,
+    };
+    runtime.block_on(async move {
+        while !input.is_empty() {
+            driver.run(Op::decode(&mut input)).await;
+        }
+        driver.drain().await;
+    });
+});
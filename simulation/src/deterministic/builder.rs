@@ -0,0 +1,429 @@
+//! Options for constructing a [`DeterministicRuntime`].
+//!
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+use super::{
+    AcceptOrder, AutoAdvanceClock, Clock, CloseOnDrop, DescriptorTable, DeterministicChannels,
+    DeterministicChaosLog, DeterministicDiscovery, DeterministicDns, DeterministicEventBus,
+    DeterministicFs, DeterministicMetrics, DeterministicNetwork, DeterministicRandom,
+    DeterministicRuntime, DeterministicRuntimeHandle, DeterministicTime, EventLog, FaultPlan,
+    FlowTrace, MemoryMeter, PanicPolicy, ProcessTable, Timeline, MAX_DELAY,
+};
+use crate::Error;
+use std::{collections, net, sync, time::Duration};
+use tokio_net::driver;
+
+/// Builds a [`DeterministicRuntime`], along with a root handle scoped to localhost.
+///
+/// ```
+/// # use simulation::deterministic::DeterministicRuntime;
+/// # use std::time::Duration;
+/// let (mut runtime, handle) = DeterministicRuntime::builder()
+///     .seed(7)
+///     .latency(Duration::from_millis(5))
+///     .jitter(Duration::from_millis(2))
+///     .time_limit(Duration::from_secs(60))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// [`DeterministicRuntime`]:DeterministicRuntime
+#[derive(Debug)]
+pub struct DeterministicRuntimeBuilder {
+    seed: u64,
+    clock: Box<dyn Clock>,
+    latency: Duration,
+    jitter: Duration,
+    close_on_drop: CloseOnDrop,
+    accept_order: AcceptOrder,
+    late_binding: bool,
+    refuse_delay: Duration,
+    strict_backlog: Option<usize>,
+    panic_policy: PanicPolicy,
+    time_limit: Option<Duration>,
+    memory_limit: Option<usize>,
+    watchdog: Option<Duration>,
+    fault_plan: Option<FaultPlan>,
+}
+
+impl Default for DeterministicRuntimeBuilder {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clock: Box::new(AutoAdvanceClock::default()),
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            close_on_drop: CloseOnDrop::default(),
+            accept_order: AcceptOrder::default(),
+            late_binding: false,
+            refuse_delay: Duration::from_millis(0),
+            strict_backlog: None,
+            panic_policy: PanicPolicy::default(),
+            time_limit: None,
+            memory_limit: None,
+            watchdog: None,
+            fault_plan: None,
+        }
+    }
+}
+
+impl DeterministicRuntimeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the runtime's source of randomness. Defaults to `0`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Advance time with `clock` once every task is idle, rather than jumping straight to the
+    /// next timer.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Delay sends on every new connection by `latency`, as the latency fault injector does:
+    /// the first send waits for `latency` after the connection is established, and each send
+    /// after it for `latency` after the one before. Defaults to no latency.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Add up to `jitter` on top of the latency of each end of every new connection, drawn from
+    /// the seed when the connection is established.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Close connections as `close` describes when one of their halves is dropped without being
+    /// shut down. Defaults to [`CloseOnDrop::Tcp`], which closes them cleanly unless data was
+    /// left unread, and resets them otherwise.
+    ///
+    /// [`CloseOnDrop::Tcp`]:CloseOnDrop::Tcp
+    pub fn close_on_drop(mut self, close: CloseOnDrop) -> Self {
+        self.close_on_drop = close;
+        self
+    }
+
+    /// Accept the connections waiting for each listener in `order`. Defaults to
+    /// [`AcceptOrder::Arrival`]; [`AcceptOrder::Shuffled`] draws the order from the seed, so
+    /// running a test under several seeds explores different arrival orders.
+    ///
+    /// [`AcceptOrder::Arrival`]:AcceptOrder::Arrival
+    /// [`AcceptOrder::Shuffled`]:AcceptOrder::Shuffled
+    pub fn accept_order(mut self, order: AcceptOrder) -> Self {
+        self.accept_order = order;
+        self
+    }
+
+    /// Let connections to an address nothing is listening on wait until a listener is bound to
+    /// it, instead of refusing them. This allows clients to be started before their servers,
+    /// but a client connecting to a host which is down hangs rather than failing.
+    pub fn late_binding(mut self) -> Self {
+        self.late_binding = true;
+        self
+    }
+
+    /// Bound every queue the network keeps, so that overflowing one is a reported event rather
+    /// than silent growth which hides missing backpressure. Listeners hold at most `backlog`
+    /// connections waiting to be accepted and refuse any more, recording
+    /// [`TimelineKind::BacklogFull`]. Writes are cut short to the room left in the peer's
+    /// buffer, recording [`TimelineKind::BufferFull`] when they are cut short or have to wait.
+    ///
+    /// [`TimelineKind::BacklogFull`]:crate::deterministic::TimelineKind::BacklogFull
+    /// [`TimelineKind::BufferFull`]:crate::deterministic::TimelineKind::BufferFull
+    pub fn strict_queues(mut self, backlog: usize) -> Self {
+        self.strict_backlog = Some(backlog);
+        self
+    }
+
+    /// Refuse connections to an address nothing is listening on once `delay` has passed, as a
+    /// remote host answering with a reset would. Defaults to refusing them immediately. Has no
+    /// effect with [`late_binding`].
+    ///
+    /// [`late_binding`]:DeterministicRuntimeBuilder::late_binding
+    pub fn refuse_delay(mut self, delay: Duration) -> Self {
+        self.refuse_delay = delay;
+        self
+    }
+
+    /// Make a panic in a task spawned on behalf of a host act as `policy` describes. Defaults
+    /// to [`PanicPolicy::Abort`], which aborts the whole simulation; [`PanicPolicy::CrashHost`]
+    /// only kills the host of the task, so tests can check how its peers cope with it dying.
+    ///
+    /// [`PanicPolicy::Abort`]:PanicPolicy::Abort
+    /// [`PanicPolicy::CrashHost`]:PanicPolicy::CrashHost
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Limit the simulated time since the runtime was created which [`block_on`] may run
+    /// until, panicking once it is exceeded. This fails simulations which never complete,
+    /// rather than letting them spin forever.
+    ///
+    /// [`block_on`]:DeterministicRuntime::block_on
+    pub fn time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    /// Limit the approximate memory held by the simulation to `bytes`, making [`block_on`]
+    /// panic with a breakdown of the [`MemoryUsage`] once it is exceeded. This points long
+    /// simulations which would otherwise run out of memory at whatever grew.
+    ///
+    /// [`block_on`]:DeterministicRuntime::block_on
+    /// [`MemoryUsage`]:crate::deterministic::MemoryUsage
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Abort the process with a report of the work the simulation is waiting on once neither
+    /// simulated time has advanced nor any task been polled for `limit` of real time while the
+    /// runtime is running. This turns a simulation which hangs silently into a report.
+    pub fn watchdog(mut self, limit: Duration) -> Self {
+        self.watchdog = Some(limit);
+        self
+    }
+
+    /// Inject `plan` into the first [`Cluster`] created on the runtime, starting as soon as
+    /// the cluster is created.
+    ///
+    /// [`Cluster`]:crate::deterministic::Cluster
+    pub fn fault_plan(mut self, plan: FaultPlan) -> Self {
+        self.fault_plan = Some(plan);
+        self
+    }
+
+    /// Create the runtime, returning it along with a handle scoped to localhost.
+    ///
+    /// Returns [`Error::TimeOverflow`] if the time limit or the fault plan reach further
+    /// ahead than [`MAX_DELAY`].
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:crate::deterministic::MAX_DELAY
+    pub fn build(self) -> Result<(DeterministicRuntime, DeterministicRuntimeHandle), Error> {
+        if let Some(limit) = self.time_limit.filter(|limit| *limit > MAX_DELAY) {
+            return Err(Error::TimeOverflow {
+                operation: format!("setting a time limit of {:?}", limit),
+            });
+        }
+        if let Some(plan) = &self.fault_plan {
+            plan.check()?;
+        }
+        let reactor = driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
+
+        let mut time = DeterministicTime::new_with_clock(reactor, self.clock);
+        let time_handle = time.handle();
+        let phase = sync::Arc::new(sync::Mutex::new(None));
+        let events = EventLog::new(time_handle.reader());
+        let chaos_log = DeterministicChaosLog::new(
+            time_handle.clone(),
+            sync::Arc::clone(&phase),
+            events.clone(),
+        );
+        let timeline = Timeline::new(time_handle.clone(), chaos_log.handle(), events.clone());
+        time.set_timeline(timeline.clone());
+        let descriptors = DescriptorTable::new();
+        let random = DeterministicRandom::new_with_seed(self.seed);
+        random.set_events(events);
+        let network = DeterministicNetwork::new(time_handle.clone(), descriptors.clone());
+        network.set_timeline(timeline.clone());
+        let flows = FlowTrace::new(time_handle.reader());
+        network.set_flows(flows.clone());
+        network.set_latency(self.latency, self.jitter, random.handle());
+        network.set_close_on_drop(self.close_on_drop);
+        network.set_accept_order(self.accept_order);
+        network.set_unbound(self.late_binding, self.refuse_delay);
+        if let Some(backlog) = self.strict_backlog {
+            network.set_strict(backlog);
+        }
+        let processes = ProcessTable::new(timeline.clone());
+        processes.set_panic_policy(self.panic_policy);
+        let executor = tokio_executor::current_thread::CurrentThread::new_with_park(time);
+        let fs = DeterministicFs::new(time_handle.clone(), random.handle(), descriptors.clone());
+        let channels = DeterministicChannels::new(time_handle.clone(), random.handle());
+        let discovery = DeterministicDiscovery::new(time_handle.clone(), random.handle());
+        let dns = DeterministicDns::new(time_handle.clone(), random.handle());
+        let event_bus = DeterministicEventBus::new(time_handle.clone());
+        let metrics = DeterministicMetrics::new(time_handle.clone());
+        let localhost = net::Ipv4Addr::LOCALHOST.into();
+        let memory = MemoryMeter {
+            network: network.scoped(localhost),
+            fs: fs.scoped(localhost),
+            timeline: timeline.clone(),
+            chaos_log: chaos_log.handle(),
+            metrics: metrics.scoped(localhost),
+        };
+        let runtime = DeterministicRuntime {
+            seed: self.seed,
+            executor,
+            time_handle,
+            network,
+            fs,
+            random,
+            channels,
+            discovery,
+            dns,
+            event_bus,
+            chaos_log,
+            metrics,
+            phase,
+            hostnames: sync::Arc::new(sync::Mutex::new(collections::HashMap::new())),
+            config: sync::Arc::new(sync::Mutex::new(collections::HashMap::new())),
+            processes,
+            descriptors,
+            timeline,
+            flows,
+            time_limit: self.time_limit,
+            memory_limit: self.memory_limit,
+            watchdog: self.watchdog.map(|limit| (limit, super::watchdog::abort())),
+            memory,
+            profiler: Default::default(),
+            wall: Duration::from_secs(0),
+            fault_plan: sync::Arc::new(sync::Mutex::new(self.fault_plan)),
+        };
+        let handle = runtime.localhost_handle();
+        Ok((runtime, handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{ChaosKind, Cluster, HostFault};
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::{net, panic};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Returns the simulated time it takes for two requests sent over a new connection to be
+    /// answered.
+    fn round_trip(builder: DeterministicRuntimeBuilder) -> Duration {
+        let (mut runtime, handle) = builder.build().unwrap();
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+                let mut listener = handle.bind(addr).await.unwrap();
+                handle.spawn(async move {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut buf = [0; 8];
+                    socket.read_exact(&mut buf).await.unwrap();
+                    socket.write_all(&buf[..4]).await.unwrap();
+                });
+                let start = handle.now();
+                let mut socket = handle.connect(addr).await.unwrap();
+                socket.write_all(b"ping").await.unwrap();
+                socket.write_all(b"ping").await.unwrap();
+                let mut reply = [0; 4];
+                socket.read_exact(&mut reply).await.unwrap();
+                handle.now() - start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that new connections are delayed by the latency, plus jitter drawn from the seed.
+    fn latency_and_jitter() {
+        assert_eq!(
+            round_trip(DeterministicRuntime::builder()),
+            Duration::from_millis(0)
+        );
+        let latency = DeterministicRuntime::builder().latency(Duration::from_millis(10));
+        assert_eq!(round_trip(latency), Duration::from_millis(20));
+
+        let jittered = |seed| {
+            DeterministicRuntime::builder()
+                .seed(seed)
+                .latency(Duration::from_millis(10))
+                .jitter(Duration::from_millis(5))
+        };
+        let elapsed = round_trip(jittered(1));
+        assert!(elapsed >= Duration::from_millis(20) && elapsed < Duration::from_millis(30));
+        assert_eq!(round_trip(jittered(1)), elapsed);
+    }
+
+    #[test]
+    /// Test that block_on panics once the time limit passes, even if every task is stuck.
+    fn time_limit() {
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .time_limit(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        runtime
+            .block_on(handle.delay_from(Duration::from_secs(30)))
+            .unwrap();
+        let stuck = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime.block_on(futures::future::pending::<()>()).unwrap()
+        }));
+        assert!(stuck.is_err());
+        assert_eq!(handle.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    /// Test that a time limit or fault plan reaching past the furthest delay which can be set
+    /// fails to build, rather than panicking inside the timer once the runtime runs.
+    fn time_overflow() {
+        let limit = DeterministicRuntime::builder()
+            .time_limit(Duration::from_secs(u64::MAX))
+            .build();
+        match limit {
+            Err(Error::TimeOverflow { operation }) => assert!(operation.contains("time limit")),
+            other => panic!("expected a time overflow, got {:?}", other.map(|_| ())),
+        }
+        let plan = FaultPlan::new()
+            .fault(Duration::from_secs(5), 0, HostFault::Restart)
+            .fault(
+                Duration::from_secs(10),
+                1,
+                HostFault::Crash {
+                    downtime: Duration::from_secs(u64::MAX),
+                },
+            );
+        match DeterministicRuntime::builder().fault_plan(plan).build() {
+            Err(Error::TimeOverflow { operation }) => {
+                assert!(operation.contains("after fault 1"), "{}", operation)
+            }
+            other => panic!("expected a time overflow, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    /// Test that the fault plan is injected into the first cluster created on the runtime.
+    fn fault_plan() {
+        let plan = FaultPlan::new().fault(Duration::from_secs(5), 0, HostFault::Restart);
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .fault_plan(plan)
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                cluster
+                    .add_host(|handle| async move {
+                        handle.delay_from(Duration::from_secs(3600)).await;
+                    })
+                    .await;
+                let second =
+                    Cluster::new_with_subnet(handle.clone(), net::Ipv4Addr::new(10, 1, 0, 0), 16);
+                second
+                    .add_host(|handle| async move {
+                        handle.delay_from(Duration::from_secs(3600)).await;
+                    })
+                    .await;
+                handle.delay_from(Duration::from_secs(10)).await;
+                let log = handle.chaos_log_handle().query();
+                assert_eq!(log.clone().kind(ChaosKind::Kill).count(), 1);
+                let first = cluster.hosts()[0];
+                assert_eq!(log.kind(ChaosKind::Kill).host(first).count(), 1);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Running a simulation against many seeds.
+//!
+//! A [`Campaign`] runs the same test once for each seed in a range, each against a fresh
+//! runtime, and collects the seeds whose run panicked rather than stopping at the first. Its
+//! [`CampaignProgress`] can be read from another thread while the campaign runs, giving the
+//! seeds completed, the failures found so far, and the simulated time and active faults of the
+//! run in progress. With the `tui` feature, [`Campaign::monitor`] draws these to the terminal
+//! as the campaign runs, so a long chaos run is no longer a black box until it finishes.
+//!
+//! A failure which cannot be replayed from its seed costs hours of chasing a bug which never
+//! shows up again. With [`Campaign::rerun_failures`], each failing seed is run a second time
+//! before it is reported, and unless the rerun fails with the same message and executes the
+//! same way, as compared by the [`fingerprint`] of both runs, the failure is flagged as
+//! nondeterministic rather than reported as a bug in the code under test.
+//!
+//! [`Campaign`]:Campaign
+//! [`CampaignProgress`]:CampaignProgress
+//! [`Campaign::monitor`]:Campaign::monitor
+//! [`Campaign::rerun_failures`]:Campaign::rerun_failures
+//! [`fingerprint`]:crate::deterministic::DeterministicRuntime::fingerprint
+use super::{
+    dump, escape, ChaosKind, ChaosTarget, DeterministicChaosLogHandle, DeterministicRuntime,
+    TimeReader,
+};
+use std::{collections, net, ops, panic, sync, time};
+
+/// A seed whose run failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CampaignFailure {
+    pub seed: u64,
+    /// The message the run panicked with.
+    pub message: String,
+    /// Set when the seed was run again and did not fail the same way, so the failure cannot
+    /// be replayed from the seed.
+    pub nondeterministic: bool,
+}
+
+/// A snapshot of the progress of a campaign.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CampaignStatus {
+    /// Number of seeds the campaign runs.
+    pub total: u64,
+    /// Number of seeds which have been run, passing or failing.
+    pub completed: u64,
+    /// Seeds which failed so far, in the order they were run.
+    pub failures: Vec<CampaignFailure>,
+    /// Seed of the run in progress, if any.
+    pub seed: Option<u64>,
+    /// Simulated time elapsed in the run in progress.
+    pub simulated: Option<time::Duration>,
+    /// Faults in effect in the run in progress, such as hosts which were killed and have not
+    /// been booted again.
+    pub active_faults: Vec<String>,
+    /// Real time elapsed since the campaign started running.
+    pub elapsed: time::Duration,
+}
+
+/// The run in progress.
+#[derive(Debug)]
+struct Current {
+    seed: u64,
+    time: TimeReader,
+    chaos_log: DeterministicChaosLogHandle,
+}
+
+#[derive(Debug)]
+struct State {
+    total: u64,
+    completed: u64,
+    failures: Vec<CampaignFailure>,
+    current: Option<Current>,
+    started: time::Instant,
+}
+
+/// Handle for reading the progress of a campaign, from any thread.
+#[derive(Debug, Clone)]
+pub struct CampaignProgress {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl CampaignProgress {
+    /// Returns the progress of the campaign now.
+    pub fn status(&self) -> CampaignStatus {
+        let state = self.state.lock().unwrap();
+        let current = state.current.as_ref();
+        CampaignStatus {
+            total: state.total,
+            completed: state.completed,
+            failures: state.failures.clone(),
+            seed: current.map(|current| current.seed),
+            simulated: current.map(|current| current.time.elapsed()),
+            active_faults: current
+                .map(|current| active_faults(&current.chaos_log))
+                .unwrap_or_default(),
+            elapsed: escape::unguarded(|| state.started.elapsed()),
+        }
+    }
+}
+
+/// Returns the hosts which were killed and have not been booted again.
+fn active_faults(chaos_log: &DeterministicChaosLogHandle) -> Vec<String> {
+    let mut down = collections::BTreeSet::<net::IpAddr>::new();
+    for action in chaos_log.actions() {
+        match (action.kind, action.target) {
+            (ChaosKind::Kill, ChaosTarget::Host(addr)) => {
+                down.insert(addr);
+            }
+            (ChaosKind::Boot, ChaosTarget::Host(addr)) => {
+                down.remove(&addr);
+            }
+            _ => {}
+        }
+    }
+    down.into_iter()
+        .map(|addr| format!("{} killed", addr))
+        .collect()
+}
+
+/// Runs a test once for each seed in a range.
+#[derive(Debug)]
+pub struct Campaign {
+    seeds: ops::Range<u64>,
+    progress: CampaignProgress,
+    rerun: bool,
+    #[cfg(feature = "tui")]
+    monitor: Option<time::Duration>,
+}
+
+impl Campaign {
+    /// Create a campaign running each seed in `seeds`.
+    pub fn new(seeds: ops::Range<u64>) -> Self {
+        let state = State {
+            total: seeds.end.saturating_sub(seeds.start),
+            completed: 0,
+            failures: vec![],
+            current: None,
+            started: time::Instant::now(),
+        };
+        Self {
+            seeds,
+            progress: CampaignProgress {
+                state: sync::Arc::new(sync::Mutex::new(state)),
+            },
+            rerun: false,
+            #[cfg(feature = "tui")]
+            monitor: None,
+        }
+    }
+
+    /// Draw the progress of the campaign to the terminal every `refresh` while it runs.
+    #[cfg(feature = "tui")]
+    pub fn monitor(mut self, refresh: time::Duration) -> Self {
+        self.monitor = Some(refresh);
+        self
+    }
+
+    /// Run each failing seed a second time before reporting it, flagging the failure as
+    /// nondeterministic unless the rerun fails with the same message and executes the same way.
+    pub fn rerun_failures(mut self) -> Self {
+        self.rerun = true;
+        self
+    }
+
+    /// Returns a handle for reading the progress of the campaign while it runs.
+    pub fn progress(&self) -> CampaignProgress {
+        self.progress.clone()
+    }
+
+    /// Run `test` against a runtime seeded with each seed in turn, returning the seeds whose
+    /// run panicked.
+    pub fn run<F>(self, mut test: F) -> Vec<CampaignFailure>
+    where
+        F: FnMut(&mut DeterministicRuntime),
+    {
+        self.progress.state.lock().unwrap().started = time::Instant::now();
+        #[cfg(feature = "tui")]
+        let _monitor = self
+            .monitor
+            .map(|refresh| super::monitor::Monitor::start(self.progress(), refresh));
+        for seed in self.seeds.clone() {
+            let (message, fingerprint) = self.run_seed(seed, &mut test);
+            let failure = message.map(|message| {
+                let nondeterministic = self.rerun
+                    && self.run_seed(seed, &mut test) != (Some(message.clone()), fingerprint);
+                CampaignFailure {
+                    seed,
+                    message,
+                    nondeterministic,
+                }
+            });
+            let mut state = self.progress.state.lock().unwrap();
+            state.completed += 1;
+            state.failures.extend(failure);
+        }
+        let state = self.progress.state.lock().unwrap();
+        state.failures.clone()
+    }
+
+    /// Run `test` against a fresh runtime seeded with `seed`, returning the message it panicked
+    /// with, if it did, and the fingerprint of the run.
+    fn run_seed<F>(&self, seed: u64, test: &mut F) -> (Option<String>, u64)
+    where
+        F: FnMut(&mut DeterministicRuntime),
+    {
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .seed(seed)
+            .build()
+            .expect("failed to build runtime");
+        self.progress.state.lock().unwrap().current = Some(Current {
+            seed,
+            time: handle.time_reader(),
+            chaos_log: handle.chaos_log_handle(),
+        });
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| test(&mut runtime)));
+        self.progress.state.lock().unwrap().current = None;
+        let message = result.err().map(|payload| dump::panic_message(&*payload));
+        (message, runtime.fingerprint())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TimeEnv;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that every seed is run, that failing seeds are collected, and that the progress
+    /// shows the time and faults of the run in progress.
+    fn campaign() {
+        let campaign = Campaign::new(10..15);
+        let progress = campaign.progress();
+        let failures = campaign.run(|runtime| {
+            let handle = runtime.localhost_handle();
+            let seed = runtime.seed();
+            let progress = progress.clone();
+            runtime
+                .block_on(async move {
+                    handle.delay_from(Duration::from_secs(seed)).await;
+                    handle.kill(net::Ipv4Addr::new(10, 0, 0, 1).into());
+                    let status = progress.status();
+                    assert_eq!(status.seed, Some(seed));
+                    assert_eq!(status.completed, seed - 10);
+                    assert_eq!(status.simulated, Some(Duration::from_secs(seed)));
+                    assert_eq!(status.active_faults, vec!["10.0.0.1 killed".to_string()]);
+                    assert!(seed % 2 == 0, "odd seed");
+                })
+                .unwrap();
+        });
+        assert_eq!(
+            failures,
+            vec![
+                CampaignFailure {
+                    seed: 11,
+                    message: "odd seed".to_string(),
+                    nondeterministic: false,
+                },
+                CampaignFailure {
+                    seed: 13,
+                    message: "odd seed".to_string(),
+                    nondeterministic: false,
+                },
+            ]
+        );
+        let status = progress.status();
+        assert_eq!((status.total, status.completed), (5, 5));
+        assert_eq!(status.failures, failures);
+        assert_eq!(status.seed, None);
+        assert!(status.active_faults.is_empty());
+    }
+
+    #[test]
+    /// Test that failing seeds are run again, and flagged as nondeterministic unless the rerun
+    /// fails the same way, while passing seeds are run once.
+    fn rerun_failures() {
+        let runs = sync::Arc::new(sync::Mutex::new(collections::BTreeMap::<u64, u64>::new()));
+        let counted = sync::Arc::clone(&runs);
+        let failures = Campaign::new(0..4).rerun_failures().run(move |runtime| {
+            let handle = runtime.localhost_handle();
+            let seed = runtime.seed();
+            let run = {
+                let mut runs = counted.lock().unwrap();
+                let run = runs.entry(seed).or_insert(0);
+                *run += 1;
+                *run
+            };
+            runtime
+                .block_on(async move {
+                    match seed {
+                        1 => panic!("fails every run"),
+                        2 => assert!(run > 1, "fails the first run"),
+                        3 => {
+                            handle.delay_from(Duration::from_secs(run)).await;
+                            panic!("fails every run, after a different delay");
+                        }
+                        _ => {}
+                    }
+                })
+                .unwrap();
+        });
+        let flagged: Vec<_> = failures
+            .iter()
+            .map(|failure| (failure.seed, failure.nondeterministic))
+            .collect();
+        assert_eq!(flagged, vec![(1, false), (2, true), (3, true)]);
+        let runs: Vec<_> = runs.lock().unwrap().clone().into_iter().collect();
+        assert_eq!(runs, vec![(0, 1), (1, 2), (2, 2), (3, 2)]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Faults injected into channels created through the deterministic runtime.
+//!
+//! Each message received over a channel created by a [`DeterministicRuntimeHandle`] is held
+//! back for a delay sampled from the configured range, and messages received over lossy
+//! channels are dropped with the configured probability. Both are driven by the
+//! deterministic source of randomness, so the resulting interleavings are reproducible from
+//! the seed. Faults can be changed mid-run, and apply to channels which already exist.
+//!
+//! [`DeterministicRuntimeHandle`]:crate::deterministic::DeterministicRuntimeHandle
+use crate::{
+    deterministic::{DeterministicRandomHandle, DeterministicTimeHandle},
+    sync::{BoxDelay, Faults},
+};
+use std::{ops, sync, time};
+use tracing::trace;
+
+#[derive(Debug)]
+struct Inner {
+    time: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+    delay: Option<ops::Range<time::Duration>>,
+    drop_probability: f64,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeterministicChannels {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicChannels {
+    pub(crate) fn new(time: DeterministicTimeHandle, random: DeterministicRandomHandle) -> Self {
+        let inner = Inner {
+            time,
+            random,
+            delay: None,
+            drop_probability: 0.0,
+        };
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        Self { inner }
+    }
+
+    pub(crate) fn handle(&self) -> DeterministicChannelHandle {
+        let inner = sync::Arc::clone(&self.inner);
+        DeterministicChannelHandle { inner }
+    }
+}
+
+/// Handle for injecting faults into channels.
+#[derive(Debug, Clone)]
+pub struct DeterministicChannelHandle {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicChannelHandle {
+    /// Hold back each message received by a duration sampled from `range`.
+    pub fn set_delay(&self, range: ops::Range<time::Duration>) {
+        self.inner.lock().unwrap().delay.replace(range);
+    }
+
+    /// Drop messages received over lossy channels with the provided probability.
+    pub fn set_drop_probability(&self, probability: f64) {
+        self.inner.lock().unwrap().drop_probability = probability;
+    }
+
+    /// Remove all injected channel faults.
+    pub fn clear_faults(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.delay.take();
+        lock.drop_probability = 0.0;
+    }
+
+    pub(crate) fn faults(&self) -> sync::Arc<dyn Faults> {
+        sync::Arc::new(self.clone())
+    }
+}
+
+impl Faults for DeterministicChannelHandle {
+    fn delay(&self) -> Option<BoxDelay> {
+        let lock = self.inner.lock().unwrap();
+        let delay = lock.random.gen_range(lock.delay.clone()?);
+        trace!("delaying channel message by {:?}", delay);
+        Some(Box::pin(lock.time.delay_from(delay)))
+    }
+
+    fn should_drop(&self) -> bool {
+        let lock = self.inner.lock().unwrap();
+        if lock.drop_probability > 0.0 && lock.random.should_fault(lock.drop_probability) {
+            trace!("dropping channel message");
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, SpawnEnv};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that messages are delivered in order after the injected delay, and that only lossy
+    /// channels drop them.
+    fn channel_faults() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(5).build().unwrap();
+        runtime
+            .block_on(async {
+                let channels = handle.channel_handle();
+                channels.set_delay(Duration::from_millis(10)..Duration::from_millis(20));
+                let (mut tx, mut rx) = handle.channel(10);
+                let start = handle.now();
+                for i in 0..5 {
+                    tx.send(i).await.unwrap();
+                }
+                drop(tx);
+                for i in 0..5 {
+                    assert_eq!(rx.recv().await, Some(i));
+                }
+                assert_eq!(rx.recv().await, None);
+                assert!(handle.now() - start >= Duration::from_millis(50));
+
+                let (tx, rx) = handle.oneshot();
+                tx.send("ping").unwrap();
+                assert_eq!(rx.await, Ok("ping"));
+
+                channels.clear_faults();
+                channels.set_drop_probability(0.5);
+                let (tx, mut rx) = handle.unbounded_channel();
+                let (lossy_tx, mut lossy_rx) = handle.lossy_channel();
+                for i in 0..100 {
+                    tx.send(i).unwrap();
+                    lossy_tx.send(i).unwrap();
+                }
+                drop((tx, lossy_tx));
+                let mut received = 0;
+                while rx.recv().await.is_some() {
+                    received += 1;
+                }
+                assert_eq!(received, 100);
+                let mut received = 0;
+                while lossy_rx.recv().await.is_some() {
+                    received += 1;
+                }
+                assert!(received > 0 && received < 100);
+
+                let (tx, mut rx1) = handle.broadcast();
+                let mut rx2 = tx.subscribe();
+                assert_eq!(tx.send(1), 2);
+                assert_eq!(rx1.recv().await, Some(1));
+                assert_eq!(rx2.recv().await, Some(1));
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+//! Log of every chaos action taken during a simulation run.
+//!
+//! Fault injectors and the cluster harness record each action they take, such as killing a
+//! host or adjusting network latency, along with the simulated time and scenario phase at
+//! which it happened. Tests can then query the log to make assertions which are conditional
+//! on the faults that were actually injected, for example that no data was lost even though
+//! a particular host was killed twice during the chaos phase.
+//!
+//! Errors which simulated sockets return because of a chaos action carry an [`InjectedFault`]
+//! identifying the action, so tests can check that the application handled the fault which
+//! was injected rather than an unrelated error.
+//!
+//! [`InjectedFault`]:InjectedFault
+use crate::deterministic::{DeterministicTimeHandle, EventKind, EventLog, Phase, TimelineKind};
+use std::{error, fmt, io, mem, net, sync, time};
+use tracing::trace;
+
+/// The kind of action taken by a fault injector.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChaosKind {
+    /// A host was killed, cancelling all of its tasks and connections.
+    Kill,
+    /// A host was booted again after being killed.
+    Boot,
+    /// A new host joined the cluster.
+    Join,
+    /// A host was retired from the cluster.
+    Retire,
+    /// Latency across network connections was adjusted.
+    Latency,
+    /// An external service responded with an error.
+    ServiceError,
+    /// An external service dropped a connection.
+    ServiceDrop,
+    /// An external service stopped responding to a request.
+    ServiceHang,
+    /// An action recorded by a user supplied fault injector.
+    Custom(String),
+}
+
+/// The target of a chaos action.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChaosTarget {
+    Host(net::IpAddr),
+    Network,
+    Service(String),
+}
+
+/// A single chaos action.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosAction {
+    /// Identifies the action, numbering actions in the order they were taken.
+    pub id: u64,
+    /// Simulated time at which the action was taken.
+    pub at: time::Instant,
+    /// Scenario phase during which the action was taken, if any.
+    pub phase: Option<Phase>,
+    pub kind: ChaosKind,
+    pub target: ChaosTarget,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeterministicChaosLog {
+    time: DeterministicTimeHandle,
+    phase: sync::Arc<sync::Mutex<Option<Phase>>>,
+    actions: sync::Arc<sync::Mutex<Vec<ChaosAction>>>,
+    events: EventLog,
+}
+
+impl DeterministicChaosLog {
+    pub(crate) fn new(
+        time: DeterministicTimeHandle,
+        phase: sync::Arc<sync::Mutex<Option<Phase>>>,
+        events: EventLog,
+    ) -> Self {
+        Self {
+            time,
+            phase,
+            actions: sync::Arc::new(sync::Mutex::new(vec![])),
+            events,
+        }
+    }
+
+    pub(crate) fn handle(&self) -> DeterministicChaosLogHandle {
+        DeterministicChaosLogHandle {
+            time: self.time.clone(),
+            phase: sync::Arc::clone(&self.phase),
+            actions: sync::Arc::clone(&self.actions),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Handle for recording and querying chaos actions.
+#[derive(Debug, Clone)]
+pub struct DeterministicChaosLogHandle {
+    time: DeterministicTimeHandle,
+    phase: sync::Arc<sync::Mutex<Option<Phase>>>,
+    actions: sync::Arc<sync::Mutex<Vec<ChaosAction>>>,
+    events: EventLog,
+}
+
+impl DeterministicChaosLogHandle {
+    /// Record that an action of `kind` was taken against `target` at the current time,
+    /// returning the id of the action.
+    pub fn record(&self, kind: ChaosKind, target: ChaosTarget) -> u64 {
+        let mut actions = self.actions.lock().unwrap();
+        let action = ChaosAction {
+            id: actions.len() as u64,
+            at: self.time.now(),
+            phase: *self.phase.lock().unwrap(),
+            kind,
+            target,
+        };
+        trace!("chaos {:?} on {:?}", action.kind, action.target);
+        self.events.record_with(|| {
+            EventKind::Timeline(TimelineKind::Fault {
+                kind: format!("{:?}", action.kind),
+                target: format!("{:?}", action.target),
+            })
+        });
+        let id = action.id;
+        actions.push(action);
+        id
+    }
+
+    /// Record that an action of `kind` was taken against `target`, returning the fault to
+    /// attach to errors caused by it.
+    pub(crate) fn inject(&self, kind: ChaosKind, target: ChaosTarget) -> InjectedFault {
+        let id = self.record(kind.clone(), target);
+        InjectedFault { id, kind }
+    }
+
+    /// Returns every action recorded so far, in the order they were taken.
+    pub fn actions(&self) -> Vec<ChaosAction> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    /// Returns a query over every action recorded so far.
+    pub fn query(&self) -> ChaosQuery {
+        ChaosQuery {
+            actions: self.actions(),
+        }
+    }
+
+    /// Returns the approximate number of bytes held by the recorded actions.
+    pub(crate) fn memory(&self) -> usize {
+        self.actions.lock().unwrap().len() * mem::size_of::<ChaosAction>()
+    }
+}
+
+/// Filters over a snapshot of the chaos log. Each filter narrows the set of matching actions.
+#[derive(Debug, Clone)]
+pub struct ChaosQuery {
+    actions: Vec<ChaosAction>,
+}
+
+impl ChaosQuery {
+    /// Only match actions of `kind`.
+    pub fn kind(self, kind: ChaosKind) -> Self {
+        self.filter(|action| action.kind == kind)
+    }
+
+    /// Only match actions against `target`.
+    pub fn target(self, target: ChaosTarget) -> Self {
+        self.filter(|action| action.target == target)
+    }
+
+    /// Only match actions against the host `addr`.
+    pub fn host(self, addr: net::IpAddr) -> Self {
+        self.target(ChaosTarget::Host(addr))
+    }
+
+    /// Only match actions taken during `phase`.
+    pub fn phase(self, phase: Phase) -> Self {
+        self.filter(|action| action.phase == Some(phase))
+    }
+
+    /// Only match actions taken at or after `start`, and before `end`.
+    pub fn between(self, start: time::Instant, end: time::Instant) -> Self {
+        self.filter(|action| action.at >= start && action.at < end)
+    }
+
+    fn filter<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ChaosAction) -> bool,
+    {
+        self.actions.retain(|action| f(action));
+        self
+    }
+
+    /// Returns the number of matching actions.
+    pub fn count(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Returns true if no actions match.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Returns the matching actions, in the order they were taken.
+    pub fn actions(self) -> Vec<ChaosAction> {
+        self.actions
+    }
+}
+
+/// Marks an error as caused by a chaos action, rather than by the application or the
+/// simulator. Simulated sockets return it wrapped in an `io::Error`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InjectedFault {
+    /// Id of the action in the chaos log.
+    pub id: u64,
+    pub kind: ChaosKind,
+}
+
+impl InjectedFault {
+    /// Returns the fault which caused `error`, searching its chain of sources, or `None` if it
+    /// was not caused by an injected fault.
+    pub fn find<'a>(error: &'a (dyn error::Error + 'static)) -> Option<&'a InjectedFault> {
+        let mut next = Some(error);
+        while let Some(error) = next {
+            if let Some(fault) = error.downcast_ref::<InjectedFault>() {
+                return Some(fault);
+            }
+            // the source of an io::Error skips the error it wraps, so check that first.
+            let wrapped = error
+                .downcast_ref::<io::Error>()
+                .and_then(|error| error.get_ref())
+                .and_then(|error| error.downcast_ref::<InjectedFault>());
+            if wrapped.is_some() {
+                return wrapped;
+            }
+            next = error.source();
+        }
+        None
+    }
+
+    /// Returns an io error of `kind` caused by this fault.
+    pub(crate) fn into_io(self, kind: io::ErrorKind) -> io::Error {
+        io::Error::new(kind, self)
+    }
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "injected {:?} fault #{}", self.kind, self.id)
+    }
+}
+
+impl error::Error for InjectedFault {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{
+        Cluster, DeterministicRuntime, DeterministicRuntimeHandle, Scenario,
+    };
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::{net, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn idle(handle: DeterministicRuntimeHandle) {
+        handle.delay_from(Duration::from_secs(3600)).await;
+    }
+
+    #[test]
+    /// Test that host faults are recorded with the phase they were injected in.
+    fn record_and_query() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                let mut hosts = vec![];
+                for _ in 0..3 {
+                    hosts.push(cluster.add_host(idle).await.local_addr());
+                }
+                let chaos = cluster.clone();
+                let node = hosts[2];
+                Scenario::new()
+                    .phase(Phase::Setup, Duration::from_secs(10))
+                    .phase(Phase::Chaos, Duration::from_secs(60))
+                    .fault(async move {
+                        for _ in 0..2 {
+                            chaos.handle(node).delay_from(Duration::from_secs(10)).await;
+                            chaos.restart_host(node);
+                        }
+                    })
+                    .phase(Phase::Verification, Duration::from_secs(10))
+                    .run(&handle)
+                    .await
+                    .unwrap();
+                cluster.retire_host(hosts[0]).await;
+
+                let log = handle.chaos_log_handle();
+                assert_eq!(log.query().kind(ChaosKind::Join).count(), 3);
+                let kills = log.query().kind(ChaosKind::Kill).host(node);
+                assert_eq!(kills.count(), 2);
+                assert_eq!(kills.clone().phase(Phase::Chaos).count(), 2);
+                assert!(kills.phase(Phase::Setup).is_empty());
+                assert_eq!(log.query().kind(ChaosKind::Boot).host(node).count(), 2);
+                assert_eq!(log.query().host(hosts[0]).actions().len(), 2);
+                assert_eq!(
+                    log.actions().last().map(|action| action.kind.clone()),
+                    Some(ChaosKind::Retire)
+                );
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that errors caused by killing a host carry the fault recorded in the chaos log.
+    fn injected_fault() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let server = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                server.spawn(async move {
+                    let (_socket, _) = listener.accept().await.unwrap();
+                    futures::future::pending::<()>().await;
+                });
+                let mut socket = handle.connect(addr).await.unwrap();
+                socket.write_all(b"ping").await.unwrap();
+                handle.kill(addr.ip());
+
+                let err = socket.read(&mut [0; 4]).await.unwrap_err();
+                let fault = InjectedFault::find(&err).unwrap().clone();
+                assert_eq!(fault.kind, ChaosKind::Kill);
+                let kill = handle
+                    .chaos_log_handle()
+                    .query()
+                    .kind(ChaosKind::Kill)
+                    .actions()[0]
+                    .clone();
+                assert_eq!(fault.id, kill.id);
+
+                let err = crate::Error::SimClient {
+                    name: String::from("client"),
+                    source: Box::new(err),
+                };
+                assert_eq!(err.injected_fault(), Some(&fault));
+                assert_eq!(
+                    InjectedFault::find(&io::Error::from(io::ErrorKind::Other)),
+                    None
+                );
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+//! Harness for changing the set of simulated hosts mid-simulation.
+//!
+//! A `Cluster` allocates addresses for new hosts, boots a process on each of them, and retires
+//! hosts once they are no longer needed. Applications typically run a join protocol when a
+//! host is added, and drain work away from a host before it is removed. These steps are
+//! supplied as hooks, which the cluster runs as part of adding and retiring hosts.
+//!
+//! Lifecycle hooks run around the process on each host rather than as part of the cluster's
+//! membership changes. They allow tests to seed durable state before a process boots, capture
+//! metrics when a process crashes and verify cleanup once a process has shut down, without
+//! adding that logic to the application under test.
+use crate::{
+    deterministic::{ChaosKind, ChaosTarget, DeterministicRuntimeHandle},
+    SpawnEnv,
+};
+use futures::Future;
+use std::{collections, fmt, net, pin::Pin, sync};
+use tracing::debug;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+type Hook = sync::Arc<dyn Fn(DeterministicRuntimeHandle) -> BoxFuture + Send + Sync>;
+type Callback = sync::Arc<dyn Fn(DeterministicRuntimeHandle) + Send + Sync>;
+
+fn hook<F, U>(f: F) -> Hook
+where
+    F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+    U: Future<Output = ()> + Send + 'static,
+{
+    sync::Arc::new(move |handle| Box::pin(f(handle)))
+}
+
+struct Host {
+    boot: Hook,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_host: u32,
+    hosts: collections::BTreeMap<net::IpAddr, Host>,
+    on_join: Option<Hook>,
+    on_leave: Option<Hook>,
+    on_boot: Option<Hook>,
+    on_crash: Option<Callback>,
+    on_shutdown: Option<Callback>,
+}
+
+/// A dynamic set of simulated hosts.
+#[derive(Clone)]
+pub struct Cluster {
+    handle: DeterministicRuntimeHandle,
+    subnet: net::Ipv4Addr,
+    prefix_len: u8,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hosts: Vec<net::IpAddr> = self.hosts();
+        write!(
+            f,
+            "Cluster {{ subnet: {}/{}, hosts: {:?} }}",
+            self.subnet, self.prefix_len, hosts
+        )
+    }
+}
+
+impl Cluster {
+    /// Create a new cluster which allocates host addresses from `10.0.0.0/8`.
+    pub fn new(handle: DeterministicRuntimeHandle) -> Self {
+        Cluster::new_with_subnet(handle, net::Ipv4Addr::new(10, 0, 0, 0), 8)
+    }
+
+    /// Create a new cluster which allocates host addresses sequentially from the subnet of
+    /// `subnet` with a prefix of `prefix_len` bits, starting after `subnet`.
+    ///
+    /// If the runtime was built with a fault plan which no other cluster has taken, the plan
+    /// starts running against this cluster.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix_len` is greater than 32.
+    pub fn new_with_subnet(
+        handle: DeterministicRuntimeHandle,
+        subnet: net::Ipv4Addr,
+        prefix_len: u8,
+    ) -> Self {
+        assert!(prefix_len <= 32, "invalid prefix length {}", prefix_len);
+        let cluster = Self {
+            handle,
+            subnet,
+            prefix_len,
+            inner: sync::Arc::new(sync::Mutex::new(Inner::default())),
+        };
+        if let Some(plan) = cluster.handle.take_fault_plan() {
+            let run = plan.run(cluster.clone());
+            cluster.handle.spawn(async move {
+                // the plan was checked when the runtime was built.
+                run.await
+                    .expect("fault plan does not fit in simulated time");
+            });
+        }
+        cluster
+    }
+
+    /// Set the hook run after a new host has booted, used to drive the application's join
+    /// protocol. The hook is passed the handle for the new host.
+    pub fn on_join<F, U>(&self, f: F)
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.lock().unwrap().on_join.replace(hook(f));
+    }
+
+    /// Set the hook run before a host is retired, used to drain work from the host. The hook
+    /// is passed the handle for the retiring host.
+    pub fn on_leave<F, U>(&self, f: F)
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.lock().unwrap().on_leave.replace(hook(f));
+    }
+
+    /// Set the hook run each time a host boots, before its process is started. The hook is
+    /// passed the handle for the booting host.
+    pub fn on_boot<F, U>(&self, f: F)
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.lock().unwrap().on_boot.replace(hook(f));
+    }
+
+    /// Set the callback run immediately after a host is killed. The callback is passed the
+    /// handle for the killed host.
+    pub fn on_crash<F>(&self, f: F)
+    where
+        F: Fn(DeterministicRuntimeHandle) + Send + Sync + 'static,
+    {
+        self.inner
+            .lock()
+            .unwrap()
+            .on_crash
+            .replace(sync::Arc::new(f));
+    }
+
+    /// Set the callback run after a retired host's process has been stopped. The callback is
+    /// passed the handle for the retired host.
+    pub fn on_shutdown<F>(&self, f: F)
+    where
+        F: Fn(DeterministicRuntimeHandle) + Send + Sync + 'static,
+    {
+        self.inner
+            .lock()
+            .unwrap()
+            .on_shutdown
+            .replace(sync::Arc::new(f));
+    }
+
+    /// Returns the addresses of all hosts which are currently part of the cluster.
+    pub fn hosts(&self) -> Vec<net::IpAddr> {
+        self.inner.lock().unwrap().hosts.keys().cloned().collect()
+    }
+
+    /// Returns a handle scoped to the host `addr`.
+    pub fn handle(&self, addr: net::IpAddr) -> DeterministicRuntimeHandle {
+        self.handle.scoped(addr)
+    }
+
+    /// Allocate an address for a new host, spawn the process returned by `boot` on it and run
+    /// the join hook. Returns the handle for the new host.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every address of the subnet has been allocated. The last address of the
+    /// subnet is its broadcast address, and is never allocated.
+    pub async fn add_host<F, U>(&self, boot: F) -> DeterministicRuntimeHandle
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        let (addr, boot, on_join) = {
+            let mut lock = self.inner.lock().unwrap();
+            let offset = lock.next_host + 1;
+            let size = 1u64 << (32 - self.prefix_len);
+            let addr = u32::from(self.subnet)
+                .checked_add(offset)
+                .filter(|_| u64::from(offset) + 1 < size)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "no addresses left in {}/{} for a new host",
+                        self.subnet, self.prefix_len
+                    )
+                });
+            lock.next_host = offset;
+            let addr = net::Ipv4Addr::from(addr).into();
+            let boot = hook(boot);
+            lock.hosts.insert(
+                addr,
+                Host {
+                    boot: sync::Arc::clone(&boot),
+                },
+            );
+            (addr, boot, lock.on_join.clone())
+        };
+        debug!("adding host {}", addr);
+        self.handle
+            .chaos_log_handle()
+            .record(ChaosKind::Join, ChaosTarget::Host(addr));
+        let handle = self.spawn_process(addr, boot);
+        if let Some(on_join) = on_join {
+            on_join(handle.clone()).await;
+        }
+        handle
+    }
+
+    /// Spawn the process returned by `boot` on `addr`, preceded by the boot hook.
+    fn spawn_process(&self, addr: net::IpAddr, boot: Hook) -> DeterministicRuntimeHandle {
+        let on_boot = self.inner.lock().unwrap().on_boot.clone();
+        let handle = self.handle(addr);
+        let on_boot = on_boot.map(|on_boot| on_boot(handle.clone()));
+        let process = boot(handle.clone());
+        handle.spawn(async move {
+            if let Some(on_boot) = on_boot {
+                on_boot.await;
+            }
+            process.await;
+        });
+        handle
+    }
+
+    /// Kill the process running on `addr`, leaving the host as a member of the cluster. The
+    /// crash callback is run once the process has been killed.
+    pub fn kill_host(&self, addr: net::IpAddr) {
+        debug!("killing host {}", addr);
+        self.handle.kill(addr);
+        let on_crash = self.inner.lock().unwrap().on_crash.clone();
+        if let Some(on_crash) = on_crash {
+            on_crash(self.handle(addr));
+        }
+    }
+
+    /// Boot the process for `addr` again, typically after it has been killed.
+    pub fn boot_host(&self, addr: net::IpAddr) {
+        let boot = match self.inner.lock().unwrap().hosts.get(&addr) {
+            Some(host) => sync::Arc::clone(&host.boot),
+            None => panic!("host {} is not a member of the cluster", addr),
+        };
+        debug!("booting host {}", addr);
+        self.handle
+            .chaos_log_handle()
+            .record(ChaosKind::Boot, ChaosTarget::Host(addr));
+        self.spawn_process(addr, boot);
+    }
+
+    /// Kill the process running on `addr` and immediately boot it again.
+    pub fn restart_host(&self, addr: net::IpAddr) {
+        self.kill_host(addr);
+        self.boot_host(addr);
+    }
+
+    /// Run the leave hook for `addr`, then kill the host and remove it from the cluster. The
+    /// shutdown callback is run once the host's process has been stopped.
+    pub async fn retire_host(&self, addr: net::IpAddr) {
+        let on_leave = self.inner.lock().unwrap().on_leave.clone();
+        debug!("retiring host {}", addr);
+        if let Some(on_leave) = on_leave {
+            on_leave(self.handle(addr)).await;
+        }
+        self.inner.lock().unwrap().hosts.remove(&addr);
+        self.handle
+            .chaos_log_handle()
+            .record(ChaosKind::Retire, ChaosTarget::Host(addr));
+        self.handle.stop(addr);
+        let on_shutdown = self.inner.lock().unwrap().on_shutdown.clone();
+        if let Some(on_shutdown) = on_shutdown {
+            on_shutdown(self.handle(addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+
+    async fn server(handle: DeterministicRuntimeHandle) {
+        let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+        let mut listener = handle.bind(addr).await.unwrap();
+        while let Ok((_socket, _)) = listener.accept().await {}
+    }
+
+    #[test]
+    /// Test that hosts can join and leave, running the application's hooks.
+    fn join_and_retire() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                cluster.on_join(|handle| async move {
+                    let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+                    handle.discovery_handle().register("server", addr);
+                });
+                cluster.on_leave(|handle| async move {
+                    let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+                    handle.discovery_handle().deregister("server", addr);
+                    handle.delay_from(Duration::from_secs(5)).await;
+                });
+                let host1 = cluster.add_host(server).await;
+                let host2 = cluster.add_host(server).await;
+                assert_eq!(
+                    host1.local_addr(),
+                    net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, 1))
+                );
+                assert_eq!(
+                    cluster.hosts(),
+                    vec![host1.local_addr(), host2.local_addr()]
+                );
+                assert_eq!(handle.discovery_handle().lookup("server").len(), 2);
+
+                handle.delay_from(Duration::from_secs(1)).await;
+                assert_eq!(handle.task_count(host1.local_addr()), 1);
+                cluster.retire_host(host1.local_addr()).await;
+                assert_eq!(cluster.hosts(), vec![host2.local_addr()]);
+                assert_eq!(handle.task_count(host1.local_addr()), 0);
+                assert_eq!(
+                    handle.discovery_handle().lookup("server"),
+                    vec![net::SocketAddr::new(host2.local_addr(), 9092)]
+                );
+
+                // the retired address can be reused by a new process.
+                let rebound = handle.scoped(host1.local_addr());
+                handle.delay_from(Duration::from_secs(1)).await;
+                let addr = net::SocketAddr::new(host1.local_addr(), 9092);
+                assert!(rebound.bind(addr).await.is_ok());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "no addresses left in 10.0.0.0/30")]
+    /// Test that adding more hosts than the subnet has addresses for panics.
+    fn subnet_exhausted() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new_with_subnet(handle, net::Ipv4Addr::new(10, 0, 0, 0), 30);
+                let host1 = cluster.add_host(server).await;
+                let host2 = cluster.add_host(server).await;
+                assert_eq!(
+                    vec![host1.local_addr(), host2.local_addr()],
+                    vec![
+                        net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, 1)),
+                        net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, 2))
+                    ]
+                );
+                cluster.add_host(server).await;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that lifecycle hooks run around each boot, crash and shutdown of a host.
+    fn lifecycle_hooks() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let seeded = sync::Arc::new(sync::Mutex::new(collections::BTreeSet::new()));
+                let events = sync::Arc::new(sync::Mutex::new(vec![]));
+                let cluster = Cluster::new(handle.clone());
+
+                let boot_seeded = sync::Arc::clone(&seeded);
+                cluster.on_boot(move |handle| {
+                    let seeded = sync::Arc::clone(&boot_seeded);
+                    async move {
+                        handle.delay_from(Duration::from_secs(1)).await;
+                        seeded.lock().unwrap().insert(handle.local_addr());
+                    }
+                });
+                let crash_events = sync::Arc::clone(&events);
+                cluster.on_crash(move |handle| {
+                    let tasks = handle.task_count(handle.local_addr());
+                    crash_events.lock().unwrap().push(("crash", tasks));
+                });
+                let shutdown_events = sync::Arc::clone(&events);
+                cluster.on_shutdown(move |handle| {
+                    let tasks = handle.task_count(handle.local_addr());
+                    shutdown_events.lock().unwrap().push(("shutdown", tasks));
+                });
+
+                let boot_seeded = sync::Arc::clone(&seeded);
+                let boot_events = sync::Arc::clone(&events);
+                let host = cluster
+                    .add_host(move |handle: DeterministicRuntimeHandle| {
+                        let seeded = sync::Arc::clone(&boot_seeded);
+                        let events = sync::Arc::clone(&boot_events);
+                        async move {
+                            assert!(seeded.lock().unwrap().contains(&handle.local_addr()));
+                            events.lock().unwrap().push(("boot", 1));
+                            server(handle).await;
+                        }
+                    })
+                    .await;
+                handle.delay_from(Duration::from_secs(5)).await;
+                cluster.restart_host(host.local_addr());
+                handle.delay_from(Duration::from_secs(5)).await;
+                cluster.retire_host(host.local_addr()).await;
+                assert_eq!(
+                    *events.lock().unwrap(),
+                    vec![("boot", 1), ("crash", 0), ("boot", 1), ("shutdown", 0)]
+                );
+            })
+            .unwrap();
+    }
+}
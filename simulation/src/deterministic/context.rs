@@ -0,0 +1,123 @@
+//! Tracking of the simulated host the current task is running on behalf of.
+//!
+//! Every task spawned through a [`DeterministicRuntimeHandle`] is polled with that handle set
+//! as the current context, as is the future passed to [`DeterministicRuntime::block_on`] and
+//! the closure passed to [`DeterministicRuntime::enter`]. This allows code which is not passed
+//! an [`Environment`] to find the simulation it is running in.
+//!
+//! [`DeterministicRuntimeHandle`]:crate::deterministic::DeterministicRuntimeHandle
+//! [`DeterministicRuntime::block_on`]:crate::deterministic::DeterministicRuntime::block_on
+//! [`DeterministicRuntime::enter`]:crate::deterministic::DeterministicRuntime::enter
+//! [`Environment`]:crate::Environment
+use super::profile::{self, Subsystem};
+use super::DeterministicRuntimeHandle;
+use futures::Future;
+use std::{
+    cell::RefCell,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<DeterministicRuntimeHandle>> = const { RefCell::new(None) };
+}
+
+/// Returns the handle of the simulated host the current task is running on, if any.
+pub(crate) fn current() -> Option<DeterministicRuntimeHandle> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Restores the previous context when dropped, returning the handle which was set to `slot`.
+struct Reset<'a> {
+    slot: &'a mut Option<DeterministicRuntimeHandle>,
+    previous: Option<DeterministicRuntimeHandle>,
+}
+
+impl Drop for Reset<'_> {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        *self.slot = CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/// Run `f` with `handle` set as the current context.
+pub(crate) fn enter<F, R>(handle: DeterministicRuntimeHandle, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = CURRENT.with(|current| current.replace(Some(handle)));
+    let mut slot = None;
+    let _reset = Reset {
+        slot: &mut slot,
+        previous,
+    };
+    f()
+}
+
+/// A future which is polled with a handle set as the current context.
+pub(crate) struct Scoped<F> {
+    handle: Option<DeterministicRuntimeHandle>,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Scoped<F> {
+    pub(crate) fn new(handle: DeterministicRuntimeHandle, future: F) -> Self {
+        Self {
+            handle: Some(handle),
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl<F> Future for Scoped<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let previous = CURRENT.with(|current| current.replace(this.handle.take()));
+        let _reset = Reset {
+            slot: &mut this.handle,
+            previous,
+        };
+        let _span = profile::span(Subsystem::Tasks);
+        this.future.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use std::{net, time::Duration};
+
+    #[test]
+    /// Test that the free functions in the crate root act on behalf of the host whose context
+    /// they are called in.
+    fn ambient_context() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let addr: net::IpAddr = net::Ipv4Addr::new(10, 0, 0, 1).into();
+        let handle = runtime.handle(addr);
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        runtime.enter(addr, || {
+            crate::spawn(async move {
+                let start = crate::now();
+                crate::delay_for(Duration::from_secs(10)).await;
+                // tasks spawned from a task stay on the host of their parent.
+                crate::spawn(futures::future::pending());
+                let _ = done_tx.send(crate::now() - start);
+            })
+        });
+        assert_eq!(handle.task_count(addr), 1);
+        runtime
+            .block_on(async {
+                assert_eq!(done_rx.await.unwrap(), Duration::from_secs(10));
+                let timeout =
+                    crate::timeout(futures::future::pending::<()>(), Duration::from_secs(1));
+                assert!(timeout.await.is_err());
+            })
+            .unwrap();
+        assert_eq!(handle.task_count(addr), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Tracking of the file descriptors held by each simulated host.
+//!
+//! Every simulated file, socket and listener holds a descriptor allocated against the
+//! host which opened it, which is released when it is dropped. A limit can be placed on the
+//! number of descriptors each host may hold at once, after which opening a file, connecting,
+//! binding or accepting fails with `EMFILE`, as it would once a process exhausts its file
+//! descriptors. Each descriptor records the [`Resource`] it was allocated for, so the resources
+//! a host holds open can be listed.
+//!
+//! [`Resource`]:super::Resource
+use super::Resource;
+use std::{collections, io, net, sync};
+use tracing::trace;
+
+/// Error code returned when a host has too many open files.
+const EMFILE: i32 = 24;
+
+#[derive(Debug, Default)]
+struct Descriptors {
+    limit: Option<usize>,
+    open: collections::BTreeMap<u64, Resource>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    hosts: collections::BTreeMap<net::IpAddr, Descriptors>,
+    next_id: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DescriptorTable {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DescriptorTable {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate a descriptor for `addr` to hold `resource` open, failing with `EMFILE` if the
+    /// host is at its limit.
+    pub(crate) fn allocate(&self, addr: net::IpAddr, resource: Resource) -> io::Result<Descriptor> {
+        let mut lock = self.inner.lock().unwrap();
+        let id = lock.next_id;
+        let descriptors = lock.hosts.entry(addr).or_default();
+        if let Some(limit) = descriptors.limit {
+            if descriptors.open.len() >= limit {
+                trace!("{} has exhausted its {} descriptors", addr, limit);
+                return Err(io::Error::from_raw_os_error(EMFILE));
+            }
+        }
+        descriptors.open.insert(id, resource);
+        lock.next_id += 1;
+        Ok(Descriptor {
+            addr,
+            id,
+            table: self.clone(),
+        })
+    }
+
+    /// Limit the number of descriptors `addr` may hold at once. Descriptors which are already
+    /// open are unaffected.
+    pub(crate) fn set_limit(&self, addr: net::IpAddr, limit: Option<usize>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.hosts.entry(addr).or_default().limit = limit;
+    }
+
+    /// Returns the number of descriptors currently held by `addr`.
+    pub(crate) fn open(&self, addr: net::IpAddr) -> usize {
+        let lock = self.inner.lock().unwrap();
+        lock.hosts
+            .get(&addr)
+            .map(|descriptors| descriptors.open.len())
+            .unwrap_or(0)
+    }
+
+    /// Returns the resources `addr` holds descriptors for, in the order they were opened.
+    pub(crate) fn resources(&self, addr: net::IpAddr) -> Vec<Resource> {
+        let lock = self.inner.lock().unwrap();
+        lock.hosts
+            .get(&addr)
+            .map(|descriptors| descriptors.open.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// A descriptor held by a simulated host, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Descriptor {
+    addr: net::IpAddr,
+    id: u64,
+    table: DescriptorTable,
+}
+
+impl Drop for Descriptor {
+    fn drop(&mut self) {
+        let mut lock = self.table.inner.lock().unwrap();
+        if let Some(descriptors) = lock.hosts.get_mut(&self.addr) {
+            descriptors.open.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{FsEnv, NetEnv, TcpListener};
+    use std::net;
+
+    #[test]
+    /// Test that files, sockets and listeners share the descriptor limit of their host.
+    fn descriptor_limit() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let server_addr = net::SocketAddr::new(server.local_addr(), 9092);
+                server.set_descriptor_limit(server.local_addr(), Some(2));
+                client.set_descriptor_limit(client.local_addr(), Some(1));
+
+                let mut listener = server.bind(server_addr).await.unwrap();
+                let file = server.create("data").await.unwrap();
+                assert_eq!(server.open_descriptors(server.local_addr()), 2);
+                let err = server.create("other").await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(24));
+
+                // the server is out of descriptors, so the accepted connection is dropped.
+                let socket = client.connect(server_addr).await.unwrap();
+                let err = client.connect(server_addr).await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(24));
+                assert_eq!(
+                    listener.accept().await.unwrap_err().raw_os_error(),
+                    Some(24)
+                );
+
+                drop(file);
+                drop(socket);
+                let _socket = client.connect(server_addr).await.unwrap();
+                listener.accept().await.unwrap();
+                assert_eq!(server.open_descriptors(server.local_addr()), 1);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! In-simulation service registry.
+//!
+//! Services register the address they are reachable at under a name, and clients either
+//! look up the current set of addresses for a name or watch it for changes.
+//!
+//! The registry supports injecting the faults commonly seen with real discovery systems.
+//! Propagation delay holds back updates to watchers for a random amount of time, and stale
+//! reads cause lookups to return the set of addresses which preceded the latest update.
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use futures::{channel::mpsc, FutureExt, Poll, Stream, StreamExt};
+use std::{collections, mem, net, ops, pin::Pin, sync, task::Context, time};
+use tracing::trace;
+
+#[derive(Debug, Default)]
+struct Service {
+    /// Addresses currently registered under this service name.
+    current: collections::BTreeSet<net::SocketAddr>,
+    /// Addresses which were registered before the most recent update.
+    previous: collections::BTreeSet<net::SocketAddr>,
+    watchers: Vec<mpsc::UnboundedSender<(time::Instant, Vec<net::SocketAddr>)>>,
+    /// The time at which the most recent update becomes visible to watchers.
+    visible_at: Option<time::Instant>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    time: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+    services: collections::BTreeMap<String, Service>,
+    propagation_delay: Option<ops::Range<time::Duration>>,
+    stale_probability: f64,
+}
+
+impl Inner {
+    fn new(time: DeterministicTimeHandle, random: DeterministicRandomHandle) -> Self {
+        Self {
+            time,
+            random,
+            services: collections::BTreeMap::new(),
+            propagation_delay: None,
+            stale_probability: 0.0,
+        }
+    }
+
+    /// Record a change to the provided service, notifying watchers once the propagation
+    /// delay has elapsed. Changes which `f` reports as having no effect are ignored without
+    /// drawing randomness, so they don't shift later fault decisions.
+    fn update<F>(&mut self, name: &str, f: F)
+    where
+        F: FnOnce(&mut collections::BTreeSet<net::SocketAddr>) -> bool,
+    {
+        let mut current = self
+            .services
+            .get(name)
+            .map(|service| service.current.clone())
+            .unwrap_or_default();
+        if !f(&mut current) {
+            return;
+        }
+        let now = self.time.now();
+        let delay = match self.propagation_delay.clone() {
+            Some(range) => self.random.gen_range(range),
+            None => time::Duration::from_millis(0),
+        };
+        let service = self.services.entry(name.to_string()).or_default();
+        service.previous = mem::replace(&mut service.current, current);
+        // Updates are never allowed to overtake each other, so the visibility deadline
+        // for a new update is at least the deadline of the update preceding it.
+        let visible_at = match service.visible_at {
+            Some(last) if last > now + delay => last,
+            _ => now + delay,
+        };
+        service.visible_at.replace(visible_at);
+        let addrs: Vec<net::SocketAddr> = service.current.iter().cloned().collect();
+        trace!("service {} updated to {:?}", name, addrs);
+        service
+            .watchers
+            .retain(|tx| tx.unbounded_send((visible_at, addrs.clone())).is_ok());
+    }
+}
+
+/// A registry of named services, shared by all hosts in the simulation.
+#[derive(Debug)]
+pub(crate) struct DeterministicDiscovery {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicDiscovery {
+    pub(crate) fn new(time: DeterministicTimeHandle, random: DeterministicRandomHandle) -> Self {
+        let inner = Inner::new(time, random);
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        Self { inner }
+    }
+
+    pub fn handle(&self) -> DeterministicDiscoveryHandle {
+        let inner = sync::Arc::clone(&self.inner);
+        DeterministicDiscoveryHandle { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeterministicDiscoveryHandle {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicDiscoveryHandle {
+    /// Register `addr` as an instance of the service `name`.
+    pub fn register(&self, name: &str, addr: net::SocketAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.update(name, |current| current.insert(addr));
+    }
+
+    /// Remove `addr` from the set of instances of the service `name`.
+    pub fn deregister(&self, name: &str, addr: net::SocketAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.update(name, |current| current.remove(&addr));
+    }
+
+    /// Returns the addresses registered for the service `name`. If stale reads are being
+    /// injected, this may return the addresses which preceded the most recent update.
+    pub fn lookup(&self, name: &str) -> Vec<net::SocketAddr> {
+        let lock = self.inner.lock().unwrap();
+        let service = match lock.services.get(name) {
+            Some(service) => service,
+            None => return vec![],
+        };
+        if lock.stale_probability > 0.0 && lock.random.should_fault(lock.stale_probability) {
+            trace!("injecting stale lookup for service {}", name);
+            service.previous.iter().cloned().collect()
+        } else {
+            service.current.iter().cloned().collect()
+        }
+    }
+
+    /// Returns a stream which yields the current set of addresses for the service `name`,
+    /// followed by the full set of addresses each time the service is updated.
+    pub fn watch(&self, name: &str) -> Watch {
+        let mut lock = self.inner.lock().unwrap();
+        let time = lock.time.clone();
+        let now = time.now();
+        let service = lock.services.entry(name.to_string()).or_default();
+        let (tx, rx) = mpsc::unbounded();
+        let addrs = service.current.iter().cloned().collect();
+        tx.unbounded_send((now, addrs))
+            .expect("receiver should be live");
+        service.watchers.push(tx);
+        Watch {
+            time,
+            rx,
+            staged: None,
+            delay: None,
+        }
+    }
+
+    /// Delay delivering updates to watchers by a duration sampled from `range`.
+    pub fn set_propagation_delay(&self, range: ops::Range<time::Duration>) {
+        self.inner.lock().unwrap().propagation_delay.replace(range);
+    }
+
+    /// Cause lookups to return stale results with the provided probability.
+    pub fn set_stale_probability(&self, probability: f64) {
+        self.inner.lock().unwrap().stale_probability = probability;
+    }
+
+    /// Remove all injected discovery faults.
+    pub fn clear_faults(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.propagation_delay.take();
+        lock.stale_probability = 0.0;
+    }
+}
+
+/// Stream of updates to a service, returned by [`DeterministicDiscoveryHandle::watch`].
+///
+/// [`DeterministicDiscoveryHandle::watch`]:DeterministicDiscoveryHandle::watch
+pub struct Watch {
+    time: DeterministicTimeHandle,
+    rx: mpsc::UnboundedReceiver<(time::Instant, Vec<net::SocketAddr>)>,
+    staged: Option<Vec<net::SocketAddr>>,
+    delay: Option<tokio_timer::Delay>,
+}
+
+impl Stream for Watch {
+    type Item = Vec<net::SocketAddr>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                futures::ready!(delay.poll_unpin(cx));
+                this.delay.take();
+                return Poll::Ready(this.staged.take());
+            }
+            match futures::ready!(this.rx.poll_next_unpin(cx)) {
+                Some((visible_at, addrs)) => {
+                    this.staged.replace(addrs);
+                    this.delay.replace(this.time.delay(visible_at));
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, RandEnv};
+    use futures::StreamExt;
+    use rand::RngCore;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that registered services can be looked up and deregistered.
+    fn register_lookup() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let discovery = runtime.localhost_handle().discovery_handle();
+        let addr1 = "10.0.0.1:9092".parse().unwrap();
+        let addr2 = "10.0.0.2:9092".parse().unwrap();
+        discovery.register("kafka", addr2);
+        discovery.register("kafka", addr1);
+        assert_eq!(discovery.lookup("kafka"), vec![addr1, addr2]);
+        discovery.deregister("kafka", addr2);
+        assert_eq!(discovery.lookup("kafka"), vec![addr1]);
+        assert!(discovery.lookup("zookeeper").is_empty());
+    }
+
+    #[test]
+    /// Test that watchers observe updates only after the injected propagation delay.
+    fn watch_propagation_delay() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let discovery = handle.discovery_handle();
+        runtime
+            .block_on(async {
+                let addr = "10.0.0.1:9092".parse().unwrap();
+                let mut watch = discovery.watch("kafka");
+                assert!(watch.next().await.unwrap().is_empty());
+
+                discovery.set_propagation_delay(Duration::from_secs(5)..Duration::from_secs(6));
+                let start = handle.now();
+                discovery.register("kafka", addr);
+                assert_eq!(watch.next().await.unwrap(), vec![addr]);
+                assert!(handle.now() - start >= Duration::from_secs(5));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that stale lookups return the addresses preceding the latest update.
+    fn stale_lookup() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let discovery = runtime.localhost_handle().discovery_handle();
+        let addr1 = "10.0.0.1:9092".parse().unwrap();
+        let addr2 = "10.0.0.2:9092".parse().unwrap();
+        discovery.register("kafka", addr1);
+        discovery.register("kafka", addr2);
+        discovery.set_stale_probability(1.0);
+        assert_eq!(discovery.lookup("kafka"), vec![addr1]);
+        discovery.clear_faults();
+        assert_eq!(discovery.lookup("kafka"), vec![addr1, addr2]);
+    }
+
+    #[test]
+    /// Test that registering an address twice or deregistering an unknown one draws no
+    /// randomness and creates no service.
+    fn redundant_updates() {
+        let draw = |redundant: bool| {
+            let (_runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+            let discovery = handle.discovery_handle();
+            discovery.set_propagation_delay(Duration::from_secs(1)..Duration::from_secs(5));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            discovery.register("kafka", addr);
+            if redundant {
+                discovery.register("kafka", addr);
+                discovery.deregister("zookeeper", addr);
+                assert!(!discovery
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .services
+                    .contains_key("zookeeper"));
+            }
+            handle.random().next_u64()
+        };
+        assert_eq!(draw(false), draw(true));
+    }
+}
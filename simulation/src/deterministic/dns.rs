@@ -0,0 +1,327 @@
+//! Simulated DNS, with A and SRV records cached by each host for their TTL.
+//!
+//! Records are added to a single zone shared by all hosts in the simulation, while lookups go
+//! through a cache scoped to the host performing them. A cached answer is returned until its
+//! TTL, the smallest TTL of the records it contains, has elapsed in simulated time. Changes to
+//! the zone are therefore only observed by a host once its cached answer expires, which
+//! exercises client-side load balancing and DNS-based discovery the way real resolvers do.
+//! Killing a host clears its cache.
+//!
+//! [`DeterministicDnsHandle::resolve_srv`] selects a single address for a service as
+//! described in RFC 2782: only records with the lowest priority are considered, and one of
+//! them is picked at random in proportion to its weight, using the deterministic source of
+//! randomness.
+//!
+//! [`DeterministicDnsHandle::resolve_srv`]:DeterministicDnsHandle::resolve_srv
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{collections, net, sync, time};
+use tracing::trace;
+
+/// A SRV record, pointing at a port on the host `target`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SrvRecord {
+    /// Records with a lower priority are always preferred.
+    pub priority: u16,
+    /// Relative weight for selecting between records with the same priority.
+    pub weight: u16,
+    pub port: u16,
+    /// Name of the host, which is resolved with its A records.
+    pub target: String,
+}
+
+#[derive(Debug, Clone)]
+struct Record<T> {
+    data: T,
+    ttl: time::Duration,
+}
+
+#[derive(Debug)]
+struct Cached<T> {
+    expires: time::Instant,
+    data: Vec<T>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    a: collections::BTreeMap<String, Cached<net::IpAddr>>,
+    srv: collections::BTreeMap<String, Cached<SrvRecord>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    time: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+    a: collections::BTreeMap<String, Vec<Record<net::IpAddr>>>,
+    srv: collections::BTreeMap<String, Vec<Record<SrvRecord>>>,
+    caches: collections::BTreeMap<net::IpAddr, Cache>,
+}
+
+/// Returns the answer for `name` from `cache` if it has not expired, otherwise caches the
+/// records currently in the zone. Empty answers are not cached.
+fn resolve<T>(
+    now: time::Instant,
+    name: &str,
+    zone: &collections::BTreeMap<String, Vec<Record<T>>>,
+    cache: &mut collections::BTreeMap<String, Cached<T>>,
+) -> Vec<T>
+where
+    T: Clone,
+{
+    if let Some(cached) = cache.get(name) {
+        if cached.expires > now {
+            return cached.data.clone();
+        }
+    }
+    let records = match zone.get(name) {
+        Some(records) if !records.is_empty() => records,
+        _ => {
+            cache.remove(name);
+            return vec![];
+        }
+    };
+    let ttl = records.iter().map(|record| record.ttl).min().unwrap();
+    let data: Vec<T> = records.iter().map(|record| record.data.clone()).collect();
+    cache.insert(
+        name.to_string(),
+        Cached {
+            expires: now + ttl,
+            data: data.clone(),
+        },
+    );
+    data
+}
+
+/// The zone and per-host caches, shared by all hosts in the simulation.
+#[derive(Debug)]
+pub(crate) struct DeterministicDns {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicDns {
+    pub(crate) fn new(time: DeterministicTimeHandle, random: DeterministicRandomHandle) -> Self {
+        let inner = Inner {
+            time,
+            random,
+            a: collections::BTreeMap::new(),
+            srv: collections::BTreeMap::new(),
+            caches: collections::BTreeMap::new(),
+        };
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        Self { inner }
+    }
+
+    pub(crate) fn scoped(&self, host: net::IpAddr) -> DeterministicDnsHandle {
+        let inner = sync::Arc::clone(&self.inner);
+        DeterministicDnsHandle { inner, host }
+    }
+}
+
+/// Handle for managing the zone and resolving names, scoped to a particular host.
+#[derive(Debug, Clone)]
+pub struct DeterministicDnsHandle {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    host: net::IpAddr,
+}
+
+impl DeterministicDnsHandle {
+    pub(crate) fn scoped(&self, host: net::IpAddr) -> Self {
+        Self {
+            host,
+            ..self.clone()
+        }
+    }
+
+    /// Add an A record resolving `name` to `addr`, replacing the TTL of an existing one.
+    pub fn add_a(&self, name: &str, addr: net::IpAddr, ttl: time::Duration) {
+        let mut lock = self.inner.lock().unwrap();
+        let records = lock.a.entry(name.to_string()).or_default();
+        records.retain(|record| record.data != addr);
+        records.push(Record { data: addr, ttl });
+    }
+
+    /// Remove the A record resolving `name` to `addr`.
+    pub fn remove_a(&self, name: &str, addr: net::IpAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(records) = lock.a.get_mut(name) {
+            records.retain(|record| record.data != addr);
+        }
+    }
+
+    /// Add a SRV record for the service `name`, replacing an existing record with the same
+    /// target and port.
+    pub fn add_srv(&self, name: &str, record: SrvRecord, ttl: time::Duration) {
+        let mut lock = self.inner.lock().unwrap();
+        let records = lock.srv.entry(name.to_string()).or_default();
+        records.retain(|existing| {
+            existing.data.target != record.target || existing.data.port != record.port
+        });
+        records.push(Record { data: record, ttl });
+    }
+
+    /// Remove the SRV record of the service `name` pointing at `port` on `target`.
+    pub fn remove_srv(&self, name: &str, target: &str, port: u16) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(records) = lock.srv.get_mut(name) {
+            records.retain(|record| record.data.target != target || record.data.port != port);
+        }
+    }
+
+    /// Returns the addresses `name` resolves to, in the order their records were added.
+    pub fn lookup_a(&self, name: &str) -> Vec<net::IpAddr> {
+        let mut lock = self.inner.lock().unwrap();
+        let Inner {
+            time, a, caches, ..
+        } = &mut *lock;
+        let cache = caches.entry(self.host).or_default();
+        resolve(time.now(), name, a, &mut cache.a)
+    }
+
+    /// Returns the SRV records of the service `name`, in the order they were added.
+    pub fn lookup_srv(&self, name: &str) -> Vec<SrvRecord> {
+        let mut lock = self.inner.lock().unwrap();
+        let Inner {
+            time, srv, caches, ..
+        } = &mut *lock;
+        let cache = caches.entry(self.host).or_default();
+        resolve(time.now(), name, srv, &mut cache.srv)
+    }
+
+    /// Select an address for the service `name` from its SRV records, ignoring records whose
+    /// target does not resolve. If the selected target has multiple A records, one of them is
+    /// picked at random.
+    pub fn resolve_srv(&self, name: &str) -> Option<net::SocketAddr> {
+        let candidates: Vec<_> = self
+            .lookup_srv(name)
+            .into_iter()
+            .map(|record| {
+                let addrs = self.lookup_a(&record.target);
+                (record, addrs)
+            })
+            .filter(|(_, addrs)| !addrs.is_empty())
+            .collect();
+        let priority = candidates.iter().map(|(record, _)| record.priority).min()?;
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|(record, _)| record.priority == priority)
+            .collect();
+        let random = self.inner.lock().unwrap().random.clone();
+        let total: u32 = candidates
+            .iter()
+            .map(|(record, _)| u32::from(record.weight))
+            .sum();
+        let index = if total == 0 {
+            random.gen_index(candidates.len())
+        } else {
+            // records with a weight of zero are only selected if every record has one.
+            let mut pick = random.gen_range(0..total);
+            candidates
+                .iter()
+                .position(|(record, _)| {
+                    let weight = u32::from(record.weight);
+                    if pick < weight {
+                        return true;
+                    }
+                    pick -= weight;
+                    false
+                })
+                .unwrap()
+        };
+        let (record, addrs) = &candidates[index];
+        let addr = addrs[random.gen_index(addrs.len())];
+        trace!(
+            "resolved {} to {} at {}:{}",
+            name,
+            record.target,
+            addr,
+            record.port
+        );
+        Some(net::SocketAddr::new(addr, record.port))
+    }
+
+    /// Clear the cache of the host this handle is scoped to.
+    pub fn flush_cache(&self) {
+        self.inner.lock().unwrap().caches.remove(&self.host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TimeEnv};
+    use std::time::Duration;
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 9092,
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    /// Test that answers are cached by each host until their TTL expires.
+    fn ttl_expiry() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let client1 = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        let client2 = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 2).into());
+        let addr1 = net::Ipv4Addr::new(10, 0, 0, 1).into();
+        let addr2 = net::Ipv4Addr::new(10, 0, 0, 2).into();
+        runtime
+            .block_on(async {
+                let dns = client1.dns_handle();
+                dns.add_a("kafka", addr1, Duration::from_secs(30));
+                dns.add_a("kafka", addr2, Duration::from_secs(60));
+                assert_eq!(dns.lookup_a("kafka"), vec![addr1, addr2]);
+
+                dns.remove_a("kafka", addr1);
+                client1.delay_from(Duration::from_secs(29)).await;
+                assert_eq!(dns.lookup_a("kafka"), vec![addr1, addr2]);
+                assert_eq!(client2.dns_handle().lookup_a("kafka"), vec![addr2]);
+                client1.delay_from(Duration::from_secs(1)).await;
+                assert_eq!(dns.lookup_a("kafka"), vec![addr2]);
+
+                dns.add_a("kafka", addr1, Duration::from_secs(30));
+                client1.kill(client1.local_addr());
+                assert_eq!(dns.lookup_a("kafka"), vec![addr2, addr1]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that SRV records are selected by priority, then in proportion to their weight.
+    fn weighted_srv() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let dns = runtime.localhost_handle().dns_handle();
+        let ttl = Duration::from_secs(30);
+        let addrs: Vec<net::IpAddr> = (1..5)
+            .map(|i| net::Ipv4Addr::new(10, 0, 0, i).into())
+            .collect();
+        for (i, addr) in addrs.iter().enumerate() {
+            dns.add_a(&format!("broker{}", i), *addr, ttl);
+        }
+        dns.add_srv("_kafka._tcp", srv(10, 3, "broker0"), ttl);
+        dns.add_srv("_kafka._tcp", srv(10, 1, "broker1"), ttl);
+        dns.add_srv("_kafka._tcp", srv(10, 0, "broker2"), ttl);
+        dns.add_srv("_kafka._tcp", srv(20, 1, "broker3"), ttl);
+        // a lower priority target which does not resolve is ignored.
+        dns.add_srv("_kafka._tcp", srv(0, 1, "missing"), ttl);
+
+        let mut counts = collections::BTreeMap::new();
+        for _ in 0..1000 {
+            let addr = dns.resolve_srv("_kafka._tcp").unwrap();
+            assert_eq!(addr.port(), 9092);
+            *counts.entry(addr.ip()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 2);
+        assert!(counts[&addrs[0]] > 2 * counts[&addrs[1]]);
+
+        dns.remove_srv("_kafka._tcp", "broker0", 9092);
+        dns.remove_srv("_kafka._tcp", "broker1", 9092);
+        dns.remove_srv("_kafka._tcp", "broker2", 9092);
+        dns.flush_cache();
+        let addr = dns.resolve_srv("_kafka._tcp").unwrap();
+        assert_eq!(addr, net::SocketAddr::new(addrs[3], 9092));
+        assert_eq!(dns.resolve_srv("_zookeeper._tcp"), None);
+    }
+}
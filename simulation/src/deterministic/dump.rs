@@ -0,0 +1,362 @@
+//! Describing the simulated context of a panic.
+//!
+//! A panic in a spawned task on its own only says where in the code it happened. While the
+//! runtime is driving tasks, or running a closure passed to [`enter`], a panic hook prints the
+//! seed of the runtime, the simulated time, the task being polled, the most recent advances of
+//! simulated time and the tail of the [`EventLog`] before the panic unwinds, which is usually
+//! enough to reproduce the run and see what led up to it.
+//!
+//! The hook is installed once per process and wraps the hook which was installed before it,
+//! so panics on threads which are not running a simulation are reported as before.
+//!
+//! [`sim_assert!`] and [`sim_assert_eq!`] put the same description in the message of a failed
+//! assertion, so it is kept wherever the message is, such as by a test harness or a
+//! [`Campaign`], rather than only printed to standard error.
+//!
+//! [`enter`]:crate::deterministic::DeterministicRuntime::enter
+//! [`EventLog`]:crate::deterministic::EventLog
+//! [`sim_assert!`]:crate::sim_assert
+//! [`sim_assert_eq!`]:crate::sim_assert_eq
+//! [`Campaign`]:crate::deterministic::Campaign
+use super::{process, ClockAdvance, EventLog, TimeReader};
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    fmt::Write as _,
+    net, panic, sync,
+};
+
+/// Number of events from the end of the event log included in the dump.
+const TAIL: usize = 20;
+
+/// What is known about the simulation running on a thread.
+#[derive(Debug, Clone)]
+pub(crate) struct SimContext {
+    pub(crate) seed: u64,
+    pub(crate) time: TimeReader,
+    pub(crate) events: EventLog,
+}
+
+impl SimContext {
+    /// Describe the simulation at the point of a panic. Only tries to take locks, as the panic
+    /// may have happened while one was held.
+    fn dump(&self) -> String {
+        format!("simulation panicked with {}", self.describe(None))
+    }
+
+    /// Describe the seed, the simulated time, the task being polled, or otherwise `host`, the
+    /// most recent advances of time and the tail of the event log.
+    fn describe(&self, host: Option<net::IpAddr>) -> String {
+        let mut description = self.seed_and_time();
+        match (process::current_task(), host) {
+            (Some((host, task)), _) => {
+                let _ = write!(description, ", while polling task {} of {}", task, host);
+            }
+            (None, Some(host)) => {
+                let _ = write!(description, ", on {} outside of any spawned task", host);
+            }
+            (None, None) => description.push_str(", outside of any spawned task"),
+        }
+        self.write_recent(&mut description);
+        description
+    }
+
+    /// Describe the seed, the simulated time, the most recent advances of time and the tail of
+    /// the event log, from a thread other than the one running the simulation.
+    pub(crate) fn describe_outside(&self) -> String {
+        let mut description = self.seed_and_time();
+        self.write_recent(&mut description);
+        description
+    }
+
+    fn seed_and_time(&self) -> String {
+        let mut description = format!("seed {}", self.seed);
+        if let Some(elapsed) = self.time.try_elapsed() {
+            let _ = write!(description, " after {:?} of simulated time", elapsed);
+        }
+        description
+    }
+
+    /// Append the most recent advances of time and the tail of the event log to `description`.
+    fn write_recent(&self, description: &mut String) {
+        if let Some(history) = self.time.try_history() {
+            description.push_str(&clock_history(&history));
+        }
+        match self.events.try_tail(TAIL) {
+            Some(events) if !events.is_empty() => {
+                let _ = write!(description, "\nlast {} events:", events.len());
+                for event in events {
+                    let _ = write!(description, "\n  {}", event.to_json());
+                }
+            }
+            Some(_) => description
+                .push_str("\nno events recorded, see DeterministicRuntime::record_events"),
+            None => description.push_str("\nevent log unavailable"),
+        }
+    }
+}
+
+/// Describe the last advances of time in `history`, as a section to append to a report.
+pub(crate) fn clock_history(history: &[ClockAdvance]) -> String {
+    if history.is_empty() {
+        return "\nsimulated time has not advanced".to_string();
+    }
+    let tail = &history[history.len().saturating_sub(TAIL)..];
+    let mut description = format!("\nlast {} clock advances:", tail.len());
+    for advance in tail {
+        let _ = write!(description, "\n  {}", advance);
+    }
+    description
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<SimContext>> = const { RefCell::new(None) };
+    /// Set when the message of the panic about to happen already describes the simulation.
+    static DESCRIBED: Cell<bool> = const { Cell::new(false) };
+}
+
+static INSTALL: sync::Once = sync::Once::new();
+
+/// Returns the context of the simulation running on the current thread, if any.
+fn current() -> Option<SimContext> {
+    CURRENT
+        .try_with(|current| {
+            current
+                .try_borrow()
+                .ok()
+                .and_then(|context| context.clone())
+        })
+        .ok()
+        .flatten()
+}
+
+/// Returns the message a panic was raised with, given its payload.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// Describe the simulation on the current thread in the event of a panic until the returned
+/// guard is dropped.
+pub(crate) fn enter(context: SimContext) -> Guard {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let described = DESCRIBED.try_with(|described| described.replace(false));
+            if let (Some(context), Ok(false)) = (current(), described) {
+                eprintln!("{}", context.dump());
+            }
+            previous(info)
+        }));
+    });
+    let previous = CURRENT.with(|current| current.replace(Some(context)));
+    Guard { previous }
+}
+
+/// Restores the context of the enclosing simulation, if any, when dropped.
+#[derive(Debug)]
+pub(crate) struct Guard {
+    previous: Option<SimContext>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/// Count an assertion evaluated by the simulation running on the current thread, if any.
+#[doc(hidden)]
+pub fn assertion_checked() {
+    if let Some(context) = current() {
+        context.events.tally().invariant_checked();
+    }
+}
+
+/// Returns a description of the simulation running on the current thread to append to the
+/// message of a failed assertion, or an empty string outside of a simulation.
+#[doc(hidden)]
+pub fn assertion_context() -> String {
+    let context = match current() {
+        Some(context) => context,
+        None => return String::new(),
+    };
+    let host = super::current().map(|handle| handle.local_addr());
+    let _ = DESCRIBED.try_with(|described| described.set(true));
+    format!("\nsimulation: {}", context.describe(host))
+}
+
+/// Asserts that a boolean expression is true, like `assert!`. When run inside a simulation,
+/// the panic message also gives the seed, the simulated time, the current host and task, and
+/// the most recent events.
+#[macro_export]
+macro_rules! sim_assert {
+    ($cond:expr $(,)?) => {{
+        $crate::deterministic::assertion_checked();
+        if !$cond {
+            panic!(
+                "assertion failed: {}{}",
+                stringify!($cond),
+                $crate::deterministic::assertion_context()
+            );
+        }
+    }};
+    ($cond:expr, $($arg:tt)+) => {{
+        $crate::deterministic::assertion_checked();
+        if !$cond {
+            panic!(
+                "assertion failed: {}: {}{}",
+                stringify!($cond),
+                format_args!($($arg)+),
+                $crate::deterministic::assertion_context()
+            );
+        }
+    }};
+}
+
+/// Asserts that two expressions are equal, like `assert_eq!`, describing the simulation in
+/// the panic message as [`sim_assert!`] does.
+///
+/// [`sim_assert!`]:crate::sim_assert
+#[macro_export]
+macro_rules! sim_assert_eq {
+    ($left:expr, $right:expr $(,)?) => {{
+        $crate::deterministic::assertion_checked();
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    panic!(
+                        "assertion failed: `(left == right)`\n  left: `{:?}`,\n right: `{:?}`{}",
+                        left,
+                        right,
+                        $crate::deterministic::assertion_context()
+                    );
+                }
+            }
+        }
+    }};
+    ($left:expr, $right:expr, $($arg:tt)+) => {{
+        $crate::deterministic::assertion_checked();
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    panic!(
+                        "assertion failed: `(left == right)`\n  left: `{:?}`,\n right: `{:?}`: {}{}",
+                        left,
+                        right,
+                        format_args!($($arg)+),
+                        $crate::deterministic::assertion_context()
+                    );
+                }
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::SpawnEnv;
+    use std::net;
+
+    #[test]
+    /// Test that the dump names the seed, the time, the task being polled, the advances of time
+    /// and the last events.
+    fn dump() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(7).build().unwrap();
+        let events = runtime.record_events(100);
+        let host = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let dump = runtime
+            .block_on(async {
+                let (tx, rx) = futures::channel::oneshot::channel();
+                host.spawn(async move {
+                    let _ = tx.send(current().unwrap().dump());
+                });
+                rx.await.unwrap()
+            })
+            .unwrap();
+        assert!(
+            dump.starts_with(
+                "simulation panicked with seed 7 after 0ns of simulated time, \
+                 while polling task 0 of 10.0.0.1\nsimulated time has not advanced\n\
+                 last 2 events:"
+            ),
+            "{}",
+            dump
+        );
+        assert!(dump.ends_with(&events.events()[1].to_json()), "{}", dump);
+
+        let dump = runtime.enter(host.local_addr(), || current().unwrap().dump());
+        assert!(dump.contains("outside of any spawned task"), "{}", dump);
+        assert!(current().is_none());
+    }
+
+    /// Returns the message `f` panics with.
+    fn panic_message<F: FnOnce()>(f: F) -> String {
+        let payload = panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_err();
+        payload
+            .downcast::<String>()
+            .map(|message| *message)
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that failed assertions describe the simulation, and only the assertion outside of
+    /// one.
+    fn assertions() {
+        let mut runtime = DeterministicRuntime::builder().seed(3).build().unwrap().0;
+        let message = panic_message(|| {
+            runtime
+                .block_on(async {
+                    crate::delay_for(std::time::Duration::from_secs(2)).await;
+                    crate::sim_assert_eq!(1 + 1, 3, "adding {}", "numbers");
+                })
+                .unwrap()
+        });
+        assert!(
+            message.starts_with(
+                "assertion failed: `(left == right)`\n  left: `2`,\n right: `3`: adding numbers\n\
+                 simulation: seed 3 after 2s of simulated time, on 127.0.0.1 outside of any \
+                 spawned task\nlast 1 clock advances:\n  0ns -> 2s, idle until a timer outside \
+                 of any host\nno events recorded"
+            ),
+            "{}",
+            message
+        );
+
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let message = panic_message(|| {
+            runtime
+                .block_on(async {
+                    let (tx, rx) = futures::channel::oneshot::channel();
+                    host.spawn(async move {
+                        let message = panic_message(|| crate::sim_assert!(false));
+                        let _ = tx.send(message);
+                    });
+                    panic!("{}", rx.await.unwrap())
+                })
+                .unwrap()
+        });
+        assert!(
+            message.starts_with("assertion failed: false\nsimulation: seed 0"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("while polling task 0 of 10.0.0.1"),
+            "{}",
+            message
+        );
+
+        let message = panic_message(|| crate::sim_assert!(1 > 2, "{} is small", 1));
+        assert_eq!(message, "assertion failed: 1 > 2: 1 is small");
+    }
+}
@@ -0,0 +1,624 @@
+//! Detecting simulated code which escapes the simulation.
+//!
+//! A simulation is only deterministic while the code under test takes its time, sockets and
+//! files from its [`Environment`]. Code which reaches past it sees something different on every
+//! run, which makes a simulation flaky however deterministic the rest of it is, or hangs it.
+//!
+//! Simulated futures, such as delays and connections, panic when polled on a thread where their
+//! runtime is not running, such as by a Tokio runtime or `futures::executor::block_on`, naming
+//! the code which polled them. Polled there, they would wait on simulated time which never
+//! advances, or race with the runtime.
+//!
+//! With the `clock-guard` feature on Linux, the crate defines its own `clock_gettime`, which the
+//! standard library calls to read the clock and which takes precedence over the one in libc.
+//! With the `blocking-guard` feature, it does the same for `nanosleep`, which `thread::sleep`
+//! calls, and for the calls which read, write, connect and accept on files and sockets. A call
+//! made while the runtime is polling a task, or the future passed to [`block_on`], is noted,
+//! and the poll panics once it returns, naming the task and the code which made the call.
+//! Sleeps and blocking calls are skipped rather than made, so that they cannot hang the poll
+//! first. Calls made anywhere else are passed on to libc.
+//!
+//! [`Environment`]:crate::Environment
+//! [`block_on`]:crate::deterministic::DeterministicRuntime::block_on
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    /// Set while a runtime is running on this thread.
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+    /// Set while the runtime is polling simulated code on this thread.
+    static ARMED: Cell<bool> = const { Cell::new(false) };
+    /// The first call which escaped the simulation during the current poll.
+    static ESCAPED: RefCell<Option<Escape>> = const { RefCell::new(None) };
+}
+
+/// A call made by simulated code which bypassed its environment.
+#[derive(Debug)]
+struct Escape {
+    /// What the call did, such as reading the real clock.
+    what: &'static str,
+    /// What the code should have done instead.
+    instead: &'static str,
+    /// The code which made the call.
+    location: String,
+}
+
+/// Marks a runtime as running on this thread until dropped.
+#[derive(Debug)]
+pub(crate) struct Running {
+    previous: bool,
+}
+
+/// Mark a runtime as running on this thread, so that simulated futures may be polled.
+pub(crate) fn enter() -> Running {
+    let previous = RUNNING.with(|running| running.replace(true));
+    Running { previous }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(self.previous));
+    }
+}
+
+/// Panic unless a runtime is running on this thread, as `polled` is a simulated future which
+/// only makes progress when polled by its runtime.
+pub(crate) fn check_executor<F>(polled: F)
+where
+    F: FnOnce() -> String,
+{
+    if RUNNING.with(Cell::get) {
+        return;
+    }
+    let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+    let location = caller(
+        &backtrace,
+        |name| SIMULATED.iter().any(|module| name.starts_with(module)),
+        &[
+            "std::",
+            "core::",
+            "alloc::",
+            "futures_core::",
+            "futures_util::",
+        ],
+    );
+    panic!(
+        "{} polled outside of the simulation, by an executor other than its runtime: {}",
+        polled(),
+        location
+    );
+}
+
+/// Modules of the simulated futures which check their executor.
+const SIMULATED: &[&str] = &[
+    "simulation::deterministic::time::",
+    "simulation::deterministic::network::",
+];
+
+/// Counts calls which escape the simulation on this thread until dropped.
+#[derive(Debug)]
+pub(crate) struct Armed {
+    previous: bool,
+}
+
+/// Count calls which escape the simulation on this thread, while simulated code is polled.
+pub(crate) fn arm() -> Armed {
+    let previous = ARMED.with(|armed| armed.replace(true));
+    Armed { previous }
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        ARMED.with(|armed| armed.set(self.previous));
+    }
+}
+
+/// Run `f`, which escapes the simulation on purpose, such as to profile the runtime or write
+/// its output, without counting against the code being polled.
+pub(crate) fn unguarded<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _armed = Armed {
+        previous: ARMED.with(|armed| armed.replace(false)),
+    };
+    f()
+}
+
+/// Panic if simulated code escaped the simulation during the poll of `polled`, which just
+/// returned.
+pub(crate) fn check<F>(polled: F)
+where
+    F: FnOnce() -> String,
+{
+    if let Some(escape) = ESCAPED.with(|escaped| escaped.borrow_mut().take()) {
+        panic!(
+            "{} while polling {}, {}: {}",
+            escape.what,
+            polled(),
+            escape.instead,
+            escape.location
+        );
+    }
+}
+
+/// Note a call to `call` which escapes the simulation, returning true if simulated code is
+/// being polled.
+#[cfg(all(
+    any(feature = "clock-guard", feature = "blocking-guard"),
+    target_os = "linux"
+))]
+fn escaped(call: &'static str, what: &'static str, instead: &'static str) -> bool {
+    ARMED
+        .try_with(|armed| {
+            // the panic hook may read files to print a backtrace.
+            if !armed.get() || std::thread::panicking() {
+                return false;
+            }
+            if ESCAPED.with(|escaped| escaped.borrow().is_none()) {
+                // capturing the backtrace reads the clock and files itself.
+                armed.set(false);
+                let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+                let location = caller(&backtrace, |name| name == call, &["std::"]);
+                ESCAPED.with(|escaped| {
+                    *escaped.borrow_mut() = Some(Escape {
+                        what,
+                        instead,
+                        location,
+                    })
+                });
+                armed.set(true);
+            }
+            true
+        })
+        .unwrap_or(false)
+}
+
+/// Returns the function named by `line` of a backtrace, if it starts a frame.
+fn frame(line: &str) -> Option<&str> {
+    let (index, name) = line.trim().split_once(": ")?;
+    if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Returns the frame of `backtrace` which called into the first frame `called` matches, with
+/// its location if known, or the whole backtrace if it cannot be found, such as when symbols
+/// are unavailable. Frames `called` matches, and those in the `library` modules, are skipped.
+fn caller<F>(backtrace: &str, called: F, library: &[&str]) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    let lines: Vec<_> = backtrace.lines().map(str::trim).collect();
+    let start = lines.iter().position(|line| match frame(line) {
+        Some(name) => called(name.trim_start_matches('<')),
+        None => false,
+    });
+    // the caller is past the library methods, such as `Instant::elapsed`, which made the call
+    // on its behalf.
+    let caller = start.and_then(|start| {
+        let offset = lines[start + 1..]
+            .iter()
+            .position(|line| match frame(line) {
+                Some(name) => {
+                    let name = name.trim_start_matches('<');
+                    !called(name) && !library.iter().any(|module| name.starts_with(module))
+                }
+                None => false,
+            })?;
+        Some(start + 1 + offset)
+    });
+    match caller {
+        Some(caller) => {
+            let name = frame(lines[caller]).unwrap_or_default();
+            match lines.get(caller + 1) {
+                Some(at) if at.starts_with("at ") => format!("{} {}", name, at),
+                _ => name.to_string(),
+            }
+        }
+        None => format!("\n{}", backtrace),
+    }
+}
+
+#[cfg(all(feature = "clock-guard", target_os = "linux"))]
+mod clock {
+    use std::sync::atomic;
+
+    /// Address of the `clock_gettime` of libc, once looked up.
+    static NEXT: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+    /// Reads the clock as libc does, noting the read if simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// `time` must be valid for writes, as for `clock_gettime` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn clock_gettime(
+        clock: libc::clockid_t,
+        time: *mut libc::timespec,
+    ) -> libc::c_int {
+        super::escaped(
+            "clock_gettime",
+            "real clock read",
+            "use the time of the environment instead",
+        );
+        let mut next = NEXT.load(atomic::Ordering::Relaxed);
+        if next == 0 {
+            next = libc::dlsym(libc::RTLD_NEXT, "clock_gettime\0".as_ptr() as *const _) as usize;
+            NEXT.store(next, atomic::Ordering::Relaxed);
+        }
+        if next == 0 {
+            // linked statically, so there is no libc to defer to.
+            return libc::syscall(libc::SYS_clock_gettime, clock, time) as libc::c_int;
+        }
+        let next: unsafe extern "C" fn(libc::clockid_t, *mut libc::timespec) -> libc::c_int =
+            std::mem::transmute(next);
+        next(clock, time)
+    }
+}
+
+#[cfg(all(feature = "blocking-guard", target_os = "linux"))]
+mod blocking {
+    use libc::{c_int, c_void, size_t, sockaddr, socklen_t, ssize_t, timespec};
+
+    const SLEEP: &str = "thread slept";
+    const SLEEP_INSTEAD: &str = "use a delay from the environment instead";
+    const IO: &str = "blocking I/O performed";
+    const IO_INSTEAD: &str = "use the sockets and files of the environment instead";
+
+    /// Note a sleep, returning true if it should be skipped.
+    fn sleep(call: &'static str) -> bool {
+        super::escaped(call, SLEEP, SLEEP_INSTEAD)
+    }
+
+    /// Note blocking I/O on `fd`, returning true if it should be refused. Standard streams
+    /// and pipes, which the runtime wakes itself through, are left alone.
+    unsafe fn io(call: &'static str, fd: c_int) -> bool {
+        if fd <= 2 {
+            return false;
+        }
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) != 0 {
+            return false;
+        }
+        let kind = stat.st_mode & libc::S_IFMT;
+        if kind != libc::S_IFREG && kind != libc::S_IFSOCK {
+            return false;
+        }
+        if super::escaped(call, IO, IO_INSTEAD) {
+            *libc::__errno_location() = libc::EPERM;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the function of libc named `$name`, which the function of the same name here
+    /// hides.
+    macro_rules! next {
+        ($name:ident: fn($($arg:ty),*) -> $ret:ty) => {{
+            static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            let mut next = NEXT.load(std::sync::atomic::Ordering::Relaxed);
+            if next == 0 {
+                let name = concat!(stringify!($name), "\0");
+                next = libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const _) as usize;
+                NEXT.store(next, std::sync::atomic::Ordering::Relaxed);
+            }
+            std::mem::transmute::<usize, unsafe extern "C" fn($($arg),*) -> $ret>(next)
+        }};
+    }
+
+    /// Sleeps as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `nanosleep` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn nanosleep(request: *const timespec, remain: *mut timespec) -> c_int {
+        if sleep("nanosleep") {
+            return 0;
+        }
+        next!(nanosleep: fn(*const timespec, *mut timespec) -> c_int)(request, remain)
+    }
+
+    /// Sleeps as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `clock_nanosleep` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn clock_nanosleep(
+        clock: libc::clockid_t,
+        flags: c_int,
+        request: *const timespec,
+        remain: *mut timespec,
+    ) -> c_int {
+        if sleep("clock_nanosleep") {
+            return 0;
+        }
+        next!(clock_nanosleep: fn(libc::clockid_t, c_int, *const timespec, *mut timespec) -> c_int)(
+            clock, flags, request, remain,
+        )
+    }
+
+    /// Reads as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `read` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t {
+        if io("read", fd) {
+            return -1;
+        }
+        next!(read: fn(c_int, *mut c_void, size_t) -> ssize_t)(fd, buf, count)
+    }
+
+    /// Writes as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `write` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t {
+        if io("write", fd) {
+            return -1;
+        }
+        next!(write: fn(c_int, *const c_void, size_t) -> ssize_t)(fd, buf, count)
+    }
+
+    /// Receives as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `recv` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn recv(
+        fd: c_int,
+        buf: *mut c_void,
+        len: size_t,
+        flags: c_int,
+    ) -> ssize_t {
+        if io("recv", fd) {
+            return -1;
+        }
+        next!(recv: fn(c_int, *mut c_void, size_t, c_int) -> ssize_t)(fd, buf, len, flags)
+    }
+
+    /// Receives as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `recvfrom` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn recvfrom(
+        fd: c_int,
+        buf: *mut c_void,
+        len: size_t,
+        flags: c_int,
+        addr: *mut sockaddr,
+        addr_len: *mut socklen_t,
+    ) -> ssize_t {
+        if io("recvfrom", fd) {
+            return -1;
+        }
+        next!(recvfrom: fn(c_int, *mut c_void, size_t, c_int, *mut sockaddr, *mut socklen_t) -> ssize_t)(
+            fd, buf, len, flags, addr, addr_len,
+        )
+    }
+
+    /// Sends as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `send` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn send(
+        fd: c_int,
+        buf: *const c_void,
+        len: size_t,
+        flags: c_int,
+    ) -> ssize_t {
+        if io("send", fd) {
+            return -1;
+        }
+        next!(send: fn(c_int, *const c_void, size_t, c_int) -> ssize_t)(fd, buf, len, flags)
+    }
+
+    /// Sends as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `sendto` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn sendto(
+        fd: c_int,
+        buf: *const c_void,
+        len: size_t,
+        flags: c_int,
+        addr: *const sockaddr,
+        addr_len: socklen_t,
+    ) -> ssize_t {
+        if io("sendto", fd) {
+            return -1;
+        }
+        next!(sendto: fn(c_int, *const c_void, size_t, c_int, *const sockaddr, socklen_t) -> ssize_t)(
+            fd, buf, len, flags, addr, addr_len,
+        )
+    }
+
+    /// Connects as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `connect` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn connect(fd: c_int, addr: *const sockaddr, len: socklen_t) -> c_int {
+        if io("connect", fd) {
+            return -1;
+        }
+        next!(connect: fn(c_int, *const sockaddr, socklen_t) -> c_int)(fd, addr, len)
+    }
+
+    /// Accepts as libc does, unless simulated code is being polled.
+    ///
+    /// # Safety
+    ///
+    /// As for `accept4` in libc.
+    #[no_mangle]
+    pub unsafe extern "C" fn accept4(
+        fd: c_int,
+        addr: *mut sockaddr,
+        len: *mut socklen_t,
+        flags: c_int,
+    ) -> c_int {
+        if io("accept4", fd) {
+            return -1;
+        }
+        next!(accept4: fn(c_int, *mut sockaddr, *mut socklen_t, c_int) -> c_int)(
+            fd, addr, len, flags,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TimeEnv;
+    use std::{panic, time};
+
+    #[test]
+    /// Test that the caller of the clock is found in a backtrace.
+    fn caller_of_clock() {
+        let backtrace = "   0: simulation::deterministic::escape::escaped\n\
+             \x20            at ./src/deterministic/escape.rs:70:24\n\
+             \x20  1: clock_gettime\n\
+             \x20  2: <std::sys::pal::unix::time::Timespec>::now\n\
+             \x20  3: <std::time::Instant>::now\n\
+             \x20            at /rustc/library/std/src/time.rs:289:9\n\
+             \x20  4: <std::time::Instant>::elapsed\n\
+             \x20  5: app::retry::{{closure}}\n\
+             \x20            at ./src/retry.rs:12:20\n\
+             \x20  6: main\n";
+        let clock = |name: &str| name == "clock_gettime";
+        assert_eq!(
+            caller(backtrace, clock, &["std::"]),
+            "app::retry::{{closure}} at ./src/retry.rs:12:20"
+        );
+        assert_eq!(
+            caller("   0: main\n", clock, &["std::"]),
+            "\n   0: main\n".to_string()
+        );
+    }
+
+    #[test]
+    /// Test that a simulated future polled outside of its runtime panics, naming the code which
+    /// polled it.
+    fn foreign_executor() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let delay = runtime
+            .localhost_handle()
+            .delay_from(time::Duration::from_secs(1));
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            futures::executor::block_on(async {
+                delay.await;
+            })
+        }));
+        let message = failed.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.starts_with(
+                "delay of 127.0.0.1 polled outside of the simulation, by an executor other than \
+                 its runtime: "
+            ),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("escape::tests::foreign_executor"),
+            "{}",
+            message
+        );
+    }
+
+    #[cfg(all(feature = "clock-guard", target_os = "linux"))]
+    #[test]
+    /// Test that a task reading the real clock fails the run, naming the task, while reads
+    /// outside of a poll pass.
+    fn real_clock() {
+        use crate::SpawnEnv;
+        let _ = time::Instant::now();
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(std::net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime.block_on(async {}).unwrap();
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime
+                .block_on(async {
+                    host.spawn(async {
+                        let _ = time::SystemTime::now();
+                    });
+                    futures::future::pending::<()>().await;
+                })
+                .unwrap()
+        }));
+        let message = failed.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.starts_with(
+                "real clock read while polling task 0 of 10.0.0.1, use the time of the \
+                 environment instead: "
+            ),
+            "{}",
+            message
+        );
+        assert!(message.contains("escape::tests::real_clock"), "{}", message);
+        let _ = time::SystemTime::now();
+    }
+
+    #[cfg(all(feature = "blocking-guard", target_os = "linux"))]
+    #[test]
+    /// Test that sleeping the thread or connecting a real socket fails the run, without
+    /// making the call, naming the code which made it.
+    fn blocking() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime
+                .block_on(async {
+                    std::thread::sleep(time::Duration::from_secs(60));
+                })
+                .unwrap()
+        }));
+        let message = failed.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.starts_with(
+                "thread slept while polling the future passed to block_on, use a delay from the \
+                 environment instead: "
+            ),
+            "{}",
+            message
+        );
+        assert!(message.contains("escape::tests::blocking"), "{}", message);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let failed = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime
+                .block_on(async move {
+                    let error = std::net::TcpStream::connect(addr).unwrap_err();
+                    assert_eq!(error.raw_os_error(), Some(libc::EPERM));
+                })
+                .unwrap()
+        }));
+        let message = failed.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.starts_with(
+                "blocking I/O performed while polling the future passed to block_on, use the sockets and \
+                 files of the environment instead: "
+            ),
+            "{}",
+            message
+        );
+        // outside of a poll, the socket connects.
+        std::net::TcpStream::connect(addr).unwrap();
+    }
+}
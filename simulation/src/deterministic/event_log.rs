@@ -0,0 +1,368 @@
+//! Append-only log of everything which happened during a simulation run.
+//!
+//! Where the [`Timeline`] keeps the coarse events a visualizer renders, the event log records
+//! every decision which shapes a run: each poll of a task by the executor, each timer which is
+//! started or fires, each chunk sent over a simulated connection, each draw from the random
+//! number generator and each fault injected, along with everything in the timeline. Every event is
+//! stamped with the simulated time and an id numbering events in the order they happened, so
+//! two runs with the same seed produce the same log, and the first event at which two logs
+//! differ is where the runs diverged.
+//!
+//! The log is recorded once [`record_events`] is called, keeping only the most recent events
+//! in memory, or once [`record_events_to`] is called, appending every event to a file as a line
+//! of JSON:
+//!
+//! ```text
+//! {"id":0,"at_us":0,"kind":"task_spawned","host":"10.0.0.1","task":0}
+//! {"id":1,"at_us":0,"kind":"task_polled","host":"10.0.0.1","task":0}
+//! {"id":2,"at_us":0,"kind":"timer_set","host":"10.0.0.1","timer":0}
+//! {"id":3,"at_us":1000000,"kind":"timer_fired","host":"10.0.0.1","timer":0}
+//! ```
+//!
+//! [`Timeline`]:crate::deterministic::Timeline
+//! [`record_events`]:crate::deterministic::DeterministicRuntime::record_events
+//! [`record_events_to`]:crate::deterministic::DeterministicRuntime::record_events_to
+use super::{escape, summary::Tally, timeline, trace, TimeReader, TimelineKind};
+use std::{collections, fmt, fmt::Write as _, io, mem, net, sync, time};
+
+/// What happened at a point in the run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// An event which is also recorded in the timeline.
+    Timeline(TimelineKind),
+    /// The executor polled `task`, spawned on behalf of `host`.
+    TaskPolled { host: net::IpAddr, task: u64 },
+    /// `host` started the delay numbered `timer`.
+    TimerSet { host: net::IpAddr, timer: u64 },
+    /// The delay numbered `timer`, started by `host`, completed.
+    TimerFired { host: net::IpAddr, timer: u64 },
+    /// `len` bytes were sent from `local` to `peer`.
+    Sent {
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        len: usize,
+    },
+    /// A value was drawn from the random number generator, the `draw`th since it was seeded.
+    RandomDraw { draw: u64 },
+}
+
+/// An event in the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRecord {
+    /// Identifies the event, numbering events in the order they happened.
+    pub id: u64,
+    /// Simulated time elapsed since the runtime was created.
+    pub at: time::Duration,
+    pub kind: EventKind,
+}
+
+impl EventRecord {
+    /// Returns the event as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"id\":{},\"at_us\":{}",
+            self.id,
+            self.at.as_micros()
+        );
+        let _ = match &self.kind {
+            EventKind::Timeline(kind) => timeline::write_kind(&mut json, kind),
+            EventKind::TaskPolled { host, task } => write!(
+                json,
+                ",\"kind\":\"task_polled\",\"host\":\"{}\",\"task\":{}",
+                host, task
+            ),
+            EventKind::TimerSet { host, timer } => write!(
+                json,
+                ",\"kind\":\"timer_set\",\"host\":\"{}\",\"timer\":{}",
+                host, timer
+            ),
+            EventKind::TimerFired { host, timer } => write!(
+                json,
+                ",\"kind\":\"timer_fired\",\"host\":\"{}\",\"timer\":{}",
+                host, timer
+            ),
+            EventKind::Sent { local, peer, len } => write!(
+                json,
+                ",\"kind\":\"sent\",\"local\":\"{}\",\"peer\":\"{}\",\"len\":{}",
+                local, peer, len
+            ),
+            EventKind::RandomDraw { draw } => {
+                write!(json, ",\"kind\":\"random_draw\",\"draw\":{}", draw)
+            }
+        };
+        json.push('}');
+        json
+    }
+}
+
+/// Where recorded events are kept.
+struct Recorder {
+    next_id: u64,
+    /// The most recent events, up to `capacity`. Empty when events are written to `sink`.
+    events: collections::VecDeque<EventRecord>,
+    capacity: usize,
+    sink: Option<Box<dyn io::Write + Send>>,
+    /// The first error writing to `sink`, after which no more events are written.
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("next_id", &self.next_id)
+            .field("events", &self.events.len())
+            .field("capacity", &self.capacity)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// Handle for recording and retrieving the event log of a run.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    time: TimeReader,
+    /// Where events are recorded, or `None` if recording is not enabled.
+    recorder: sync::Arc<sync::Mutex<Option<Recorder>>>,
+    tally: sync::Arc<Tally>,
+}
+
+impl EventLog {
+    pub(crate) fn new(time: TimeReader) -> Self {
+        Self {
+            time,
+            recorder: sync::Arc::new(sync::Mutex::new(None)),
+            tally: sync::Arc::default(),
+        }
+    }
+
+    /// Returns the counts of work done during the run, which are kept even when events are not
+    /// recorded.
+    pub(crate) fn tally(&self) -> &Tally {
+        &self.tally
+    }
+
+    /// Start keeping the most recent `capacity` events in memory, unless already recording.
+    pub(crate) fn enable(&self, capacity: usize) {
+        self.start(capacity, None);
+    }
+
+    /// Start appending every event to `sink`, unless already recording.
+    pub(crate) fn enable_sink(&self, sink: Box<dyn io::Write + Send>) {
+        self.start(0, Some(sink));
+    }
+
+    fn start(&self, capacity: usize, sink: Option<Box<dyn io::Write + Send>>) {
+        let mut lock = self.recorder.lock().unwrap();
+        if lock.is_none() {
+            *lock = Some(Recorder {
+                next_id: 0,
+                events: collections::VecDeque::new(),
+                capacity,
+                sink,
+                error: None,
+            });
+        }
+    }
+
+    /// Record that `kind` happened now, if recording is enabled.
+    pub(crate) fn record(&self, kind: EventKind) {
+        self.record_with(|| kind);
+    }
+
+    /// Record the event returned by `kind` now, only calling it if recording is enabled.
+    pub(crate) fn record_with<F>(&self, kind: F)
+    where
+        F: FnOnce() -> EventKind,
+    {
+        let mut lock = self.recorder.lock().unwrap();
+        let recorder = match lock.as_mut() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let event = EventRecord {
+            id: recorder.next_id,
+            at: self.time.elapsed(),
+            kind: kind(),
+        };
+        recorder.next_id += 1;
+        if let Some(sink) = &mut recorder.sink {
+            if recorder.error.is_none() {
+                let line = event.to_json() + "\n";
+                recorder.error = escape::unguarded(|| sink.write_all(line.as_bytes())).err();
+            }
+            return;
+        }
+        if recorder.events.len() == recorder.capacity {
+            recorder.events.pop_front();
+        }
+        if recorder.capacity > 0 {
+            recorder.events.push_back(event);
+        }
+    }
+
+    /// Returns the events kept in memory, oldest first. Only the most recent events are kept,
+    /// so the id of the first shows how many came before it.
+    pub fn events(&self) -> Vec<EventRecord> {
+        let lock = self.recorder.lock().unwrap();
+        lock.as_ref()
+            .map(|recorder| recorder.events.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the events kept in memory as a JSON document in the Chrome trace event format,
+    /// which `about://tracing` in Chrome or Perfetto open as a view of each host over simulated
+    /// time. Record events with a capacity large enough to keep the whole run.
+    pub fn to_chrome_trace(&self) -> String {
+        trace::chrome_trace(&self.events())
+    }
+
+    /// Write the events kept in memory to `writer` in the Chrome trace event format.
+    pub fn write_chrome_trace<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all(self.to_chrome_trace().as_bytes())
+    }
+
+    /// Returns up to the last `len` events kept in memory, unless the log is locked.
+    pub(crate) fn try_tail(&self, len: usize) -> Option<Vec<EventRecord>> {
+        let lock = self.recorder.try_lock().ok()?;
+        let events = lock.as_ref().map(|recorder| &recorder.events);
+        let skip = events.map_or(0, |events| events.len().saturating_sub(len));
+        Some(events.into_iter().flatten().skip(skip).cloned().collect())
+    }
+
+    /// Returns the number of events recorded so far, including those no longer kept.
+    pub fn len(&self) -> u64 {
+        let lock = self.recorder.lock().unwrap();
+        lock.as_ref().map_or(0, |recorder| recorder.next_id)
+    }
+
+    /// Returns true if no events have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Flush the events written to the file passed to [`record_events_to`], returning the
+    /// first error writing them, if any.
+    ///
+    /// [`record_events_to`]:crate::deterministic::DeterministicRuntime::record_events_to
+    pub fn flush(&self) -> io::Result<()> {
+        let mut lock = self.recorder.lock().unwrap();
+        let recorder = match lock.as_mut() {
+            Some(recorder) => recorder,
+            None => return Ok(()),
+        };
+        if let Some(error) = recorder.error.take() {
+            // keep failing, so the error is not lost if flushed again.
+            recorder.error = Some(io::Error::new(error.kind(), error.to_string()));
+            return Err(error);
+        }
+        match &mut recorder.sink {
+            Some(sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the approximate number of bytes held by the events kept in memory.
+    pub(crate) fn memory(&self) -> usize {
+        let lock = self.recorder.lock().unwrap();
+        lock.as_ref().map_or(0, |recorder| recorder.events.len()) * mem::size_of::<EventRecord>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, RandEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    /// Run a small workload touching tasks, timers, the network and the random number
+    /// generator, returning the log of events.
+    fn run(log: impl FnOnce(&DeterministicRuntime) -> EventLog) -> EventLog {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let log = log(&runtime);
+        let handle = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+                let mut listener = handle.bind(addr).await.unwrap();
+                let client = handle.clone();
+                handle.spawn(async move {
+                    client.delay_from(Duration::from_secs(1)).await;
+                    let mut socket = client.connect(addr).await.unwrap();
+                    socket.write_all(b"hello").await.unwrap();
+                });
+                let _ = listener.accept().await.unwrap();
+                handle.random().should_fault(0.5);
+            })
+            .unwrap();
+        log
+    }
+
+    #[test]
+    /// Test that polls, timers, sends and random draws are logged with ids in the order they
+    /// happened, and that the log is the same in every run.
+    fn record_events() {
+        let log = run(|runtime| runtime.record_events(1000));
+        let events = log.events();
+        assert_eq!(log.len(), events.len() as u64);
+        assert!(events.iter().enumerate().all(|(i, e)| e.id == i as u64));
+        let host = net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, 1));
+        let kinds: Vec<_> = events.iter().map(|event| &event.kind).collect();
+        let position = |kind: &EventKind| kinds.iter().position(|k| *k == kind).unwrap();
+        let spawned = position(&EventKind::Timeline(TimelineKind::TaskSpawned {
+            host,
+            task: 0,
+        }));
+        let polled = position(&EventKind::TaskPolled { host, task: 0 });
+        let fired = position(&EventKind::TimerFired { host, timer: 0 });
+        assert!(spawned < polled && polled < fired);
+        assert_eq!(events[fired].at, Duration::from_secs(1));
+        assert!(kinds.iter().any(|kind| match kind {
+            EventKind::Sent { len, .. } => *len == 5,
+            _ => false,
+        }));
+        assert!(kinds
+            .iter()
+            .any(|kind| **kind == EventKind::RandomDraw { draw: 0 }));
+        assert_eq!(run(|runtime| runtime.record_events(1000)).events(), events);
+
+        let recent = run(|runtime| runtime.record_events(3)).events();
+        assert_eq!(recent, events[events.len() - 3..].to_vec());
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Test that every event is written to the sink as a line of JSON.
+    fn record_events_to() {
+        let events = run(|runtime| runtime.record_events(1000)).events();
+        let buffer = SharedBuffer::default();
+        let sink = buffer.clone();
+        let log = run(move |runtime| runtime.record_events_to(sink));
+        log.flush().unwrap();
+        assert!(log.events().is_empty());
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = events.iter().map(|event| event.to_json() + "\n").collect();
+        assert_eq!(written, lines.concat());
+        assert!(written.contains(
+            "{\"id\":0,\"at_us\":0,\"kind\":\"task_spawned\",\"host\":\"10.0.0.1\",\"task\":0}\n"
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! Typed publish/subscribe event bus for domain events.
+//!
+//! Application code and workloads publish events such as "became leader" or "snapshot
+//! installed" through a handle scoped to the publishing host. Subscribers receive every
+//! event of the type they subscribed to, stamped with the simulated time at which it was
+//! published and the address of the publisher. Every published event is also emitted as a
+//! trace event.
+use crate::deterministic::DeterministicTimeHandle;
+use futures::{channel::mpsc, Poll, Stream, StreamExt};
+use std::{any, collections, fmt, net, pin::Pin, sync, task::Context, time};
+use tracing::trace;
+
+/// An event published on the event bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event<T> {
+    /// Simulated time at which the event was published.
+    pub at: time::Instant,
+    /// Address of the host which published the event.
+    pub source: net::IpAddr,
+    pub payload: T,
+}
+
+#[derive(Debug)]
+struct Inner {
+    time: DeterministicTimeHandle,
+    /// Subscribers keyed by event type. Each entry holds boxed `mpsc::UnboundedSender<Event<T>>`
+    /// values for the corresponding `T`.
+    subscribers: collections::HashMap<any::TypeId, Vec<Box<dyn any::Any + Send>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeterministicEventBus {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicEventBus {
+    pub(crate) fn new(time: DeterministicTimeHandle) -> Self {
+        let inner = Inner {
+            time,
+            subscribers: collections::HashMap::new(),
+        };
+        let inner = sync::Arc::new(sync::Mutex::new(inner));
+        Self { inner }
+    }
+
+    /// Returns a handle which publishes events on behalf of `source`.
+    pub(crate) fn scoped(&self, source: net::IpAddr) -> DeterministicEventBusHandle {
+        DeterministicEventBusHandle {
+            source,
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Handle for publishing and subscribing to events, scoped to a particular host.
+#[derive(Debug, Clone)]
+pub struct DeterministicEventBusHandle {
+    source: net::IpAddr,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl DeterministicEventBusHandle {
+    /// Returns a handle to the same event bus, publishing on behalf of `source`.
+    pub(crate) fn scoped(&self, source: net::IpAddr) -> Self {
+        Self {
+            source,
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+
+    /// Publish `payload` to all subscribers of events of type `T`.
+    pub fn publish<T>(&self, payload: T)
+    where
+        T: fmt::Debug + Clone + Send + 'static,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        let event = Event {
+            at: lock.time.now(),
+            source: self.source,
+            payload,
+        };
+        trace!("{} published {:?}", self.source, event.payload);
+        if let Some(subscribers) = lock.subscribers.get_mut(&any::TypeId::of::<T>()) {
+            subscribers.retain(|subscriber| {
+                let tx = subscriber
+                    .downcast_ref::<mpsc::UnboundedSender<Event<T>>>()
+                    .expect("subscribers should be keyed by event type");
+                tx.unbounded_send(event.clone()).is_ok()
+            });
+        }
+    }
+
+    /// Subscribe to all events of type `T` published after this call.
+    pub fn subscribe<T>(&self) -> Subscription<T>
+    where
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded::<Event<T>>();
+        let mut lock = self.inner.lock().unwrap();
+        lock.subscribers
+            .entry(any::TypeId::of::<T>())
+            .or_default()
+            .push(Box::new(tx));
+        Subscription { rx }
+    }
+}
+
+/// Stream of events returned by [`DeterministicEventBusHandle::subscribe`].
+///
+/// [`DeterministicEventBusHandle::subscribe`]:DeterministicEventBusHandle::subscribe
+#[derive(Debug)]
+pub struct Subscription<T> {
+    rx: mpsc::UnboundedReceiver<Event<T>>,
+}
+
+impl<T> Subscription<T> {
+    /// Returns the next event if one has already been published, without waiting.
+    pub fn try_next(&mut self) -> Option<Event<T>> {
+        self.rx.try_next().ok().and_then(|event| event)
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = Event<T>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TimeEnv;
+    use futures::StreamExt;
+    use std::{net, time::Duration};
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Raft {
+        BecameLeader { term: u64 },
+    }
+
+    #[test]
+    /// Test that subscribers receive events of their type, stamped with the publisher and time.
+    fn publish_subscribe() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let node_addr = net::Ipv4Addr::new(10, 0, 0, 1).into();
+        let node = runtime.handle(node_addr);
+        runtime
+            .block_on(async {
+                let mut leaders = handle.event_bus_handle().subscribe::<Raft>();
+                let mut numbers = handle.event_bus_handle().subscribe::<u64>();
+                node.delay_from(Duration::from_secs(5)).await;
+                node.event_bus_handle()
+                    .publish(Raft::BecameLeader { term: 2 });
+                let event = leaders.next().await.unwrap();
+                assert_eq!(event.payload, Raft::BecameLeader { term: 2 });
+                assert_eq!(event.source, node_addr);
+                assert_eq!(event.at, node.now());
+                assert!(numbers.try_next().is_none());
+            })
+            .unwrap();
+    }
+}
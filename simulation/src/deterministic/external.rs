@@ -0,0 +1,298 @@
+//! Test doubles for services which live outside of the system under test.
+//!
+//! Real systems often depend on services they do not own, such as an object store or a
+//! webhook endpoint. An `ExternalService` stands in for such a dependency inside the
+//! simulation. It listens on a simulated address, registers itself with the discovery
+//! registry under a name, and answers each request using a handler supplied by the test.
+//!
+//! Requests and responses are framed with a tokio codec, and the handler is passed the
+//! deterministic RNG so responses can vary with the seed. Faults are injected on top of the
+//! handler: responses can be delayed, replaced with an error response, never sent, or the
+//! connection can be dropped before responding. Clients see a dropped connection as an error
+//! carrying the `InjectedFault` recorded for the drop.
+use crate::{
+    deterministic::{
+        ChaosKind, ChaosTarget, DeterministicRandomHandle, DeterministicRuntimeHandle,
+    },
+    NetEnv, SpawnEnv, TcpListener, TcpStream, TimeEnv,
+};
+use futures::{future, SinkExt, StreamExt};
+use std::{fmt, io, net, ops, sync, time};
+use tokio::codec::{Decoder, Encoder, Framed};
+use tracing::{debug, trace};
+
+type Handler<Req, Resp> =
+    sync::Arc<sync::Mutex<dyn FnMut(Req, &DeterministicRandomHandle) -> Resp + Send>>;
+/// Probability of responding with an error, and the function producing the error response.
+type ErrorResponse<Req, Resp> = (f64, sync::Arc<dyn Fn(&Req) -> Resp + Send + Sync>);
+
+/// A simulated external service, answering requests framed with the codec `C`.
+pub struct ExternalService<C>
+where
+    C: Decoder + Encoder,
+{
+    name: String,
+    codec: C,
+    handler: Handler<<C as Decoder>::Item, <C as Encoder>::Item>,
+    latency: Option<ops::Range<time::Duration>>,
+    error: Option<ErrorResponse<<C as Decoder>::Item, <C as Encoder>::Item>>,
+    drop_probability: f64,
+    hang_probability: f64,
+}
+
+impl<C> fmt::Debug for ExternalService<C>
+where
+    C: Decoder + Encoder,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ExternalService {{ name: {}, latency: {:?}, drop_probability: {}, hang_probability: {} }}",
+            self.name, self.latency, self.drop_probability, self.hang_probability
+        )
+    }
+}
+
+impl<C> ExternalService<C>
+where
+    C: Decoder + Encoder + Clone + Unpin + Send + Sync + 'static,
+    <C as Decoder>::Item: fmt::Debug + Clone + Send + 'static,
+    <C as Encoder>::Item: Send + 'static,
+{
+    /// Create a new external service which will be registered under `name`. Each request
+    /// decoded by `codec` is passed to `handler`, and the returned response is encoded back
+    /// to the client.
+    pub fn new<F>(name: &str, codec: C, handler: F) -> Self
+    where
+        F: FnMut(<C as Decoder>::Item, &DeterministicRandomHandle) -> <C as Encoder>::Item
+            + Send
+            + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            codec,
+            handler: sync::Arc::new(sync::Mutex::new(handler)),
+            latency: None,
+            error: None,
+            drop_probability: 0.0,
+            hang_probability: 0.0,
+        }
+    }
+
+    /// Delay each response by a random duration in `range`.
+    pub fn latency(mut self, range: ops::Range<time::Duration>) -> Self {
+        self.latency.replace(range);
+        self
+    }
+
+    /// With the provided probability, respond with the result of `response` instead of
+    /// calling the handler.
+    pub fn error<F>(mut self, probability: f64, response: F) -> Self
+    where
+        F: Fn(&<C as Decoder>::Item) -> <C as Encoder>::Item + Send + Sync + 'static,
+    {
+        self.error.replace((probability, sync::Arc::new(response)));
+        self
+    }
+
+    /// With the provided probability, close the connection upon receiving a request.
+    pub fn drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability;
+        self
+    }
+
+    /// With the provided probability, never respond to a request.
+    pub fn hang_probability(mut self, probability: f64) -> Self {
+        self.hang_probability = probability;
+        self
+    }
+
+    /// Bind the service to `addr` and register it with the discovery registry. The service
+    /// runs as a process on the host `addr.ip()`, so killing that host stops the service.
+    pub async fn serve(
+        self,
+        handle: &DeterministicRuntimeHandle,
+        addr: net::SocketAddr,
+    ) -> io::Result<ExternalServiceHandle<<C as Decoder>::Item>> {
+        let handle = handle.scoped(addr.ip());
+        let mut listener = handle.bind(addr).await?;
+        handle.discovery_handle().register(&self.name, addr);
+        debug!("external service {} listening on {}", self.name, addr);
+        let requests = sync::Arc::new(sync::Mutex::new(vec![]));
+        let service = ExternalServiceHandle {
+            name: self.name.clone(),
+            addr,
+            requests: sync::Arc::clone(&requests),
+        };
+        let service_state = sync::Arc::new(self);
+        let env = handle.clone();
+        handle.spawn(async move {
+            while let Ok((socket, peer)) = listener.accept().await {
+                trace!("external service {} accepted {}", service_state.name, peer);
+                let service_state = sync::Arc::clone(&service_state);
+                let requests = sync::Arc::clone(&requests);
+                let conn_env = env.clone();
+                env.spawn(async move {
+                    let transport = Framed::new(socket, service_state.codec.clone());
+                    service_state
+                        .handle_connection(conn_env, transport, requests)
+                        .await;
+                });
+            }
+        });
+        Ok(service)
+    }
+
+    async fn handle_connection(
+        &self,
+        handle: DeterministicRuntimeHandle,
+        mut transport: Framed<crate::deterministic::Socket, C>,
+        requests: sync::Arc<sync::Mutex<Vec<<C as Decoder>::Item>>>,
+    ) {
+        let random = handle.random_handle();
+        let chaos = handle.chaos_log_handle();
+        let target = ChaosTarget::Service(self.name.clone());
+        loop {
+            let request = match transport.next().await {
+                Some(Ok(request)) => request,
+                _ => return,
+            };
+            trace!("external service {} received {:?}", self.name, request);
+            requests.lock().unwrap().push(request.clone());
+            if random.should_fault(self.drop_probability) {
+                debug!("external service {} dropping connection", self.name);
+                let fault = chaos.inject(ChaosKind::ServiceDrop, target.clone());
+                let socket = transport.get_ref();
+                if let (Ok(local), Ok(peer)) = (socket.local_addr(), socket.peer_addr()) {
+                    handle.disconnect(local, peer, fault);
+                }
+                return;
+            }
+            if random.should_fault(self.hang_probability) {
+                debug!("external service {} hanging on {:?}", self.name, request);
+                chaos.record(ChaosKind::ServiceHang, target.clone());
+                future::pending::<()>().await;
+            }
+            if let Some(latency) = self.latency.clone() {
+                handle.delay_from(random.gen_range(latency)).await;
+            }
+            let response = match self.error.as_ref() {
+                Some((probability, error)) if random.should_fault(*probability) => {
+                    chaos.record(ChaosKind::ServiceError, target.clone());
+                    error(&request)
+                }
+                _ => {
+                    let mut handler = self.handler.lock().unwrap();
+                    (*handler)(request, &random)
+                }
+            };
+            if transport.send(response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Handle to a running [`ExternalService`], used to inspect the requests it received.
+///
+/// [`ExternalService`]:ExternalService
+#[derive(Debug, Clone)]
+pub struct ExternalServiceHandle<Req> {
+    name: String,
+    addr: net::SocketAddr,
+    requests: sync::Arc<sync::Mutex<Vec<Req>>>,
+}
+
+impl<Req> ExternalServiceHandle<Req>
+where
+    Req: Clone,
+{
+    /// Returns the name the service is registered under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the address the service is listening on.
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Returns every request received by the service so far, in the order they arrived.
+    pub fn requests(&self) -> Vec<Req> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use tokio::codec::LinesCodec;
+
+    fn object_store() -> ExternalService<LinesCodec> {
+        let mut objects = std::collections::HashMap::new();
+        ExternalService::new(
+            "object-store",
+            LinesCodec::new(),
+            move |request: String, _: &DeterministicRandomHandle| {
+                let parts: Vec<&str> = request.splitn(3, ' ').collect();
+                match parts.as_slice() {
+                    ["PUT", key, value] => {
+                        objects.insert(key.to_string(), value.to_string());
+                        String::from("200")
+                    }
+                    ["GET", key] => match objects.get(*key) {
+                        Some(value) => format!("200 {}", value),
+                        None => String::from("404"),
+                    },
+                    _ => String::from("400"),
+                }
+            },
+        )
+        .latency(time::Duration::from_millis(10)..time::Duration::from_millis(50))
+        .error(0.3, |_| String::from("503"))
+    }
+
+    fn run(seed: u64) -> Vec<String> {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+        runtime
+            .block_on(async {
+                let addr: net::SocketAddr = "10.1.0.1:443".parse().unwrap();
+                let service = object_store().serve(&handle, addr).await.unwrap();
+                let addrs = handle.discovery_handle().lookup("object-store");
+                assert_eq!(addrs, vec![addr]);
+
+                let socket = handle.connect(addrs[0]).await.unwrap();
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                let mut responses = vec![];
+                for request in &["PUT a 1", "GET a", "GET b", "GET a"] {
+                    transport.send(request.to_string()).await.unwrap();
+                    responses.push(transport.next().await.unwrap().unwrap());
+                }
+                assert_eq!(service.requests().len(), 4);
+                responses
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that external services respond deterministically for a given seed, including
+    /// injected errors.
+    fn scripted_responses() {
+        let responses = run(1);
+        assert_eq!(responses, run(1));
+        assert_eq!(responses.len(), 4);
+        for response in &responses {
+            assert!(
+                ["200", "200 1", "404", "503"].contains(&response.as_str()),
+                "unexpected response {}",
+                response
+            );
+        }
+        let errors = (0..10)
+            .flat_map(run)
+            .filter(|response| response == "503")
+            .count();
+        assert!(errors > 0);
+    }
+}
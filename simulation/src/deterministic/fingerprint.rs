@@ -0,0 +1,118 @@
+//! A hasher whose output is the same on every platform, for fingerprints of runs which are
+//! compared between machines.
+//!
+//! `DefaultHasher` is free to change its algorithm between releases of Rust, and the `Hash`
+//! implementations of `usize` and `isize` write as many bytes as a pointer has, in the byte
+//! order of the platform. [`FingerprintHasher`] is FNV-1a over integers written as
+//! little-endian, with `usize` and `isize` widened to 64 bits.
+//!
+//! [`FingerprintHasher`]:FingerprintHasher
+use std::hash::Hasher;
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a, writing integers in a fixed width and byte order.
+#[derive(Debug, Clone)]
+pub(crate) struct FingerprintHasher {
+    state: u64,
+}
+
+impl Default for FingerprintHasher {
+    fn default() -> Self {
+        Self {
+            state: OFFSET_BASIS,
+        }
+    }
+}
+
+impl Hasher for FingerprintHasher {
+    fn finish(&self) -> u64 {
+        self.state
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u64::from(*byte);
+            self.state = self.state.wrapping_mul(PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{hash::Hash, time::Duration};
+
+    fn fingerprint<T: Hash>(value: &T) -> u64 {
+        let mut hasher = FingerprintHasher::default();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    /// Test that hashes match the published FNV-1a test vectors and known values for the
+    /// types fingerprints are made of, so they are the same on every platform.
+    fn test_vectors() {
+        let mut hasher = FingerprintHasher::default();
+        assert_eq!(hasher.finish(), 0xcbf2_9ce4_8422_2325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+        let mut hasher = FingerprintHasher::default();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.finish(), 0x8594_4171_f739_67e8);
+
+        assert_eq!(fingerprint(&7usize), fingerprint(&7u64));
+        assert_eq!(fingerprint(&-7isize), fingerprint(&-7i64));
+        assert_eq!(fingerprint(&0x0102_0304u32), {
+            let mut hasher = FingerprintHasher::default();
+            hasher.write(&[4, 3, 2, 1]);
+            hasher.finish()
+        });
+        // 1 second as a `u64`, then 500,000,000 nanoseconds as a `u32`.
+        assert_eq!(
+            fingerprint(&Duration::from_millis(1500)),
+            0x596c_9b3a_4fc2_c61d
+        );
+    }
+}
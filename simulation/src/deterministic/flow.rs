@@ -0,0 +1,405 @@
+//! Tracing requests across hosts.
+//!
+//! Once enabled with [`DeterministicRuntime::trace_flows`], a task can start a flow with
+//! [`begin_flow`], naming a request it is about to make. Connections opened and bytes written
+//! by the task from then on are tagged with the flow, and a task which accepts a tagged
+//! connection or reads tagged bytes joins the flow itself, so the reply written by a server is
+//! attributed to the request it answers. Tasks spawned by a task in a flow join it too.
+//!
+//! Each [`Flow`] is a timeline in simulated time of the request as it crosses hosts: when the
+//! client wrote each message, when it reached the pipe of the peer after any latency or clog,
+//! and when the peer read it. The gaps between these break the latency of a request down into
+//! time spent on the network and time spent waiting for the receiving task.
+//!
+//! [`DeterministicRuntime::trace_flows`]:crate::deterministic::DeterministicRuntime::trace_flows
+//! [`begin_flow`]:crate::deterministic::DeterministicRuntimeHandle::begin_flow
+//! [`Flow`]:Flow
+use super::{timeline, TimeReader};
+use std::{cell::Cell, fmt::Write as _, io, net, sync, time};
+
+thread_local! {
+    static CURRENT_FLOW: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Returns the flow of the task being polled on the current thread, if any.
+pub(crate) fn current() -> Option<u64> {
+    CURRENT_FLOW.try_with(Cell::get).ok().flatten()
+}
+
+/// Join `flow` for the rest of the poll of the current task.
+pub(crate) fn adopt(flow: u64) {
+    CURRENT_FLOW.with(|current| current.set(Some(flow)));
+}
+
+/// Makes a task's flow current while the task is polled, saving any flow it began or joined
+/// during the poll back into it when dropped.
+pub(crate) struct Entered<'a> {
+    flow: &'a mut Option<u64>,
+    previous: Option<u64>,
+}
+
+/// Make `flow` current until the returned guard is dropped.
+pub(crate) fn enter(flow: &mut Option<u64>) -> Entered<'_> {
+    let previous = CURRENT_FLOW.with(|current| current.replace(*flow));
+    Entered { flow, previous }
+}
+
+impl Drop for Entered<'_> {
+    fn drop(&mut self) {
+        *self.flow = CURRENT_FLOW.with(|current| current.replace(self.previous));
+    }
+}
+
+/// A step of a flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlowStage {
+    /// The flow was begun.
+    Started,
+    /// A connection was opened from `source` to `dest`.
+    Connected {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    },
+    /// The connection from `source` was accepted by a listener on `dest`.
+    Accepted {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    },
+    /// A write of `bytes` was issued from `source` to `dest`.
+    Sent {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        bytes: usize,
+    },
+    /// The write reached `dest`, once any latency had passed and any clog had cleared.
+    Delivered {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        bytes: usize,
+    },
+    /// `dest` began reading the write.
+    Received {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        bytes: usize,
+    },
+    /// The flow was ended.
+    Finished,
+}
+
+/// A step of a flow, and when and where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowEvent {
+    /// Simulated time elapsed since the runtime was created.
+    pub at: time::Duration,
+    /// The host the step happened on.
+    pub host: net::IpAddr,
+    pub stage: FlowStage,
+}
+
+/// The timeline of a request across hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flow {
+    pub id: u64,
+    pub name: String,
+    /// Steps of the flow, in the order they happened.
+    pub events: Vec<FlowEvent>,
+}
+
+impl Flow {
+    /// Returns the simulated time between the first and last steps of the flow.
+    pub fn duration(&self) -> time::Duration {
+        match (self.events.first(), self.events.last()) {
+            (Some(first), Some(last)) => last.at - first.at,
+            _ => time::Duration::from_secs(0),
+        }
+    }
+}
+
+/// Handle for tracing flows and exporting them.
+#[derive(Debug, Clone)]
+pub struct FlowTrace {
+    time: TimeReader,
+    /// Flows begun so far, indexed by id, or `None` if tracing is not enabled.
+    flows: sync::Arc<sync::Mutex<Option<Vec<Flow>>>>,
+}
+
+impl FlowTrace {
+    pub(crate) fn new(time: TimeReader) -> Self {
+        Self {
+            time,
+            flows: sync::Arc::new(sync::Mutex::new(None)),
+        }
+    }
+
+    /// Start tracing flows.
+    pub(crate) fn enable(&self) {
+        let mut lock = self.flows.lock().unwrap();
+        if lock.is_none() {
+            *lock = Some(vec![]);
+        }
+    }
+
+    /// Begin a flow named `name` on `host`, making it the flow of the current task. Returns
+    /// the id of the flow, or `None` if tracing is not enabled.
+    pub(crate) fn begin(&self, host: net::IpAddr, name: &str) -> Option<u64> {
+        let id = {
+            let mut lock = self.flows.lock().unwrap();
+            let flows = lock.as_mut()?;
+            let id = flows.len() as u64;
+            flows.push(Flow {
+                id,
+                name: name.to_string(),
+                events: vec![],
+            });
+            id
+        };
+        adopt(id);
+        self.record_for(id, host, FlowStage::Started);
+        Some(id)
+    }
+
+    /// End the flow of the current task, if any.
+    pub(crate) fn end(&self, host: net::IpAddr) {
+        if self.record(host, FlowStage::Finished) {
+            CURRENT_FLOW.with(|current| current.set(None));
+        }
+    }
+
+    /// Record `stage` on `host` in the flow of the current task, returning false if the task
+    /// is in no flow.
+    pub(crate) fn record(&self, host: net::IpAddr, stage: FlowStage) -> bool {
+        match current() {
+            Some(flow) => {
+                self.record_for(flow, host, stage);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record `stage` on `host` in `flow`.
+    pub(crate) fn record_for(&self, flow: u64, host: net::IpAddr, stage: FlowStage) {
+        let at = self.time.elapsed();
+        let mut lock = self.flows.lock().unwrap();
+        if let Some(flow) = lock.as_mut().and_then(|flows| flows.get_mut(flow as usize)) {
+            flow.events.push(FlowEvent { at, host, stage });
+        }
+    }
+
+    /// Returns every flow begun so far, in the order they were begun.
+    pub fn flows(&self) -> Vec<Flow> {
+        self.flows.lock().unwrap().clone().unwrap_or_default()
+    }
+
+    /// Returns the flow with `id`, if it has been begun.
+    pub fn flow(&self, id: u64) -> Option<Flow> {
+        let lock = self.flows.lock().unwrap();
+        lock.as_ref()
+            .and_then(|flows| flows.get(id as usize))
+            .cloned()
+    }
+
+    /// Returns every flow as a JSON document. Timestamps are in microseconds of simulated
+    /// time.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"flows\":[");
+        for (i, flow) in self.flows().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "\n{{\"id\":{},\"name\":\"{}\",\"duration_us\":{},\"events\":[",
+                flow.id,
+                timeline::escape(&flow.name),
+                flow.duration().as_micros()
+            );
+            for (i, event) in flow.events.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                let _ = write!(
+                    json,
+                    "\n{{\"at_us\":{},\"host\":\"{}\",{}}}",
+                    event.at.as_micros(),
+                    event.host,
+                    stage_fields(&event.stage)
+                );
+            }
+            json.push_str("\n]}");
+        }
+        json.push_str("\n]}\n");
+        json
+    }
+
+    /// Write every flow as JSON to `writer`.
+    pub fn write_json<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all(self.to_json().as_bytes())
+    }
+}
+
+/// Returns the JSON fields describing `stage`.
+fn stage_fields(stage: &FlowStage) -> String {
+    let addrs = |stage: &str, source: &net::SocketAddr, dest: &net::SocketAddr| {
+        format!(
+            "\"stage\":\"{}\",\"source\":\"{}\",\"dest\":\"{}\"",
+            stage, source, dest
+        )
+    };
+    match stage {
+        FlowStage::Started => "\"stage\":\"started\"".to_string(),
+        FlowStage::Connected { source, dest } => addrs("connected", source, dest),
+        FlowStage::Accepted { source, dest } => addrs("accepted", source, dest),
+        FlowStage::Sent {
+            source,
+            dest,
+            bytes,
+        } => format!("{},\"bytes\":{}", addrs("sent", source, dest), bytes),
+        FlowStage::Delivered {
+            source,
+            dest,
+            bytes,
+        } => format!("{},\"bytes\":{}", addrs("delivered", source, dest), bytes),
+        FlowStage::Received {
+            source,
+            dest,
+            bytes,
+        } => format!("{},\"bytes\":{}", addrs("received", source, dest), bytes),
+        FlowStage::Finished => "\"stage\":\"finished\"".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that a request and its reply are traced across the client and server, and that
+    /// traffic outside of the flow is not.
+    fn request_flow() {
+        let (mut runtime, _) = DeterministicRuntime::builder()
+            .latency(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let flows = runtime.trace_flows();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                server.spawn(async move {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut buf = [0; 4];
+                    socket.read_exact(&mut buf).await.unwrap();
+                    socket.write_all(b"pong").await.unwrap();
+                    socket.read_exact(&mut buf).await.unwrap();
+                });
+
+                assert_eq!(client.begin_flow("ping"), Some(0));
+                let mut socket = client.connect(addr).await.unwrap();
+                socket.write_all(b"ping").await.unwrap();
+                let mut buf = [0; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                client.end_flow();
+                socket.write_all(b"done").await.unwrap();
+                client.delay_from(Duration::from_secs(1)).await;
+            })
+            .unwrap();
+
+        let source: net::SocketAddr = "10.0.0.2:65535".parse().unwrap();
+        let dest: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let (client, server) = (source.ip(), dest.ip());
+        let event = |millis, host, stage| FlowEvent {
+            at: Duration::from_millis(millis),
+            host,
+            stage,
+        };
+        let flow = flows.flow(0).unwrap();
+        assert_eq!(flow.name, "ping");
+        assert_eq!(
+            flow.events,
+            vec![
+                event(0, client, FlowStage::Started),
+                event(0, client, FlowStage::Connected { source, dest }),
+                event(
+                    0,
+                    client,
+                    FlowStage::Sent {
+                        source,
+                        dest,
+                        bytes: 4
+                    }
+                ),
+                event(0, server, FlowStage::Accepted { source, dest }),
+                event(
+                    10,
+                    server,
+                    FlowStage::Delivered {
+                        source,
+                        dest,
+                        bytes: 4
+                    }
+                ),
+                event(
+                    10,
+                    server,
+                    FlowStage::Received {
+                        source,
+                        dest,
+                        bytes: 4
+                    }
+                ),
+                event(
+                    10,
+                    server,
+                    FlowStage::Sent {
+                        source: dest,
+                        dest: source,
+                        bytes: 4
+                    }
+                ),
+                event(
+                    10,
+                    client,
+                    FlowStage::Delivered {
+                        source: dest,
+                        dest: source,
+                        bytes: 4
+                    }
+                ),
+                event(
+                    10,
+                    client,
+                    FlowStage::Received {
+                        source: dest,
+                        dest: source,
+                        bytes: 4
+                    }
+                ),
+                event(10, client, FlowStage::Finished),
+            ]
+        );
+        assert_eq!(flow.duration(), Duration::from_millis(10));
+        assert_eq!(flows.flows().len(), 1);
+
+        let json = flows.to_json();
+        assert!(json.starts_with(
+            "{\"flows\":[\n{\"id\":0,\"name\":\"ping\",\"duration_us\":10000,\"events\":[\n"
+        ));
+        assert!(json.contains(
+            "{\"at_us\":10000,\"host\":\"10.0.0.1\",\"stage\":\"received\",\
+             \"source\":\"10.0.0.2:65535\",\"dest\":\"10.0.0.1:9092\",\"bytes\":4}"
+        ));
+        assert!(json
+            .ends_with("{\"at_us\":10000,\"host\":\"10.0.0.2\",\"stage\":\"finished\"}\n]}\n]}\n"));
+    }
+}
@@ -0,0 +1,171 @@
+use super::{stall, transfer, Inner};
+use crate::deterministic::Descriptor;
+use async_trait::async_trait;
+use futures::{FutureExt, Poll};
+use std::{io, net, pin::Pin, sync, task::Context};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Handle to a file on a simulated host's filesystem.
+///
+/// Reads and writes through `AsyncRead` and `AsyncWrite` start at the file's cursor and
+/// advance it, while `read_at` and `write_at` leave it in place.
+#[derive(Debug)]
+pub struct File {
+    addr: net::IpAddr,
+    inode: u64,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    cursor: u64,
+    /// Delay for the transfer of an in progress `AsyncRead` or `AsyncWrite` operation.
+    transfer: Option<tokio_timer::Delay>,
+    _descriptor: Descriptor,
+}
+
+impl File {
+    pub(crate) fn new(
+        addr: net::IpAddr,
+        inode: u64,
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        descriptor: Descriptor,
+    ) -> Self {
+        Self {
+            addr,
+            inode,
+            inner,
+            cursor: 0,
+            transfer: None,
+            _descriptor: descriptor,
+        }
+    }
+}
+
+impl File {
+    /// Wait for the disk to transfer any data written since the file was last synced, and for
+    /// any stall injected into the sync.
+    async fn flush(&self) -> io::Result<()> {
+        let unsynced = {
+            let mut lock = self.inner.lock().unwrap();
+            lock.disk(self.addr).inode(self.inode)?.unsynced_bytes()
+        };
+        transfer(&self.inner, self.addr, unsynced).await;
+        stall(&self.inner, self.addr).await;
+        Ok(())
+    }
+
+    /// Poll for the disk to transfer `bytes` for an `AsyncRead` or `AsyncWrite` operation.
+    fn poll_transfer(&mut self, cx: &mut Context<'_>, bytes: usize) -> Poll<()> {
+        if self.transfer.is_none() {
+            let mut lock = self.inner.lock().unwrap();
+            if !lock.begin(self.addr) {
+                return Poll::Pending;
+            }
+            let delay = lock.schedule(self.addr, bytes as u64);
+            drop(lock);
+            match delay {
+                Some(delay) => self.transfer = Some(delay),
+                None => return Poll::Ready(()),
+            }
+        }
+        futures::ready!(self.transfer.as_mut().unwrap().poll_unpin(cx));
+        self.transfer = None;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().close(self.addr, self.inode);
+    }
+}
+
+impl AsyncRead for File {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_transfer(cx, buf.len()));
+        let (addr, inode, cursor) = (self.addr, self.inode, self.cursor);
+        let read = self
+            .inner
+            .lock()
+            .unwrap()
+            .read_at(addr, inode, cursor, buf)?;
+        self.cursor += read as u64;
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl AsyncWrite for File {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_transfer(cx, buf.len()));
+        let (addr, inode, cursor) = (self.addr, self.inode, self.cursor);
+        self.inner
+            .lock()
+            .unwrap()
+            .write_at(addr, inode, cursor as usize, buf)?;
+        self.cursor += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl crate::File for File {
+    async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(offset) => {
+                self.cursor = offset;
+                return Ok(offset);
+            }
+            io::SeekFrom::End(offset) => (crate::File::size(self).await?, offset),
+            io::SeekFrom::Current(offset) => (self.cursor, offset),
+        };
+        let cursor = base as i64 + offset;
+        if cursor < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.cursor = cursor as u64;
+        Ok(self.cursor)
+    }
+
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        transfer(&self.inner, self.addr, buf.len() as u64).await;
+        let mut lock = self.inner.lock().unwrap();
+        lock.read_at(self.addr, self.inode, offset, buf)
+    }
+
+    async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        transfer(&self.inner, self.addr, buf.len() as u64).await;
+        let mut lock = self.inner.lock().unwrap();
+        lock.write_at(self.addr, self.inode, offset as usize, buf)
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        let mut lock = self.inner.lock().unwrap();
+        Ok(lock.disk(self.addr).inode(self.inode)?.data().len() as u64)
+    }
+
+    async fn sync_all(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.inner.lock().unwrap().sync(self.addr, self.inode)
+    }
+
+    async fn sync_data(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.inner.lock().unwrap().sync(self.addr, self.inode)
+    }
+}
@@ -0,0 +1,848 @@
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{cmp, collections, io, net, ops, path, time};
+use tracing::trace;
+
+/// Size of a simulated disk sector. Writes within a single sector are atomic, while writes
+/// spanning multiple sectors may be torn by a crash.
+pub(crate) const SECTOR_SIZE: usize = 512;
+
+/// Error code returned when a disk is out of space.
+const ENOSPC: i32 = 28;
+/// Error code returned when writing to a read-only filesystem.
+const EROFS: i32 = 30;
+/// Error code returned when removing a directory which is not empty.
+const ENOTEMPTY: i32 = 39;
+
+/// A modification to a file which has not yet been synced.
+#[derive(Debug, Clone)]
+enum PendingWrite {
+    Write { offset: usize, data: Vec<u8> },
+    Truncate { len: usize },
+}
+
+impl PendingWrite {
+    fn apply(&self, data: &mut Vec<u8>) {
+        match self {
+            PendingWrite::Write { offset, data: buf } => {
+                if data.len() < offset + buf.len() {
+                    data.resize(offset + buf.len(), 0);
+                }
+                data[*offset..offset + buf.len()].copy_from_slice(buf);
+            }
+            PendingWrite::Truncate { len } => data.truncate(*len),
+        }
+    }
+
+    /// Returns the portion of this write which reached the disk if it was torn by a crash,
+    /// split at a sector boundary chosen by `random`. Returns `None` if the write cannot be
+    /// torn because it does not span multiple sectors.
+    fn tear(&self, random: &DeterministicRandomHandle) -> Option<PendingWrite> {
+        match self {
+            PendingWrite::Write { offset, data } => {
+                let first_boundary = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+                let end = offset + data.len();
+                if first_boundary >= end {
+                    return None;
+                }
+                let boundaries = (end - first_boundary - 1) / SECTOR_SIZE + 1;
+                let boundary = first_boundary + random.gen_index(boundaries) * SECTOR_SIZE;
+                Some(PendingWrite::Write {
+                    offset: *offset,
+                    data: data[..boundary - offset].to_vec(),
+                })
+            }
+            PendingWrite::Truncate { .. } => None,
+        }
+    }
+}
+
+/// Faults injected into the filesystem of a single host.
+#[derive(Debug, Clone)]
+pub(crate) struct DiskFaults {
+    /// Probability that the first write lost in a crash is partially applied.
+    pub(crate) torn_write_probability: f64,
+    /// Whether unsynced writes may reach the disk in a different order to the one they were
+    /// issued in.
+    pub(crate) reorder_writes: bool,
+    /// Maximum number of bytes which can be stored on the disk.
+    pub(crate) capacity: Option<u64>,
+    /// Probability that a write fails because the disk is out of space.
+    pub(crate) no_space_probability: f64,
+    /// Latency added to every operation.
+    pub(crate) latency: Option<ops::Range<time::Duration>>,
+    /// Maximum number of bytes per second which can be read or written.
+    pub(crate) throughput: Option<u64>,
+    /// Factor by which latency and throughput are degraded.
+    pub(crate) slowdown: u32,
+    /// Probability that a sync stalls, and the range of durations it stalls for.
+    pub(crate) sync_stall: Option<(f64, ops::Range<time::Duration>)>,
+    /// Probability that a read finds a byte in the data being read corrupted.
+    pub(crate) corruption_probability: f64,
+    /// Whether the filesystem has been remounted read-only, failing all modifications.
+    pub(crate) read_only: bool,
+    /// Whether renames are atomic. Non-atomic renames remove the destination, link it, then
+    /// unlink the source as separate steps.
+    pub(crate) atomic_rename: bool,
+    /// Whether changes to a directory's entries only survive a crash once the directory has
+    /// been synced.
+    pub(crate) dir_sync_required: bool,
+}
+
+impl Default for DiskFaults {
+    fn default() -> Self {
+        Self {
+            torn_write_probability: 0.0,
+            reorder_writes: false,
+            capacity: None,
+            no_space_probability: 0.0,
+            latency: None,
+            throughput: None,
+            slowdown: 1,
+            sync_stall: None,
+            corruption_probability: 0.0,
+            read_only: false,
+            atomic_rename: true,
+            dir_sync_required: false,
+        }
+    }
+}
+
+/// Contents of a single file. Files are referenced by inode number so that open handles
+/// continue to refer to the same file across renames and removals.
+///
+/// Writes are applied to `data` immediately, but only become durable once the file is
+/// synced. Until then they are held in `pending`, and may be lost if the host crashes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Inode {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+    pending: Vec<PendingWrite>,
+    /// Number of open handles referring to this inode.
+    handles: usize,
+}
+
+impl Inode {
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn write(&mut self, offset: usize, buf: &[u8]) {
+        let write = PendingWrite::Write {
+            offset,
+            data: buf.to_vec(),
+        };
+        write.apply(&mut self.data);
+        self.pending.push(write);
+    }
+
+    /// Returns the number of bytes written since the last sync.
+    pub(crate) fn unsynced_bytes(&self) -> u64 {
+        self.pending
+            .iter()
+            .map(|write| match write {
+                PendingWrite::Write { data, .. } => data.len() as u64,
+                PendingWrite::Truncate { .. } => 0,
+            })
+            .sum()
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        let truncate = PendingWrite::Truncate { len };
+        truncate.apply(&mut self.data);
+        self.pending.push(truncate);
+    }
+
+    /// Returns true if the file has been modified since it was last synced.
+    fn is_dirty(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Make all writes to this file durable.
+    pub(crate) fn sync(&mut self) {
+        self.durable = self.data.clone();
+        self.pending.clear();
+    }
+
+    /// Discard unsynced writes, as if the host crashed. A prefix of the unsynced writes,
+    /// chosen by `random`, is retained to model writes which reached the disk before the
+    /// crash. If writes can be reordered, any subset of them may be retained instead. The
+    /// first write which was lost may be torn, leaving some of its sectors on disk.
+    fn crash(&mut self, random: &DeterministicRandomHandle, faults: &DiskFaults) {
+        if faults.reorder_writes {
+            let mut lost = vec![];
+            for write in self.pending.drain(..) {
+                if random.should_fault(0.5) {
+                    write.apply(&mut self.durable);
+                } else {
+                    lost.push(write);
+                }
+            }
+            self.pending = lost;
+        } else {
+            let retained = random.gen_index(self.pending.len() + 1);
+            for write in self.pending.drain(..retained) {
+                write.apply(&mut self.durable);
+            }
+        }
+        if let Some(write) = self.pending.first() {
+            if faults.torn_write_probability > 0.0
+                && random.should_fault(faults.torn_write_probability)
+            {
+                if let Some(torn) = write.tear(random) {
+                    trace!("crash tore write {:?}", torn);
+                    torn.apply(&mut self.durable);
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            trace!("crash discarded {} unsynced writes", self.pending.len());
+        }
+        self.pending.clear();
+        self.data = self.durable.clone();
+    }
+}
+
+/// Directory whose contents survive the host being killed, as long as they were synced.
+pub(crate) const DURABLE_DIR: &str = "/data";
+/// Directory whose contents are discarded when the host is killed.
+pub(crate) const VOLATILE_DIR: &str = "/tmp";
+
+/// A corruption injected into stored file data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    /// Simulated time at which the corruption was injected.
+    pub at: time::Instant,
+    /// Path of the corrupted file, if it is still linked.
+    pub path: Option<path::PathBuf>,
+    /// Offset of the corrupted byte within the file.
+    pub offset: u64,
+}
+
+/// Returns true if `child` is an entry directly within the directory `parent`.
+fn is_entry(parent: &path::Path, child: &path::Path) -> bool {
+    child.parent() == Some(parent)
+}
+
+/// A copy of the files and directories stored on a simulated host's filesystem, including
+/// writes which have not been synced.
+#[derive(Debug, Clone)]
+pub struct DiskSnapshot {
+    paths: collections::BTreeMap<path::PathBuf, u64>,
+    dirs: collections::BTreeSet<path::PathBuf>,
+    durable_paths: collections::BTreeMap<path::PathBuf, u64>,
+    durable_dirs: collections::BTreeSet<path::PathBuf>,
+    inodes: collections::BTreeMap<u64, Inode>,
+}
+
+impl DiskSnapshot {
+    /// Returns the paths of every file in the snapshot, in sorted order.
+    pub fn files(&self) -> Vec<path::PathBuf> {
+        self.paths.keys().cloned().collect()
+    }
+}
+
+/// The filesystem belonging to a single simulated host.
+///
+/// `paths` and `dirs` make up the namespace as currently visible. `durable_paths` and
+/// `durable_dirs` hold the namespace as of the last directory sync, which is restored by a
+/// crash when directory syncs are required.
+#[derive(Debug)]
+pub(crate) struct Disk {
+    pub(crate) faults: DiskFaults,
+    /// Time at which the disk will have finished transferring all previously issued IO.
+    busy_until: Option<time::Instant>,
+    pub(crate) corruptions: Vec<Corruption>,
+    /// Number of operations which have been started on the disk.
+    pub(crate) operations: u64,
+    /// Number of operations after which the disk stops completing operations.
+    pub(crate) freeze_after: Option<u64>,
+    next_inode: u64,
+    paths: collections::BTreeMap<path::PathBuf, u64>,
+    dirs: collections::BTreeSet<path::PathBuf>,
+    durable_paths: collections::BTreeMap<path::PathBuf, u64>,
+    durable_dirs: collections::BTreeSet<path::PathBuf>,
+    inodes: collections::BTreeMap<u64, Inode>,
+}
+
+impl Default for Disk {
+    fn default() -> Self {
+        let dirs: collections::BTreeSet<path::PathBuf> = vec!["/", DURABLE_DIR, VOLATILE_DIR]
+            .into_iter()
+            .map(path::PathBuf::from)
+            .collect();
+        Self {
+            faults: DiskFaults::default(),
+            busy_until: None,
+            corruptions: vec![],
+            operations: 0,
+            freeze_after: None,
+            next_inode: 0,
+            paths: collections::BTreeMap::new(),
+            dirs: dirs.clone(),
+            durable_paths: collections::BTreeMap::new(),
+            durable_dirs: dirs,
+            inodes: collections::BTreeMap::new(),
+        }
+    }
+}
+
+impl Disk {
+    /// Fail with `EROFS` if the filesystem is read-only.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.faults.read_only {
+            return Err(io::Error::from_raw_os_error(EROFS));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_dir(&self, path: &path::Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.contains(path)
+    }
+
+    /// Check that the directory which would contain `path` exists, and that `path` is not
+    /// itself a directory.
+    fn check_parent(&self, path: &path::Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(io::ErrorKind::NotFound.into()),
+            _ if self.is_dir(path) => Err(io::ErrorKind::AlreadyExists.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn lookup(&self, path: &path::Path) -> io::Result<u64> {
+        self.paths
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    /// Returns the inode for `path`, creating an empty file if it does not exist. Existing
+    /// files are truncated.
+    fn create(&mut self, path: &path::Path) -> io::Result<u64> {
+        if let Some(inode) = self.paths.get(path).cloned() {
+            self.inodes.get_mut(&inode).unwrap().truncate(0);
+            return Ok(inode);
+        }
+        self.check_parent(path)?;
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.inodes.insert(inode, Inode::default());
+        self.paths.insert(path.to_path_buf(), inode);
+        Ok(inode)
+    }
+
+    /// Remove every inode which has no open handles and is not referenced by a path, either
+    /// currently or as of the last directory sync.
+    fn collect_garbage(&mut self) {
+        let mut referenced: collections::BTreeSet<u64> = self.paths.values().cloned().collect();
+        if self.faults.dir_sync_required {
+            referenced.extend(self.durable_paths.values().cloned());
+        }
+        self.inodes
+            .retain(|inode, node| node.handles > 0 || referenced.contains(inode));
+    }
+
+    /// Returns a copy of the files and directories on the disk. Files which are open but no
+    /// longer linked to a path are not included.
+    pub(crate) fn snapshot(&self) -> DiskSnapshot {
+        let linked: collections::BTreeSet<u64> = self
+            .paths
+            .values()
+            .chain(self.durable_paths.values())
+            .cloned()
+            .collect();
+        let inodes = self
+            .inodes
+            .iter()
+            .filter(|(inode, _)| linked.contains(inode))
+            .map(|(inode, node)| {
+                let node = Inode {
+                    handles: 0,
+                    ..node.clone()
+                };
+                (*inode, node)
+            })
+            .collect();
+        DiskSnapshot {
+            paths: self.paths.clone(),
+            dirs: self.dirs.clone(),
+            durable_paths: self.durable_paths.clone(),
+            durable_dirs: self.durable_dirs.clone(),
+            inodes,
+        }
+    }
+
+    /// Replace the files and directories on the disk with those in `snapshot`. Files which
+    /// are open remain usable, but are no longer linked to a path.
+    pub(crate) fn restore(&mut self, snapshot: &DiskSnapshot) {
+        // renumber the snapshot's inodes so that they cannot collide with open files.
+        let base = self.next_inode;
+        let renumber = |paths: &collections::BTreeMap<path::PathBuf, u64>| {
+            paths
+                .iter()
+                .map(|(path, inode)| (path.clone(), base + inode))
+                .collect()
+        };
+        self.paths = renumber(&snapshot.paths);
+        self.durable_paths = renumber(&snapshot.durable_paths);
+        self.dirs = snapshot.dirs.clone();
+        self.durable_dirs = snapshot.durable_dirs.clone();
+        self.inodes.retain(|_, node| node.handles > 0);
+        for (inode, node) in snapshot.inodes.iter() {
+            self.inodes.insert(base + inode, node.clone());
+        }
+        self.next_inode = base + snapshot.inodes.keys().last().map_or(0, |inode| inode + 1);
+    }
+
+    /// Move the directory `from`, along with everything within it, to `to`. Directory renames
+    /// are always atomic.
+    fn rename_dir(&mut self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        if to.starts_with(from) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        if self.paths.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.check_parent(to).or_else(|e| match e.kind() {
+            // replacing an empty directory is permitted.
+            io::ErrorKind::AlreadyExists => Ok(()),
+            _ => Err(e),
+        })?;
+        if self.dirs.contains(to) {
+            let occupied = self.paths.keys().any(|entry| is_entry(to, entry))
+                || self.dirs.iter().any(|entry| is_entry(to, entry));
+            if occupied {
+                return Err(io::Error::from_raw_os_error(ENOTEMPTY));
+            }
+        }
+        let moved = |path: &path::PathBuf| match path.strip_prefix(from) {
+            Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+            Ok(rest) => to.join(rest),
+            Err(_) => path.clone(),
+        };
+        self.paths = self
+            .paths
+            .iter()
+            .map(|(path, inode)| (moved(path), *inode))
+            .collect();
+        self.dirs = self.dirs.iter().map(moved).collect();
+        Ok(())
+    }
+
+    /// Make the entries of the directory `dir` survive a crash.
+    fn sync_dir(&mut self, dir: &path::Path) -> io::Result<()> {
+        if !self.is_dir(dir) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.durable_paths.retain(|path, _| !is_entry(dir, path));
+        self.durable_dirs.retain(|path| !is_entry(dir, path));
+        for (path, inode) in self.paths.iter() {
+            if is_entry(dir, path) {
+                self.durable_paths.insert(path.clone(), *inode);
+            }
+        }
+        for path in self.dirs.iter() {
+            if is_entry(dir, path) {
+                self.durable_dirs.insert(path.clone());
+            }
+        }
+        self.collect_garbage();
+        Ok(())
+    }
+
+    /// Discard changes to the namespace which are not durable, as if the host crashed.
+    fn crash_namespace(&mut self) {
+        if self.faults.dir_sync_required {
+            self.paths = self.durable_paths.clone();
+            self.dirs = self.durable_dirs.clone();
+        }
+        let volatile = path::Path::new(VOLATILE_DIR);
+        self.paths.retain(|path, _| !path.starts_with(volatile));
+        self.dirs
+            .retain(|path| path == volatile || !path.starts_with(volatile));
+        self.durable_paths = self.paths.clone();
+        self.durable_dirs = self.dirs.clone();
+        self.collect_garbage();
+    }
+
+    /// Returns the number of bytes held in memory for the disk: the current contents of each
+    /// file, the copy which survives a crash, and the writes which have not been synced.
+    fn memory(&self) -> usize {
+        self.inodes
+            .values()
+            .map(|inode| inode.data.len() + inode.durable.len() + inode.unsynced_bytes() as usize)
+            .sum()
+    }
+
+    /// Returns the number of bytes stored on the disk.
+    fn used(&self) -> u64 {
+        self.inodes
+            .values()
+            .map(|inode| inode.data.len() as u64)
+            .sum()
+    }
+
+    /// Check that the disk has room to grow by `growth` bytes, injecting out of space errors.
+    fn reserve(&self, growth: u64, random: &DeterministicRandomHandle) -> io::Result<()> {
+        if let Some(capacity) = self.faults.capacity {
+            if self.used() + growth > capacity {
+                return Err(io::Error::from_raw_os_error(ENOSPC));
+            }
+        }
+        if self.faults.no_space_probability > 0.0
+            && random.should_fault(self.faults.no_space_probability)
+        {
+            trace!("injecting out of space error");
+            return Err(io::Error::from_raw_os_error(ENOSPC));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn inode(&self, inode: u64) -> io::Result<&Inode> {
+        self.inodes
+            .get(&inode)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    pub(crate) fn inode_mut(&mut self, inode: u64) -> io::Result<&mut Inode> {
+        self.inodes
+            .get_mut(&inode)
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Inner {
+    time: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+    disks: collections::BTreeMap<net::IpAddr, Disk>,
+}
+
+/// Returns how long transferring `bytes` takes at `throughput` bytes per second, which must
+/// be nonzero.
+pub(crate) fn transfer_time(bytes: u64, throughput: u64) -> time::Duration {
+    // whole seconds first, so that transfers of any size fit.
+    let secs = bytes / throughput;
+    let nanos = u128::from(bytes % throughput) * 1_000_000_000 / u128::from(throughput);
+    time::Duration::new(secs, nanos as u32)
+}
+
+impl Inner {
+    pub(crate) fn new(time: DeterministicTimeHandle, random: DeterministicRandomHandle) -> Self {
+        Self {
+            time,
+            random,
+            disks: collections::BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of bytes held in memory for the disks of every host.
+    pub(crate) fn memory(&self) -> usize {
+        self.disks.values().map(Disk::memory).sum()
+    }
+
+    /// Start an operation on the disk of `addr`. Returns false if the disk is frozen, in which
+    /// case the operation never completes.
+    pub(crate) fn begin(&mut self, addr: net::IpAddr) -> bool {
+        let disk = self.disk(addr);
+        if let Some(limit) = disk.freeze_after {
+            if disk.operations >= limit {
+                trace!("disk of {} is frozen after {} operations", addr, limit);
+                return false;
+            }
+        }
+        disk.operations += 1;
+        true
+    }
+
+    /// Schedule an operation transferring `bytes` on the disk of `addr`, returning a delay
+    /// which completes once the operation would have completed. Transfers are queued behind
+    /// previously scheduled transfers when throughput is limited. Returns `None` if the disk
+    /// completes operations immediately.
+    pub(crate) fn schedule(&mut self, addr: net::IpAddr, bytes: u64) -> Option<tokio_timer::Delay> {
+        let now = self.time.now();
+        let random = self.random.clone();
+        let disk = self.disk(addr);
+        if disk.faults.latency.is_none() && disk.faults.throughput.is_none() {
+            return None;
+        }
+        let slowdown = disk.faults.slowdown;
+        let mut complete = now;
+        if let Some(throughput) = disk.faults.throughput {
+            let start = cmp::max(now, disk.busy_until.unwrap_or(now));
+            let transfer = transfer_time(bytes, throughput);
+            complete = start + transfer * slowdown;
+            disk.busy_until.replace(complete);
+        }
+        if let Some(latency) = disk.faults.latency.clone() {
+            complete += random.gen_range(latency) * slowdown;
+        }
+        Some(self.time.delay(complete))
+    }
+
+    /// Returns a delay for which a sync on the disk of `addr` stalls, if it stalls.
+    pub(crate) fn sync_stall(&mut self, addr: net::IpAddr) -> Option<tokio_timer::Delay> {
+        let random = self.random.clone();
+        let (probability, range) = self.disk(addr).faults.sync_stall.clone()?;
+        if !random.should_fault(probability) {
+            return None;
+        }
+        let stall = random.gen_range(range);
+        trace!("stalling sync on {} for {:?}", addr, stall);
+        Some(self.time.delay_from(stall))
+    }
+
+    pub(crate) fn delay_from(&self, duration: time::Duration) -> tokio_timer::Delay {
+        self.time.delay_from(duration)
+    }
+
+    pub(crate) fn disk(&mut self, addr: net::IpAddr) -> &mut Disk {
+        self.disks.entry(addr).or_default()
+    }
+
+    /// Open the existing file at `path`, returning its inode.
+    pub(crate) fn open(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<u64> {
+        let disk = self.disk(addr);
+        let inode = disk.lookup(path)?;
+        disk.inode_mut(inode)?.handles += 1;
+        Ok(inode)
+    }
+
+    /// Create or truncate the file at `path`, returning its inode.
+    pub(crate) fn create(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<u64> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        let inode = disk.create(path)?;
+        disk.inodes.get_mut(&inode).unwrap().handles += 1;
+        Ok(inode)
+    }
+
+    /// Release a handle to `inode`, removing it if it is no longer linked to any path.
+    pub(crate) fn close(&mut self, addr: net::IpAddr, inode: u64) {
+        let disk = self.disk(addr);
+        if let Some(node) = disk.inodes.get_mut(&inode) {
+            node.handles -= 1;
+        }
+        disk.collect_garbage();
+    }
+
+    /// Possibly flip a bit in the range `offset..offset + len` of `inode`, as if the stored
+    /// data had silently been corrupted. The corruption is permanent.
+    fn corrupt(&mut self, addr: net::IpAddr, inode: u64, offset: usize, len: usize) {
+        let now = self.time.now();
+        let random = self.random.clone();
+        let disk = self.disk(addr);
+        let probability = disk.faults.corruption_probability;
+        if len == 0 || probability == 0.0 || !random.should_fault(probability) {
+            return;
+        }
+        let target = offset + random.gen_index(len);
+        let bit = 1 << random.gen_range(0..8);
+        let node = match disk.inodes.get_mut(&inode) {
+            Some(node) => node,
+            None => return,
+        };
+        node.data[target] ^= bit;
+        if let Some(byte) = node.durable.get_mut(target) {
+            *byte ^= bit;
+        }
+        let path = disk
+            .paths
+            .iter()
+            .find(|(_, linked)| **linked == inode)
+            .map(|(path, _)| path.clone());
+        trace!("corrupting byte {} of {:?}", target, path);
+        disk.corruptions.push(Corruption {
+            at: now,
+            path,
+            offset: target as u64,
+        });
+    }
+
+    /// Read from `inode` at `offset` into `buf`, returning the number of bytes read.
+    pub(crate) fn read_at(
+        &mut self,
+        addr: net::IpAddr,
+        inode: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let len = self.disk(addr).inode(inode)?.data.len();
+        let offset = cmp::min(offset as usize, len);
+        let len = cmp::min(buf.len(), len - offset);
+        self.corrupt(addr, inode, offset, len);
+        let data = &self.disk(addr).inode(inode)?.data;
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+
+    /// Returns the length of the file at `path`.
+    pub(crate) fn len(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<usize> {
+        let disk = self.disk(addr);
+        let inode = disk.lookup(path)?;
+        Ok(disk.inode(inode)?.data.len())
+    }
+
+    pub(crate) fn read(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<Vec<u8>> {
+        let inode = self.disk(addr).lookup(path)?;
+        let len = self.disk(addr).inode(inode)?.data.len();
+        self.corrupt(addr, inode, 0, len);
+        Ok(self.disk(addr).inode(inode)?.data.clone())
+    }
+
+    /// Discard unsynced writes to every file on `addr`.
+    pub(crate) fn crash(&mut self, addr: net::IpAddr) {
+        let random = self.random.clone();
+        let disk = self.disk(addr);
+        for inode in disk.inodes.values_mut() {
+            inode.crash(&random, &disk.faults);
+        }
+        disk.crash_namespace();
+    }
+
+    /// Write `buf` at `offset` in `inode`, failing if the disk is out of space.
+    pub(crate) fn write_at(
+        &mut self,
+        addr: net::IpAddr,
+        inode: u64,
+        offset: usize,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        let random = self.random.clone();
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        let len = disk.inode(inode)?.data.len();
+        let growth = (offset + buf.len()).saturating_sub(len);
+        disk.reserve(growth as u64, &random)?;
+        disk.inode_mut(inode)?.write(offset, buf);
+        Ok(())
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        addr: net::IpAddr,
+        path: &path::Path,
+        contents: &[u8],
+    ) -> io::Result<()> {
+        let random = self.random.clone();
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        let existing = match disk.paths.get(path) {
+            Some(inode) => disk.inode(*inode)?.data.len(),
+            None => 0,
+        };
+        let growth = contents.len().saturating_sub(existing);
+        disk.reserve(growth as u64, &random)?;
+        let inode = disk.create(path)?;
+        disk.inodes.get_mut(&inode).unwrap().write(0, contents);
+        Ok(())
+    }
+
+    pub(crate) fn rename(
+        &mut self,
+        addr: net::IpAddr,
+        from: &path::Path,
+        to: &path::Path,
+    ) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        if disk.dirs.contains(from) {
+            return disk.rename_dir(from, to);
+        }
+        let inode = disk.lookup(from)?;
+        disk.check_parent(to)?;
+        disk.paths.remove(from);
+        disk.paths.insert(to.to_path_buf(), inode);
+        disk.collect_garbage();
+        Ok(())
+    }
+
+    /// Link `to` to the file at `from`, replacing any file already at `to`, without
+    /// unlinking `from`. Used to perform non-atomic renames.
+    pub(crate) fn link(
+        &mut self,
+        addr: net::IpAddr,
+        from: &path::Path,
+        to: &path::Path,
+    ) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        let inode = disk.lookup(from)?;
+        disk.check_parent(to)?;
+        disk.paths.insert(to.to_path_buf(), inode);
+        disk.collect_garbage();
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        disk.lookup(path)?;
+        disk.paths.remove(path);
+        disk.collect_garbage();
+        Ok(())
+    }
+
+    pub(crate) fn create_dir(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        if disk.paths.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        disk.check_parent(path)?;
+        disk.dirs.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    pub(crate) fn remove_dir(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        if !disk.dirs.contains(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let occupied = disk.paths.keys().any(|entry| is_entry(path, entry))
+            || disk.dirs.iter().any(|entry| is_entry(path, entry));
+        if occupied {
+            return Err(io::Error::from_raw_os_error(ENOTEMPTY));
+        }
+        disk.dirs.remove(path);
+        Ok(())
+    }
+
+    /// Returns the paths of the entries in the directory `path`, in sorted order.
+    pub(crate) fn read_dir(
+        &mut self,
+        addr: net::IpAddr,
+        path: &path::Path,
+    ) -> io::Result<Vec<path::PathBuf>> {
+        let disk = self.disk(addr);
+        if !disk.is_dir(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let mut entries: Vec<path::PathBuf> = disk
+            .paths
+            .keys()
+            .chain(disk.dirs.iter())
+            .filter(|entry| is_entry(path, entry))
+            .cloned()
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    pub(crate) fn sync_dir(&mut self, addr: net::IpAddr, path: &path::Path) -> io::Result<()> {
+        let disk = self.disk(addr);
+        disk.check_writable()?;
+        disk.sync_dir(path)
+    }
+
+    /// Make all writes to `inode` durable.
+    pub(crate) fn sync(&mut self, addr: net::IpAddr, inode: u64) -> io::Result<()> {
+        let disk = self.disk(addr);
+        if disk.inode(inode)?.is_dirty() {
+            disk.check_writable()?;
+        }
+        disk.inode_mut(inode)?.sync();
+        Ok(())
+    }
+}
@@ -0,0 +1,898 @@
+//! In memory filesystem
+//!
+//! Each simulated host has its own filesystem, keyed by the address the host is reachable
+//! at. Files are stored entirely in memory, and unless latency or throughput limits are set
+//! for the host, every operation completes immediately.
+//!
+//! Like a real disk with a write cache, writes are visible as soon as they are made but are
+//! only durable once the file is synced. When a host is killed, writes which were not synced
+//! are lost. A prefix of them, chosen by the seed, may survive the crash. Hosts can allow
+//! unsynced writes to be reordered, in which case any subset of them may survive instead.
+//!
+//! Files under the durable directory, `/data`, survive the host being killed and restarted,
+//! as a restarted host is reachable at the same address and so shares the same filesystem.
+//! Files under the volatile directory, `/tmp`, are removed when the host is killed, whether
+//! or not they were synced.
+//!
+//! Directories must be created before files can be created within them. By default, changes
+//! to directory entries such as creating, renaming or removing a file survive a crash as soon
+//! as they are made. Filesystems differ here, so each host can instead require directories to
+//! be synced before changes to their entries are durable, and can make renames non-atomic.
+//!
+//! The contents of a host's filesystem can be captured in a `DiskSnapshot`, and restored
+//! later to the same host or to another host, to test backup and restore or to branch a
+//! scenario from a known storage state.
+//!
+//! Faults are configured separately for each host's filesystem:
+//!
+//! - Torn writes cause a write spanning multiple 512 byte sectors to be partially applied
+//!   when it is lost in a crash, split at a sector boundary chosen by the seed.
+//! - Capacity limits the number of bytes which can be stored, after which writes fail with
+//!   `ENOSPC`. Out of space errors can also be injected at random.
+//! - Latency and throughput limits cause operations to take simulated time to complete.
+//!   Throughput limited transfers are queued behind each other, so a large write delays the
+//!   operations issued after it. Both can be degraded to simulate a slow disk.
+//! - Syncs can stall for a long time before completing, rather than failing.
+//! - The filesystem can be remounted read-only, as an operating system would after disk
+//!   errors, causing every modification to fail with `EROFS`.
+//! - Silent corruption flips a bit in the data being read, at a rate chosen per host. The
+//!   corruption is persisted, and every corruption injected is reported so tests can assert
+//!   that it was detected.
+use crate::deterministic::{
+    DescriptorTable, DeterministicRandomHandle, DeterministicTimeHandle, Resource,
+};
+use futures::future;
+use std::{io, net, ops, path, sync, time};
+mod file;
+mod inner;
+pub use file::File;
+pub(crate) use inner::Inner;
+pub use inner::{Corruption, DiskSnapshot};
+use inner::{DURABLE_DIR, VOLATILE_DIR};
+
+/// Wait for any stall injected into a sync on the disk of `addr`.
+pub(crate) async fn stall(inner: &sync::Arc<sync::Mutex<Inner>>, addr: net::IpAddr) {
+    let delay = inner.lock().unwrap().sync_stall(addr);
+    if let Some(delay) = delay {
+        delay.await;
+    }
+}
+
+/// Wait for an operation transferring `bytes` on the disk of `addr` to complete.
+pub(crate) async fn transfer(inner: &sync::Arc<sync::Mutex<Inner>>, addr: net::IpAddr, bytes: u64) {
+    if !inner.lock().unwrap().begin(addr) {
+        future::pending::<()>().await;
+    }
+    let delay = inner.lock().unwrap().schedule(addr, bytes);
+    if let Some(delay) = delay {
+        delay.await;
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct DeterministicFs {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    descriptors: DescriptorTable,
+}
+
+impl DeterministicFs {
+    pub(crate) fn new(
+        time: DeterministicTimeHandle,
+        random: DeterministicRandomHandle,
+        descriptors: DescriptorTable,
+    ) -> Self {
+        let inner = sync::Arc::new(sync::Mutex::new(Inner::new(time, random)));
+        Self { inner, descriptors }
+    }
+
+    pub(crate) fn scoped(&self, local_addr: net::IpAddr) -> DeterministicFsHandle {
+        DeterministicFsHandle {
+            local_addr,
+            inner: sync::Arc::clone(&self.inner),
+            descriptors: self.descriptors.clone(),
+        }
+    }
+}
+
+/// Handle to the filesystem of a particular simulated host.
+#[derive(Debug, Clone)]
+pub struct DeterministicFsHandle {
+    local_addr: net::IpAddr,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    descriptors: DescriptorTable,
+}
+
+impl DeterministicFsHandle {
+    /// Returns a handle to the filesystem of `local_addr`.
+    pub(crate) fn scoped(&self, local_addr: net::IpAddr) -> Self {
+        Self {
+            local_addr,
+            inner: sync::Arc::clone(&self.inner),
+            descriptors: self.descriptors.clone(),
+        }
+    }
+
+    /// Returns the directory whose contents survive this host being killed and restarted.
+    pub fn durable_dir(&self) -> &'static path::Path {
+        path::Path::new(DURABLE_DIR)
+    }
+
+    /// Returns the directory whose contents are removed when this host is killed.
+    pub fn volatile_dir(&self) -> &'static path::Path {
+        path::Path::new(VOLATILE_DIR)
+    }
+
+    /// Returns the number of bytes held in memory for the files of every host.
+    pub(crate) fn memory(&self) -> usize {
+        self.inner.lock().unwrap().memory()
+    }
+
+    /// Discard unsynced writes to every file on `addr`, and remove every file in its volatile
+    /// directory, as if the host crashed.
+    pub(crate) fn crash(&self, addr: net::IpAddr) {
+        self.inner.lock().unwrap().crash(addr);
+    }
+
+    /// Set the probability that a write spanning multiple sectors is partially applied when
+    /// it is lost in a crash of this host.
+    pub fn set_torn_write_probability(&self, probability: f64) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.torn_write_probability = probability;
+    }
+
+    /// Set whether unsynced writes to this host's filesystem may reach the disk out of order,
+    /// so that a crash can retain a later write while losing an earlier one.
+    pub fn set_reorder_writes(&self, reorder: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.reorder_writes = reorder;
+    }
+
+    /// Limit the number of bytes which can be stored on this host's filesystem. Writes which
+    /// would exceed the capacity fail with `ENOSPC`.
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.capacity = capacity;
+    }
+
+    /// Set the probability that a write to this host's filesystem fails with `ENOSPC`,
+    /// regardless of how much space is available.
+    pub fn set_no_space_probability(&self, probability: f64) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.no_space_probability = probability;
+    }
+
+    /// Add latency in `range` to every operation on this host's filesystem.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is empty.
+    pub fn set_latency(&self, range: Option<ops::Range<time::Duration>>) {
+        if let Some(range) = &range {
+            assert!(range.start < range.end, "latency range must not be empty");
+        }
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.latency = range;
+    }
+
+    /// Limit the number of bytes per second which can be read from or written to this host's
+    /// filesystem.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes_per_second` is zero.
+    pub fn set_throughput(&self, bytes_per_second: Option<u64>) {
+        assert!(bytes_per_second != Some(0), "throughput must be nonzero");
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.throughput = bytes_per_second;
+    }
+
+    /// Degrade the latency and throughput of this host's filesystem by `factor`. A factor of
+    /// 1 restores normal performance.
+    pub fn set_slowdown(&self, factor: u32) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.slowdown = factor;
+    }
+
+    /// With the provided probability, stall each sync of a file or directory on this host's
+    /// filesystem for a duration in `range` before it completes.
+    pub fn set_sync_stall(&self, probability: f64, range: ops::Range<time::Duration>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.sync_stall = Some((probability, range));
+    }
+
+    /// Fault injector which degrades this host's filesystem by `factor` for `duration`.
+    pub async fn slow_disk(self, factor: u32, duration: time::Duration) {
+        self.set_slowdown(factor);
+        let delay = self.inner.lock().unwrap().delay_from(duration);
+        delay.await;
+        self.set_slowdown(1);
+    }
+
+    /// Set whether this host's filesystem is read-only. While read-only, creating, writing,
+    /// renaming and removing files, and syncing modified files, fail with `EROFS`.
+    pub fn set_read_only(&self, read_only: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.read_only = read_only;
+    }
+
+    /// Fault injector which remounts this host's filesystem read-only for `duration`.
+    pub async fn read_only(self, duration: time::Duration) {
+        self.set_read_only(true);
+        let delay = self.inner.lock().unwrap().delay_from(duration);
+        delay.await;
+        self.set_read_only(false);
+    }
+
+    /// Set the probability that a read from this host's filesystem finds the data being read
+    /// silently corrupted.
+    pub fn set_corruption_probability(&self, probability: f64) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.corruption_probability = probability;
+    }
+
+    /// Set whether renames on this host's filesystem are atomic. Renames are atomic by
+    /// default.
+    pub fn set_atomic_rename(&self, atomic: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.atomic_rename = atomic;
+    }
+
+    /// Set whether changes to directory entries on this host's filesystem only survive a
+    /// crash once the directory has been synced with `sync_dir`.
+    pub fn set_dir_sync_required(&self, required: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).faults.dir_sync_required = required;
+    }
+
+    /// Returns a snapshot of the files and directories on this host's filesystem, including
+    /// writes which have not been synced.
+    pub fn snapshot(&self) -> DiskSnapshot {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).snapshot()
+    }
+
+    /// Replace the files and directories on this host's filesystem with those in `snapshot`.
+    /// Files which are open remain usable, but are no longer linked to a path. Faults
+    /// configured for this host are unaffected.
+    pub fn restore(&self, snapshot: &DiskSnapshot) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).restore(snapshot);
+    }
+
+    /// Returns the number of operations started on this host's filesystem.
+    pub(crate) fn operations(&self) -> u64 {
+        self.inner.lock().unwrap().disk(self.local_addr).operations
+    }
+
+    /// Stop completing operations on this host's filesystem once `operations` have been
+    /// started, freezing the disk in the state it reached.
+    pub(crate) fn freeze_after(&self, operations: Option<u64>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).freeze_after = operations;
+    }
+
+    /// Returns every corruption injected into this host's filesystem so far.
+    pub fn corruptions(&self) -> Vec<Corruption> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.disk(self.local_addr).corruptions.clone()
+    }
+
+    /// Open the existing file at `path` for reading and writing.
+    pub async fn open(&self, path: &path::Path) -> io::Result<File> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        let resource = Resource::File {
+            path: path.to_path_buf(),
+        };
+        let descriptor = self.descriptors.allocate(self.local_addr, resource)?;
+        let inode = self.inner.lock().unwrap().open(self.local_addr, path)?;
+        Ok(File::new(
+            self.local_addr,
+            inode,
+            sync::Arc::clone(&self.inner),
+            descriptor,
+        ))
+    }
+
+    /// Open the file at `path` for reading and writing, creating it if it does not exist
+    /// and truncating it if it does.
+    pub async fn create(&self, path: &path::Path) -> io::Result<File> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        let resource = Resource::File {
+            path: path.to_path_buf(),
+        };
+        let descriptor = self.descriptors.allocate(self.local_addr, resource)?;
+        let inode = self.inner.lock().unwrap().create(self.local_addr, path)?;
+        Ok(File::new(
+            self.local_addr,
+            inode,
+            sync::Arc::clone(&self.inner),
+            descriptor,
+        ))
+    }
+
+    /// Read the entire contents of the file at `path`.
+    pub async fn read(&self, path: &path::Path) -> io::Result<Vec<u8>> {
+        let len = self.inner.lock().unwrap().len(self.local_addr, path)?;
+        transfer(&self.inner, self.local_addr, len as u64).await;
+        self.inner.lock().unwrap().read(self.local_addr, path)
+    }
+
+    /// Replace the contents of the file at `path` with `contents`, creating it if required.
+    pub async fn write(&self, path: &path::Path, contents: &[u8]) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, contents.len() as u64).await;
+        self.inner
+            .lock()
+            .unwrap()
+            .write(self.local_addr, path, contents)
+    }
+
+    /// Rename the file at `from` to `to`, replacing any file already at `to`.
+    ///
+    /// If renames are not atomic, the file at `to` is removed, `to` is linked to the file and
+    /// then `from` is unlinked, with each step taking the disk's latency. A crash or concurrent
+    /// operation between the steps observes the intermediate state.
+    pub async fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        let atomic = {
+            let mut lock = self.inner.lock().unwrap();
+            let disk = lock.disk(self.local_addr);
+            disk.faults.atomic_rename || disk.is_dir(from)
+        };
+        if atomic {
+            return self.inner.lock().unwrap().rename(self.local_addr, from, to);
+        }
+        if from == to {
+            return Ok(());
+        }
+        match self.inner.lock().unwrap().remove(self.local_addr, to) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().link(self.local_addr, from, to)?;
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().remove(self.local_addr, from)
+    }
+
+    /// Remove the file at `path`. Open handles to the file remain usable.
+    pub async fn remove(&self, path: &path::Path) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().remove(self.local_addr, path)
+    }
+
+    /// Create a directory at `path`. The directory containing it must already exist.
+    pub async fn create_dir(&self, path: &path::Path) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().create_dir(self.local_addr, path)
+    }
+
+    /// Remove the empty directory at `path`.
+    pub async fn remove_dir(&self, path: &path::Path) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().remove_dir(self.local_addr, path)
+    }
+
+    /// Returns the paths of the files and directories within the directory `path`, in sorted
+    /// order.
+    pub async fn read_dir(&self, path: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        self.inner.lock().unwrap().read_dir(self.local_addr, path)
+    }
+
+    /// Make changes to the entries of the directory `path` survive a crash.
+    pub async fn sync_dir(&self, path: &path::Path) -> io::Result<()> {
+        transfer(&self.inner, self.local_addr, 0).await;
+        stall(&self.inner, self.local_addr).await;
+        self.inner.lock().unwrap().sync_dir(self.local_addr, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{File, FsEnv, SpawnEnv, TimeEnv};
+    use std::{io, net, path, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that files can be written, read back, renamed and removed, and that each host
+    /// has its own filesystem.
+    fn per_host_files() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host1 = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let host2 = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let mut file = host1.create("data").await.unwrap();
+                file.write_at(b"hello world", 0).await.unwrap();
+                file.write_at(b"there", 6).await.unwrap();
+                file.sync_all().await.unwrap();
+                assert_eq!(file.size().await.unwrap(), 11);
+                let mut buf = [0; 5];
+                assert_eq!(file.read_at(&mut buf, 6).await.unwrap(), 5);
+                assert_eq!(&buf, b"there");
+                assert_eq!(file.read_at(&mut buf, 20).await.unwrap(), 0);
+
+                assert_eq!(host1.read("data").await.unwrap(), b"hello there");
+                let err = host2.read("data").await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+                host1.write("other", b"replaced").await.unwrap();
+                host1.rename("data", "other").await.unwrap();
+                assert_eq!(host1.read("other").await.unwrap(), b"hello there");
+                assert!(host1.open("data").await.is_err());
+
+                // open handles remain usable after the file is removed.
+                host1.remove("other").await.unwrap();
+                assert!(host1.open("other").await.is_err());
+                assert_eq!(file.size().await.unwrap(), 11);
+            })
+            .unwrap();
+    }
+
+    /// Write a synced record followed by an unsynced record, then crash the host and return
+    /// the contents of the file after the crash.
+    fn crash_after_unsynced_write(seed: u64) -> Vec<u8> {
+        let (mut runtime, _) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let mut file = host.create("wal").await.unwrap();
+                file.write_at(b"synced", 0).await.unwrap();
+                file.sync_data().await.unwrap();
+                file.write_at(b"unsynced", 6).await.unwrap();
+                assert_eq!(host.read("wal").await.unwrap(), b"syncedunsynced");
+                drop(file);
+                host.kill(host.local_addr());
+                host.read("wal").await.unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that unsynced writes may be lost when a host crashes, while synced writes are not.
+    fn unsynced_writes_lost_on_crash() {
+        let outcomes: Vec<Vec<u8>> = (0..20).map(crash_after_unsynced_write).collect();
+        assert!(outcomes.iter().any(|data| data == b"synced"));
+        assert!(outcomes.iter().any(|data| data == b"syncedunsynced"));
+        for seed in 0..20 {
+            assert_eq!(crash_after_unsynced_write(seed), outcomes[seed as usize]);
+        }
+    }
+
+    #[test]
+    /// Test that files can be used through `AsyncRead`, `AsyncWrite` and `seek`.
+    fn async_read_write_seek() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                host.fs_handle().set_throughput(Some(1024));
+                let mut file = host.create("log").await.unwrap();
+                let start = host.now();
+                file.write_all(b"hello world").await.unwrap();
+                assert!(host.now() > start);
+                assert_eq!(file.seek(io::SeekFrom::Current(-5)).await.unwrap(), 6);
+                file.write_all(b"there").await.unwrap();
+                file.write_at(b"HELLO", 0).await.unwrap();
+
+                file.seek(io::SeekFrom::Start(0)).await.unwrap();
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).await.unwrap();
+                assert_eq!(contents, "HELLO there");
+                assert_eq!(file.seek(io::SeekFrom::End(-5)).await.unwrap(), 6);
+                assert!(file.seek(io::SeekFrom::End(-12)).await.is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that directories can be created, listed, renamed and removed.
+    fn directories() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let err = host.write("/data/db/a", b"a").await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                host.create_dir("/data/db").await.unwrap();
+                host.write("/data/db/b", b"b").await.unwrap();
+                host.write("/data/db/a", b"a").await.unwrap();
+                host.create_dir("/data/db/wal").await.unwrap();
+                assert_eq!(
+                    host.read_dir("/data/db").await.unwrap(),
+                    vec![
+                        path::PathBuf::from("/data/db/a"),
+                        "/data/db/b".into(),
+                        "/data/db/wal".into()
+                    ]
+                );
+
+                let err = host.remove_dir("/data/db").await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(39));
+                host.rename("/data/db", "/data/old").await.unwrap();
+                assert_eq!(host.read("/data/old/a").await.unwrap(), b"a");
+                assert!(host.read_dir("/data/db").await.is_err());
+                host.remove_dir("/data/old/wal").await.unwrap();
+                assert_eq!(host.read_dir("/data/old").await.unwrap().len(), 2);
+            })
+            .unwrap();
+    }
+
+    /// Replace `/data/current` with a new version using rename, killing the host after
+    /// `kill_after`. Returns the contents of `/data/current` once the host is restarted.
+    fn replace_and_crash(atomic: bool, dir_sync: bool, kill_after: Duration) -> Option<Vec<u8>> {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let fs = host.fs_handle();
+                host.write("/data/current", b"old").await.unwrap();
+                host.open("/data/current")
+                    .await
+                    .unwrap()
+                    .sync_all()
+                    .await
+                    .unwrap();
+                host.sync_dir("/data").await.unwrap();
+
+                fs.set_atomic_rename(atomic);
+                fs.set_dir_sync_required(dir_sync);
+                fs.set_latency(Some(Duration::from_millis(10)..Duration::from_millis(11)));
+                let writer = host.clone();
+                host.spawn(async move {
+                    writer.write("/data/next", b"new").await.unwrap();
+                    let mut file = writer.open("/data/next").await.unwrap();
+                    file.sync_all().await.unwrap();
+                    writer.rename("/data/next", "/data/current").await.unwrap();
+                });
+                handle.delay_from(kill_after).await;
+                host.kill(host.local_addr());
+                host.read("/data/current").await.ok()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that renames are only durable once the directory is synced when directory syncs
+    /// are required, and that non-atomic renames expose intermediate states to crashes.
+    fn rename_atomicity() {
+        let done = Duration::from_millis(200);
+        assert_eq!(replace_and_crash(true, false, done), Some(b"new".to_vec()));
+        assert_eq!(replace_and_crash(true, true, done), Some(b"old".to_vec()));
+        // the write, open, sync and first step of the rename each take 10ms, after which a
+        // non-atomic rename has removed the destination but not yet linked it.
+        let mid_rename = Duration::from_millis(45);
+        assert_eq!(
+            replace_and_crash(true, false, mid_rename),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(replace_and_crash(false, false, mid_rename), None);
+        assert_eq!(
+            replace_and_crash(false, true, mid_rename),
+            Some(b"old".to_vec())
+        );
+    }
+
+    #[test]
+    /// Test that the durable directory survives a restart, and the volatile directory does not.
+    fn durable_and_volatile_dirs() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = crate::deterministic::Cluster::new(handle.clone());
+                let host = cluster
+                    .add_host(
+                        |host: crate::deterministic::DeterministicRuntimeHandle| async move {
+                            let fs = host.fs_handle();
+                            let state = fs.durable_dir().join("state");
+                            let boots = match host.read(&state).await {
+                                Ok(data) => data[0] + 1,
+                                Err(_) => 1,
+                            };
+                            host.write(&state, [boots]).await.unwrap();
+                            host.open(&state).await.unwrap().sync_all().await.unwrap();
+                            if boots == 1 {
+                                let scratch = fs.volatile_dir().join("scratch");
+                                host.write(&scratch, b"scratch").await.unwrap();
+                                host.open(&scratch).await.unwrap().sync_all().await.unwrap();
+                            }
+                        },
+                    )
+                    .await;
+                let addr = host.local_addr();
+                host.delay_from(Duration::from_secs(1)).await;
+                let scratch = host.fs_handle().volatile_dir().join("scratch");
+                assert_eq!(host.read(&scratch).await.unwrap(), b"scratch");
+                cluster.restart_host(addr);
+                host.delay_from(Duration::from_secs(1)).await;
+
+                let state = host.fs_handle().durable_dir().join("state");
+                assert_eq!(host.read(&state).await.unwrap(), vec![2]);
+                assert!(host.read(&scratch).await.is_err());
+            })
+            .unwrap();
+    }
+
+    /// Append three records to an empty file without syncing, then crash.
+    fn crash_after_appends(seed: u64, reorder: bool) -> Vec<u8> {
+        let (mut runtime, _) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                host.fs_handle().set_reorder_writes(reorder);
+                let mut file = host.create("log").await.unwrap();
+                file.sync_all().await.unwrap();
+                for (offset, record) in b"abc".iter().enumerate() {
+                    file.write_at(&[*record], offset as u64).await.unwrap();
+                }
+                drop(file);
+                host.kill(host.local_addr());
+                host.read("log").await.unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that reordered writes can survive a crash while earlier writes are lost.
+    fn reordered_writes() {
+        let reordered = |data: &Vec<u8>| data.len() == 3 && data[..2] != b"ab"[..];
+        assert!(!(0..50).any(|seed| reordered(&crash_after_appends(seed, false))));
+        let outcomes: Vec<Vec<u8>> = (0..50)
+            .map(|seed| crash_after_appends(seed, true))
+            .collect();
+        assert!(outcomes.iter().any(reordered));
+        for (seed, outcome) in outcomes.iter().enumerate() {
+            assert_eq!(&crash_after_appends(seed as u64, true), outcome);
+        }
+    }
+
+    #[test]
+    /// Test that writes spanning multiple sectors are torn at sector boundaries by a crash.
+    fn torn_writes() {
+        let mut lengths = std::collections::BTreeSet::new();
+        for seed in 0..30 {
+            let (mut runtime, _) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+            let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+            let len = runtime
+                .block_on(async {
+                    host.fs_handle().set_torn_write_probability(1.0);
+                    let mut file = host.create("data").await.unwrap();
+                    file.sync_all().await.unwrap();
+                    file.write_at(&[1; 2048], 0).await.unwrap();
+                    drop(file);
+                    host.kill(host.local_addr());
+                    let data = host.read("data").await.unwrap();
+                    assert!(data.iter().all(|b| *b == 1));
+                    data.len()
+                })
+                .unwrap();
+            assert_eq!(len % 512, 0);
+            lengths.insert(len);
+        }
+        assert!(lengths.contains(&2048));
+        assert!(lengths.iter().any(|len| *len > 0 && *len < 2048));
+    }
+
+    #[test]
+    /// Test that writes beyond a host's capacity fail with ENOSPC.
+    fn out_of_space() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                host.fs_handle().set_capacity(Some(1024));
+                let mut file = host.create("log").await.unwrap();
+                file.write_at(&[0; 1000], 0).await.unwrap();
+                // overwriting existing data does not consume any more space.
+                file.write_at(&[1; 1000], 0).await.unwrap();
+                let err = file.write_at(&[0; 100], 1000).await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(28));
+                assert!(host.write("other", [0; 100]).await.is_err());
+                assert_eq!(file.size().await.unwrap(), 1000);
+
+                host.remove("log").await.unwrap();
+                drop(file);
+                host.write("other", [0; 100]).await.unwrap();
+
+                host.fs_handle().set_capacity(None);
+                host.fs_handle().set_no_space_probability(1.0);
+                assert!(host.write("other", [0; 100]).await.is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that disk operations are charged against simulated time.
+    fn latency_and_throughput() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let fs = host.fs_handle();
+                fs.set_throughput(Some(1_000_000));
+                fs.set_latency(Some(Duration::from_millis(1)..Duration::from_millis(2)));
+                let start = host.now();
+                host.write("data", vec![0; 1_000_000]).await.unwrap();
+                let elapsed = host.now() - start;
+                // 1 second to transfer the data, plus 1ms of latency rounded up to the timer resolution.
+                assert!(
+                    elapsed >= Duration::from_millis(1001)
+                        && elapsed <= Duration::from_millis(1003)
+                );
+
+                // concurrent transfers are queued behind each other.
+                let start = host.now();
+                let (first, second) = futures::join!(
+                    host.write("a", vec![0; 500_000]),
+                    host.write("b", vec![0; 500_000])
+                );
+                first.unwrap();
+                second.unwrap();
+                assert!(host.now() - start >= Duration::from_secs(1));
+
+                host.spawn(fs.clone().slow_disk(10, Duration::from_secs(60)));
+                let start = host.now();
+                let mut file = host.open("data").await.unwrap();
+                file.write_at(&[1; 100_000], 0).await.unwrap();
+                assert!(host.now() - start >= Duration::from_secs(1));
+
+                host.delay_from(Duration::from_secs(60)).await;
+                let start = host.now();
+                file.write_at(&[1; 100_000], 0).await.unwrap();
+                assert!(host.now() - start < Duration::from_millis(200));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that the time to transfer data at a limited throughput is exact for transfers of
+    /// any size.
+    fn large_transfers() {
+        use super::inner::transfer_time;
+        assert_eq!(transfer_time(1_000_000, 1_000_000), Duration::from_secs(1));
+        assert_eq!(transfer_time(1, 3), Duration::from_nanos(333_333_333));
+        assert_eq!(
+            transfer_time(20_000_000_000, 1_000_000_000),
+            Duration::from_secs(20)
+        );
+        assert_eq!(transfer_time(u64::MAX, 1), Duration::from_secs(u64::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "throughput must be nonzero")]
+    /// Test that a throughput of zero bytes per second is rejected.
+    fn zero_throughput() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        host.fs_handle().set_throughput(Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "latency range must not be empty")]
+    /// Test that an empty range of latencies is rejected.
+    fn empty_latency() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let latency = Duration::from_millis(5);
+        host.fs_handle().set_latency(Some(latency..latency));
+    }
+
+    #[test]
+    /// Test that snapshots can be restored to the same host or another host, and that
+    /// unsynced writes in the snapshot remain unsynced.
+    fn snapshot_and_restore() {
+        let (mut runtime, _) = DeterministicRuntime::builder().seed(3).build().unwrap();
+        let host1 = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let host2 = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                host1.create_dir("/data/db").await.unwrap();
+                host1.write("/data/db/synced", b"synced").await.unwrap();
+                let mut file = host1.open("/data/db/synced").await.unwrap();
+                file.sync_all().await.unwrap();
+                host1.write("/data/db/unsynced", b"unsynced").await.unwrap();
+                let snapshot = host1.fs_handle().snapshot();
+                assert_eq!(snapshot.files().len(), 2);
+
+                host1.remove("/data/db/synced").await.unwrap();
+                host1.fs_handle().restore(&snapshot);
+                assert_eq!(host1.read("/data/db/synced").await.unwrap(), b"synced");
+                // the open file is no longer linked to the restored path.
+                file.write_at(b"SYNCED", 0).await.unwrap();
+                assert_eq!(host1.read("/data/db/synced").await.unwrap(), b"synced");
+
+                host2.fs_handle().restore(&snapshot);
+                assert_eq!(
+                    host2.read_dir("/data/db").await.unwrap(),
+                    host1.read_dir("/data/db").await.unwrap()
+                );
+                host2.kill(host2.local_addr());
+                assert_eq!(host2.read("/data/db/synced").await.unwrap(), b"synced");
+                let unsynced = host2.read("/data/db/unsynced").await.unwrap();
+                assert!(unsynced.is_empty() || unsynced == b"unsynced");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that syncs stall for a duration in the configured range.
+    fn sync_stall() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let mut file = host.create("data").await.unwrap();
+                file.write_at(b"data", 0).await.unwrap();
+                let stall = Duration::from_secs(5)..Duration::from_secs(30);
+                host.fs_handle().set_sync_stall(1.0, stall.clone());
+                let start = host.now();
+                file.sync_data().await.unwrap();
+                assert!(stall.contains(&(host.now() - start)));
+                // other operations are unaffected.
+                let start = host.now();
+                host.read("data").await.unwrap();
+                assert_eq!(host.now(), start);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a read-only filesystem rejects modifications until it is remounted.
+    fn read_only() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let mut file = host.create("data").await.unwrap();
+                file.write_at(b"dirty", 0).await.unwrap();
+                host.spawn(host.fs_handle().read_only(Duration::from_secs(10)));
+                host.delay_from(Duration::from_secs(1)).await;
+
+                let erofs = Some(30);
+                assert_eq!(file.sync_all().await.unwrap_err().raw_os_error(), erofs);
+                let err = file.write_at(b"more", 5).await.unwrap_err();
+                assert_eq!(err.raw_os_error(), erofs);
+                let err = host.create("other").await.unwrap_err();
+                assert_eq!(err.raw_os_error(), erofs);
+                let err = host.remove("data").await.unwrap_err();
+                assert_eq!(err.raw_os_error(), erofs);
+                assert_eq!(host.read("data").await.unwrap(), b"dirty");
+
+                host.delay_from(Duration::from_secs(10)).await;
+                file.sync_all().await.unwrap();
+                host.write("other", b"other").await.unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that corruptions flip a single bit of stored data, and are reported.
+    fn silent_corruption() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let host = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                let original = vec![0; 4096];
+                host.write("block", &original).await.unwrap();
+                host.fs_handle().set_corruption_probability(1.0);
+                let corrupted = host.read("block").await.unwrap();
+                host.fs_handle().set_corruption_probability(0.0);
+
+                let corruptions = host.fs_handle().corruptions();
+                assert_eq!(corruptions.len(), 1);
+                assert_eq!(corruptions[0].path, Some("block".into()));
+                let offset = corruptions[0].offset as usize;
+                for (i, byte) in corrupted.iter().enumerate() {
+                    if i == offset {
+                        assert_eq!(byte.count_ones(), 1);
+                    } else {
+                        assert_eq!(*byte, 0);
+                    }
+                }
+                // the corruption persists for later reads.
+                assert_eq!(host.read("block").await.unwrap(), corrupted);
+            })
+            .unwrap();
+    }
+}
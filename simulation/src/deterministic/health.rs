@@ -0,0 +1,404 @@
+//! Periodic health checks of simulated servers.
+//!
+//! Nearly every scenario wants a baseline probe which checks that the servers under test are
+//! reachable. A `HealthCheck` probes each of its targets over the simulated network at a fixed
+//! interval. The result of every probe is published on the event bus as a [`HealthStatus`], so
+//! checkers can react to it while the run progresses, and each round of probes is recorded in a
+//! [`HealthLog`]. Once the run is complete, the log can check invariants such as "at least one
+//! replica was healthy at all times outside of partition windows".
+//!
+//! By default a target is healthy if a connection to it can be established within the probe
+//! timeout. With the `hyper` feature, targets can instead be probed with an HTTP request or with
+//! the standard gRPC health checking protocol.
+use crate::{deterministic::DeterministicRuntimeHandle, Error, NetEnv, TimeEnv};
+use futures::{future, Future};
+use std::{
+    fmt, net,
+    ops::Range,
+    pin::Pin,
+    sync,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+type BoxProbe = Box<
+    dyn Fn(
+            DeterministicRuntimeHandle,
+            net::SocketAddr,
+        ) -> Pin<Box<dyn Future<Output = bool> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// The result of probing a single target, published on the event bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HealthStatus {
+    pub target: net::SocketAddr,
+    pub healthy: bool,
+}
+
+/// The results of probing every target once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthRound {
+    /// Simulated time at which the round of probes started.
+    pub at: Instant,
+    pub healthy: Vec<net::SocketAddr>,
+    pub unhealthy: Vec<net::SocketAddr>,
+}
+
+/// Rounds of probes recorded by a [`HealthCheck`].
+#[derive(Debug, Clone, Default)]
+pub struct HealthLog {
+    rounds: sync::Arc<sync::Mutex<Vec<HealthRound>>>,
+}
+
+impl HealthLog {
+    /// Returns every round recorded so far, in the order they started.
+    pub fn rounds(&self) -> Vec<HealthRound> {
+        self.rounds.lock().unwrap().clone()
+    }
+
+    /// Check that at least `min` targets were healthy in every round, other than rounds which
+    /// started within one of the `excluded` windows, such as the time a partition was in place.
+    pub fn check_min_healthy(&self, min: usize, excluded: &[Range<Instant>]) -> Result<(), Error> {
+        let rounds = self.rounds.lock().unwrap();
+        let start = match rounds.first() {
+            Some(round) => round.at,
+            None => return Ok(()),
+        };
+        rounds
+            .iter()
+            .filter(|round| !excluded.iter().any(|window| window.contains(&round.at)))
+            .find(|round| round.healthy.len() < min)
+            .map_or(Ok(()), |round| {
+                Err(Error::Unhealthy {
+                    elapsed: round.at - start,
+                    healthy: round.healthy.len(),
+                    min,
+                })
+            })
+    }
+}
+
+/// A workload which periodically probes a set of targets.
+pub struct HealthCheck {
+    handle: DeterministicRuntimeHandle,
+    targets: Vec<net::SocketAddr>,
+    interval: Duration,
+    timeout: Duration,
+    probe: BoxProbe,
+    log: HealthLog,
+}
+
+impl fmt::Debug for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HealthCheck")
+            .field("targets", &self.targets)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl HealthCheck {
+    /// Create a health check which probes `targets` from the host of `handle`, once a second.
+    pub fn new<I>(handle: DeterministicRuntimeHandle, targets: I) -> Self
+    where
+        I: IntoIterator<Item = net::SocketAddr>,
+    {
+        Self {
+            handle,
+            targets: targets.into_iter().collect(),
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_millis(500),
+            probe: Box::new(|handle, target| Box::pin(connect_probe(handle, target))),
+            log: HealthLog::default(),
+        }
+    }
+
+    /// Set the time between the start of each round of probes.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the time after which a probe which has not completed is considered unhealthy.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Probe each target with `probe`, which resolves to whether the target is healthy.
+    pub fn probe<F, U>(mut self, probe: F) -> Self
+    where
+        F: Fn(DeterministicRuntimeHandle, net::SocketAddr) -> U + Send + Sync + 'static,
+        U: Future<Output = bool> + Send + 'static,
+    {
+        self.probe = Box::new(move |handle, target| Box::pin(probe(handle, target)));
+        self
+    }
+
+    /// Probe each target with an HTTP GET request for `path`, which is healthy if the response
+    /// has a successful status code.
+    #[cfg(feature = "hyper")]
+    pub fn http(self, path: &str) -> Self {
+        let path = path.to_string();
+        self.probe(move |handle, target| {
+            let uri = format!("http://{}{}", target, path);
+            http_probe(handle, uri)
+        })
+    }
+
+    /// Probe each target using the gRPC health checking protocol, which is healthy if
+    /// `service` is reported as serving. An empty `service` checks the server as a whole.
+    #[cfg(feature = "hyper")]
+    pub fn grpc(self, service: &str) -> Self {
+        let service = service.to_string();
+        self.probe(move |handle, target| grpc_probe(handle, target, service.clone()))
+    }
+
+    /// Returns the log which rounds of probes are recorded to.
+    pub fn log(&self) -> HealthLog {
+        self.log.clone()
+    }
+
+    /// Probe the targets at each interval, forever.
+    pub async fn run(self) {
+        let events = self.handle.event_bus_handle();
+        loop {
+            let at = self.handle.now();
+            let probes = self.targets.iter().map(|target| {
+                let probe = (self.probe)(self.handle.clone(), *target);
+                let probe = self.handle.timeout(probe, self.timeout);
+                async move { probe.await.unwrap_or(false) }
+            });
+            let results = future::join_all(probes).await;
+            let mut round = HealthRound {
+                at,
+                healthy: vec![],
+                unhealthy: vec![],
+            };
+            for (target, healthy) in self.targets.iter().zip(results) {
+                if healthy {
+                    round.healthy.push(*target);
+                } else {
+                    debug!("health check of {} failed", target);
+                    round.unhealthy.push(*target);
+                }
+                events.publish(HealthStatus {
+                    target: *target,
+                    healthy,
+                });
+            }
+            self.log.rounds.lock().unwrap().push(round);
+            self.handle.delay(at + self.interval).await;
+        }
+    }
+}
+
+/// A target is healthy if a connection to it can be established.
+async fn connect_probe(handle: DeterministicRuntimeHandle, target: net::SocketAddr) -> bool {
+    handle.connect(target).await.is_ok()
+}
+
+#[cfg(feature = "hyper")]
+async fn http_probe(handle: DeterministicRuntimeHandle, uri: String) -> bool {
+    use crate::hyper::{HyperConnect, HyperExecutor};
+    let uri = match uri.parse() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    let client = ::hyper::Client::builder()
+        .executor(HyperExecutor::new(handle.clone()))
+        .build::<_, ::hyper::Body>(HyperConnect::new(handle));
+    match client.get(uri).await {
+        Ok(response) => response.status().is_success(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(feature = "hyper")]
+async fn grpc_probe(
+    handle: DeterministicRuntimeHandle,
+    target: net::SocketAddr,
+    service: String,
+) -> bool {
+    use crate::hyper::{HyperConnect, HyperExecutor};
+    // a HealthCheckRequest, with the service name as field 1 when it is not empty.
+    let mut message = vec![];
+    if !service.is_empty() {
+        message.push(0x0a);
+        let mut len = service.len();
+        while len >= 0x80 {
+            message.push((len as u8) | 0x80);
+            len >>= 7;
+        }
+        message.push(len as u8);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    let request = ::hyper::Request::post(format!("http://{}/grpc.health.v1.Health/Check", target))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(::hyper::Body::from(frame));
+    let request = match request {
+        Ok(request) => request,
+        Err(_) => return false,
+    };
+    let client = ::hyper::Client::builder()
+        .http2_only(true)
+        .executor(HyperExecutor::new(handle.clone()))
+        .build::<_, ::hyper::Body>(HyperConnect::new(handle));
+    let mut body = match client.request(request).await {
+        Ok(response) if response.status().is_success() => response.into_body(),
+        _ => return false,
+    };
+    let mut data = vec![];
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(_) => return false,
+        }
+    }
+    // a HealthCheckResponse with its status field set to SERVING.
+    data.len() == 7 && data[5..] == [0x08, 0x01]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::Cluster, deterministic::DeterministicRuntime, SpawnEnv, TcpListener,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn serve(handle: DeterministicRuntimeHandle) {
+        let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+        let mut listener = handle.bind(addr).await.unwrap();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            handle.spawn(async move {
+                let _ = socket.write_all(b"ok").await;
+                let _ = socket.read(&mut [0; 1]).await;
+            });
+        }
+    }
+
+    /// A target is healthy if it greets new connections. Connections to a host which is down
+    /// are queued until it binds again, so establishing one is not enough.
+    async fn greeted(handle: DeterministicRuntimeHandle, target: net::SocketAddr) -> bool {
+        match handle.connect(target).await {
+            Ok(mut socket) => socket.read_exact(&mut [0; 2]).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    /// Test that targets are probed at each interval, and that the minimum number of healthy
+    /// targets is only checked outside of excluded windows.
+    fn min_healthy() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let checker = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                for _ in 0..2 {
+                    cluster.add_host(serve).await;
+                }
+                let hosts = cluster.hosts();
+                let targets = hosts.iter().map(|host| net::SocketAddr::new(*host, 9092));
+                let check = HealthCheck::new(checker.clone(), targets).probe(greeted);
+                let log = check.log();
+                let mut statuses = handle.event_bus_handle().subscribe::<HealthStatus>();
+                let start = handle.now();
+                checker.spawn(check.run());
+
+                handle.delay(start + Duration::from_millis(4750)).await;
+                cluster.kill_host(hosts[0]);
+                handle.delay(start + Duration::from_millis(6750)).await;
+                cluster.kill_host(hosts[1]);
+                handle.delay(start + Duration::from_millis(8750)).await;
+                cluster.boot_host(hosts[0]);
+                handle.delay(start + Duration::from_millis(10750)).await;
+
+                let rounds = log.rounds();
+                assert_eq!(rounds.len(), 11);
+                let healthy: Vec<_> = rounds.iter().map(|round| round.healthy.len()).collect();
+                assert_eq!(healthy, vec![2, 2, 2, 2, 2, 1, 1, 0, 0, 1, 1]);
+                match log.check_min_healthy(1, &[]) {
+                    Err(Error::Unhealthy {
+                        elapsed,
+                        healthy: 0,
+                        min: 1,
+                    }) => assert_eq!(elapsed, Duration::from_secs(7)),
+                    result => panic!("unexpected result {:?}", result),
+                }
+                let partition =
+                    start + Duration::from_millis(6750)..start + Duration::from_millis(8750);
+                log.check_min_healthy(1, &[partition]).unwrap();
+
+                let mut published = 0;
+                while statuses.try_next().is_some() {
+                    published += 1;
+                }
+                assert_eq!(published, 22);
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "hyper")]
+    #[test]
+    #[cfg_attr(feature = "clock-guard", ignore = "hyper reads the real clock")]
+    /// Test that the gRPC probe reports a target as healthy only if it is serving.
+    fn grpc_probe() {
+        use crate::hyper::{HyperAccept, HyperExecutor};
+        use ::hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response, Server,
+        };
+        use std::io;
+
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let checker = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 50051);
+                let listener = server.bind(addr).await.unwrap();
+                let make_service = make_service_fn(|_| async {
+                    Ok::<_, io::Error>(service_fn(|request: Request<Body>| async move {
+                        let mut body = request.into_body();
+                        let mut message = vec![];
+                        while let Some(chunk) = body.next().await {
+                            message.extend_from_slice(&chunk.unwrap());
+                        }
+                        // SERVING for the "ready" service, NOT_SERVING for anything else.
+                        let status = if &message[5..] == b"\x0a\x05ready" {
+                            1
+                        } else {
+                            2
+                        };
+                        let response = vec![0, 0, 0, 0, 2, 0x08, status];
+                        Ok::<_, io::Error>(Response::new(Body::from(response)))
+                    }))
+                });
+                let grpc = Server::builder(HyperAccept::new(listener))
+                    .http2_only(true)
+                    .executor(HyperExecutor::new(server.clone()))
+                    .serve(make_service);
+                server.spawn(async move {
+                    grpc.await.unwrap();
+                });
+
+                for (service, healthy) in &[("ready", 1), ("starting", 0)] {
+                    let check = HealthCheck::new(checker.clone(), vec![addr]).grpc(service);
+                    let log = check.log();
+                    checker.spawn(check.run());
+                    checker.delay_from(Duration::from_millis(750)).await;
+                    assert_eq!(log.rounds()[0].healthy.len(), *healthy);
+                }
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Scheduled maintenance events.
+//!
+//! `RollingRestart` restarts each host in a [`Cluster`] one at a time, waiting for the
+//! restarted host to become healthy before moving on to the next, as a deployment tool would
+//! during an upgrade. It is driven entirely by simulated time, so it can be spawned alongside
+//! fault injectors to test rollouts under faults.
+//!
+//! [`Cluster`]:crate::deterministic::Cluster
+use crate::{
+    deterministic::{Cluster, DeterministicRuntimeHandle},
+    TimeEnv,
+};
+use futures::Future;
+use std::{fmt, pin::Pin, sync, time::Duration};
+use tracing::debug;
+
+type HealthCheck = sync::Arc<
+    dyn Fn(DeterministicRuntimeHandle) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync,
+>;
+
+/// Restarts every host in a cluster, one at a time.
+pub struct RollingRestart {
+    cluster: Cluster,
+    interval: Duration,
+    downtime: Duration,
+    health_check_interval: Duration,
+    health_check: Option<HealthCheck>,
+}
+
+impl fmt::Debug for RollingRestart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RollingRestart {{ interval: {:?}, downtime: {:?}, health_check_interval: {:?} }}",
+            self.interval, self.downtime, self.health_check_interval
+        )
+    }
+}
+
+impl RollingRestart {
+    /// Create a rolling restart of all hosts in `cluster`. By default hosts are restarted
+    /// immediately one after the other, with no downtime and no health check.
+    pub fn new(cluster: Cluster) -> Self {
+        Self {
+            cluster,
+            interval: Duration::from_secs(0),
+            downtime: Duration::from_secs(0),
+            health_check_interval: Duration::from_secs(1),
+            health_check: None,
+        }
+    }
+
+    /// Wait for `interval` after a restarted host becomes healthy before restarting the next host.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Keep each host down for `downtime` before booting it again.
+    pub fn downtime(mut self, downtime: Duration) -> Self {
+        self.downtime = downtime;
+        self
+    }
+
+    /// Wait for `check` to return true for a restarted host before moving on to the next
+    /// host, retrying every `interval`.
+    pub fn health_check<F, U>(mut self, interval: Duration, check: F) -> Self
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = bool> + Send + 'static,
+    {
+        self.health_check_interval = interval;
+        self.health_check = Some(sync::Arc::new(move |handle| Box::pin(check(handle))));
+        self
+    }
+
+    /// Restart each host which is a member of the cluster when the rolling restart begins.
+    pub async fn run(self) {
+        for addr in self.cluster.hosts() {
+            let handle = self.cluster.handle(addr);
+            debug!("rolling restart of {}", addr);
+            self.cluster.kill_host(addr);
+            handle.delay_from(self.downtime).await;
+            self.cluster.boot_host(addr);
+            if let Some(check) = self.health_check.as_ref() {
+                while !check(handle.clone()).await {
+                    handle.delay_from(self.health_check_interval).await;
+                }
+            }
+            handle.delay_from(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::{collections, net};
+
+    #[test]
+    /// Test that each host is restarted once, waiting for health checks between restarts.
+    fn rolling_restart() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let boots = sync::Arc::new(sync::Mutex::new(collections::BTreeMap::new()));
+                let cluster = Cluster::new(handle.clone());
+                for _ in 0..3 {
+                    let boots = sync::Arc::clone(&boots);
+                    cluster
+                        .add_host(move |handle: DeterministicRuntimeHandle| {
+                            let boots = sync::Arc::clone(&boots);
+                            async move {
+                                // hosts take 4.5 seconds to start up.
+                                handle.delay_from(Duration::from_millis(4500)).await;
+                                *boots
+                                    .lock()
+                                    .unwrap()
+                                    .entry(handle.local_addr())
+                                    .or_insert(0) += 1;
+                                handle.delay_from(Duration::from_secs(3600)).await;
+                            }
+                        })
+                        .await;
+                }
+                handle.delay_from(Duration::from_secs(10)).await;
+
+                let start = handle.now();
+                let health = sync::Arc::clone(&boots);
+                RollingRestart::new(cluster.clone())
+                    .downtime(Duration::from_secs(1))
+                    .interval(Duration::from_secs(10))
+                    .health_check(Duration::from_secs(1), move |handle| {
+                        let health = sync::Arc::clone(&health);
+                        async move { health.lock().unwrap()[&handle.local_addr()] == 2 }
+                    })
+                    .run()
+                    .await;
+
+                let boots: Vec<(net::IpAddr, usize)> =
+                    boots.lock().unwrap().clone().into_iter().collect();
+                let hosts: Vec<(net::IpAddr, usize)> =
+                    cluster.hosts().into_iter().map(|addr| (addr, 2)).collect();
+                assert_eq!(boots, hosts);
+                // each host is down for 1s, is seen to be healthy by the health check 5s after
+                // booting, then the next restart waits for the 10s interval.
+                assert_eq!(handle.now() - start, Duration::from_secs(48));
+            })
+            .unwrap();
+    }
+}
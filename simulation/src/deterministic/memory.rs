@@ -0,0 +1,129 @@
+//! Accounting for the memory held by a simulation.
+//!
+//! Long simulations accumulate state which a real deployment would not keep in memory: bytes
+//! in flight on simulated connections, the contents of every simulated file, and the traces
+//! recorded for debugging. [`memory_usage`] breaks the approximate size of each down, and a
+//! runtime built with a [`memory_limit`] fails with that breakdown once their total exceeds
+//! it, rather than running the test process out of memory.
+//!
+//! [`memory_usage`]:crate::deterministic::DeterministicRuntimeHandle::memory_usage
+//! [`memory_limit`]:crate::deterministic::DeterministicRuntimeBuilder::memory_limit
+use super::{
+    DeterministicChaosLogHandle, DeterministicFsHandle, DeterministicMetricsHandle,
+    DeterministicNetworkHandle, Timeline,
+};
+use std::fmt;
+
+/// Approximate memory held by a simulation, broken down by what holds it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes written to simulated connections which have not yet been read.
+    pub network: usize,
+    /// Bytes stored in simulated files, including writes which have not been synced and the
+    /// copy of each file which survives a crash.
+    pub files: usize,
+    /// Bytes held by the timeline, event log, chaos log and metrics recorded so far.
+    pub traces: usize,
+}
+
+impl MemoryUsage {
+    /// Returns the total number of bytes held.
+    pub fn total(&self) -> usize {
+        self.network + self.files + self.traces
+    }
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "network buffers {} bytes, files {} bytes, traces {} bytes",
+            self.network, self.files, self.traces
+        )
+    }
+}
+
+/// Measures the memory held by each part of a runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryMeter {
+    pub(crate) network: DeterministicNetworkHandle,
+    pub(crate) fs: DeterministicFsHandle,
+    pub(crate) timeline: Timeline,
+    pub(crate) chaos_log: DeterministicChaosLogHandle,
+    pub(crate) metrics: DeterministicMetricsHandle,
+}
+
+impl MemoryMeter {
+    pub(crate) fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            network: self.network.buffered(),
+            files: self.fs.memory(),
+            traces: self.timeline.memory()
+                + self.timeline.events().memory()
+                + self.chaos_log.memory()
+                + self.metrics.memory(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{FsEnv, NetEnv, TcpListener};
+    use std::{net, panic};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that unread bytes on connections and file contents are counted until they are
+    /// read or removed.
+    fn memory_usage() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+                let mut listener = handle.bind(addr).await.unwrap();
+                let mut client = handle.connect(addr).await.unwrap();
+                let (mut server, _) = listener.accept().await.unwrap();
+                assert_eq!(handle.memory_usage(), MemoryUsage::default());
+
+                client.write_all(&[1; 1000]).await.unwrap();
+                assert_eq!(handle.memory_usage().network, 1000);
+                server.read_exact(&mut [0; 600]).await.unwrap();
+                assert_eq!(handle.memory_usage().network, 400);
+                drop(server);
+                assert_eq!(handle.memory_usage().network, 0);
+
+                handle.write("data", vec![1; 1000]).await.unwrap();
+                // the contents, and the unsynced write which may be lost in a crash.
+                assert_eq!(handle.memory_usage().files, 2000);
+                handle.remove("data").await.unwrap();
+                assert_eq!(handle.memory_usage().files, 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that block_on panics once the memory limit is exceeded.
+    fn memory_limit() {
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .memory_limit(10_000)
+            .build()
+            .unwrap();
+        runtime
+            .block_on(handle.write("small", vec![1; 1000]))
+            .unwrap()
+            .unwrap();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            runtime
+                .block_on(async {
+                    handle.write("large", vec![1; 10_000]).await.unwrap();
+                    futures::future::pending::<()>().await
+                })
+                .unwrap()
+        }));
+        let message = result.unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(message.contains("files 22000 bytes"), "{}", message);
+    }
+}
@@ -0,0 +1,327 @@
+//! Counters, gauges and latency histograms recorded in simulated time.
+//!
+//! Workloads record measurements through a handle scoped to the host they run on, and
+//! optionally to a single connection of that host. Every sample is stamped with the simulated
+//! time at which it was recorded, so at the end of a run tests can query the recorded metrics
+//! to assert on performance under the faults injected, such as the p99 latency of requests
+//! during the chaos phase, or the throughput of a host which had its connections clogged.
+use crate::deterministic::DeterministicTimeHandle;
+use std::{mem, net, sync, time};
+use tracing::trace;
+
+/// A single measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    /// An amount added to a counter.
+    Counter(u64),
+    /// The value a gauge was set to.
+    Gauge(f64),
+    /// The latency of a single operation.
+    Latency(time::Duration),
+}
+
+/// A measurement recorded against a metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSample {
+    /// Simulated time at which the sample was recorded.
+    pub at: time::Instant,
+    /// Address of the host which recorded the sample.
+    pub host: net::IpAddr,
+    /// The local and peer address of the connection the sample was recorded against, if any.
+    pub connection: Option<(net::SocketAddr, net::SocketAddr)>,
+    pub name: String,
+    pub value: MetricValue,
+}
+
+#[derive(Debug)]
+pub(crate) struct DeterministicMetrics {
+    time: DeterministicTimeHandle,
+    samples: sync::Arc<sync::Mutex<Vec<MetricSample>>>,
+}
+
+impl DeterministicMetrics {
+    pub(crate) fn new(time: DeterministicTimeHandle) -> Self {
+        Self {
+            time,
+            samples: sync::Arc::new(sync::Mutex::new(vec![])),
+        }
+    }
+
+    /// Returns a handle which records samples on behalf of `host`.
+    pub(crate) fn scoped(&self, host: net::IpAddr) -> DeterministicMetricsHandle {
+        DeterministicMetricsHandle {
+            time: self.time.clone(),
+            host,
+            connection: None,
+            samples: sync::Arc::clone(&self.samples),
+        }
+    }
+}
+
+/// Handle for recording and querying metrics, scoped to a particular host.
+#[derive(Debug, Clone)]
+pub struct DeterministicMetricsHandle {
+    time: DeterministicTimeHandle,
+    host: net::IpAddr,
+    connection: Option<(net::SocketAddr, net::SocketAddr)>,
+    samples: sync::Arc<sync::Mutex<Vec<MetricSample>>>,
+}
+
+impl DeterministicMetricsHandle {
+    /// Returns a handle to the same metrics, recording on behalf of `host`.
+    pub(crate) fn scoped(&self, host: net::IpAddr) -> Self {
+        Self {
+            host,
+            connection: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a handle which records samples against the connection between `local` and
+    /// `peer`.
+    pub fn connection(&self, local: net::SocketAddr, peer: net::SocketAddr) -> Self {
+        Self {
+            connection: Some((local, peer)),
+            ..self.clone()
+        }
+    }
+
+    fn record(&self, name: &str, value: MetricValue) {
+        let sample = MetricSample {
+            at: self.time.now(),
+            host: self.host,
+            connection: self.connection,
+            name: name.to_string(),
+            value,
+        };
+        trace!("{} recorded {} {:?}", self.host, sample.name, sample.value);
+        self.samples.lock().unwrap().push(sample);
+    }
+
+    /// Add `amount` to the counter `name`.
+    pub fn increment(&self, name: &str, amount: u64) {
+        self.record(name, MetricValue::Counter(amount));
+    }
+
+    /// Set the gauge `name` to `value`.
+    pub fn gauge(&self, name: &str, value: f64) {
+        self.record(name, MetricValue::Gauge(value));
+    }
+
+    /// Record `latency` in the histogram `name`.
+    pub fn latency(&self, name: &str, latency: time::Duration) {
+        self.record(name, MetricValue::Latency(latency));
+    }
+
+    /// Start timing an operation, which is recorded in the histogram `name` when the returned
+    /// timer is stopped or dropped.
+    pub fn timer(&self, name: &str) -> LatencyTimer {
+        LatencyTimer {
+            handle: self.clone(),
+            name: name.to_string(),
+            start: self.time.now(),
+            stopped: false,
+        }
+    }
+
+    /// Returns a query over every sample recorded so far, by any host.
+    pub fn query(&self) -> MetricsQuery {
+        MetricsQuery {
+            samples: self.samples.lock().unwrap().clone(),
+        }
+    }
+
+    /// Returns the approximate number of bytes held by the recorded samples.
+    pub(crate) fn memory(&self) -> usize {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|sample| mem::size_of::<MetricSample>() + sample.name.len())
+            .sum()
+    }
+}
+
+/// Records the simulated time between its creation and being stopped or dropped.
+#[derive(Debug)]
+pub struct LatencyTimer {
+    handle: DeterministicMetricsHandle,
+    name: String,
+    start: time::Instant,
+    stopped: bool,
+}
+
+impl LatencyTimer {
+    /// Record the time elapsed since the timer was started, and return it.
+    pub fn stop(mut self) -> time::Duration {
+        self.stopped = true;
+        let latency = self.handle.time.now() - self.start;
+        self.handle.latency(&self.name, latency);
+        latency
+    }
+}
+
+impl Drop for LatencyTimer {
+    fn drop(&mut self) {
+        if !self.stopped {
+            let latency = self.handle.time.now() - self.start;
+            self.handle.latency(&self.name, latency);
+        }
+    }
+}
+
+/// Filters over a snapshot of the recorded samples. Each filter narrows the set of matching
+/// samples.
+#[derive(Debug, Clone)]
+pub struct MetricsQuery {
+    samples: Vec<MetricSample>,
+}
+
+impl MetricsQuery {
+    /// Only match samples recorded by the host `addr`.
+    pub fn host(self, addr: net::IpAddr) -> Self {
+        self.filter(|sample| sample.host == addr)
+    }
+
+    /// Only match samples recorded against the connection between `local` and `peer`.
+    pub fn connection(self, local: net::SocketAddr, peer: net::SocketAddr) -> Self {
+        self.filter(|sample| sample.connection == Some((local, peer)))
+    }
+
+    /// Only match samples recorded at or after `start`, and before `end`.
+    pub fn between(self, start: time::Instant, end: time::Instant) -> Self {
+        self.filter(|sample| sample.at >= start && sample.at < end)
+    }
+
+    fn filter<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&MetricSample) -> bool,
+    {
+        self.samples.retain(|sample| f(sample));
+        self
+    }
+
+    /// Returns the matching samples, in the order they were recorded.
+    pub fn samples(self) -> Vec<MetricSample> {
+        self.samples
+    }
+
+    /// Returns the sum of the matching samples of the counter `name`.
+    pub fn counter(&self, name: &str) -> u64 {
+        self.samples
+            .iter()
+            .filter(|sample| sample.name == name)
+            .filter_map(|sample| match sample.value {
+                MetricValue::Counter(amount) => Some(amount),
+                _ => None,
+            })
+            .sum()
+    }
+
+    /// Returns the most recent matching value of the gauge `name`.
+    pub fn gauge(&self, name: &str) -> Option<f64> {
+        self.samples
+            .iter()
+            .rev()
+            .filter(|sample| sample.name == name)
+            .find_map(|sample| match sample.value {
+                MetricValue::Gauge(value) => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Returns a histogram of the matching latencies recorded in `name`.
+    pub fn histogram(&self, name: &str) -> Histogram {
+        let mut latencies: Vec<_> = self
+            .samples
+            .iter()
+            .filter(|sample| sample.name == name)
+            .filter_map(|sample| match sample.value {
+                MetricValue::Latency(latency) => Some(latency),
+                _ => None,
+            })
+            .collect();
+        latencies.sort();
+        Histogram { latencies }
+    }
+}
+
+/// The distribution of a set of latencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Every latency, in ascending order.
+    latencies: Vec<time::Duration>,
+}
+
+impl Histogram {
+    /// Returns the number of latencies recorded.
+    pub fn count(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Returns the smallest latency such that at least `percentile` percent of latencies are
+    /// no greater than it, or `None` if no latencies were recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<time::Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let index = rank.max(1).min(self.latencies.len()) - 1;
+        Some(self.latencies[index])
+    }
+
+    /// Returns the mean latency, or `None` if no latencies were recorded.
+    pub fn mean(&self) -> Option<time::Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let total: time::Duration = self.latencies.iter().sum();
+        Some(total / self.latencies.len() as u32)
+    }
+
+    /// Returns the largest latency, or `None` if no latencies were recorded.
+    pub fn max(&self) -> Option<time::Duration> {
+        self.latencies.last().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TimeEnv;
+    use std::{net, time::Duration};
+
+    #[test]
+    /// Test that latencies are measured in simulated time, and can be queried by host and
+    /// time range.
+    fn record_and_query() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let first = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let second = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let start = first.now();
+                for millis in 1..=100 {
+                    let timer = first.metrics_handle().timer("request");
+                    first.delay_from(Duration::from_millis(millis)).await;
+                    timer.stop();
+                    first.metrics_handle().increment("requests", 1);
+                }
+                let end = first.now() + Duration::from_millis(1);
+                first.delay_from(Duration::from_secs(1)).await;
+                second.metrics_handle().increment("requests", 5);
+                second.metrics_handle().gauge("queue", 3.0);
+                second.metrics_handle().gauge("queue", 1.0);
+
+                let query = first.metrics_handle().query();
+                let histogram = query.clone().host(first.local_addr()).histogram("request");
+                assert_eq!(histogram.count(), 100);
+                assert_eq!(histogram.percentile(99.0), Some(Duration::from_millis(99)));
+                assert_eq!(histogram.max(), Some(Duration::from_millis(100)));
+                assert_eq!(query.counter("requests"), 105);
+                assert_eq!(query.clone().between(start, end).counter("requests"), 100);
+                assert_eq!(query.host(second.local_addr()).gauge("queue"), Some(1.0));
+            })
+            .unwrap();
+    }
+}
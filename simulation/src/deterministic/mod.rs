@@ -6,124 +6,909 @@
 //! There are 3 layers on which the `DeterministicRuntime` is built.
 //!
 //! - `DeterministicRandom` allows for accessing a deterministic source of randomness.
-//! - `DeterministicTime` provides a deterministic time source.
+//! - `DeterministicTime` provides a deterministic time source, advanced by a pluggable `Clock`.
 //! - `DeterministicNetwork` provides a process wide networking in memory networking implementation.
+//! - `DeterministicFs` provides an in memory filesystem for each simulated host.
+//!
+//! Alongside these, `DeterministicDiscovery` provides a simulated service registry which can be
+//! used by hosts to find each other, `DeterministicDns` resolves A and SRV records through
+//! per-host caches which expire in simulated time, and `DeterministicEventBus` carries domain
+//! events published by application code to any interested checkers. Channels created through
+//! a `DeterministicRuntimeHandle` delay and drop messages as configured with the
+//! `DeterministicChannelHandle`. Every fault injected is recorded in the
+//! `DeterministicChaosLog`. `ExternalService` stands in for dependencies
+//! which live outside of the system under test, and `WalChecker` crash tests write ahead logs
+//! built on the simulated filesystem. `DeterministicMetrics` records counters, gauges and
+//! latencies in simulated time, for assertions on performance under faults. `Sim` offers a
+//! turmoil style interface of named hosts and clients on top of the runtime, for tests ported
+//! from that crate. A `Timeline` of tasks, timers, connections and faults can be exported as
+//! JSON for visualization, and a `FlowTrace` follows individual requests across hosts. A
+//! `FaultPlan` lists ahead of time which hosts of a `Cluster` are crashed or restarted and
+//! when, so that failing plans can be shrunk by property testing libraries. A `HealthCheck`
+//! periodically probes servers over the simulated network, so scenarios can check that enough
+//! of them stayed healthy. With the `tower` feature, a `ServiceChannel` wires a client directly
+//! to a tower service on another host, skipping the simulated TCP stack while still injecting
+//! network faults into each message.
 //!
 //! `DeterministicRuntime` uses these to support deterministic task scheduling and fault injection.
-use crate::Error;
+use crate::{
+    sync::{broadcast, mpsc, oneshot},
+    Error,
+};
 use async_trait::async_trait;
-use futures::Future;
+use futures::{future, Future, FutureExt, Poll};
 use std::{
-    io, net,
-    time::{Duration, Instant},
+    collections,
+    hash::{Hash, Hasher},
+    io, net, panic, path, sync,
+    time::{Duration, Instant, SystemTime},
 };
 
+mod builder;
+mod campaign;
+mod channel;
+mod chaos;
+mod cluster;
+mod context;
+mod descriptor;
+mod discovery;
+mod dns;
+mod dump;
+mod escape;
+mod event_log;
+mod events;
+mod external;
+mod fingerprint;
+mod flow;
+mod fs;
+mod health;
+mod maintenance;
+mod memory;
+mod metrics;
+#[cfg(feature = "tui")]
+mod monitor;
 mod network;
+mod otlp;
+mod pending;
+mod plan;
+mod process;
+mod profile;
 mod random;
+mod resources;
+mod scenario;
+#[cfg(feature = "tower")]
+mod service;
+mod sim;
+mod summary;
 mod time;
+mod timeline;
+mod topology;
+mod trace;
+mod wal;
+mod watchdog;
+pub use builder::DeterministicRuntimeBuilder;
+pub use campaign::{Campaign, CampaignFailure, CampaignProgress, CampaignStatus};
+pub use channel::DeterministicChannelHandle;
+pub(crate) use channel::DeterministicChannels;
+pub(crate) use chaos::DeterministicChaosLog;
+pub use chaos::{
+    ChaosAction, ChaosKind, ChaosQuery, ChaosTarget, DeterministicChaosLogHandle, InjectedFault,
+};
+pub use cluster::Cluster;
+pub(crate) use context::current;
+use context::Scoped;
+pub(crate) use descriptor::{Descriptor, DescriptorTable};
+pub(crate) use discovery::DeterministicDiscovery;
+pub use discovery::{DeterministicDiscoveryHandle, Watch};
+pub(crate) use dns::DeterministicDns;
+pub use dns::{DeterministicDnsHandle, SrvRecord};
+use dump::SimContext;
+#[doc(hidden)]
+pub use dump::{assertion_checked, assertion_context};
+#[cfg(feature = "subscriber")]
+pub(crate) use escape::unguarded;
+pub use event_log::{EventKind, EventLog, EventRecord};
+pub(crate) use events::DeterministicEventBus;
+pub use events::{DeterministicEventBusHandle, Event, Subscription};
+pub use external::{ExternalService, ExternalServiceHandle};
+use fingerprint::FingerprintHasher;
+pub use flow::{Flow, FlowEvent, FlowStage, FlowTrace};
+pub(crate) use fs::DeterministicFs;
+pub use fs::{Corruption, DeterministicFsHandle, DiskSnapshot, File};
+pub use health::{HealthCheck, HealthLog, HealthRound, HealthStatus};
+pub use maintenance::RollingRestart;
+use memory::MemoryMeter;
+pub use memory::MemoryUsage;
+pub(crate) use metrics::DeterministicMetrics;
+pub use metrics::{
+    DeterministicMetricsHandle, Histogram, LatencyTimer, MetricSample, MetricValue, MetricsQuery,
+};
+pub use network::{
+    AcceptOrder, CloseOnDrop, ConnectionStats, DirectionStats, Listener, Socket, Transport,
+    TransportGate, TransportListener,
+};
 pub(crate) use network::{DeterministicNetwork, DeterministicNetworkHandle};
-pub use network::{Listener, Socket};
+pub use pending::{InFlight, PendingTask, PendingTimer, PendingWork};
+pub use plan::{FaultPlan, HostFault, PlannedFault};
+pub use process::PanicPolicy;
+pub(crate) use process::ProcessTable;
+pub use profile::{Profile, Profiler};
 pub(crate) use random::{DeterministicRandom, DeterministicRandomHandle};
+pub use resources::{OpenResources, Resource};
+pub use scenario::{Phase, Scenario};
+#[cfg(feature = "tower")]
+pub use service::ServiceChannel;
+pub use sim::{lookup, Sim, SimBuilder, SimResult};
+pub use summary::Summary;
+pub use time::{
+    AdvanceCause, AutoAdvanceClock, Clock, ClockAdvance, Delay, TickClock, TimeReader, MAX_DELAY,
+};
 pub(crate) use time::{DeterministicTime, DeterministicTimeHandle};
+pub use timeline::{Timeline, TimelineEntry, TimelineKind};
 use tokio_net::driver;
+pub use topology::{LiveConnection, Topology, TopologyHost};
+pub use wal::WalChecker;
+use watchdog::Watchdog;
 
+/// A handle to a [`DeterministicRuntime`], scoped to one of its hosts.
+///
+/// # Threads
+///
+/// The handle is `Send` and `Sync`, so it may be moved to threads outside of the runtime, such
+/// as a thread writing logs. Only its read only operations should be used from those threads:
+/// [`now`], [`elapsed`], [`time_reader`] and the queries on the metrics and chaos log. Their
+/// results are consistent with the simulation, but depend on when the operating system runs
+/// the thread, so they must not influence the simulation.
+///
+/// Everything else, including spawning tasks, creating timers and sockets and drawing random
+/// numbers, must only happen on tasks of the runtime. Doing so from another thread does not
+/// cause undefined behavior, but it races with the simulation and changes the outcome from one
+/// run with a seed to the next. Primitives in [`sync`] also only wake waiters in an order
+/// drawn from the seed when used on the runtime.
+///
+/// [`DeterministicRuntime`]:DeterministicRuntime
+/// [`now`]:DeterministicRuntimeHandle::now
+/// [`elapsed`]:DeterministicRuntimeHandle::elapsed
+/// [`time_reader`]:DeterministicRuntimeHandle::time_reader
+/// [`sync`]:crate::sync
 #[derive(Debug, Clone)]
 pub struct DeterministicRuntimeHandle {
+    shared: sync::Arc<Shared>,
+}
+
+/// The state behind a [`DeterministicRuntimeHandle`], shared between its clones so that
+/// cloning a handle into a task only increments a reference count.
+///
+/// [`DeterministicRuntimeHandle`]:DeterministicRuntimeHandle
+#[derive(Debug)]
+struct Shared {
     time_handle: time::DeterministicTimeHandle,
     network_handle: DeterministicNetworkHandle,
+    fs_handle: DeterministicFsHandle,
     executor_handle: tokio_executor::current_thread::Handle,
     random_handle: DeterministicRandomHandle,
+    channel_handle: DeterministicChannelHandle,
+    discovery_handle: DeterministicDiscoveryHandle,
+    dns_handle: DeterministicDnsHandle,
+    event_bus_handle: DeterministicEventBusHandle,
+    chaos_log_handle: DeterministicChaosLogHandle,
+    metrics_handle: DeterministicMetricsHandle,
+    phase: sync::Arc<sync::Mutex<Option<Phase>>>,
+    hostnames: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, String>>>,
+    config: sync::Arc<sync::Mutex<collections::HashMap<(net::IpAddr, String), String>>>,
+    processes: ProcessTable,
+    descriptors: DescriptorTable,
+    fault_plan: sync::Arc<sync::Mutex<Option<FaultPlan>>>,
+    memory: MemoryMeter,
+    flows: FlowTrace,
 }
 
+// the handle is documented as safe to move to other threads, so keep it that way.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<DeterministicRuntimeHandle>();
+    assert_send_sync::<TimeReader>();
+};
+
 impl DeterministicRuntimeHandle {
     pub fn now(&self) -> Instant {
-        self.time_handle.now()
+        self.shared.time_handle.now()
+    }
+    /// Returns the simulated time which has passed since the runtime was created.
+    pub fn elapsed(&self) -> Duration {
+        self.shared.time_handle.elapsed()
+    }
+    /// Returns the most recent advances of simulated time, oldest first, and what caused each.
+    pub fn clock_history(&self) -> Vec<ClockAdvance> {
+        self.shared.time_handle.history()
+    }
+    /// Returns a read only view of simulated time, for threads outside of the runtime which
+    /// only need to read the time and should not hold a handle to the whole runtime.
+    pub fn time_reader(&self) -> TimeReader {
+        self.shared.time_handle.reader()
+    }
+    /// Returns a delay of this host which completes at `deadline`, or [`Error::TimeOverflow`]
+    /// if `deadline` is more than [`MAX_DELAY`] ahead. [`TimeEnv::delay`] leaves the
+    /// overflow to be returned by the runtime instead.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    /// [`TimeEnv::delay`]:crate::TimeEnv::delay
+    pub fn try_delay(&self, deadline: Instant) -> Result<Delay, Error> {
+        self.shared
+            .time_handle
+            .try_host_delay(self.local_addr(), deadline)
+    }
+    /// Returns a delay of this host which completes once `from_now` has passed, or
+    /// [`Error::TimeOverflow`] if `from_now` is longer than [`MAX_DELAY`].
+    /// [`TimeEnv::delay_from`] leaves the overflow to be returned by the runtime instead.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    /// [`TimeEnv::delay_from`]:crate::TimeEnv::delay_from
+    pub fn try_delay_from(&self, from_now: Duration) -> Result<Delay, Error> {
+        let addr = self.local_addr();
+        let deadline = time::checked_add(self.now(), from_now, || {
+            format!("setting a delay of {} for {:?}", addr, from_now)
+        })?;
+        self.shared.time_handle.try_host_delay(addr, deadline)
+    }
+    /// Returns the address of the host this handle is scoped to.
+    pub fn local_addr(&self) -> net::IpAddr {
+        self.shared.network_handle.local_addr()
+    }
+    /// Returns a handle to the same runtime, scoped to the host `addr`.
+    pub fn scoped(&self, addr: net::IpAddr) -> DeterministicRuntimeHandle {
+        let shared = Shared {
+            time_handle: self.shared.time_handle.clone(),
+            network_handle: self.shared.network_handle.scoped(addr),
+            fs_handle: self.shared.fs_handle.scoped(addr),
+            executor_handle: self.shared.executor_handle.clone(),
+            random_handle: self.shared.random_handle.clone(),
+            channel_handle: self.shared.channel_handle.clone(),
+            discovery_handle: self.shared.discovery_handle.clone(),
+            dns_handle: self.shared.dns_handle.scoped(addr),
+            event_bus_handle: self.shared.event_bus_handle.scoped(addr),
+            chaos_log_handle: self.shared.chaos_log_handle.clone(),
+            metrics_handle: self.shared.metrics_handle.scoped(addr),
+            phase: sync::Arc::clone(&self.shared.phase),
+            hostnames: sync::Arc::clone(&self.shared.hostnames),
+            config: sync::Arc::clone(&self.shared.config),
+            processes: self.shared.processes.clone(),
+            descriptors: self.shared.descriptors.clone(),
+            fault_plan: sync::Arc::clone(&self.shared.fault_plan),
+            memory: self.shared.memory.clone(),
+            flows: self.shared.flows.clone(),
+        };
+        DeterministicRuntimeHandle {
+            shared: sync::Arc::new(shared),
+        }
+    }
+    /// Kill the host `addr`, cancelling all of its tasks and disconnecting all of its
+    /// connections. Writes to the host's filesystem which were not synced are lost, and its DNS
+    /// cache is cleared. The kill is recorded in the chaos log, and errors on the disconnected
+    /// connections carry an [`InjectedFault`] identifying it.
+    ///
+    /// [`InjectedFault`]:InjectedFault
+    pub fn kill(&self, addr: net::IpAddr) {
+        let fault = self
+            .shared
+            .chaos_log_handle
+            .inject(ChaosKind::Kill, ChaosTarget::Host(addr));
+        self.shared.processes.kill(addr);
+        self.shared.network_handle.kill(addr, Some(fault));
+        self.shared.fs_handle.crash(addr);
+        self.shared.dns_handle.scoped(addr).flush_cache();
+    }
+    /// Kill the host `addr` without recording the kill in the chaos log.
+    pub(crate) fn stop(&self, addr: net::IpAddr) {
+        self.shared.processes.kill(addr);
+        self.shared.network_handle.kill(addr, None);
+    }
+    /// Disconnect the connection between `local` and `peer`, failing operations on both of its
+    /// ends with an error caused by `fault`.
+    pub(crate) fn disconnect(
+        &self,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        fault: InjectedFault,
+    ) {
+        self.shared.network_handle.disconnect(local, peer, fault);
+    }
+    /// Bind a listener for connections over the custom `transport` to `addr` on this host.
+    pub fn bind_transport<T>(
+        &self,
+        addr: net::SocketAddr,
+        transport: T,
+    ) -> io::Result<TransportListener<T>>
+    where
+        T: Transport,
+    {
+        self.shared.network_handle.bind_transport(addr, transport)
+    }
+    /// Connect to the listener for the transport `T` bound to `addr`, returning the client end
+    /// of the new connection. Fails with `ConnectionRefused` if no listener for `T` is bound.
+    pub fn connect_transport<T>(&self, addr: net::SocketAddr) -> io::Result<T::Endpoint>
+    where
+        T: Transport,
+    {
+        self.shared.network_handle.connect_transport::<T>(addr)
+    }
+    /// Spawn `future` on this host, like [`SpawnEnv::spawn`], naming it `name` in the `task`
+    /// span entered each time it is polled.
+    ///
+    /// [`SpawnEnv::spawn`]:crate::SpawnEnv::spawn
+    pub fn spawn_named<F>(&self, name: &str, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn_task(Some(name), future);
+    }
+    fn spawn_task<F>(&self, name: Option<&str>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let addr = self.local_addr();
+        let hostname = crate::HostEnv::hostname(self);
+        let future = Scoped::new(self.clone(), future);
+        let future = match self.shared.processes.panic_policy() {
+            PanicPolicy::Abort => future::Either::Left(future),
+            PanicPolicy::CrashHost => {
+                let handle = self.clone();
+                let future = panic::AssertUnwindSafe(future).catch_unwind();
+                future::Either::Right(future.map(move |result| {
+                    if let Err(payload) = result {
+                        let message = dump::panic_message(&*payload);
+                        handle.shared.processes.panicked(addr, message);
+                        handle.kill(addr);
+                    }
+                }))
+            }
+        };
+        let future = self
+            .shared
+            .processes
+            .register(addr, &hostname, name, future);
+        self.shared
+            .executor_handle
+            .spawn(future)
+            .expect("failed to spawn");
+    }
+    /// Returns the statistics of the most recent connection from `source` to `dest`, whether
+    /// or not it is still open.
+    pub fn connection_stats(
+        &self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    ) -> Option<ConnectionStats> {
+        self.shared.network_handle.connection_stats(source, dest)
+    }
+    /// Returns the statistics of every connection established between the hosts `a` and `b`
+    /// in either direction, whether or not they are still open, in the order they were
+    /// established. A closed connection is left out once a newer connection between the same
+    /// addresses replaces it.
+    pub fn connection_stats_between(&self, a: net::IpAddr, b: net::IpAddr) -> Vec<ConnectionStats> {
+        self.shared.network_handle.connection_stats_between(a, b)
+    }
+    /// Returns the number of tasks running on behalf of the host `addr`.
+    pub fn task_count(&self, addr: net::IpAddr) -> usize {
+        self.shared.processes.task_count(addr)
+    }
+    /// Returns the code passed to [`Environment::exit`] the last time the process running on
+    /// `addr` exited, or `None` if it has not exited.
+    ///
+    /// [`Environment::exit`]:crate::Environment::exit
+    pub fn exit_code(&self, addr: net::IpAddr) -> Option<i32> {
+        self.shared.processes.exit_code(addr)
+    }
+    /// Returns the message of the most recent panic which crashed the host `addr` under
+    /// [`PanicPolicy::CrashHost`], or `None` if no panic has.
+    ///
+    /// [`PanicPolicy::CrashHost`]:PanicPolicy::CrashHost
+    pub fn panic_message(&self, addr: net::IpAddr) -> Option<String> {
+        self.shared.processes.panic_message(addr)
+    }
+    /// Limit the number of files, sockets and listeners the host `addr` may have open at
+    /// once. Beyond the limit, opening another fails with `EMFILE`.
+    pub fn set_descriptor_limit(&self, addr: net::IpAddr, limit: Option<usize>) {
+        self.shared.descriptors.set_limit(addr, limit);
+    }
+    /// Name the host `addr`. Hosts which have not been named are named after their address.
+    pub fn set_hostname(&self, addr: net::IpAddr, name: &str) {
+        self.shared
+            .hostnames
+            .lock()
+            .unwrap()
+            .insert(addr, name.to_string());
+    }
+    /// Set the configuration value for `key` on the host `addr`, which is returned by
+    /// [`Environment::config`] from then on. Hosts see no configuration until it is set, so
+    /// the environment of the process running the simulation does not leak into it.
+    ///
+    /// [`Environment::config`]:crate::Environment::config
+    pub fn set_config(&self, addr: net::IpAddr, key: &str, value: &str) {
+        self.shared
+            .config
+            .lock()
+            .unwrap()
+            .insert((addr, key.to_string()), value.to_string());
+    }
+    /// Remove the configuration value for `key` from the host `addr`.
+    pub fn remove_config(&self, addr: net::IpAddr, key: &str) {
+        self.shared
+            .config
+            .lock()
+            .unwrap()
+            .remove(&(addr, key.to_string()));
+    }
+    /// Returns the number of files, sockets and listeners the host `addr` has open.
+    pub fn open_descriptors(&self, addr: net::IpAddr) -> usize {
+        self.shared.descriptors.open(addr)
+    }
+    /// Returns the files, listeners, connections, timers and tasks the host `addr` has open.
+    pub fn open_resources(&self, addr: net::IpAddr) -> OpenResources {
+        OpenResources {
+            descriptors: self.shared.descriptors.resources(addr),
+            timers: self.shared.time_handle.timers(addr),
+            tasks: self.shared.processes.task_count(addr),
+        }
+    }
+    /// Returns the timers, tasks and messages the simulation is waiting on, across every
+    /// host.
+    pub fn pending_work(&self) -> PendingWork {
+        PendingWork {
+            timers: self.shared.time_handle.pending(),
+            tasks: self.shared.processes.pending(),
+            messages: self.shared.network_handle.in_flight(),
+        }
+    }
+    /// Returns the hosts of the simulation, the ports they listen on, the connections open
+    /// between them and the partitions in place, as of now.
+    pub fn topology(&self) -> Topology {
+        let mut hosts = topology::Hosts::default();
+        for (addr, hostname) in self.shared.hostnames.lock().unwrap().iter() {
+            hosts.host(*addr).hostname = Some(hostname.clone());
+        }
+        self.shared.processes.add_hosts(&mut hosts);
+        let (listeners, connections, partitions) = self.shared.network_handle.topology();
+        Topology::new(self.elapsed(), hosts, listeners, connections, partitions)
+    }
+    /// Begin a flow named `name`, tagging the connections opened and bytes written by the
+    /// calling task from now on, until [`end_flow`]. Returns the id of the flow, or `None` if
+    /// flows are not being traced.
+    ///
+    /// [`end_flow`]:DeterministicRuntimeHandle::end_flow
+    pub fn begin_flow(&self, name: &str) -> Option<u64> {
+        self.shared.flows.begin(self.local_addr(), name)
+    }
+    /// End the flow of the calling task, if it is in one.
+    pub fn end_flow(&self) {
+        self.shared.flows.end(self.local_addr());
+    }
+    /// Returns the approximate memory held by the simulation, across every host.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.shared.memory.usage()
     }
     pub fn time_handle(&self) -> time::DeterministicTimeHandle {
-        self.time_handle.clone()
+        self.shared.time_handle.clone()
     }
     pub fn random_handle(&self) -> DeterministicRandomHandle {
-        self.random_handle.clone()
+        self.shared.random_handle.clone()
+    }
+    pub fn fs_handle(&self) -> DeterministicFsHandle {
+        self.shared.fs_handle.clone()
+    }
+    pub fn channel_handle(&self) -> DeterministicChannelHandle {
+        self.shared.channel_handle.clone()
+    }
+    pub fn discovery_handle(&self) -> DeterministicDiscoveryHandle {
+        self.shared.discovery_handle.clone()
+    }
+    pub fn dns_handle(&self) -> DeterministicDnsHandle {
+        self.shared.dns_handle.clone()
+    }
+    pub fn event_bus_handle(&self) -> DeterministicEventBusHandle {
+        self.shared.event_bus_handle.clone()
+    }
+    pub fn chaos_log_handle(&self) -> DeterministicChaosLogHandle {
+        self.shared.chaos_log_handle.clone()
+    }
+    pub fn metrics_handle(&self) -> DeterministicMetricsHandle {
+        self.shared.metrics_handle.clone()
+    }
+    /// Returns the phase of the currently executing [`Scenario`], if any.
+    ///
+    /// [`Scenario`]:Scenario
+    pub fn current_phase(&self) -> Option<Phase> {
+        *self.shared.phase.lock().unwrap()
+    }
+    /// Take the fault plan the runtime was built with, if it has not been taken yet.
+    pub(crate) fn take_fault_plan(&self) -> Option<FaultPlan> {
+        self.shared.fault_plan.lock().unwrap().take()
+    }
+    pub(crate) fn set_phase(&self, phase: Option<Phase>) {
+        *self.shared.phase.lock().unwrap() = phase;
+        if let Some(phase) = phase {
+            self.shared.event_bus_handle.publish(phase);
+        }
     }
 }
 
 #[async_trait]
-impl crate::Environment for DeterministicRuntimeHandle {
-    type TcpStream = network::Socket;
-    type TcpListener = network::Listener;
+impl crate::SpawnEnv for DeterministicRuntimeHandle {
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        self.executor_handle.spawn(future).expect("failed to spawn");
+        self.spawn_task(None, future);
+    }
+    fn channel<T>(&self, buffer: usize) -> (mpsc::Sender<T>, mpsc::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::channel_with_faults(buffer, Some(self.shared.channel_handle.faults()))
+    }
+    fn unbounded_channel<T>(&self) -> (mpsc::UnboundedSender<T>, mpsc::UnboundedReceiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::unbounded_channel_with_faults(Some(self.shared.channel_handle.faults()), false)
+    }
+    fn lossy_channel<T>(&self) -> (mpsc::UnboundedSender<T>, mpsc::UnboundedReceiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::unbounded_channel_with_faults(Some(self.shared.channel_handle.faults()), true)
+    }
+    fn oneshot<T>(&self) -> (oneshot::Sender<T>, oneshot::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        oneshot::channel_with_faults(Some(self.shared.channel_handle.faults()))
     }
+    fn broadcast<T>(&self) -> (broadcast::Sender<T>, broadcast::Receiver<T>)
+    where
+        T: Clone + Send + 'static,
+    {
+        broadcast::channel_with_faults(Some(self.shared.channel_handle.faults()))
+    }
+    async fn exit(&self, code: i32) {
+        let addr = self.local_addr();
+        self.shared.processes.exit(addr, code);
+        self.shared.network_handle.kill(addr, None);
+        // the calling task has been cancelled, and is dropped when it next yields.
+        futures::future::pending::<()>().await
+    }
+}
+
+impl crate::TimeEnv for DeterministicRuntimeHandle {
+    type Delay = Delay;
     fn now(&self) -> Instant {
-        self.time_handle.now()
+        self.shared.time_handle.now()
     }
-    fn delay(&self, deadline: Instant) -> tokio_timer::Delay {
-        self.time_handle.delay(deadline)
+    fn now_system_time(&self) -> SystemTime {
+        self.shared.time_handle.system_time()
+    }
+    fn delay(&self, deadline: Instant) -> Delay {
+        self.try_delay(deadline).unwrap_or_else(|error| {
+            let addr = self.local_addr();
+            self.shared.time_handle.overflowed_delay(addr, error)
+        })
     }
-    fn timeout<T>(&self, value: T, timeout: Duration) -> tokio_timer::Timeout<T> {
-        self.time_handle.timeout(value, timeout)
+    fn delay_from(&self, from_now: Duration) -> Delay {
+        self.try_delay_from(from_now).unwrap_or_else(|error| {
+            let addr = self.local_addr();
+            self.shared.time_handle.overflowed_delay(addr, error)
+        })
     }
+}
+
+#[async_trait]
+impl crate::NetEnv for DeterministicRuntimeHandle {
+    type TcpStream = network::Socket;
+    type TcpListener = network::Listener;
     async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
     where
         A: Into<net::SocketAddr> + Send + Sync,
     {
-        self.network_handle.bind(addr.into()).await
+        self.shared.network_handle.bind(addr.into()).await
     }
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
         A: Into<net::SocketAddr> + Send + Sync,
     {
-        self.network_handle.connect(addr.into()).await
+        self.shared.network_handle.connect(addr.into()).await
+    }
+}
+
+impl crate::RandEnv for DeterministicRuntimeHandle {
+    fn random(&self) -> crate::RandomHandle {
+        crate::RandomHandle::deterministic(self.shared.random_handle.clone())
+    }
+}
+
+impl crate::HostEnv for DeterministicRuntimeHandle {
+    fn hostname(&self) -> String {
+        let addr = self.local_addr();
+        match self.shared.hostnames.lock().unwrap().get(&addr) {
+            Some(name) => name.clone(),
+            None => addr.to_string(),
+        }
+    }
+    fn host_id(&self) -> u64 {
+        // derived from the address, so it is the same in every run and after a restart.
+        match self.local_addr() {
+            net::IpAddr::V4(addr) => u64::from(u32::from(addr)),
+            net::IpAddr::V6(addr) => {
+                let addr = u128::from(addr);
+                (addr >> 64) as u64 ^ addr as u64
+            }
+        }
+    }
+    fn local_addrs(&self) -> Vec<net::IpAddr> {
+        vec![self.local_addr()]
+    }
+    fn config(&self, key: &str) -> Option<String> {
+        let key = (self.local_addr(), key.to_string());
+        self.shared.config.lock().unwrap().get(&key).cloned()
+    }
+}
+
+#[async_trait]
+impl crate::FsEnv for DeterministicRuntimeHandle {
+    type File = fs::File;
+    async fn open<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.open(path.as_ref()).await
+    }
+    async fn create<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.create(path.as_ref()).await
+    }
+    async fn read<P>(&self, path: P) -> io::Result<Vec<u8>>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.read(path.as_ref()).await
+    }
+    async fn write<P, C>(&self, path: P, contents: C) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync,
+    {
+        self.shared
+            .fs_handle
+            .write(path.as_ref(), contents.as_ref())
+            .await
+    }
+    async fn rename<P, Q>(&self, from: P, to: Q) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared
+            .fs_handle
+            .rename(from.as_ref(), to.as_ref())
+            .await
+    }
+    async fn remove<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.remove(path.as_ref()).await
+    }
+    async fn create_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.create_dir(path.as_ref()).await
+    }
+    async fn remove_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.remove_dir(path.as_ref()).await
+    }
+    async fn read_dir<P>(&self, path: P) -> io::Result<Vec<path::PathBuf>>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.read_dir(path.as_ref()).await
+    }
+    async fn sync_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.shared.fs_handle.sync_dir(path.as_ref()).await
     }
 }
 
 type Executor = tokio_executor::current_thread::CurrentThread<DeterministicTime<driver::Reactor>>;
 
 pub struct DeterministicRuntime {
+    seed: u64,
     executor: Executor,
     time_handle: DeterministicTimeHandle,
     network: DeterministicNetwork,
+    fs: DeterministicFs,
     random: DeterministicRandom,
+    channels: DeterministicChannels,
+    discovery: DeterministicDiscovery,
+    dns: DeterministicDns,
+    event_bus: DeterministicEventBus,
+    chaos_log: DeterministicChaosLog,
+    metrics: DeterministicMetrics,
+    phase: sync::Arc<sync::Mutex<Option<Phase>>>,
+    hostnames: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, String>>>,
+    config: sync::Arc<sync::Mutex<collections::HashMap<(net::IpAddr, String), String>>>,
+    processes: ProcessTable,
+    descriptors: DescriptorTable,
+    timeline: Timeline,
+    flows: FlowTrace,
+    time_limit: Option<Duration>,
+    memory_limit: Option<usize>,
+    watchdog: Option<(Duration, watchdog::OnStall)>,
+    memory: MemoryMeter,
+    profiler: Profiler,
+    /// Real time spent driving the runtime.
+    wall: Duration,
+    fault_plan: sync::Arc<sync::Mutex<Option<FaultPlan>>>,
 }
 
 impl DeterministicRuntime {
-    pub fn new() -> Result<Self, Error> {
-        DeterministicRuntime::new_with_seed(0)
-    }
-    pub fn new_with_seed(seed: u64) -> Result<Self, Error> {
-        let reactor = driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
-
-        let time = DeterministicTime::new_with_park(reactor);
-        let time_handle = time.handle();
-        let network = DeterministicNetwork::new(time_handle.clone());
-        let executor = tokio_executor::current_thread::CurrentThread::new_with_park(time);
-        let random = DeterministicRandom::new_with_seed(seed);
-        Ok(DeterministicRuntime {
-            executor,
-            time_handle,
-            network,
-            random,
-        })
+    /// Returns a builder for a runtime with options such as its seed and clock. Building it
+    /// with the default options gives a runtime seeded with `0`.
+    pub fn builder() -> DeterministicRuntimeBuilder {
+        DeterministicRuntimeBuilder::new()
     }
 
     pub fn handle(&self, addr: net::IpAddr) -> DeterministicRuntimeHandle {
-        DeterministicRuntimeHandle {
+        let shared = Shared {
             time_handle: self.time_handle.clone(),
             network_handle: self.network.scoped(addr),
+            fs_handle: self.fs.scoped(addr),
             executor_handle: self.executor.handle(),
             random_handle: self.random.handle(),
+            channel_handle: self.channels.handle(),
+            discovery_handle: self.discovery.handle(),
+            dns_handle: self.dns.scoped(addr),
+            event_bus_handle: self.event_bus.scoped(addr),
+            chaos_log_handle: self.chaos_log.handle(),
+            metrics_handle: self.metrics.scoped(addr),
+            phase: sync::Arc::clone(&self.phase),
+            hostnames: sync::Arc::clone(&self.hostnames),
+            config: sync::Arc::clone(&self.config),
+            processes: self.processes.clone(),
+            descriptors: self.descriptors.clone(),
+            fault_plan: sync::Arc::clone(&self.fault_plan),
+            memory: self.memory.clone(),
+            flows: self.flows.clone(),
+        };
+        DeterministicRuntimeHandle {
+            shared: sync::Arc::new(shared),
         }
     }
 
+    /// Record all simulated traffic on connections established from now on to `writer`, in the
+    /// pcap format. TCP/IP headers are synthesized for each write, and packets are timestamped
+    /// with the simulated time elapsed since the runtime was created, so the capture can be
+    /// inspected with Wireshark.
+    pub fn capture_packets<W>(&self, writer: W) -> io::Result<()>
+    where
+        W: io::Write + Send + 'static,
+    {
+        self.network
+            .capture_packets(self.time_handle.clone(), writer)
+    }
+
+    /// Start recording the timeline of this run, returning a handle which exports it as JSON.
+    pub fn record_timeline(&self) -> Timeline {
+        self.timeline.enable();
+        self.timeline.clone()
+    }
+
+    /// Start tracing flows begun with [`begin_flow`] from now on, returning a handle which
+    /// exports them as JSON.
+    ///
+    /// [`begin_flow`]:DeterministicRuntimeHandle::begin_flow
+    pub fn trace_flows(&self) -> FlowTrace {
+        self.flows.enable();
+        self.flows.clone()
+    }
+
+    /// Start recording the event log of this run, keeping the most recent `capacity` events in
+    /// memory, and returning a handle which retrieves them.
+    pub fn record_events(&self, capacity: usize) -> EventLog {
+        let events = self.timeline.events();
+        events.enable(capacity);
+        events.clone()
+    }
+
+    /// Start recording the event log of this run, appending every event to `writer` as a line
+    /// of JSON rather than keeping it in memory. Call [`EventLog::flush`] once the run is over.
+    ///
+    /// [`EventLog::flush`]:EventLog::flush
+    pub fn record_events_to<W>(&self, writer: W) -> EventLog
+    where
+        W: io::Write + Send + 'static,
+    {
+        let events = self.timeline.events();
+        events.enable_sink(Box::new(writer));
+        events.clone()
+    }
+
+    /// Returns the approximate memory held by the simulation, across every host.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory.usage()
+    }
+
+    /// Start attributing the real time spent driving the runtime to the network, timers,
+    /// tasks and executor, returning a profiler which reports the time spent in each.
+    pub fn profile(&self) -> Profiler {
+        self.profiler.enable();
+        self.profiler.clone()
+    }
+
+    /// Returns a summary of the run so far: the simulated and real time it took, and the work
+    /// done by tasks, timers, connections, fault injectors and assertions during it.
+    pub fn summary(&self) -> Summary {
+        Summary::new(
+            self.seed,
+            self.time_handle.elapsed(),
+            self.wall,
+            self.timeline.events().tally(),
+            self.network.traffic(),
+            self.chaos_log.handle().actions().len() as u64,
+        )
+    }
+
+    /// Returns a hash of how the run has executed so far: the work counted by [`summary`], other
+    /// than the real time it took, the polls made and the most recent advances of the clock.
+    /// Two runs with the same seed have the same fingerprint unless something outside of the
+    /// simulation, such as the real clock or a thread of its own, changed how they executed.
+    /// The hash is the same on every platform, so fingerprints can be compared between
+    /// machines replaying a seed.
+    ///
+    /// [`summary`]:DeterministicRuntime::summary
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FingerprintHasher::default();
+        let summary = Summary {
+            wall: Duration::default(),
+            ..self.summary()
+        };
+        summary.hash(&mut hasher);
+        self.timeline.events().tally().polls().hash(&mut hasher);
+        self.time_handle.history().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the events kept in memory as an OTLP/JSON trace export request, with simulated
+    /// timestamps and a resource for each host, which an OpenTelemetry collector accepts on
+    /// `/v1/traces`. Record events with a capacity large enough to keep the whole run.
+    pub fn to_otlp_json(&self) -> String {
+        let hostnames = self.hostnames.lock().unwrap();
+        otlp::otlp_json(self.seed, &hostnames, &self.timeline.events().events())
+    }
+
+    /// Write the events kept in memory to `writer` as an OTLP/JSON trace export request.
+    pub fn write_otlp_json<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all(self.to_otlp_json().as_bytes())
+    }
+
     pub fn latency_fault(&self) -> network::fault::LatencyFaultInjector {
         let network_inner = self.network.clone_inner();
         network::fault::LatencyFaultInjector::new(
             network_inner,
             self.random.handle(),
             self.time_handle.clone(),
+            self.chaos_log.handle(),
         )
     }
 
@@ -139,104 +924,627 @@ impl DeterministicRuntime {
         self
     }
 
+    /// Run every spawned task to completion.
+    ///
+    /// Returns [`Error::TimeOverflow`] if simulated time overflowed, as described in the
+    /// [`time`] module.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`time`]:time
     pub fn run(&mut self) -> Result<(), Error> {
-        self.enter(|executor| executor.run())
-            .map_err(|source| Error::CurrentThreadRun { source })
+        let time_handle = self.time_handle.clone();
+        self.with_executor(|executor| loop {
+            if let Some(error) = time_handle.take_overflow() {
+                return Err(error);
+            }
+            if executor.is_idle() {
+                return Ok(());
+            }
+            executor.turn(None).expect("run park failed");
+        })
     }
 
-    pub fn block_on<F>(&mut self, f: F) -> F::Output
+    /// Run `f` to completion, driving any spawned tasks. `f` runs in the context of
+    /// localhost.
+    ///
+    /// Returns [`Error::TimeOverflow`] rather than the output of `f` if simulated time
+    /// overflowed, as described in the [`time`] module.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the runtime was built with a time limit, and `f` has not completed by then,
+    /// or with a memory limit, and the memory held by the simulation exceeds it.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`time`]:time
+    pub fn block_on<F>(&mut self, f: F) -> Result<F::Output, Error>
     where
         F: Future,
     {
-        self.enter(|executor| executor.block_on(f))
+        let mut f = Box::pin(Scoped::new(self.localhost_handle(), f));
+        let memory = self.memory.clone();
+        let memory_limit = self.memory_limit;
+        let events = self.timeline.events().clone();
+        let time_handle = self.time_handle.clone();
+        let mut root_flow = None;
+        // the executor polls `f` on every turn, so the limits are checked as often.
+        let f = future::poll_fn(move |cx| {
+            if let Some(error) = time_handle.take_overflow() {
+                return Poll::Ready(Err(error));
+            }
+            events.tally().polled();
+            let _flow = flow::enter(&mut root_flow);
+            let armed = escape::arm();
+            let poll = f.as_mut().poll(cx);
+            drop(armed);
+            escape::check(|| "the future passed to block_on".to_string());
+            if let Poll::Ready(output) = poll {
+                return Poll::Ready(Ok(output));
+            }
+            if let Some(limit) = memory_limit {
+                let usage = memory.usage();
+                if usage.total() > limit {
+                    panic!(
+                        "simulated memory limit of {} bytes exceeded: {}",
+                        limit, usage
+                    );
+                }
+            }
+            Poll::Pending
+        });
+        let limit = match self.time_limit {
+            Some(limit) => limit,
+            None => return self.with_executor(|executor| executor.block_on(f)),
+        };
+        let start = self.time_handle.now() - self.time_handle.elapsed();
+        let deadline = self.time_handle.delay(start + limit);
+        let limited = future::select(f, deadline);
+        match self.with_executor(|executor| executor.block_on(limited)) {
+            future::Either::Left((output, _)) => output,
+            future::Either::Right(_) => panic!(
+                "simulated time limit of {:?} exceeded{}",
+                limit,
+                dump::clock_history(&self.time_handle.history())
+            ),
+        }
+    }
+
+    /// Run `f` in the context of the host `addr`, so that the free functions in the crate
+    /// root, such as [`spawn`] and [`delay_for`], act on behalf of that host. Useful for
+    /// constructing library types which spawn tasks but are not passed an [`Environment`].
+    ///
+    /// [`spawn`]:crate::spawn
+    /// [`delay_for`]:crate::delay_for
+    /// [`Environment`]:crate::Environment
+    pub fn enter<F, R>(&self, addr: net::IpAddr, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let _dump = dump::enter(self.sim_context());
+        context::enter(self.handle(addr), f)
+    }
+
+    /// Returns the seed the runtime was built with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns what a panic while the runtime is running describes about it.
+    fn sim_context(&self) -> SimContext {
+        SimContext {
+            seed: self.seed,
+            time: self.time_handle.reader(),
+            events: self.timeline.events().clone(),
+        }
     }
 
-    fn enter<F, R>(&mut self, f: F) -> R
+    fn with_executor<F, R>(&mut self, f: F) -> R
     where
         F: FnOnce(&mut Executor) -> R,
     {
+        let _dump = dump::enter(self.sim_context());
+        let _running = escape::enter();
+        let _watchdog = self.watchdog.clone().map(|(limit, on_stall)| {
+            Watchdog::start(limit, self.sim_context(), self.localhost_handle(), on_stall)
+        });
         let DeterministicRuntime {
             ref mut time_handle,
             ref mut executor,
+            ref profiler,
+            ref mut wall,
             ..
         } = *self;
+        let _profiling = profiler.install();
+        let started = Instant::now();
         // Setup mock clock globals
         let clock = tokio_timer::clock::Clock::new_with_now(time_handle.clone_now());
         let timer_handle = time_handle.clone_timer_handle();
         let _guard = tokio_timer::timer::set_default(&timer_handle);
-        tokio_timer::clock::with_default(&clock, || {
+        let output = tokio_timer::clock::with_default(&clock, || {
             let mut default_executor = tokio_executor::current_thread::TaskExecutor::current();
             tokio_executor::with_default(&mut default_executor, || f(executor))
-        })
+        });
+        *wall += started.elapsed();
+        output
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Environment;
+    use crate::{HostEnv, NetEnv, SpawnEnv, TcpListener, TimeEnv};
 
     #[test]
     /// Test that delays accurately advance the clock.
     fn delays() {
-        let mut runtime = DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let start_time = handle.now();
-            handle.delay_from(Duration::from_secs(30)).await;
-            let end_time = handle.now();
-            assert!(end_time > start_time);
-            assert_eq!(end_time - Duration::from_secs(30), start_time)
-        });
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let start_time = handle.now();
+                handle.delay_from(Duration::from_secs(30)).await;
+                let end_time = handle.now();
+                assert!(end_time > start_time);
+                assert_eq!(end_time - Duration::from_secs(30), start_time)
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that setting a delay past the furthest the timer reaches fails where it is set,
+    /// naming the operation, rather than once the delay is polled.
+    fn delay_overflow() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                handle.delay_from(MAX_DELAY).await;
+            })
+            .unwrap();
+        assert_eq!(handle.elapsed(), MAX_DELAY);
+        for duration in &[
+            MAX_DELAY + Duration::from_millis(1),
+            Duration::from_secs(u64::MAX),
+        ] {
+            let error = handle.try_delay_from(*duration).unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("Simulated time overflowed while setting a delay of 127.0.0.1"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    /// Test that a delay until a deadline past the furthest the timer reaches fails where it
+    /// is set, and that when it is set through `TimeEnv` the runtime returns the overflow
+    /// rather than advancing time.
+    fn delay_deadline_overflow() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let deadline = handle.now() + MAX_DELAY + Duration::from_millis(1);
+        assert!(handle.try_delay(deadline).is_err());
+        let error = runtime
+            .block_on(async {
+                handle.delay(deadline).await;
+            })
+            .unwrap_err();
+        match error {
+            Error::TimeOverflow { operation } => {
+                assert!(operation.starts_with("setting a delay of 127.0.0.1"))
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(handle.elapsed(), Duration::from_secs(0));
+        // the overflow is returned once, after which the runtime runs as before.
+        runtime
+            .block_on(handle.delay_from(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(handle.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    /// Test that a timeout longer than the furthest the timer reaches fails where it is set,
+    /// and otherwise is returned by the runtime.
+    fn timeout_overflow() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let time_handle = runtime.localhost_handle().time_handle();
+        let timeout = MAX_DELAY + Duration::from_millis(1);
+        let error = time_handle
+            .try_timeout(futures::future::pending::<()>(), timeout)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Simulated time overflowed while setting a timeout"));
+        let result =
+            runtime.block_on(time_handle.timeout(futures::future::pending::<()>(), timeout));
+        assert!(matches!(result, Err(Error::TimeOverflow { .. })));
     }
 
     #[test]
     /// Test that waiting on delays across spawned tasks results in the clock
     /// being advanced in accordance with the length of the delay.
     fn ordering() {
-        let mut runtime = DeterministicRuntime::new().unwrap();
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let delay1 = handle.delay_from(Duration::from_secs(10));
+                let delay2 = handle.delay_from(Duration::from_secs(30));
+
+                let handle1 = handle.clone();
+                let completed_at1 = crate::spawn_with_result(&handle1.clone(), async move {
+                    delay1.await;
+                    handle1.now()
+                })
+                .await;
+
+                let handle2 = handle.clone();
+                let completed_at2 = crate::spawn_with_result(&handle2.clone(), async move {
+                    delay2.await;
+                    handle2.now()
+                })
+                .await;
+                assert!(completed_at1 < completed_at2)
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a large number of outstanding delays each complete at their deadline, in
+    /// deadline order.
+    fn many_timers() {
+        use futures::{stream::FuturesUnordered, StreamExt};
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let start = handle.now();
+                let random = handle.random_handle();
+                let mut delays: FuturesUnordered<_> = (0..50_000)
+                    .map(|_| {
+                        let deadline =
+                            start + Duration::from_millis(random.gen_range(0..86_400_000));
+                        let delay = handle.delay(deadline);
+                        let handle = handle.clone();
+                        async move {
+                            delay.await;
+                            assert_eq!(handle.now(), deadline);
+                            deadline
+                        }
+                    })
+                    .collect();
+                let timers = || handle.open_resources(handle.local_addr()).timers.len();
+                assert_eq!(timers(), 50_000);
+                let mut last = start;
+                while let Some(deadline) = delays.next().await {
+                    assert!(deadline >= last);
+                    last = deadline;
+                }
+                assert_eq!(timers(), 0);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that delays sharing a deadline wake their tasks in the order the delays were
+    /// started, whatever order the tasks waiting on them were spawned in.
+    fn identical_deadlines() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let deadline = handle.now() + Duration::from_secs(1);
+                let mut delays: Vec<_> = (0..8).map(|i| (i, handle.delay(deadline))).collect();
+                delays.push((8, handle.delay(deadline - Duration::from_millis(1))));
+                let woken = sync::Arc::new(sync::Mutex::new(vec![]));
+                let tasks: Vec<_> = delays
+                    .into_iter()
+                    .rev()
+                    .map(|(i, delay)| {
+                        let woken = sync::Arc::clone(&woken);
+                        crate::spawn_with_result(&handle, async move {
+                            delay.await;
+                            woken.lock().unwrap().push(i);
+                        })
+                    })
+                    .collect();
+                futures::future::join_all(tasks).await;
+                assert_eq!(*woken.lock().unwrap(), vec![8, 0, 1, 2, 3, 4, 5, 6, 7]);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a handle is a single pointer to state shared by its clones, and that scoped
+    /// handles still act on behalf of their own host.
+    fn handle_clone() {
+        let (_runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        assert_eq!(
+            std::mem::size_of::<DeterministicRuntimeHandle>(),
+            std::mem::size_of::<usize>()
+        );
+        let clone = handle.clone();
+        assert!(sync::Arc::ptr_eq(&handle.shared, &clone.shared));
+        let scoped = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        assert!(!sync::Arc::ptr_eq(&handle.shared, &scoped.shared));
+        assert_eq!(scoped.local_addr(), net::Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(clone.local_addr(), handle.local_addr());
+    }
+
+    #[test]
+    /// Test that time jumps straight to the next timer when every task is idle, so that
+    /// simulating a month of idle time takes only a single advance, and a fraction of real time.
+    fn idle_fast_forward() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let timeline = runtime.record_timeline();
         let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let delay1 = handle.delay_from(Duration::from_secs(10));
-            let delay2 = handle.delay_from(Duration::from_secs(30));
-
-            let handle1 = handle.clone();
-            let completed_at1 = crate::spawn_with_result(&handle1.clone(), async move {
-                delay1.await;
-                handle1.now()
+        let idle = Duration::from_secs(30 * 24 * 60 * 60);
+        let started = std::time::Instant::now();
+        runtime
+            .block_on(async {
+                let start = handle.now();
+                handle.delay_from(idle).await;
+                assert_eq!(handle.now(), start + idle);
             })
-            .await;
+            .unwrap();
+        // generous, so that a loaded machine or a debug build doesn't fail it.
+        assert!(started.elapsed() < Duration::from_millis(100));
+        let advances: Vec<_> = timeline
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.kind == TimelineKind::TimeAdvanced)
+            .map(|entry| entry.at)
+            .collect();
+        assert_eq!(advances, vec![idle]);
+    }
 
-            let handle2 = handle.clone();
-            let completed_at2 = crate::spawn_with_result(&handle2.clone(), async move {
-                delay2.await;
-                handle2.now()
+    #[test]
+    /// Test that time is not advanced to the next timer while a task spawned through a handle
+    /// is waiting to run.
+    fn spawn_before_timers() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let start = handle.now();
+                let task = crate::spawn_with_result(&handle, async { 1 });
+                let result = handle.timeout(task, Duration::from_secs(5)).await;
+                assert_eq!(result.unwrap(), 1);
+                assert_eq!(handle.now(), start);
             })
-            .await;
-            assert!(completed_at1 < completed_at2)
-        });
+            .unwrap();
     }
 
     #[test]
     /// Test that the Tokio global timer and clock are both set correctly.
     fn globals() {
-        let mut runtime = DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let start_time = tokio_timer::clock::now();
-            assert_eq!(
-                handle.now(),
-                tokio_timer::clock::now(),
-                "expected start time to be equal"
-            );
-            let delay_duration = Duration::from_secs(1);
-            let delay = tokio::timer::delay_for(delay_duration);
-            delay.await;
-            assert_eq!(
-                start_time + delay_duration,
-                tokio_timer::clock::now(),
-                "expected elapsed time to be equal"
-            );
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let start_time = tokio_timer::clock::now();
+                assert_eq!(
+                    handle.now(),
+                    tokio_timer::clock::now(),
+                    "expected start time to be equal"
+                );
+                let delay_duration = Duration::from_secs(1);
+                let delay = tokio::timer::delay_for(delay_duration);
+                delay.await;
+                assert_eq!(
+                    start_time + delay_duration,
+                    tokio_timer::clock::now(),
+                    "expected elapsed time to be equal"
+                );
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a custom clock decides how far time advances.
+    fn tick_clock() {
+        let clock = TickClock::new(Duration::from_millis(100));
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .clock(clock)
+            .build()
+            .unwrap();
+        let elapsed = runtime
+            .block_on(async {
+                handle.delay_from(Duration::from_millis(150)).await;
+                handle.elapsed()
+            })
+            .unwrap();
+        assert_eq!(elapsed, Duration::from_millis(200));
+    }
+
+    #[test]
+    /// Test that the wall clock starts at the same time in every run, and advances with
+    /// simulated time.
+    fn system_time() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let start = handle.now_system_time();
+        let (_, other) = DeterministicRuntime::builder().build().unwrap();
+        assert_eq!(start, other.now_system_time());
+        runtime
+            .block_on(async {
+                handle.delay_from(Duration::from_secs(60)).await;
+            })
+            .unwrap();
+        let elapsed = handle.now_system_time().duration_since(start).unwrap();
+        assert_eq!(elapsed, Duration::from_secs(60));
+    }
+
+    #[test]
+    /// Test that exiting stops only the calling host, cancelling its tasks and releasing its
+    /// listeners, and records the exit code.
+    fn exit() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let addr = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+        let server = runtime.handle(addr);
+        let client = runtime.localhost_handle();
+        runtime
+            .block_on(async {
+                let process = server.clone();
+                server.spawn(async move {
+                    let _listener = process.bind((addr, 9092)).await.unwrap();
+                    process.spawn(futures::future::pending());
+                    process.delay_from(Duration::from_secs(1)).await;
+                    process.exit(3).await;
+                    unreachable!("exit returned");
+                });
+                client.delay_from(Duration::from_millis(10)).await;
+                assert_eq!(client.task_count(addr), 2);
+                assert!(client.connect((addr, 9092)).await.is_ok());
+                client.delay_from(Duration::from_secs(2)).await;
+            })
+            .unwrap();
+        assert_eq!(client.task_count(addr), 0);
+        assert_eq!(client.exit_code(addr), Some(3));
+        assert_eq!(client.exit_code(client.local_addr()), None);
+    }
+
+    #[test]
+    /// Test that under `PanicPolicy::CrashHost` a panicking task only crashes its own host,
+    /// which its peers observe as the host being killed, while the simulation carries on.
+    fn crash_host_on_panic() {
+        let (mut runtime, client) = DeterministicRuntime::builder()
+            .panic_policy(PanicPolicy::CrashHost)
+            .build()
+            .unwrap();
+        let addr = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+        let server = runtime.handle(addr);
+        runtime
+            .block_on(async {
+                let mut listener = server.bind((addr, 9092)).await.unwrap();
+                server.spawn(futures::future::pending());
+                server.spawn(async move {
+                    let _socket = listener.accept().await.unwrap();
+                    panic!("bug in the server");
+                });
+                let mut socket = client.connect((addr, 9092)).await.unwrap();
+                let mut buf = [0; 1];
+                assert!(tokio::io::AsyncReadExt::read(&mut socket, &mut buf)
+                    .await
+                    .is_err());
+                let err = client.connect((addr, 9092)).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            })
+            .unwrap();
+        assert_eq!(client.task_count(addr), 0);
+        assert_eq!(
+            client.panic_message(addr).as_deref(),
+            Some("bug in the server")
+        );
+        assert_eq!(client.panic_message(client.local_addr()), None);
+        let kills: Vec<_> = client
+            .chaos_log_handle()
+            .actions()
+            .into_iter()
+            .map(|action| (action.kind, action.target))
+            .collect();
+        assert_eq!(kills, vec![(ChaosKind::Kill, ChaosTarget::Host(addr))]);
+    }
+
+    #[test]
+    /// Test that each host is identified by its address until it is named.
+    fn host_identity() {
+        let (runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let addr = net::Ipv4Addr::new(10, 0, 0, 1);
+        let handle = runtime.handle(addr.into());
+        assert_eq!(handle.hostname(), "10.0.0.1");
+        assert_eq!(handle.host_id(), u64::from(u32::from(addr)));
+        assert_eq!(handle.local_addrs(), vec![net::IpAddr::from(addr)]);
+        handle.set_hostname(addr.into(), "kafka-0");
+        assert_eq!(runtime.handle(addr.into()).hostname(), "kafka-0");
+        assert_eq!(runtime.localhost_handle().hostname(), "127.0.0.1");
+    }
+
+    #[test]
+    /// Test that configuration is set per host, and can be changed while the simulation runs.
+    fn host_config() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let addr = net::IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, 1));
+        let handle = runtime.handle(addr);
+        handle.set_config(addr, "LOG_LEVEL", "info");
+        let levels = runtime
+            .block_on(async {
+                let mut levels = vec![handle.config("LOG_LEVEL")];
+                let harness = handle.clone();
+                handle.spawn(async move {
+                    harness.delay_from(Duration::from_secs(1)).await;
+                    harness.set_config(addr, "LOG_LEVEL", "debug");
+                });
+                handle.delay_from(Duration::from_secs(2)).await;
+                levels.push(handle.config("LOG_LEVEL"));
+                handle.remove_config(addr, "LOG_LEVEL");
+                levels.push(handle.config("LOG_LEVEL"));
+                levels
+            })
+            .unwrap();
+        let expected = vec![Some("info".to_string()), Some("debug".to_string()), None];
+        assert_eq!(levels, expected);
+        assert_eq!(runtime.localhost_handle().config("PATH"), None);
+    }
+
+    #[test]
+    /// Test that a thread outside of the runtime can read simulated time while it advances.
+    fn time_reader_thread() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let reader = handle.time_reader();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let logger = std::thread::spawn(move || {
+            let mut readings = vec![reader.elapsed()];
+            while done_rx.try_recv().is_err() {
+                readings.push(reader.elapsed());
+                std::thread::yield_now();
+            }
+            readings.push(reader.elapsed());
+            readings
         });
+        runtime
+            .block_on(async {
+                for _ in 0..10 {
+                    handle.delay_from(Duration::from_secs(1)).await;
+                }
+            })
+            .unwrap();
+        done_tx.send(()).unwrap();
+        let readings = logger.join().unwrap();
+        assert!(readings.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(readings.last(), Some(&Duration::from_secs(10)));
+        assert_eq!(handle.time_reader().now(), handle.now());
+    }
+
+    #[test]
+    /// Test that each advance of time is kept with the timer it waited for, and that exceeding
+    /// the time limit reports the most recent advances.
+    fn clock_history() {
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .time_limit(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        runtime
+            .block_on(handle.delay_from(Duration::from_secs(1)))
+            .unwrap();
+        let stuck = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            runtime.block_on(futures::future::pending::<()>()).unwrap()
+        }));
+        let history = vec![
+            ClockAdvance {
+                from: Duration::from_secs(0),
+                to: Duration::from_secs(1),
+                cause: AdvanceCause::Idle {
+                    timer: Some((handle.local_addr(), 0)),
+                },
+            },
+            ClockAdvance {
+                from: Duration::from_secs(1),
+                to: Duration::from_secs(10),
+                cause: AdvanceCause::Idle { timer: None },
+            },
+        ];
+        assert_eq!(handle.clock_history(), history);
+        let message = stuck.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(
+            *message,
+            "simulated time limit of 10s exceeded\nlast 2 clock advances:\
+             \n  0ns -> 1s, idle until timer 0 of 127.0.0.1\
+             \n  1s -> 10s, idle until a timer outside of any host"
+        );
     }
 }
@@ -0,0 +1,176 @@
+//! Live terminal view of a campaign.
+//!
+//! Redraws the [`CampaignStatus`] of a running campaign in place on standard error, so an
+//! overnight chaos run shows how many seeds have completed, which have failed, and what the
+//! run in progress is doing, without scrolling the terminal. Only plain ANSI escape codes are
+//! used, so it works in any terminal and degrades to a series of frames when redirected.
+//!
+//! [`CampaignStatus`]:crate::deterministic::CampaignStatus
+use super::{CampaignProgress, CampaignStatus};
+use std::{
+    io::{self, Write},
+    sync::{self, atomic},
+    thread, time,
+};
+
+/// Width of the progress bar, in characters.
+const BAR: usize = 30;
+/// Number of most recent failures listed.
+const FAILURES: usize = 5;
+
+/// Returns the lines of a frame showing `status`.
+pub(crate) fn render(status: &CampaignStatus) -> Vec<String> {
+    let done = if status.total > 0 {
+        status.completed as f64 / status.total as f64
+    } else {
+        1.0
+    };
+    let filled = (done * BAR as f64) as usize;
+    let mut lines = vec![format!(
+        "seeds     {}/{} [{}{}] {:.0}%  {:.1?} elapsed",
+        status.completed,
+        status.total,
+        "#".repeat(filled),
+        ".".repeat(BAR - filled),
+        done * 100.0,
+        status.elapsed
+    )];
+    lines.push(match (status.seed, status.simulated) {
+        (Some(seed), Some(simulated)) => {
+            format!(
+                "running   seed {} at {:?} of simulated time",
+                seed, simulated
+            )
+        }
+        _ => "running   -".to_string(),
+    });
+    lines.push(if status.active_faults.is_empty() {
+        "faults    none".to_string()
+    } else {
+        format!("faults    {}", status.active_faults.join(", "))
+    });
+    lines.push(format!("failures  {}", status.failures.len()));
+    let skip = status.failures.len().saturating_sub(FAILURES);
+    for failure in &status.failures[skip..] {
+        let message = failure.message.lines().next().unwrap_or_default();
+        let flag = if failure.nondeterministic {
+            " (nondeterministic)"
+        } else {
+            ""
+        };
+        lines.push(format!("  seed {}{}: {}", failure.seed, flag, message));
+    }
+    lines
+}
+
+/// Draws the progress of a campaign until dropped.
+#[derive(Debug)]
+pub(crate) struct Monitor {
+    stop: sync::Arc<atomic::AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Monitor {
+    /// Draw `progress` to standard error every `refresh`, on a thread of its own.
+    pub(crate) fn start(progress: CampaignProgress, refresh: time::Duration) -> Self {
+        let stop = sync::Arc::new(atomic::AtomicBool::new(false));
+        let stopped = sync::Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut drawn = 0;
+            loop {
+                // draw a final frame once stopped, so the outcome stays on screen.
+                let last = stopped.load(atomic::Ordering::SeqCst);
+                drawn = draw(&mut io::stderr(), &render(&progress.status()), drawn);
+                if last {
+                    break;
+                }
+                thread::park_timeout(refresh);
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop.store(true, atomic::Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Draw `lines` over the `drawn` lines of the previous frame, returning the number of lines
+/// drawn.
+fn draw<W: Write>(writer: &mut W, lines: &[String], drawn: usize) -> usize {
+    let mut frame = String::new();
+    if drawn > 0 {
+        frame.push_str(&format!("\x1b[{}A", drawn));
+    }
+    for line in lines {
+        frame.push_str("\r\x1b[2K");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    // clear lines left over from a longer previous frame.
+    frame.push_str("\x1b[J");
+    let _ = writer.write_all(frame.as_bytes());
+    let _ = writer.flush();
+    lines.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::CampaignFailure;
+
+    #[test]
+    /// Test that a frame shows the seeds completed, the run in progress, its faults and the
+    /// most recent failures, and is drawn over the previous frame.
+    fn frame() {
+        let status = CampaignStatus {
+            total: 10,
+            completed: 5,
+            failures: (0..7)
+                .map(|seed| CampaignFailure {
+                    seed,
+                    message: format!("failed\nat line {}", seed),
+                    nondeterministic: seed == 4,
+                })
+                .collect(),
+            seed: Some(5),
+            simulated: Some(time::Duration::from_secs(3)),
+            active_faults: vec!["10.0.0.1 killed".to_string()],
+            elapsed: time::Duration::from_secs(1),
+        };
+        let lines = render(&status);
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "seeds     5/10 [{}{}] 50%  1.0s elapsed",
+                    "#".repeat(15),
+                    ".".repeat(15)
+                ),
+                "running   seed 5 at 3s of simulated time".to_string(),
+                "faults    10.0.0.1 killed".to_string(),
+                "failures  7".to_string(),
+                "  seed 2: failed".to_string(),
+                "  seed 3: failed".to_string(),
+                "  seed 4 (nondeterministic): failed".to_string(),
+                "  seed 5: failed".to_string(),
+                "  seed 6: failed".to_string(),
+            ]
+        );
+
+        let mut out = vec![];
+        assert_eq!(draw(&mut out, &lines[..2], 9), 2);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x1b[9A\r\x1b[2Kseeds"));
+        assert!(out.ends_with("simulated time\n\x1b[J"));
+    }
+}
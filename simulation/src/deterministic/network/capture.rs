@@ -0,0 +1,272 @@
+//! Recording of simulated traffic in the pcap format.
+//!
+//! The simulated network carries bytes rather than packets, so TCP/IP headers are synthesized
+//! for each write: a three way handshake when a connection is established, a segment for each
+//! write and a FIN when a half is shut down. Sequence and acknowledgement numbers track the
+//! bytes written in each direction, so Wireshark can reassemble streams. Packets are
+//! timestamped with the simulated time elapsed since the runtime was created.
+use crate::deterministic::{escape, DeterministicTimeHandle};
+use std::{collections, fmt, io, net, sync};
+
+/// Link type for packets which begin with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+/// The largest payload which fits in a single IP packet alongside the synthesized headers.
+const MAX_SEGMENT: usize = 65_495;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+struct Inner {
+    time: DeterministicTimeHandle,
+    writer: Box<dyn io::Write + Send>,
+    /// The next sequence number for each direction of each connection.
+    sequences: collections::BTreeMap<(net::SocketAddr, net::SocketAddr), u32>,
+}
+
+/// Writes synthesized packets for simulated traffic to a pcap file.
+#[derive(Clone)]
+pub(crate) struct PacketCapture {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl fmt::Debug for PacketCapture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PacketCapture")
+    }
+}
+
+impl PacketCapture {
+    /// Write the pcap file header to `writer`, returning a capture which appends packets to it.
+    pub(crate) fn new<W>(time: DeterministicTimeHandle, mut writer: W) -> io::Result<Self>
+    where
+        W: io::Write + Send + 'static,
+    {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&65_535u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+        let inner = Inner {
+            time,
+            writer: Box::new(writer),
+            sequences: collections::BTreeMap::new(),
+        };
+        Ok(Self {
+            inner: sync::Arc::new(sync::Mutex::new(inner)),
+        })
+    }
+
+    /// Record the handshake of a new connection from `client` to `server`.
+    pub(crate) fn connect(&self, client: net::SocketAddr, server: net::SocketAddr) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.sequences.insert((client, server), 0);
+        lock.sequences.insert((server, client), 0);
+        lock.segment(client, server, SYN, 1, &[]);
+        lock.segment(server, client, SYN | ACK, 1, &[]);
+        lock.segment(client, server, ACK, 0, &[]);
+    }
+
+    /// Record `data` written from `source` to `dest`.
+    pub(crate) fn data(&self, source: net::SocketAddr, dest: net::SocketAddr, data: &[u8]) {
+        let mut lock = self.inner.lock().unwrap();
+        for chunk in data.chunks(MAX_SEGMENT) {
+            lock.segment(source, dest, PSH | ACK, chunk.len() as u32, chunk);
+        }
+    }
+
+    /// Record that `source` shut down its half of the connection to `dest`.
+    pub(crate) fn shutdown(&self, source: net::SocketAddr, dest: net::SocketAddr) {
+        self.inner
+            .lock()
+            .unwrap()
+            .segment(source, dest, FIN | ACK, 1, &[]);
+    }
+
+    /// Record that `source` reset its connection to `dest`.
+    pub(crate) fn reset(&self, source: net::SocketAddr, dest: net::SocketAddr) {
+        self.inner
+            .lock()
+            .unwrap()
+            .segment(source, dest, RST | ACK, 0, &[]);
+    }
+}
+
+impl Inner {
+    /// Write a TCP segment carrying `payload`, advancing the sequence number of the direction
+    /// it was sent in by `advance`.
+    fn segment(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        flags: u8,
+        advance: u32,
+        payload: &[u8],
+    ) {
+        let seq = self.sequences.entry((source, dest)).or_insert(0);
+        let sent = *seq;
+        *seq = seq.wrapping_add(advance);
+        let ack = if flags & ACK == 0 {
+            0
+        } else {
+            self.sequences.get(&(dest, source)).cloned().unwrap_or(0)
+        };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&source.port().to_be_bytes());
+        tcp.extend_from_slice(&dest.port().to_be_bytes());
+        tcp.extend_from_slice(&sent.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push(5 << 4);
+        tcp.push(flags);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        // the checksum is left empty, Wireshark does not validate it by default.
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let packet = match (source.ip(), dest.ip()) {
+            (net::IpAddr::V4(source), net::IpAddr::V4(dest)) => ipv4(source, dest, tcp),
+            (source, dest) => ipv6(to_ipv6(source), to_ipv6(dest), tcp),
+        };
+
+        let elapsed = self.time.elapsed();
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(elapsed.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&elapsed.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+        // capturing is best effort, a failing writer should not fail the simulation.
+        let writer = &mut self.writer;
+        let _ = escape::unguarded(|| writer.write_all(&record).and_then(|_| writer.flush()));
+    }
+}
+
+fn to_ipv6(addr: net::IpAddr) -> net::Ipv6Addr {
+    match addr {
+        net::IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        net::IpAddr::V6(addr) => addr,
+    }
+}
+
+fn ipv4(source: net::Ipv4Addr, dest: net::Ipv4Addr, tcp: Vec<u8>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(20 + tcp.len());
+    packet.push(0x45);
+    packet.push(0);
+    packet.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+    // identification, flags and fragment offset.
+    packet.extend_from_slice(&[0, 0, 0x40, 0]);
+    packet.push(64);
+    packet.push(6);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&dest.octets());
+    let checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn ipv6(source: net::Ipv6Addr, dest: net::Ipv6Addr, tcp: Vec<u8>) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+    packet.push(6);
+    packet.push(64);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&dest.octets());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+/// The internet checksum of `header`.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0)))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, TimeEnv};
+    use std::{io, net, sync, time::Duration};
+    use tokio::io::AsyncWriteExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            io::Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Split a pcap file into the timestamp and contents of each packet.
+    fn packets(mut pcap: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        pcap = &pcap[24..];
+        let mut packets = vec![];
+        while !pcap.is_empty() {
+            let mut word = [0; 4];
+            word.copy_from_slice(&pcap[0..4]);
+            let seconds = u32::from_le_bytes(word);
+            word.copy_from_slice(&pcap[8..12]);
+            let len = u32::from_le_bytes(word) as usize;
+            packets.push((seconds, pcap[16..16 + len].to_vec()));
+            pcap = &pcap[16 + len..];
+        }
+        packets
+    }
+
+    #[test]
+    /// Test that connections are captured as a handshake followed by a segment per write, with
+    /// sequence numbers tracking the bytes written and simulated timestamps.
+    fn capture_connection() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let buffer = Buffer::default();
+        runtime.capture_packets(buffer.clone()).unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let _listener = server.bind(addr).await.unwrap();
+                client.delay_from(Duration::from_secs(5)).await;
+                let mut socket = client.connect(addr).await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+                socket.write_all(b"world").await.unwrap();
+                socket.shutdown().await.unwrap();
+            })
+            .unwrap();
+
+        let pcap = buffer.0.lock().unwrap().clone();
+        assert_eq!(&pcap[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        let packets = packets(&pcap);
+        let flags: Vec<_> = packets.iter().map(|(_, packet)| packet[33]).collect();
+        // the listener is dropped without accepting the connection, leaving the writes unread,
+        // so the server resets it.
+        assert_eq!(flags, vec![0x02, 0x12, 0x10, 0x18, 0x18, 0x11, 0x14]);
+        assert!(packets.iter().all(|(seconds, _)| *seconds == 5));
+        let (_, second_write) = &packets[4];
+        assert_eq!(&second_write[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&second_write[16..20], &[10, 0, 0, 1]);
+        // the second write follows the SYN and the five bytes of the first write.
+        assert_eq!(&second_write[24..28], &6u32.to_be_bytes());
+        assert_eq!(&second_write[40..], b"world");
+    }
+}
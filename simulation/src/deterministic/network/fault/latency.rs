@@ -1,6 +1,9 @@
 //! Fault injector which periodically adjusts socket latency.
 use super::Inner;
-use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use crate::deterministic::{
+    ChaosKind, ChaosTarget, DeterministicChaosLogHandle, DeterministicRandomHandle,
+    DeterministicTimeHandle,
+};
 use std::{ops, sync, time};
 
 pub struct LatencyFaultInjectorConfig {
@@ -12,6 +15,7 @@ pub struct LatencyFaultInjector {
     inner: sync::Arc<sync::Mutex<Inner>>,
     random_handle: DeterministicRandomHandle,
     time_handle: DeterministicTimeHandle,
+    chaos_log_handle: DeterministicChaosLogHandle,
     config: LatencyFaultInjectorConfig,
 }
 
@@ -20,12 +24,14 @@ impl LatencyFaultInjector {
         inner: sync::Arc<sync::Mutex<Inner>>,
         random_handle: DeterministicRandomHandle,
         time_handle: DeterministicTimeHandle,
+        chaos_log_handle: DeterministicChaosLogHandle,
         config: LatencyFaultInjectorConfig,
     ) -> Self {
         Self {
             inner,
             random_handle,
             time_handle,
+            chaos_log_handle,
             config,
         }
     }
@@ -34,11 +40,13 @@ impl LatencyFaultInjector {
         inner: sync::Arc<sync::Mutex<Inner>>,
         random_handle: DeterministicRandomHandle,
         time_handle: DeterministicTimeHandle,
+        chaos_log_handle: DeterministicChaosLogHandle,
     ) -> Self {
         Self {
             inner,
             random_handle,
             time_handle,
+            chaos_log_handle,
             config: LatencyFaultInjectorConfig {
                 client_latency_range: time::Duration::from_secs(0)..time::Duration::from_secs(100),
                 server_latency_range: time::Duration::from_secs(0)..time::Duration::from_secs(100),
@@ -73,6 +81,8 @@ impl LatencyFaultInjector {
 
     /// Iterate through all connections, setting a random latency value for both server and client send/receive calls.
     fn inject_latency(&self) {
+        self.chaos_log_handle
+            .record(ChaosKind::Latency, ChaosTarget::Network);
         let mut lock = self.inner.lock().unwrap();
         for connection in lock.connections.iter_mut() {
             connection
@@ -1,5 +1,6 @@
 use super::socket;
 use super::Inner;
+use crate::deterministic::InjectedFault;
 use std::net;
 mod latency;
 mod swizzle;
@@ -48,6 +49,11 @@ impl Connection {
         self.client_fault_handle.is_fully_clogged() && self.server_fault_handle.is_fully_clogged()
     }
 
+    pub(crate) fn disconnect(&self, fault: Option<InjectedFault>) {
+        self.client_fault_handle.disconnect_with(fault.clone());
+        self.server_fault_handle.disconnect_with(fault);
+    }
+
     pub(crate) fn clog(&mut self) {
         self.client_fault_handle.clog_sends();
         self.client_fault_handle.clog_receives();
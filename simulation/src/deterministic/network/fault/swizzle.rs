@@ -159,7 +159,9 @@ impl Stream for Swizzler {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq, Copy)]
+/// A clog of new connections from `source` to `dest`. Ordered by source and then by
+/// destination.
+#[derive(Debug, Clone, PartialEq, Hash, Eq, PartialOrd, Ord, Copy)]
 pub(crate) struct CloggedConnection {
     source: net::IpAddr,
     dest: net::IpAddr,
@@ -186,39 +188,49 @@ mod tests {
 
     #[test]
     fn swizzle_clog_generator() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async move {
-            let time_handle = handle.time_handle();
-            let random_handle = handle.random_handle();
-            let conn1 =
-                CloggedConnection::new("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
-            let conn2 =
-                CloggedConnection::new("10.0.0.2".parse().unwrap(), "10.0.0.3".parse().unwrap());
-            let conn3 =
-                CloggedConnection::new("10.0.0.3".parse().unwrap(), "10.0.0.1".parse().unwrap());
-
-            let mut swizzler = Swizzler::new(random_handle, time_handle, vec![conn1, conn2, conn3]);
-            let mut results = vec![];
-            while let Some(next) = swizzler.next().await {
-                results.push(next);
-            }
-            assert_eq!(
-                results.len(),
-                6,
-                "expected 6 actions from 3 connections, clog and unclog"
-            );
-            assert_eq!(
-                vec![
-                    SwizzleAction::Clog(conn1),
-                    SwizzleAction::Clog(conn2),
-                    SwizzleAction::Clog(conn3),
-                    SwizzleAction::Unclog(conn3),
-                    SwizzleAction::Unclog(conn2),
-                    SwizzleAction::Unclog(conn1)
-                ],
-                results
-            );
-        })
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async move {
+                let time_handle = handle.time_handle();
+                let random_handle = handle.random_handle();
+                let conn1 = CloggedConnection::new(
+                    "10.0.0.1".parse().unwrap(),
+                    "10.0.0.2".parse().unwrap(),
+                );
+                let conn2 = CloggedConnection::new(
+                    "10.0.0.2".parse().unwrap(),
+                    "10.0.0.3".parse().unwrap(),
+                );
+                let conn3 = CloggedConnection::new(
+                    "10.0.0.3".parse().unwrap(),
+                    "10.0.0.1".parse().unwrap(),
+                );
+
+                let mut swizzler =
+                    Swizzler::new(random_handle, time_handle, vec![conn1, conn2, conn3]);
+                let mut results = vec![];
+                while let Some(next) = swizzler.next().await {
+                    results.push(next);
+                }
+                assert_eq!(
+                    results.len(),
+                    6,
+                    "expected 6 actions from 3 connections, clog and unclog"
+                );
+                assert_eq!(
+                    vec![
+                        SwizzleAction::Clog(conn1),
+                        SwizzleAction::Clog(conn2),
+                        SwizzleAction::Clog(conn3),
+                        SwizzleAction::Unclog(conn3),
+                        SwizzleAction::Unclog(conn2),
+                        SwizzleAction::Unclog(conn1)
+                    ],
+                    results
+                );
+            })
+            .unwrap()
     }
 }
@@ -1,27 +1,92 @@
+use super::capture::PacketCapture;
 use super::fault::{CloggedConnection, Connection};
-use super::{socket, FaultyTcpStream, Listener, ListenerState, SocketHalf};
-use futures::{channel::mpsc, Future, SinkExt};
+use super::stats::{ConnectionCounters, ConnectionStats};
+use super::table::ConnectionTable;
+use super::transport::{Bound, Registration, Transport, TransportGate, TransportListener};
+use super::{
+    listen::{self, Place},
+    socket, AcceptOrder, Backlog, FaultyTcpStream, Listener, ListenerState, SocketHalf,
+};
+use crate::deterministic::{
+    flow, DescriptorTable, DeterministicRandomHandle, FlowStage, FlowTrace, InFlight,
+    InjectedFault, LiveConnection, Resource, Timeline, TimelineKind,
+};
+use futures::{channel::mpsc, future, Future, SinkExt};
 use std::{
-    collections::{self, hash_map::Entry},
+    collections::{self, btree_map::Entry},
     io, net,
+    sync::{self, atomic},
+    time,
 };
 use tracing::trace;
 
 #[derive(Debug)]
 pub(crate) struct Inner {
     handle: crate::deterministic::DeterministicTimeHandle,
-    pub(crate) connections: Vec<Connection>,
-    clogged: collections::HashSet<CloggedConnection>,
-    endpoints: collections::HashMap<net::SocketAddr, ListenerState>,
+    pub(crate) connections: ConnectionTable,
+    // ordered rather than hashed, so that everything done for each of their entries, like
+    // dropping the listeners of a killed host, happens in the same order on every run.
+    clogged: collections::BTreeSet<CloggedConnection>,
+    endpoints: collections::BTreeMap<net::SocketAddr, ListenerState>,
+    transports: collections::BTreeMap<net::SocketAddr, Box<dyn Bound>>,
+    descriptors: DescriptorTable,
+    capture: Option<PacketCapture>,
+    timeline: Option<Timeline>,
+    flows: Option<FlowTrace>,
+    /// Bytes written to connections which have not yet been read.
+    buffered: sync::Arc<atomic::AtomicUsize>,
+    /// Statistics of the most recent connection from each address to each other, whether or
+    /// not it is still open, along with the order the connections were established in.
+    stats: collections::BTreeMap<
+        (net::SocketAddr, net::SocketAddr),
+        (u64, sync::Arc<ConnectionCounters>),
+    >,
+    next_stats: u64,
+    /// The number of connections whose statistics were replaced by those of a newer
+    /// connection between the same addresses, and the bytes written to them.
+    retired: (u64, u64),
+    /// Latency of each direction of new connections, and the jitter added on top of it.
+    latency: time::Duration,
+    jitter: time::Duration,
+    random: Option<DeterministicRandomHandle>,
+    close_on_drop: socket::CloseOnDrop,
+    accept_order: AcceptOrder,
+    /// Whether connections to an address nothing is listening on wait for a listener to be
+    /// bound, rather than being refused after `refuse_delay`.
+    late_binding: bool,
+    refuse_delay: time::Duration,
+    /// The most connections each listener holds before refusing more, and whether writes are
+    /// cut short to fit the buffers of connections, if queues are strictly bounded.
+    strict_backlog: Option<usize>,
 }
 
 impl Inner {
-    pub(crate) fn new(handle: crate::deterministic::DeterministicTimeHandle) -> Self {
+    pub(crate) fn new(
+        handle: crate::deterministic::DeterministicTimeHandle,
+        descriptors: DescriptorTable,
+    ) -> Self {
         Inner {
             handle,
-            connections: vec![],
-            clogged: collections::HashSet::new(),
-            endpoints: collections::HashMap::new(),
+            connections: ConnectionTable::default(),
+            clogged: collections::BTreeSet::new(),
+            endpoints: collections::BTreeMap::new(),
+            transports: collections::BTreeMap::new(),
+            descriptors,
+            capture: None,
+            timeline: None,
+            flows: None,
+            buffered: sync::Arc::default(),
+            stats: collections::BTreeMap::new(),
+            next_stats: 0,
+            retired: (0, 0),
+            latency: time::Duration::from_millis(0),
+            jitter: time::Duration::from_millis(0),
+            random: None,
+            close_on_drop: socket::CloseOnDrop::default(),
+            accept_order: AcceptOrder::default(),
+            late_binding: false,
+            refuse_delay: time::Duration::from_millis(0),
+            strict_backlog: None,
         }
     }
     fn register_new_connection_pair(
@@ -29,54 +94,257 @@ impl Inner {
         source: net::SocketAddr,
         dest: net::SocketAddr,
     ) -> Result<(FaultyTcpStream<SocketHalf>, FaultyTcpStream<SocketHalf>), io::Error> {
-        if self
-            .connections
-            .iter()
-            .map(|c| c.source())
-            .any(|x| x == source)
-        {
+        if self.connections.contains_source(source) {
             return Err(io::ErrorKind::AddrInUse.into());
         }
 
-        let (client, server) = socket::new_socket_pair(source, dest);
-        let (client, client_fault_handle) =
+        let (mut client, mut server) = socket::new_socket_pair(source, dest);
+        client.set_memory(sync::Arc::clone(&self.buffered));
+        server.set_memory(sync::Arc::clone(&self.buffered));
+        client.set_close_on_drop(self.close_on_drop);
+        server.set_close_on_drop(self.close_on_drop);
+        if self.strict_backlog.is_some() {
+            client.set_bounded();
+            server.set_bounded();
+        }
+        let stats = sync::Arc::new(ConnectionCounters::new(source, dest, self.handle.reader()));
+        client.set_stats(sync::Arc::clone(&stats), sync::Arc::clone(&stats.sent));
+        server.set_stats(sync::Arc::clone(&stats), sync::Arc::clone(&stats.received));
+        if let Some(capture) = &self.capture {
+            capture.connect(source, dest);
+            client.set_capture(capture.clone());
+            server.set_capture(capture.clone());
+        }
+        if let Some(timeline) = &self.timeline {
+            timeline.record(TimelineKind::Connected { source, dest });
+            client.set_timeline(timeline.clone());
+            server.set_timeline(timeline.clone());
+        }
+        if let Some(flows) = &self.flows {
+            flows.record(source.ip(), FlowStage::Connected { source, dest });
+            client.set_flows(flows.clone());
+            server.set_flows(flows.clone());
+            server.set_accept_flow(flow::current());
+        }
+        let (mut client, client_fault_handle) =
             socket::FaultyTcpStream::wrap(self.handle.clone(), client);
-        let (server, server_fault_handle) =
+        let (mut server, server_fault_handle) =
             socket::FaultyTcpStream::wrap(self.handle.clone(), server);
+        if let Some(flows) = &self.flows {
+            client.set_flows(flows.clone());
+            server.set_flows(flows.clone());
+        }
+        client_fault_handle.set_stats(
+            sync::Arc::clone(&stats.sent),
+            sync::Arc::clone(&stats.received),
+        );
+        server_fault_handle.set_stats(
+            sync::Arc::clone(&stats.received),
+            sync::Arc::clone(&stats.sent),
+        );
+        let order = self.next_stats;
+        self.next_stats += 1;
+        // a connection from `source` only replaces one which was closed, as each source
+        // address is used by one open connection at a time.
+        if let Some((_, replaced)) = self.stats.insert((source, dest), (order, stats)) {
+            let replaced = replaced.stats();
+            self.retired.0 += 1;
+            self.retired.1 += replaced.sent.bytes + replaced.received.bytes;
+        }
+        self.delay_sends(&client_fault_handle);
+        self.delay_sends(&server_fault_handle);
         let mut connection =
             Connection::new(source, dest, client_fault_handle, server_fault_handle);
         if self.should_clog(source, dest) {
             connection.clog();
         }
-        self.connections.push(connection);
+        self.connections.insert(connection);
         Ok((client, server))
     }
-    // find an unused socket port for the provided ipaddr.
-    fn unused_socket_port(&self, addr: net::IpAddr) -> u16 {
-        let mut start = 65535;
-        let occupied: collections::HashSet<u16> = self
-            .connections
+    /// Returns the statistics of the most recent connection from `source` to `dest`.
+    pub(crate) fn connection_stats(
+        &self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    ) -> Option<ConnectionStats> {
+        self.stats
+            .get(&(source, dest))
+            .map(|(_, stats)| stats.stats())
+    }
+    /// Returns the statistics kept, in the order the connections were established.
+    fn established(&self) -> Vec<&ConnectionCounters> {
+        let mut established: Vec<_> = self.stats.values().collect();
+        established.sort_by_key(|(order, _)| *order);
+        established.into_iter().map(|(_, stats)| &**stats).collect()
+    }
+    /// Returns the statistics of every connection between `a` and `b`, in either direction.
+    pub(crate) fn connection_stats_between(
+        &self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+    ) -> Vec<ConnectionStats> {
+        self.established()
+            .into_iter()
+            .filter(|stats| stats.is_between(a, b))
+            .map(|stats| stats.stats())
+            .collect()
+    }
+    /// Returns the number of connections established so far, and the bytes written to them in
+    /// both directions.
+    pub(crate) fn traffic(&self) -> (u64, u64) {
+        let (retired, retired_bytes) = self.retired;
+        let bytes: u64 = self
+            .stats
+            .values()
+            .map(|(_, stats)| {
+                let stats = stats.stats();
+                stats.sent.bytes + stats.received.bytes
+            })
+            .sum();
+        (retired + self.stats.len() as u64, retired_bytes + bytes)
+    }
+    /// Returns the messages written to connections which have not yet been read, in the order
+    /// the connections were established.
+    pub(crate) fn in_flight(&self) -> Vec<InFlight> {
+        self.established()
+            .into_iter()
+            .flat_map(|stats| stats.in_flight())
+            .collect()
+    }
+    /// Returns the addresses which have an open listener bound to them, over TCP or any other
+    /// transport.
+    pub(crate) fn listeners(&self) -> Vec<net::SocketAddr> {
+        let tcp = self
+            .endpoints
             .iter()
-            .filter(|v| v.source().ip() == addr)
-            .map(|v| v.source().port())
-            .collect();
-        loop {
-            if !occupied.contains(&start) {
-                return start;
-            }
-            if start == 0 {}
-            start -= 1;
+            .filter(|(_, state)| match state {
+                ListenerState::Bound { tx } => !tx.is_closed(),
+                ListenerState::Unbound { .. } => false,
+            })
+            .map(|(addr, _)| *addr);
+        let transports = self
+            .transports
+            .iter()
+            .filter(|(_, bound)| !bound.is_closed())
+            .map(|(addr, _)| *addr);
+        let mut listeners: Vec<_> = tcp.chain(transports).collect();
+        listeners.sort();
+        listeners.dedup();
+        listeners
+    }
+    /// Returns the connections which neither end has closed, in the order they were
+    /// established.
+    pub(crate) fn live_connections(&self) -> Vec<LiveConnection> {
+        self.connections
+            .iter()
+            .filter(|connection| !connection.is_dropped())
+            .map(|connection| LiveConnection {
+                source: connection.source(),
+                dest: connection.dest(),
+                clogged: connection.is_clogged(),
+            })
+            .collect()
+    }
+    /// Returns the pairs of hosts between which new connections are clogged, ordered by source
+    /// and then by destination.
+    pub(crate) fn partitions(&self) -> Vec<(net::IpAddr, net::IpAddr)> {
+        self.clogged
+            .iter()
+            .map(|clog| (clog.source(), clog.dest()))
+            .collect()
+    }
+    /// Returns the number of bytes written to connections which have not yet been read.
+    pub(crate) fn buffered(&self) -> usize {
+        self.buffered.load(atomic::Ordering::Relaxed)
+    }
+    /// Record the traffic of connections established from now on in `capture`.
+    pub(crate) fn set_capture(&mut self, capture: PacketCapture) {
+        self.capture = Some(capture);
+    }
+    /// Record connections established from now on in `timeline`.
+    pub(crate) fn set_timeline(&mut self, timeline: Timeline) {
+        self.timeline = Some(timeline);
+    }
+    /// Record the flows of connections established from now on in `flows`.
+    pub(crate) fn set_flows(&mut self, flows: FlowTrace) {
+        self.flows = Some(flows);
+    }
+    /// Close connections established from now on as `close` describes when one of their halves
+    /// is dropped without being shut down.
+    pub(crate) fn set_close_on_drop(&mut self, close: socket::CloseOnDrop) {
+        self.close_on_drop = close;
+    }
+    /// Accept connections to listeners bound from now on in `order`, shuffling them with the
+    /// source of randomness passed to [`set_latency`].
+    ///
+    /// [`set_latency`]:Inner::set_latency
+    pub(crate) fn set_accept_order(&mut self, order: AcceptOrder) {
+        self.accept_order = order;
+    }
+    /// Refuse connections to addresses nothing is listening on after `delay`, or let them wait
+    /// for a listener to be bound if `late_binding` is set.
+    pub(crate) fn set_unbound(&mut self, late_binding: bool, delay: time::Duration) {
+        self.late_binding = late_binding;
+        self.refuse_delay = delay;
+    }
+    /// Bound every queue of the network: listeners hold at most `backlog` connections waiting
+    /// to be accepted and refuse any more, and writes to connections established from now on
+    /// are cut short to the room left in their buffers. Each overflow is recorded in the
+    /// timeline.
+    pub(crate) fn set_strict(&mut self, backlog: usize) {
+        self.strict_backlog = Some(backlog);
+    }
+    /// Delay each direction of connections established from now on by `latency`, plus up to
+    /// `jitter` drawn from `random`.
+    pub(crate) fn set_latency(
+        &mut self,
+        latency: time::Duration,
+        jitter: time::Duration,
+        random: DeterministicRandomHandle,
+    ) {
+        self.latency = latency;
+        self.jitter = jitter;
+        self.random = Some(random);
+    }
+    // apply the configured latency to the sends of one end of a new connection.
+    fn delay_sends(&self, handle: &socket::FaultyTcpStreamHandle) {
+        let zero = time::Duration::from_millis(0);
+        let random = match &self.random {
+            Some(random) if self.latency > zero || self.jitter > zero => random,
+            _ => return,
+        };
+        let mut latency = self.latency;
+        if self.jitter > zero {
+            latency += random.gen_range(zero..self.jitter);
         }
+        handle.delay_sends(latency);
     }
-
-    fn gc_dropped(&mut self) {
-        let mut connections = vec![];
-        for connection in self.connections.iter() {
-            if !connection.is_dropped() {
-                connections.push(connection.clone());
+    // returns true if queues are strictly bounded and `queued` connections already fill the
+    // backlog of the listener on `dest`, recording the connection from `source` it refuses.
+    fn overflowed(&self, source: net::IpAddr, dest: net::SocketAddr, queued: usize) -> bool {
+        match self.strict_backlog {
+            Some(backlog) if queued >= backlog => {
+                if let Some(timeline) = &self.timeline {
+                    timeline.record(TimelineKind::BacklogFull { source, dest });
+                }
+                true
             }
+            _ => false,
         }
-        self.connections = connections;
+    }
+    // queue the connections sent over `incoming` for a new listener.
+    fn backlog(&self, incoming: mpsc::Receiver<listen::Arrival>) -> Backlog {
+        Backlog::new(incoming, self.accept_order, self.random.clone())
+    }
+    // find an unused socket port for the provided ipaddr, failing if every port is in use.
+    fn unused_socket_port(&mut self, addr: net::IpAddr) -> Result<u16, io::Error> {
+        self.connections.unused_port(addr).ok_or_else(|| {
+            trace!("{} has no unused port", addr);
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no unused port on {}", addr),
+            )
+        })
     }
 
     pub fn connect(
@@ -84,75 +352,262 @@ impl Inner {
         source: net::IpAddr,
         dest: net::SocketAddr,
     ) -> impl Future<Output = Result<socket::FaultyTcpStream<SocketHalf>, io::Error>> {
+        let listening = match self.endpoints.get(&dest) {
+            Some(state) => !state.is_closed(),
+            None => self.late_binding,
+        };
+        let queued = self.endpoints.get(&dest).map_or(0, ListenerState::queued);
+        let full = listening && self.overflowed(source, dest, queued);
+        if !listening || full {
+            trace!(
+                "refusing connection {} -> {}, {}",
+                source,
+                dest,
+                if full {
+                    "its backlog is full"
+                } else {
+                    "nothing is listening"
+                }
+            );
+            let zero = time::Duration::from_millis(0);
+            let delay = Some(self.refuse_delay)
+                .filter(|delay| *delay > zero)
+                .map(|delay| self.handle.delay_from(delay));
+            return future::Either::Left(async move {
+                if let Some(delay) = delay {
+                    delay.await;
+                }
+                Err(io::ErrorKind::ConnectionRefused.into())
+            });
+        }
+
         trace!("establishing new connection {} -> {}", source, dest);
-        self.gc_dropped();
-        let free_socket_port = self.unused_socket_port(source);
-        let source_addr = net::SocketAddr::new(source, free_socket_port);
-        let registration = self.register_new_connection_pair(source_addr, dest);
+        let registration = self.unused_socket_port(source).and_then(|port| {
+            let source_addr = net::SocketAddr::new(source, port);
+            let resource = Resource::Connection {
+                local_addr: source_addr,
+                peer_addr: dest,
+            };
+            let descriptor = self.descriptors.allocate(source, resource)?;
+            let (mut client, server) = self.register_new_connection_pair(source_addr, dest)?;
+            client.set_descriptor(descriptor);
+            Ok((client, server))
+        });
 
-        let mut channel;
-        match self.endpoints.entry(dest) {
+        let incoming = match self.endpoints.entry(dest) {
             Entry::Vacant(v) => {
-                let (tx, rx) = mpsc::channel(1);
+                let (tx, rx) = listen::queue(self.strict_backlog);
                 let state = ListenerState::Unbound { tx: tx.clone(), rx };
-                channel = tx;
                 v.insert(state);
+                tx
             }
             Entry::Occupied(o) => match o.get() {
-                ListenerState::Bound { tx } => channel = tx.clone(),
-                ListenerState::Unbound { tx, .. } => channel = tx.clone(),
+                ListenerState::Bound { tx } | ListenerState::Unbound { tx, .. } => tx.clone(),
             },
-        }
+        };
+        let place = incoming.reserve();
+        let mut channel = incoming.tx;
 
-        async move {
+        future::Either::Right(async move {
             let (client, server) = registration?;
-            match channel.send(server).await {
+            match channel.send((server, place)).await {
                 Ok(_) => Ok(client),
                 Err(_) => Err(io::ErrorKind::ConnectionRefused.into()),
             }
+        })
+    }
+
+    /// Register a connection to `dest` which carries messages rather than bytes, returning the
+    /// gates of its client and server ends. Faults injected into the connection apply to each
+    /// operation passed through them. The client end holds a descriptor of `source`; the server
+    /// end is left to take one of `dest` when it is accepted.
+    ///
+    /// Such connections carry no bytes, so they are recorded in the timeline but have no
+    /// statistics and are left out of packet captures and flows.
+    pub(crate) fn connect_gates(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    ) -> Result<(TransportGate, TransportGate), io::Error> {
+        trace!("establishing new message connection {} -> {}", source, dest);
+        let source = net::SocketAddr::new(source, self.unused_socket_port(source)?);
+        let resource = Resource::Connection {
+            local_addr: source,
+            peer_addr: dest,
+        };
+        let descriptor = self.descriptors.allocate(source.ip(), resource)?;
+        if let Some(timeline) = &self.timeline {
+            timeline.record(TimelineKind::Connected { source, dest });
+        }
+        let (client, client_fault_handle) = socket::FaultyTcpStream::wrap(self.handle.clone(), ());
+        let (server, server_fault_handle) = socket::FaultyTcpStream::wrap(self.handle.clone(), ());
+        self.delay_sends(&client_fault_handle);
+        self.delay_sends(&server_fault_handle);
+        let mut connection =
+            Connection::new(source, dest, client_fault_handle, server_fault_handle);
+        if self.should_clog(source, dest) {
+            connection.clog();
+        }
+        self.connections.insert(connection);
+        let client = TransportGate::new(source, dest, client);
+        client.set_descriptor(descriptor);
+        Ok((client, TransportGate::new(dest, source, server)))
+    }
+
+    /// Bind a listener for connections over `transport` to `bind_addr`.
+    pub(crate) fn bind_transport<T>(
+        &mut self,
+        bind_addr: net::SocketAddr,
+        transport: T,
+    ) -> Result<TransportListener<T>, io::Error>
+    where
+        T: Transport,
+    {
+        trace!("registering transport listener for {}", bind_addr);
+        if let Some(bound) = self.transports.get(&bind_addr) {
+            if !bound.is_closed() {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
         }
+        let resource = Resource::Listener {
+            local_addr: bind_addr,
+        };
+        let descriptor = self.descriptors.allocate(bind_addr.ip(), resource)?;
+        let (registration, listener) =
+            Registration::new(bind_addr, transport, descriptor, self.descriptors.clone());
+        self.transports.insert(bind_addr, Box::new(registration));
+        Ok(listener)
+    }
+
+    /// Connect to the listener for the transport `T` bound to `dest`, returning the client end
+    /// of the new connection.
+    pub(crate) fn connect_transport<T>(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    ) -> Result<T::Endpoint, io::Error>
+    where
+        T: Transport,
+    {
+        let bound = self
+            .transports
+            .get(&dest)
+            .filter(|bound| !bound.is_closed())
+            .ok_or(io::ErrorKind::ConnectionRefused)?;
+        // listeners of other transports are not reachable by this one.
+        if !bound.as_any().is::<Registration<T>>() {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        if self.overflowed(source, dest, bound.queued()) {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        let (client_gate, server_gate) = self.connect_gates(source, dest)?;
+        let source = client_gate.local_addr();
+        let registration = self
+            .transports
+            .get_mut(&dest)
+            .and_then(|bound| bound.as_any_mut().downcast_mut::<Registration<T>>())
+            .unwrap();
+        let gate = server_gate.clone();
+        let (client, server) = registration.transport.pair(client_gate, server_gate);
+        let place = Place::reserve(&registration.queued);
+        registration
+            .tx
+            .unbounded_send((server, gate, source, place))
+            .map_err(|_| io::ErrorKind::ConnectionRefused)?;
+        Ok(client)
     }
 
     pub fn listen(&mut self, bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
         trace!("registering listener for {}", bind_addr);
-        self.gc_dropped();
+        let resource = Resource::Listener {
+            local_addr: bind_addr,
+        };
+        let descriptor = self.descriptors.allocate(bind_addr.ip(), resource)?;
         match self.endpoints.remove(&bind_addr) {
             Some(listener_state) => {
                 if let ListenerState::Unbound { tx, rx } = listener_state {
-                    let listener = Listener::new(bind_addr, rx);
+                    let listener = Listener::new(
+                        bind_addr,
+                        self.backlog(rx),
+                        descriptor,
+                        self.descriptors.clone(),
+                    );
                     let new_state = ListenerState::Bound { tx };
                     self.endpoints.insert(bind_addr, new_state);
                     Ok(listener)
+                } else if listener_state.is_closed() {
+                    // the previous listener was dropped, allow the address to be reused.
+                    let (tx, rx) = listen::queue(self.strict_backlog);
+                    let state = ListenerState::Bound { tx };
+                    self.endpoints.insert(bind_addr, state);
+                    Ok(Listener::new(
+                        bind_addr,
+                        self.backlog(rx),
+                        descriptor,
+                        self.descriptors.clone(),
+                    ))
                 } else {
                     self.endpoints.insert(bind_addr, listener_state);
                     Err(io::ErrorKind::AddrInUse.into())
                 }
             }
             _ => {
-                let (tx, rx) = mpsc::channel(1);
+                let (tx, rx) = listen::queue(self.strict_backlog);
                 let state = ListenerState::Bound { tx };
                 self.endpoints.insert(bind_addr, state);
-                let listener = Listener::new(bind_addr, rx);
+                let listener = Listener::new(
+                    bind_addr,
+                    self.backlog(rx),
+                    descriptor,
+                    self.descriptors.clone(),
+                );
                 Ok(listener)
             }
         }
     }
 
-    /// Determines if a connection should be clogged based on the state of clogged connections.
-    fn should_clog(&self, source: net::SocketAddr, dest: net::SocketAddr) -> bool {
-        let source_ip = source.ip();
-        let dest_ip = dest.ip();
-        for connection in self.clogged.iter() {
-            if connection.source() == source_ip && connection.dest() == dest_ip {
-                return true;
+    /// Disconnect all connections to and from the provided IP address, and release any
+    /// addresses it was listening on. Operations on the connections fail with an error caused
+    /// by `fault`, if the host was killed by one.
+    pub(crate) fn kill_host(&mut self, addr: net::IpAddr, fault: Option<InjectedFault>) {
+        trace!("killing host {}", addr);
+        for connection in self.connections.iter() {
+            if connection.source().ip() == addr || connection.dest().ip() == addr {
+                connection.disconnect(fault.clone());
             }
         }
-        false
+        self.endpoints.retain(|bind_addr, _| bind_addr.ip() != addr);
+        self.transports
+            .retain(|bind_addr, _| bind_addr.ip() != addr);
+        self.connections.gc_dropped();
+    }
+
+    /// Disconnect the connection between `local` and `peer`, failing operations on both of its
+    /// ends with an error caused by `fault`.
+    pub(crate) fn disconnect(
+        &mut self,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        fault: InjectedFault,
+    ) {
+        for connection in self.connections.iter() {
+            let (source, dest) = (connection.source(), connection.dest());
+            if (source, dest) == (local, peer) || (source, dest) == (peer, local) {
+                connection.disconnect(Some(fault.clone()));
+            }
+        }
+    }
+
+    /// Determines if a connection should be clogged based on the state of clogged connections.
+    fn should_clog(&self, source: net::SocketAddr, dest: net::SocketAddr) -> bool {
+        self.clogged
+            .contains(&CloggedConnection::new(source.ip(), dest.ip()))
     }
 
     /// Clog all new connections from one IP to another. If there are any existing connections, they
     /// are also clogged.
-    fn clog_connection(&mut self, clog: CloggedConnection) {
+    pub(crate) fn clog_connection(&mut self, clog: CloggedConnection) {
         trace!("clogging connection {:?}", clog);
         let clog_source = clog.source();
         let clog_dest = clog.dest();
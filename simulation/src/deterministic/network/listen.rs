@@ -1,26 +1,191 @@
 use super::{FaultyTcpStream, SocketHalf};
+use crate::deterministic::{
+    escape, Descriptor, DescriptorTable, DeterministicRandomHandle, Resource,
+};
 use crate::TcpStream;
 use async_trait::async_trait;
 use futures::{channel::mpsc, Poll, Stream, StreamExt};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use std::{
+    collections, fmt, io, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+};
 use tracing::trace;
 
+/// A connection on its way to a listener, holding its place in the listener's queue.
+pub(crate) type Arrival = (FaultyTcpStream<SocketHalf>, Place);
+
+/// Creates the queue of connections to a listener, returning the end connections are sent
+/// over and the end the listener accepts them from. Without a `backlog`, connecting waits for
+/// the listener to take the connections ahead of it off the queue; with one, connections are
+/// refused before they would fill it, so connecting never waits.
+pub(crate) fn queue(backlog: Option<usize>) -> (Incoming, mpsc::Receiver<Arrival>) {
+    let (tx, rx) = mpsc::channel(backlog.unwrap_or(1));
+    let queued = sync::Arc::default();
+    (Incoming { tx, queued }, rx)
+}
+
+/// The end of a listener's queue which connections are sent over.
+#[derive(Debug, Clone)]
+pub(crate) struct Incoming {
+    pub(crate) tx: mpsc::Sender<Arrival>,
+    /// Counts the connections made to the listener which it has not accepted yet.
+    queued: sync::Arc<atomic::AtomicUsize>,
+}
+
+impl Incoming {
+    pub(crate) fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Returns the number of connections made to the listener which it has not accepted yet,
+    /// including those still on their way to it.
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Take a place in the queue for a new connection.
+    pub(crate) fn reserve(&self) -> Place {
+        Place::reserve(&self.queued)
+    }
+}
+
+/// A place in the queue of a listener, given up once the connection holding it is accepted,
+/// or dropped without being accepted.
+#[derive(Debug)]
+pub(crate) struct Place {
+    queued: sync::Arc<atomic::AtomicUsize>,
+}
+
+impl Place {
+    /// Take a place in the queue whose length is counted by `queued`.
+    pub(crate) fn reserve(queued: &sync::Arc<atomic::AtomicUsize>) -> Self {
+        queued.fetch_add(1, atomic::Ordering::Relaxed);
+        Self {
+            queued: sync::Arc::clone(queued),
+        }
+    }
+}
+
+impl Drop for Place {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, atomic::Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug)]
 /// ListenerState represents both the bound and unbound state of a Listener.
 /// This allows supporting late binding of Listeners to sockets.
 pub(crate) enum ListenerState {
     Unbound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
-        rx: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        tx: Incoming,
+        rx: mpsc::Receiver<Arrival>,
     },
     Bound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
+        tx: Incoming,
     },
 }
 
+impl ListenerState {
+    /// Returns true if the listener for this address has been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        match self {
+            ListenerState::Unbound { .. } => false,
+            ListenerState::Bound { tx } => tx.is_closed(),
+        }
+    }
+
+    /// Returns the number of connections to this address which have not been accepted yet.
+    pub(crate) fn queued(&self) -> usize {
+        match self {
+            ListenerState::Unbound { tx, .. } | ListenerState::Bound { tx } => tx.queued(),
+        }
+    }
+}
+
+/// The order in which a listener accepts the connections waiting for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcceptOrder {
+    /// Accept connections in the order they reached the listener, which is the order their
+    /// `connect` futures were first polled in.
+    #[default]
+    Arrival,
+    /// Accept a connection drawn from the seed among those waiting, so that each seed explores
+    /// a different, but reproducible, arrival order.
+    Shuffled,
+}
+
+/// The connections which reached a listener and are waiting to be accepted.
+pub(crate) struct Backlog {
+    incoming: mpsc::Receiver<Arrival>,
+    waiting: collections::VecDeque<Arrival>,
+    /// Set once `incoming` is closed and drained.
+    ended: bool,
+    order: AcceptOrder,
+    random: Option<DeterministicRandomHandle>,
+}
+
+impl Backlog {
+    /// Accept the connections sent over `incoming` in `order`, drawing from `random` if they
+    /// are shuffled.
+    pub(crate) fn new(
+        incoming: mpsc::Receiver<Arrival>,
+        order: AcceptOrder,
+        random: Option<DeterministicRandomHandle>,
+    ) -> Self {
+        Self {
+            incoming,
+            waiting: collections::VecDeque::new(),
+            ended: false,
+            order,
+            random,
+        }
+    }
+
+    /// Refuse new connections, leaving those already waiting to be accepted.
+    fn close(&mut self) {
+        self.incoming.close();
+    }
+}
+
+impl Stream for Backlog {
+    type Item = FaultyTcpStream<SocketHalf>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        // move every connection which has arrived into the backlog, so that a shuffled listener
+        // chooses among all of them.
+        while !this.ended {
+            match this.incoming.poll_next_unpin(cx) {
+                Poll::Ready(Some(connection)) => this.waiting.push_back(connection),
+                Poll::Ready(None) => this.ended = true,
+                Poll::Pending => break,
+            }
+        }
+        let index = match (this.order, &this.random) {
+            (AcceptOrder::Shuffled, Some(random)) if this.waiting.len() > 1 => {
+                random.gen_index(this.waiting.len())
+            }
+            _ => 0,
+        };
+        match this.waiting.remove(index) {
+            Some((connection, _place)) => Poll::Ready(Some(connection)),
+            None if this.ended => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Accepts connections to an address of the simulated network. Waiting connections are
+/// accepted in the [`AcceptOrder`] the runtime was built with, which defaults to the order they
+/// reached the listener in.
+///
+/// [`AcceptOrder`]:AcceptOrder
 pub struct Listener {
     local_addr: net::SocketAddr,
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+    incoming: Backlog,
+    descriptor: Descriptor,
+    descriptors: DescriptorTable,
 }
 
 impl fmt::Debug for Listener {
@@ -31,15 +196,45 @@ impl fmt::Debug for Listener {
 }
 
 impl Listener {
-    pub fn new(
+    pub(crate) fn new(
         local_addr: net::SocketAddr,
-        incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        incoming: Backlog,
+        descriptor: Descriptor,
+        descriptors: DescriptorTable,
     ) -> Self {
         Self {
             local_addr,
             incoming,
+            descriptor,
+            descriptors,
         }
     }
+
+    /// Stop accepting connections. New connections to the listener's address, including those
+    /// still waiting for room in its queue, fail with `ConnectionRefused`. Connections which
+    /// were already queued can still be accepted, after which `accept` fails with
+    /// `NotConnected`. Dropping the listener instead of
+    /// draining it drops the queued connections.
+    ///
+    /// The address can be bound again once the listener is closed.
+    pub fn close(&mut self) {
+        trace!("closing listener for {}", self.local_addr);
+        self.incoming.close();
+    }
+}
+
+/// Allocate a descriptor for a connection from `peer_addr` accepted by the listener bound to
+/// `local_addr`.
+fn allocate(
+    descriptors: &DescriptorTable,
+    local_addr: net::SocketAddr,
+    peer_addr: net::SocketAddr,
+) -> io::Result<Descriptor> {
+    let resource = Resource::Connection {
+        local_addr,
+        peer_addr,
+    };
+    descriptors.allocate(local_addr.ip(), resource)
 }
 
 impl Listener {
@@ -48,8 +243,12 @@ impl Listener {
     async fn accept(
         &mut self,
     ) -> Result<(FaultyTcpStream<SocketHalf>, net::SocketAddr), io::Error> {
-        if let Some(next) = self.incoming.next().await {
+        if let Some(mut next) = self.incoming.next().await {
             let addr = next.peer_addr()?;
+            // if the host has no descriptors left, the connection is dropped.
+            let descriptor = allocate(&self.descriptors, self.local_addr, addr)?;
+            next.set_descriptor(descriptor);
+            next.accepted();
             trace!("accepted new connection from {}", addr);
             Ok((next, addr))
         } else {
@@ -60,14 +259,28 @@ impl Listener {
 }
 
 struct ListenerStream {
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+    local_addr: net::SocketAddr,
+    incoming: Backlog,
+    _descriptor: Descriptor,
+    descriptors: DescriptorTable,
 }
 
 impl Stream for ListenerStream {
     type Item = Result<FaultyTcpStream<SocketHalf>, io::Error>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        escape::check_executor(|| format!("listener on {}", self.local_addr));
         match futures::ready!(self.incoming.poll_next_unpin(cx)) {
-            Some(item) => Poll::Ready(Some(Ok(item))),
+            Some(mut item) => match item
+                .peer_addr()
+                .and_then(|peer_addr| allocate(&self.descriptors, self.local_addr, peer_addr))
+            {
+                Ok(descriptor) => {
+                    item.set_descriptor(descriptor);
+                    item.accepted();
+                    Poll::Ready(Some(Ok(item)))
+                }
+                Err(e) => Poll::Ready(Some(Err(e))),
+            },
             None => Poll::Ready(None),
         }
     }
@@ -89,7 +302,17 @@ impl crate::TcpListener for Listener {
         Ok(())
     }
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>> {
-        let Listener { incoming, .. } = self;
-        Box::pin(ListenerStream { incoming })
+        let Listener {
+            local_addr,
+            incoming,
+            descriptor,
+            descriptors,
+        } = self;
+        Box::pin(ListenerStream {
+            local_addr,
+            incoming,
+            _descriptor: descriptor,
+            descriptors,
+        })
     }
 }
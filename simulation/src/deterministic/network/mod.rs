@@ -4,16 +4,30 @@
 //! be accepted or rejected depending on the current fault state of the network.
 //!
 //! The network can inject partitions between machines.
+//!
+//! Besides TCP, user implemented transports can be plugged into the network through the
+//! [`Transport`] trait.
+//!
+//! [`Transport`]:Transport
 
-use std::{io, net, sync};
+use crate::deterministic::profile::{self, Subsystem};
+use crate::deterministic::{DescriptorTable, InFlight, InjectedFault, LiveConnection};
+use std::{io, net, sync, time};
+mod capture;
 pub(crate) mod fault;
 mod inner;
 mod listen;
 pub(crate) mod socket;
+mod stats;
+mod table;
+mod transport;
 pub(crate) use inner::Inner;
-pub use listen::Listener;
-use listen::ListenerState;
+pub use listen::{AcceptOrder, Listener};
+use listen::{Backlog, ListenerState};
+pub use socket::CloseOnDrop;
 use socket::{FaultyTcpStream, SocketHalf};
+pub use stats::{ConnectionStats, DirectionStats};
+pub use transport::{Transport, TransportGate, TransportListener};
 
 pub type Socket = FaultyTcpStream<SocketHalf>;
 pub struct DeterministicNetwork {
@@ -23,8 +37,9 @@ pub struct DeterministicNetwork {
 impl DeterministicNetwork {
     pub(crate) fn new(
         handle: crate::deterministic::DeterministicTimeHandle,
+        descriptors: DescriptorTable,
     ) -> DeterministicNetwork {
-        let inner = Inner::new(handle);
+        let inner = Inner::new(handle, descriptors);
         let inner = sync::Arc::new(sync::Mutex::new(inner));
         DeterministicNetwork { inner }
     }
@@ -35,6 +50,74 @@ impl DeterministicNetwork {
         DeterministicNetworkHandle::new(local_addr.into(), sync::Arc::clone(&self.inner))
     }
 
+    /// Record the traffic of connections established from now on to `writer`, in the pcap
+    /// format.
+    pub(crate) fn capture_packets<W>(
+        &self,
+        time: crate::deterministic::DeterministicTimeHandle,
+        writer: W,
+    ) -> io::Result<()>
+    where
+        W: io::Write + Send + 'static,
+    {
+        let capture = capture::PacketCapture::new(time, writer)?;
+        self.inner.lock().unwrap().set_capture(capture);
+        Ok(())
+    }
+
+    /// Record connections established from now on in `timeline`.
+    pub(crate) fn set_timeline(&self, timeline: crate::deterministic::Timeline) {
+        self.inner.lock().unwrap().set_timeline(timeline);
+    }
+
+    /// Record the flows of connections established from now on in `flows`.
+    pub(crate) fn set_flows(&self, flows: crate::deterministic::FlowTrace) {
+        self.inner.lock().unwrap().set_flows(flows);
+    }
+
+    /// Close connections established from now on as `close` describes when one of their halves
+    /// is dropped without being shut down.
+    pub(crate) fn set_close_on_drop(&self, close: socket::CloseOnDrop) {
+        self.inner.lock().unwrap().set_close_on_drop(close);
+    }
+
+    /// Bound every queue of the network, refusing connections to listeners with `backlog`
+    /// connections waiting and cutting writes short to fit their buffers.
+    pub(crate) fn set_strict(&self, backlog: usize) {
+        self.inner.lock().unwrap().set_strict(backlog);
+    }
+
+    /// Accept connections to listeners bound from now on in `order`.
+    pub(crate) fn set_accept_order(&self, order: AcceptOrder) {
+        self.inner.lock().unwrap().set_accept_order(order);
+    }
+
+    /// Refuse connections to addresses nothing is listening on after `delay`, or let them wait
+    /// for a listener to be bound if `late_binding` is set.
+    pub(crate) fn set_unbound(&self, late_binding: bool, delay: time::Duration) {
+        self.inner.lock().unwrap().set_unbound(late_binding, delay);
+    }
+
+    /// Delay each direction of connections established from now on by `latency`, plus up to
+    /// `jitter` drawn from `random`.
+    pub(crate) fn set_latency(
+        &self,
+        latency: time::Duration,
+        jitter: time::Duration,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_latency(latency, jitter, random);
+    }
+
+    /// Returns the number of connections established so far, and the bytes written to them in
+    /// both directions.
+    pub(crate) fn traffic(&self) -> (u64, u64) {
+        self.inner.lock().unwrap().traffic()
+    }
+
     pub(crate) fn clone_inner(&self) -> sync::Arc<sync::Mutex<Inner>> {
         sync::Arc::clone(&self.inner)
     }
@@ -54,8 +137,111 @@ impl DeterministicNetworkHandle {
         DeterministicNetworkHandle { local_addr, inner }
     }
 
+    /// Returns a handle to the same network, scoped to `local_addr`.
+    pub(crate) fn scoped(&self, local_addr: net::IpAddr) -> Self {
+        DeterministicNetworkHandle::new(local_addr, sync::Arc::clone(&self.inner))
+    }
+
+    pub(crate) fn local_addr(&self) -> net::IpAddr {
+        self.local_addr
+    }
+
+    /// Returns the number of bytes written to connections which have not yet been read.
+    pub(crate) fn buffered(&self) -> usize {
+        self.inner.lock().unwrap().buffered()
+    }
+
+    /// Returns the messages written to connections which have not yet been read.
+    pub(crate) fn in_flight(&self) -> Vec<InFlight> {
+        self.inner.lock().unwrap().in_flight()
+    }
+
+    /// Returns the addresses listened on, the live connections and the partitions of the
+    /// network.
+    pub(crate) fn topology(
+        &self,
+    ) -> (
+        Vec<net::SocketAddr>,
+        Vec<LiveConnection>,
+        Vec<(net::IpAddr, net::IpAddr)>,
+    ) {
+        let lock = self.inner.lock().unwrap();
+        (lock.listeners(), lock.live_connections(), lock.partitions())
+    }
+
+    /// Returns the statistics of the most recent connection from `source` to `dest`, whether
+    /// or not it is still open.
+    pub fn connection_stats(
+        &self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    ) -> Option<ConnectionStats> {
+        self.inner.lock().unwrap().connection_stats(source, dest)
+    }
+
+    /// Returns the statistics of every connection established between the hosts `a` and `b`
+    /// in either direction, whether or not they are still open, in the order they were
+    /// established. A closed connection is left out once a newer connection between the same
+    /// addresses replaces it.
+    pub fn connection_stats_between(&self, a: net::IpAddr, b: net::IpAddr) -> Vec<ConnectionStats> {
+        self.inner.lock().unwrap().connection_stats_between(a, b)
+    }
+
+    /// Disconnect all connections involving `addr` and release its listening addresses.
+    /// Operations on the connections fail with an error caused by `fault`, if given.
+    pub(crate) fn kill(&self, addr: net::IpAddr, fault: Option<InjectedFault>) {
+        self.inner.lock().unwrap().kill_host(addr, fault);
+    }
+
+    /// Disconnect the connection between `local` and `peer`, failing operations on both of its
+    /// ends with an error caused by `fault`.
+    pub(crate) fn disconnect(
+        &self,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+        fault: InjectedFault,
+    ) {
+        self.inner.lock().unwrap().disconnect(local, peer, fault);
+    }
+
+    /// Register a connection to `dest` which carries messages rather than bytes, returning the
+    /// gates of its client and server ends.
+    pub(crate) fn connect_gates(
+        &self,
+        dest: net::SocketAddr,
+    ) -> Result<(TransportGate, TransportGate), io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.connect_gates(self.local_addr, dest)
+    }
+
+    pub(crate) fn bind_transport<T>(
+        &self,
+        mut bind_addr: net::SocketAddr,
+        transport: T,
+    ) -> Result<TransportListener<T>, io::Error>
+    where
+        T: Transport,
+    {
+        bind_addr.set_ip(self.local_addr);
+        let _span = profile::span(Subsystem::Network);
+        let mut lock = self.inner.lock().unwrap();
+        lock.bind_transport(bind_addr, transport)
+    }
+
+    pub(crate) fn connect_transport<T>(
+        &self,
+        dest: net::SocketAddr,
+    ) -> Result<T::Endpoint, io::Error>
+    where
+        T: Transport,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        lock.connect_transport::<T>(self.local_addr, dest)
+    }
+
     pub async fn bind(&self, mut bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
         bind_addr.set_ip(self.local_addr);
+        let _span = profile::span(Subsystem::Network);
         let mut lock = self.inner.lock().unwrap();
         lock.listen(bind_addr)
     }
@@ -65,6 +251,7 @@ impl DeterministicNetworkHandle {
         dest: net::SocketAddr,
     ) -> Result<FaultyTcpStream<SocketHalf>, io::Error> {
         let connfut = {
+            let _span = profile::span(Subsystem::Network);
             let mut lock = self.inner.lock().unwrap();
             let ret = lock.connect(self.local_addr, dest);
             drop(lock);
@@ -77,9 +264,9 @@ impl DeterministicNetworkHandle {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Environment, TcpListener};
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
     use futures::{SinkExt, StreamExt};
-    use std::net;
+    use std::{net, time::Duration};
     use tokio::codec::{Framed, LinesCodec};
 
     /// Starts a server which will forward messages to the next server in the ring.
@@ -112,61 +299,341 @@ mod tests {
     /// Forms a ring of servers which pass a message to each other. The message is incremented in each server.
     /// Once the message has passed through 1000 servers, the test is finished.
     fn test_message_ring() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        let network = DeterministicNetwork::new(handle.time_handle());
-        runtime.block_on(async {
-            for oct in 0..100 {
-                let scoped = network.scoped(net::Ipv4Addr::new(10, 0, 0, oct));
-                handle.spawn(async move {
-                    serve_message_ring(
-                        scoped,
-                        net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, oct + 1).into(), 9092),
-                    )
-                    .await
-                    .unwrap();
-                });
-            }
-            let start_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 0).into(), 9092);
-            let end_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 100).into(), 9092);
-
-            let scoped = network.scoped(end_addr.ip());
-            handle.delay_from(std::time::Duration::from_secs(10));
-
-            let client = scoped.connect(start_addr).await.unwrap();
-            let mut client_transport = Framed::new(client, LinesCodec::new());
-            client_transport.send(String::from("1")).await.unwrap();
-            let mut listener = scoped.bind(end_addr).await.unwrap();
-            while let Ok((new_conn, _)) = listener.accept().await {
-                let mut server_transport = Framed::new(new_conn, LinesCodec::new());
-                while let Some(Ok(message)) = server_transport.next().await {
-                    let decoded: usize = message.parse().unwrap();
-                    if decoded > 1000 {
-                        return;
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let network = DeterministicNetwork::new(handle.time_handle(), DescriptorTable::new());
+        // servers connect to the next one in the ring before it has bound its listener.
+        network.set_unbound(true, Duration::from_millis(0));
+        runtime
+            .block_on(async {
+                for oct in 0..100 {
+                    let scoped = network.scoped(net::Ipv4Addr::new(10, 0, 0, oct));
+                    handle.spawn(async move {
+                        serve_message_ring(
+                            scoped,
+                            net::SocketAddr::new(
+                                net::Ipv4Addr::new(10, 0, 0, oct + 1).into(),
+                                9092,
+                            ),
+                        )
+                        .await
+                        .unwrap();
+                    });
+                }
+                let start_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 0).into(), 9092);
+                let end_addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 100).into(), 9092);
+
+                let scoped = network.scoped(end_addr.ip());
+                handle.delay_from(std::time::Duration::from_secs(10));
+
+                let client = scoped.connect(start_addr).await.unwrap();
+                let mut client_transport = Framed::new(client, LinesCodec::new());
+                client_transport.send(String::from("1")).await.unwrap();
+                let mut listener = scoped.bind(end_addr).await.unwrap();
+                while let Ok((new_conn, _)) = listener.accept().await {
+                    let mut server_transport = Framed::new(new_conn, LinesCodec::new());
+                    while let Some(Ok(message)) = server_transport.next().await {
+                        let decoded: usize = message.parse().unwrap();
+                        if decoded > 1000 {
+                            return;
+                        }
+                        client_transport.send(message).await.unwrap();
                     }
-                    client_transport.send(message).await.unwrap();
                 }
-            }
-        });
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that listeners and clogs are listed in address order rather than the order they
+    /// were registered in.
+    fn address_order() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let network = DeterministicNetwork::new(handle.time_handle(), DescriptorTable::new());
+        let host = |last| net::IpAddr::from(net::Ipv4Addr::new(10, 0, 0, last));
+        for (source, dest) in &[(3, 1), (1, 3), (2, 1), (1, 2), (3, 2)] {
+            network
+                .clone_inner()
+                .lock()
+                .unwrap()
+                .clog_connection(fault::CloggedConnection::new(host(*source), host(*dest)));
+        }
+        runtime
+            .block_on(async {
+                let mut listeners = vec![];
+                for (last, port) in &[(3, 80), (1, 9092), (2, 80), (1, 80)] {
+                    let addr = net::SocketAddr::new(host(*last), *port);
+                    listeners.push(network.scoped(host(*last)).bind(addr).await.unwrap());
+                }
+                let (bound, _, partitions) = network.scoped(host(1)).topology();
+                assert_eq!(
+                    bound,
+                    vec![
+                        net::SocketAddr::new(host(1), 80),
+                        net::SocketAddr::new(host(1), 9092),
+                        net::SocketAddr::new(host(2), 80),
+                        net::SocketAddr::new(host(3), 80),
+                    ]
+                );
+                assert_eq!(
+                    partitions,
+                    vec![
+                        (host(1), host(2)),
+                        (host(1), host(3)),
+                        (host(2), host(1)),
+                        (host(3), host(1)),
+                        (host(3), host(2)),
+                    ]
+                );
+            })
+            .unwrap();
     }
 
     #[test]
     fn test_scoped_registration() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        let network = DeterministicNetwork::new(handle.time_handle());
-        runtime.block_on(async {
-            // create scoped network handle
-            let network1 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
-            let network2 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
-            let common_addr = "127.0.0.1:9092".parse().unwrap();
-            let network1_listener = network1.bind(common_addr).await.unwrap();
-            let network2_listener = network2.bind(common_addr).await.unwrap();
-            assert_ne!(
-                network1_listener.local_addr().unwrap(),
-                network2_listener.local_addr().unwrap(),
-                "expected listener local addrs to be different"
-            )
-        });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let network = DeterministicNetwork::new(handle.time_handle(), DescriptorTable::new());
+        runtime
+            .block_on(async {
+                // create scoped network handle
+                let network1 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+                let network2 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+                let common_addr = "127.0.0.1:9092".parse().unwrap();
+                let network1_listener = network1.bind(common_addr).await.unwrap();
+                let network2_listener = network2.bind(common_addr).await.unwrap();
+                assert_ne!(
+                    network1_listener.local_addr().unwrap(),
+                    network2_listener.local_addr().unwrap(),
+                    "expected listener local addrs to be different"
+                )
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a closed listener refuses new connections while queued ones can be drained.
+    fn close_listener() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let network = DeterministicNetwork::new(handle.time_handle(), DescriptorTable::new());
+        runtime
+            .block_on(async {
+                let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+                let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let _queued = client.connect(addr).await.unwrap();
+
+                listener.close();
+                let err = client.connect(addr).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+                listener.accept().await.unwrap();
+                let err = listener.accept().await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+                let mut listener = server.bind(addr).await.unwrap();
+                let _third = client.connect(addr).await.unwrap();
+                listener.accept().await.unwrap();
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that connections to an address nothing is listening on are refused, after the
+    /// configured delay, unless late binding lets them wait for a listener.
+    fn refuse_unbound() {
+        let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .refuse_delay(Duration::from_secs(3))
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let start = handle.now();
+                let err = handle.connect(addr).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+                assert_eq!(handle.now() - start, Duration::from_secs(3));
+            })
+            .unwrap();
+
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .late_binding()
+            .build()
+            .unwrap();
+        let server = runtime.handle(addr.ip());
+        runtime
+            .block_on(async {
+                let connect = async { handle.connect(addr).await.unwrap() };
+                let listen = async {
+                    server.delay_from(Duration::from_secs(1)).await;
+                    let mut listener = server.bind(addr).await.unwrap();
+                    listener.accept().await.unwrap()
+                };
+                futures::future::join(connect, listen).await;
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that listeners accept waiting connections in the order they arrived by default, and
+    /// in an order drawn from the seed when shuffled.
+    fn accept_order() {
+        fn accepted(order: AcceptOrder, seed: u64) -> Vec<net::IpAddr> {
+            let (mut runtime, _) = crate::deterministic::DeterministicRuntime::builder()
+                .seed(seed)
+                .accept_order(order)
+                .build()
+                .unwrap();
+            let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+            let clients: Vec<_> = (2..10)
+                .map(|i| runtime.handle(net::Ipv4Addr::new(10, 0, 0, i).into()))
+                .collect();
+            runtime
+                .block_on(async {
+                    let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                    let mut listener = server.bind(addr).await.unwrap();
+                    for client in &clients {
+                        let handle = client.clone();
+                        client.spawn(async move {
+                            let _socket = handle.connect(addr).await.unwrap();
+                            handle.delay_from(Duration::from_secs(10)).await;
+                        });
+                    }
+                    // let every connection reach the listener before accepting any of them.
+                    server.delay_from(Duration::from_secs(1)).await;
+                    let mut accepted = vec![];
+                    for _ in &clients {
+                        let (_, peer) = listener.accept().await.unwrap();
+                        accepted.push(peer.ip());
+                    }
+                    accepted
+                })
+                .unwrap()
+        }
+
+        let arrival: Vec<net::IpAddr> = (2..10)
+            .map(|i| net::Ipv4Addr::new(10, 0, 0, i).into())
+            .collect();
+        assert_eq!(accepted(AcceptOrder::Arrival, 0), arrival);
+        assert_eq!(accepted(AcceptOrder::Arrival, 1), arrival);
+
+        let shuffled: Vec<_> = (0..4)
+            .map(|seed| accepted(AcceptOrder::Shuffled, seed))
+            .collect();
+        for (seed, order) in shuffled.iter().enumerate() {
+            assert_eq!(accepted(AcceptOrder::Shuffled, seed as u64), *order);
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, arrival);
+        }
+        assert!(shuffled.iter().any(|order| *order != arrival));
+        assert!(shuffled.iter().any(|order| *order != shuffled[0]));
+    }
+
+    #[test]
+    /// Test that with strict queues, a listener refuses connections once its backlog is full
+    /// and writes are cut short to the room left in the peer's buffer, recording both.
+    fn strict_queues() {
+        use crate::{deterministic::TimelineKind, TcpStream};
+        use tokio::io::AsyncWriteExt;
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .strict_queues(2)
+            .build()
+            .unwrap();
+        let timeline = runtime.record_timeline();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 9092);
+        let (mut client, source) = runtime
+            .block_on(async {
+                let mut listener = server.bind(addr).await.unwrap();
+                let first = handle.connect(addr).await.unwrap();
+                let _second = handle.connect(addr).await.unwrap();
+                let err = handle.connect(addr).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+                let _accepted = listener.accept().await.unwrap();
+                let _third = handle.connect(addr).await.unwrap();
+                (first, handle.local_addr())
+            })
+            .unwrap();
+        let written = runtime
+            .block_on(async { client.write(&[0; 100 * 1024]).await.unwrap() })
+            .unwrap();
+        assert_eq!(written, 64 * 1024);
+
+        let kinds: Vec<_> = timeline.entries().into_iter().map(|e| e.kind).collect();
+        assert!(kinds.contains(&TimelineKind::BacklogFull { source, dest: addr }));
+        assert!(kinds.contains(&TimelineKind::BufferFull {
+            local: client.local_addr().unwrap(),
+            peer: addr,
+        }));
+    }
+
+    #[test]
+    /// Test that connecting from a host whose every port is in use fails rather than aborting
+    /// the simulation, and succeeds again once a connection is closed.
+    fn ports_exhausted() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let network = DeterministicNetwork::new(handle.time_handle(), DescriptorTable::new());
+        let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+        let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 1).into(), 9092);
+                let _listener = server.bind(addr).await.unwrap();
+                let mut gates: Vec<_> = (0..=u16::MAX)
+                    .map(|_| client.connect_gates(addr).unwrap())
+                    .collect();
+                let err = client.connect(addr).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+                let err = client.connect_gates(addr).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+                drop(gates.pop());
+                assert!(client.connect(addr).await.is_ok());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that 10,000 connections can be held open at once by a single host, and all of them
+    /// still carry traffic.
+    fn ten_thousand_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        const CONNECTIONS: usize = 10_000;
+        let (mut runtime, _) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let clients: Vec<_> = (0..CONNECTIONS / 100)
+            .map(|i| runtime.handle(net::Ipv4Addr::new(10, 1, 0, i as u8).into()))
+            .collect();
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let mut pairs = Vec::with_capacity(CONNECTIONS);
+                for i in 0..CONNECTIONS {
+                    let client = clients[i % clients.len()].connect(addr).await.unwrap();
+                    let (accepted, _) = listener.accept().await.unwrap();
+                    pairs.push((client, accepted));
+                }
+                let resources = server.open_resources(server.local_addr());
+                assert_eq!(resources.connections().count(), CONNECTIONS);
+                for (client, accepted) in pairs.iter_mut() {
+                    client.write_all(b"ping").await.unwrap();
+                    let mut buf = [0; 4];
+                    accepted.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(&buf, b"ping");
+                }
+            })
+            .unwrap();
     }
 }
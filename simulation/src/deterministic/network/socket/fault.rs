@@ -1,28 +1,170 @@
 //! Fault injection for AsyncRead/AsyncWrite types.
 
+use super::super::stats::DirectionCounters;
+use super::{CloseOnDrop, SocketHalf};
+use crate::deterministic::profile::{self, Subsystem};
+use crate::deterministic::{
+    Descriptor, DeterministicTimeHandle, FlowStage, FlowTrace, InjectedFault,
+};
 use crate::TcpStream;
-use futures::{task::Waker, FutureExt, Poll};
+use bytes::Bytes;
+use futures::{future, task::Waker, FutureExt, Poll};
 use std::time;
-use std::{io, net, pin::Pin, sync, task::Context};
+use std::{
+    io, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::timer::Delay;
 
 #[derive(Debug)]
 struct FaultState {
+    time: DeterministicTimeHandle,
     send_latency: time::Duration,
-    send_delay: Delay,
+    /// Started the first time a send latency is applied, so streams without latency never
+    /// touch the timer.
+    send_delay: Option<Delay>,
     receive_latency: time::Duration,
-    receive_delay: Delay,
+    receive_delay: Option<Delay>,
     send_clogged: bool,
     send_waker: Option<Waker>,
     receive_clogged: bool,
     receive_waker: Option<Waker>,
     disconnected: bool,
+    /// The fault which disconnected the stream, if it was disconnected by one.
+    fault: Option<InjectedFault>,
+    /// Counts the sends and receives which had to wait.
+    sends: DelayCount,
+    receives: DelayCount,
+}
+
+/// Counts the operations in one direction of a connection which had to wait.
+#[derive(Debug, Default)]
+struct DelayCount {
+    counters: Option<sync::Arc<DirectionCounters>>,
+    /// Set while an operation is waiting, so that it is only counted once however many times
+    /// it is polled.
+    waiting: bool,
+}
+
+impl DelayCount {
+    fn note<T>(&mut self, poll: &Poll<T>) {
+        match poll {
+            Poll::Pending if !self.waiting => {
+                self.waiting = true;
+                if let Some(counters) = &self.counters {
+                    counters.delayed();
+                }
+            }
+            Poll::Pending => {}
+            Poll::Ready(_) => self.waiting = false,
+        }
+    }
+}
+
+impl FaultState {
+    /// Returns the error for operations on the stream once it has been disconnected.
+    fn disconnected_error(&self) -> io::Error {
+        match &self.fault {
+            Some(fault) => fault.clone().into_io(io::ErrorKind::BrokenPipe),
+            None => io::ErrorKind::BrokenPipe.into(),
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let send_latency = self.send_latency;
+        if self.disconnected {
+            return Poll::Ready(Err(self.disconnected_error()));
+        }
+        // If sends are clogged, register a waker to be notified when sends are unclogged
+        // and return pending.
+        if self.send_clogged {
+            self.send_waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+        futures::ready!(poll_latency(&mut self.send_delay, send_latency, cx));
+        // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
+        // return Ready.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let receive_latency = self.receive_latency;
+        if self.disconnected {
+            return Poll::Ready(Err(self.disconnected_error()));
+        }
+        // If receives are clogged, register a waker to be notified when receives are unclogged
+        // and return pending.
+        if self.receive_clogged {
+            self.receive_waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+        futures::ready!(poll_latency(&mut self.receive_delay, receive_latency, cx));
+        // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
+        // return Ready.
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the delay of sends, starting it if no send latency has been applied before.
+    fn send_delay(&mut self) -> &mut Delay {
+        let time = &self.time;
+        self.send_delay
+            .get_or_insert_with(|| time.delay(time.now()))
+    }
+
+    /// Returns the delay of receives, starting it if no receive latency has been applied
+    /// before.
+    fn receive_delay(&mut self) -> &mut Delay {
+        let time = &self.time;
+        self.receive_delay
+            .get_or_insert_with(|| time.delay(time.now()))
+    }
+}
+
+/// Poll `delay` until it passes, then reset it to `latency` past its deadline so the next
+/// operation reflects the latency too. Streams which have never had latency applied have no
+/// delay, and are never held back.
+fn poll_latency(
+    delay: &mut Option<Delay>,
+    latency: time::Duration,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    if let Some(delay) = delay {
+        let deadline = delay.deadline();
+        futures::ready!(delay.poll_unpin(cx));
+        delay.reset(deadline + latency);
+    }
+    Poll::Ready(())
+}
+
+/// The faults applied to one end of a connection.
+#[derive(Debug)]
+struct Faults {
+    /// Set once any fault has been applied. Until then reads and writes go straight to the
+    /// wrapped stream, without locking the state.
+    injected: atomic::AtomicBool,
+    state: sync::Mutex<FaultState>,
+}
+
+impl Faults {
+    /// Returns true if no fault has ever been applied.
+    fn is_pristine(&self) -> bool {
+        !self.injected.load(atomic::Ordering::Acquire)
+    }
+
+    /// Lock the state to apply a fault, taking the stream off the fast path for good.
+    fn inject(&self) -> sync::MutexGuard<'_, FaultState> {
+        let lock = self.state.lock().unwrap();
+        self.injected.store(true, atomic::Ordering::Release);
+        lock
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct FaultyTcpStreamHandle {
-    inner: sync::Arc<sync::Mutex<FaultState>>,
+    inner: sync::Arc<Faults>,
 }
 
 impl FaultyTcpStreamHandle {
@@ -30,43 +172,81 @@ impl FaultyTcpStreamHandle {
         sync::Arc::strong_count(&self.inner) <= 1
     }
     pub fn disconnect(&self) {
-        self.inner.lock().unwrap().disconnected = true;
+        self.disconnect_with(None);
+    }
+    /// Disconnect the stream, failing operations on it with an error caused by `fault`.
+    pub(crate) fn disconnect_with(&self, fault: Option<InjectedFault>) {
+        let mut lock = self.inner.inject();
+        lock.disconnected = true;
+        if lock.fault.is_none() {
+            lock.fault = fault;
+        }
+        if let Some(v) = lock.send_waker.take() {
+            v.wake()
+        }
+        if let Some(v) = lock.receive_waker.take() {
+            v.wake()
+        }
     }
     pub fn set_send_latency(&self, duration: time::Duration) {
-        self.inner.lock().unwrap().send_latency = duration;
+        let mut lock = self.inner.inject();
+        lock.send_latency = duration;
+        lock.send_delay();
     }
     pub fn set_receive_latency(&self, duration: time::Duration) {
-        self.inner.lock().unwrap().receive_latency = duration;
+        let mut lock = self.inner.inject();
+        lock.receive_latency = duration;
+        lock.receive_delay();
+    }
+    /// Set the send latency, delaying the first send as well as those after it.
+    pub(crate) fn delay_sends(&self, duration: time::Duration) {
+        let mut lock = self.inner.inject();
+        lock.send_latency = duration;
+        let delay = lock.send_delay();
+        let deadline = delay.deadline();
+        delay.reset(deadline + duration);
+    }
+
+    /// Count the sends and receives of the stream which have to wait in `outgoing` and
+    /// `incoming`. This is not a fault, so the stream stays on the fast path.
+    pub(crate) fn set_stats(
+        &self,
+        outgoing: sync::Arc<DirectionCounters>,
+        incoming: sync::Arc<DirectionCounters>,
+    ) {
+        let mut lock = self.inner.state.lock().unwrap();
+        lock.sends.counters = Some(outgoing);
+        lock.receives.counters = Some(incoming);
     }
 
     pub fn is_fully_clogged(&self) -> bool {
-        let lock = self.inner.lock().unwrap();
+        let lock = self.inner.state.lock().unwrap();
         lock.send_clogged || lock.receive_clogged
     }
 
     pub fn clog_sends(&self) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.inner.inject();
         lock.send_clogged = true;
         if let Some(v) = lock.send_waker.take() {
             v.wake()
         }
     }
     pub fn clog_receives(&self) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.inner.inject();
         lock.receive_clogged = true;
         if let Some(v) = lock.receive_waker.take() {
             v.wake()
         }
     }
     pub fn unclog_sends(&self) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.inner.inject();
         lock.send_clogged = false;
         if let Some(v) = lock.send_waker.take() {
             v.wake()
         }
     }
     pub fn unclog_receives(&self) {
-        let mut lock = self.inner.lock().unwrap();
+        let mut lock = self.inner.inject();
         lock.receive_clogged = false;
         if let Some(v) = lock.receive_waker.take() {
             v.wake()
@@ -74,41 +254,57 @@ impl FaultyTcpStreamHandle {
     }
 }
 
+/// A stream which faults can be injected into through its [`FaultyTcpStreamHandle`].
+///
+/// Until the first fault is applied, reads and writes go straight to the wrapped stream
+/// without taking a lock, drawing random numbers or starting timers, so connections on links
+/// without faults cost no more than the stream they wrap.
+///
+/// [`FaultyTcpStreamHandle`]:FaultyTcpStreamHandle
 #[derive(Debug)]
 pub struct FaultyTcpStream<T> {
-    handle: crate::deterministic::DeterministicTimeHandle,
     inner: T,
-    fault_state: sync::Arc<sync::Mutex<FaultState>>,
+    fault_state: sync::Arc<Faults>,
+    descriptor: Option<Descriptor>,
+    flows: Option<FlowTrace>,
+    /// Set while a write recorded in a flow has not completed, so that it is recorded once
+    /// however many times it is polled.
+    sending: bool,
 }
 
 impl<T> FaultyTcpStream<T> {
     /// Wrap the provided TcpStream with fault injection support. Calls to poll_* will
     /// first attempt to inject a fault supplied by fault_stream.
     pub fn wrap(
-        handle: crate::deterministic::DeterministicTimeHandle,
+        handle: DeterministicTimeHandle,
         inner: T,
     ) -> (FaultyTcpStream<T>, FaultyTcpStreamHandle) {
-        let send_latency = time::Duration::from_millis(0);
-        let send_delay = handle.delay_from(send_latency);
-        let receive_latency = time::Duration::from_millis(0);
-        let receive_delay = handle.delay_from(send_latency);
         let fault_state = FaultState {
-            send_latency,
-            send_delay,
-            receive_latency,
-            receive_delay,
+            time: handle,
+            send_latency: time::Duration::from_millis(0),
+            send_delay: None,
+            receive_latency: time::Duration::from_millis(0),
+            receive_delay: None,
             send_clogged: false,
             send_waker: None,
             receive_clogged: false,
             receive_waker: None,
             disconnected: false,
+            fault: None,
+            sends: DelayCount::default(),
+            receives: DelayCount::default(),
         };
-        let fault_state = sync::Arc::new(sync::Mutex::new(fault_state));
+        let fault_state = sync::Arc::new(Faults {
+            injected: atomic::AtomicBool::new(false),
+            state: sync::Mutex::new(fault_state),
+        });
 
         let wrapped_stream = FaultyTcpStream {
-            handle,
             inner,
             fault_state: sync::Arc::clone(&fault_state),
+            descriptor: None,
+            flows: None,
+            sending: false,
         };
         let handle = FaultyTcpStreamHandle {
             inner: sync::Arc::clone(&fault_state),
@@ -116,48 +312,118 @@ impl<T> FaultyTcpStream<T> {
         (wrapped_stream, handle)
     }
 
-    fn poll_send_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        let mut lock = self.fault_state.lock().unwrap();
-        let send_latency = lock.send_latency;
-        if lock.disconnected {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+    /// Attach the descriptor held by the host owning this end of the connection.
+    pub(crate) fn set_descriptor(&mut self, descriptor: Descriptor) {
+        self.descriptor.replace(descriptor);
+    }
+
+    /// Record the writes to this end in the flow of the task issuing them, if any.
+    pub(crate) fn set_flows(&mut self, flows: FlowTrace) {
+        self.flows = Some(flows);
+    }
+
+    pub(crate) fn poll_send_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        if self.fault_state.is_pristine() {
+            return Poll::Ready(Ok(()));
         }
-        // If sends are clogged, register a waker to be notified when sends are unclogged
-        // and return pending.
-        if lock.send_clogged {
-            lock.send_waker.replace(cx.waker().clone());
-            return Poll::Pending;
+        let mut lock = self.fault_state.state.lock().unwrap();
+        let poll = lock.poll_send(cx);
+        lock.sends.note(&poll);
+        poll
+    }
+
+    pub(crate) fn poll_receive_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        if self.fault_state.is_pristine() {
+            return Poll::Ready(Ok(()));
         }
-        // Poll the send latency future until it passes. Once it passes, reset the delay to ensure
-        // that future calls to poll_send_delay also reflect the latency.
-        let deadline = lock.send_delay.deadline();
-        futures::ready!(lock.send_delay.poll_unpin(cx));
-        lock.send_delay.reset(deadline + send_latency);
-        // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
-        // return Ready.
-        Poll::Ready(Ok(()))
+        let mut lock = self.fault_state.state.lock().unwrap();
+        let poll = lock.poll_receive(cx);
+        lock.receives.note(&poll);
+        poll
     }
+}
 
-    fn poll_receive_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        let mut lock = self.fault_state.lock().unwrap();
-        let receive_latency = lock.receive_latency;
-        if lock.disconnected {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+impl<T> FaultyTcpStream<T>
+where
+    T: TcpStream,
+{
+    /// Record that a write of `bytes` was issued, unless this write has been recorded already.
+    fn note_send(&mut self, bytes: usize) {
+        if self.sending || bytes == 0 {
+            return;
         }
-        // If receives are clogged, register a waker to be notified when receives are unclogged
-        // and return pending.
-        if lock.receive_clogged {
-            lock.receive_waker.replace(cx.waker().clone());
-            return Poll::Pending;
+        if let (Some(flows), Ok(source), Ok(dest)) =
+            (&self.flows, self.inner.local_addr(), self.inner.peer_addr())
+        {
+            let stage = FlowStage::Sent {
+                source,
+                dest,
+                bytes,
+            };
+            self.sending = flows.record(source.ip(), stage);
         }
-        // Poll the receive latency future until it passes. Once it passes, reset the delay to ensure
-        // that future calls to poll_receive_delay also reflect the latency.
-        let deadline = lock.receive_delay.deadline();
-        futures::ready!(lock.receive_delay.poll_unpin(cx));
-        lock.receive_delay.reset(deadline + receive_latency);
-        // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
-        // return Ready.
-        Poll::Ready(Ok(()))
+    }
+}
+
+impl FaultyTcpStream<SocketHalf> {
+    /// Record that this end was accepted by a listener.
+    pub(crate) fn accepted(&mut self) {
+        self.inner.accepted();
+    }
+
+    /// Reset the connection rather than closing it cleanly: anything either end has not read is
+    /// discarded, and the reads and writes of the peer fail with `ConnectionReset` rather than
+    /// reaching the end of the stream.
+    pub fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    /// Close the connection as `close` describes if this end is dropped without being shut
+    /// down, rather than as the network was configured to.
+    pub fn set_close_on_drop(&mut self, close: CloseOnDrop) {
+        self.inner.set_close_on_drop(close);
+    }
+
+    /// Write `bytes` to the peer, handing the buffer over rather than copying it. Together
+    /// with [`read_bytes`], this lets large payloads cross the simulated network without being
+    /// copied at all.
+    ///
+    /// With strict queues, `bytes` is sent in pieces which fit the peer's buffer.
+    ///
+    /// [`read_bytes`]:FaultyTcpStream::read_bytes
+    pub async fn write_bytes(&mut self, mut bytes: Bytes) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.note_send(bytes.len());
+        let mut sent = Ok(());
+        while !bytes.is_empty() && sent.is_ok() {
+            sent = future::poll_fn(|cx| {
+                let _span = profile::span(Subsystem::Network);
+                futures::ready!(self.poll_send_delay(cx))?;
+                self.inner.poll_send_ready(cx)
+            })
+            .await
+            .and_then(|()| {
+                let _span = profile::span(Subsystem::Network);
+                let len = self.inner.fit(bytes.len());
+                self.inner.send(bytes.split_to(len))
+            });
+        }
+        self.sending = false;
+        sent
+    }
+
+    /// Read up to `max` bytes, returning a slice of the buffer written by the peer rather than
+    /// copying it into one provided by the caller. Returns an empty buffer once the peer has
+    /// closed its half and everything it wrote has been read.
+    pub async fn read_bytes(&mut self, max: usize) -> io::Result<Bytes> {
+        future::poll_fn(|cx| {
+            let _span = profile::span(Subsystem::Network);
+            futures::ready!(self.poll_receive_delay(cx))?;
+            self.inner.poll_read_bytes(cx, max)
+        })
+        .await
     }
 }
 
@@ -170,6 +436,7 @@ where
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
+        let _span = profile::span(Subsystem::Network);
         if let Err(e) = futures::ready!(self.poll_receive_delay(cx)) {
             return Poll::Ready(Err(e));
         }
@@ -186,12 +453,20 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
-            return Poll::Ready(Err(e));
+        let _span = profile::span(Subsystem::Network);
+        self.note_send(buf.len());
+        let poll = match self.poll_send_delay(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_write(cx, buf),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        };
+        if poll.is_ready() {
+            self.sending = false;
         }
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        poll
     }
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let _span = profile::span(Subsystem::Network);
         if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
             return Poll::Ready(Err(e));
         }
@@ -201,6 +476,7 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
+        let _span = profile::span(Subsystem::Network);
         if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
             return Poll::Ready(Err(e));
         }
@@ -224,7 +500,9 @@ where
 mod tests {
     use super::*;
     use crate::deterministic::network::socket::new_socket_pair;
-    use crate::Environment;
+    use crate::SpawnEnv;
+    #[cfg(feature = "quickcheck")]
+    use crate::{NetEnv, TcpListener};
 
     use futures::{SinkExt, StreamExt};
     use std::time;
@@ -233,131 +511,309 @@ mod tests {
     #[test]
     /// Test that injecting delay and disconnect faults causes the socket to delay and disconnect reads.
     fn faults() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let server_addr = "127.0.0.1:9092".parse().unwrap();
-            let client_addr = "127.0.0.1:35255".parse().unwrap();
-            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
-            let (client_conn, client_handle) =
-                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
-            client_handle.set_receive_latency(time::Duration::from_secs(10));
-
-            // spawn a server future which returns a message
-            handle.spawn(async move {
-                let mut transport = Framed::new(server_conn, LinesCodec::new());
-                while let Ok(_) = transport.send(String::from("Hello Future!")).await {}
-            });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                let (client_conn, client_handle) =
+                    FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                client_handle.set_receive_latency(time::Duration::from_secs(10));
 
-            let mut transport = Framed::new(client_conn, LinesCodec::new());
-            let start_time = handle.now();
-            let result = transport.next().await.unwrap().unwrap();
-            assert_eq!(result, String::from("Hello Future!"));
-            let elapsed = handle.now() - start_time;
-            assert!(elapsed >= time::Duration::from_secs(10));
-            client_handle.disconnect();
-
-            let result = transport.next().await.unwrap();
-            assert!(
-                result.is_err(),
-                "expected final stream item to cause a disconnect"
-            );
-        });
+                // spawn a server future which returns a message
+                handle.spawn(async move {
+                    let mut transport = Framed::new(server_conn, LinesCodec::new());
+                    while let Ok(_) = transport.send(String::from("Hello Future!")).await {}
+                });
+
+                let mut transport = Framed::new(client_conn, LinesCodec::new());
+                let start_time = handle.now();
+                let result = transport.next().await.unwrap().unwrap();
+                assert_eq!(result, String::from("Hello Future!"));
+                let elapsed = handle.now() - start_time;
+                assert!(elapsed >= time::Duration::from_secs(10));
+                client_handle.disconnect();
+
+                let result = transport.next().await.unwrap();
+                assert!(
+                    result.is_err(),
+                    "expected final stream item to cause a disconnect"
+                );
+            })
+            .unwrap();
     }
 
     #[test]
     /// tests that send and receives can be clogged/unclogged
     #[allow(unused_must_use)]
     fn clogging() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let server_addr = "127.0.0.1:9092".parse().unwrap();
-            let client_addr = "127.0.0.1:35255".parse().unwrap();
-            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
-            let (client_conn, client_handle) =
-                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
-            // clog both sends and receives
-            client_handle.clog_receives();
-            client_handle.clog_sends();
-
-            // spawn a server future which returns a message when it receives a message
-            handle.spawn(async move {
-                let mut transport = Framed::new(server_conn, LinesCodec::new());
-                while let Some(Ok(_)) = transport.next().await {
-                    transport.send(String::from("Hello Future!")).await.unwrap();
-                }
-            });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                let (client_conn, client_handle) =
+                    FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                // clog both sends and receives
+                client_handle.clog_receives();
+                client_handle.clog_sends();
 
-            let mut transport = Framed::new(client_conn, LinesCodec::new());
-            let send = transport.send(String::from("ping"));
-            futures::pin_mut!(send);
-            tokio_test::assert_pending!(
-                futures::poll!(send.as_mut()),
-                "expected clogged socket to be pending"
-            );
-            client_handle.unclog_sends();
-            tokio_test::assert_ready!(
-                futures::poll!(send),
-                "expected socket send to be ready after unclogging sends"
-            );
-
-            let receive = transport.next();
-            futures::pin_mut!(receive);
-            tokio_test::assert_pending!(
-                futures::poll!(receive.as_mut()),
-                "expected clogged socket to be pending"
-            );
-            client_handle.unclog_receives();
-            let result = receive.await.unwrap().unwrap();
-            assert_eq!(result, String::from("Hello Future!"));
-        });
+                // spawn a server future which returns a message when it receives a message
+                handle.spawn(async move {
+                    let mut transport = Framed::new(server_conn, LinesCodec::new());
+                    while let Some(Ok(_)) = transport.next().await {
+                        transport.send(String::from("Hello Future!")).await.unwrap();
+                    }
+                });
+
+                let mut transport = Framed::new(client_conn, LinesCodec::new());
+                let send = transport.send(String::from("ping"));
+                futures::pin_mut!(send);
+                tokio_test::assert_pending!(
+                    futures::poll!(send.as_mut()),
+                    "expected clogged socket to be pending"
+                );
+                client_handle.unclog_sends();
+                tokio_test::assert_ready!(
+                    futures::poll!(send),
+                    "expected socket send to be ready after unclogging sends"
+                );
+
+                let receive = transport.next();
+                futures::pin_mut!(receive);
+                tokio_test::assert_pending!(
+                    futures::poll!(receive.as_mut()),
+                    "expected clogged socket to be pending"
+                );
+                client_handle.unclog_receives();
+                let result = receive.await.unwrap().unwrap();
+                assert_eq!(result, String::from("Hello Future!"));
+            })
+            .unwrap();
     }
 
     #[test]
     /// Test that injecting no faults allows the socket to behave normally.
     fn inactive_faults() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let server_addr = "127.0.0.1:9092".parse().unwrap();
-            let client_addr = "127.0.0.1:35255".parse().unwrap();
-            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
-            let (client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
-            // spawn a server future which returns a message
-            handle.spawn(async move {
-                let mut transport = Framed::new(server_conn, LinesCodec::new());
-                while let Ok(_) = transport.send(String::from("Hello Future!")).await {}
-            });
-            let mut transport = Framed::new(client_conn, LinesCodec::new());
-            let result = transport.next().await.unwrap().unwrap();
-            assert_eq!(result, String::from("Hello Future!"));
-        });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                let (client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                // spawn a server future which returns a message
+                handle.spawn(async move {
+                    let mut transport = Framed::new(server_conn, LinesCodec::new());
+                    while let Ok(_) = transport.send(String::from("Hello Future!")).await {}
+                });
+                let mut transport = Framed::new(client_conn, LinesCodec::new());
+                let result = transport.next().await.unwrap().unwrap();
+                assert_eq!(result, String::from("Hello Future!"));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a stream without faults never starts a latency delay, and that applying one
+    /// takes it off the fast path.
+    fn fast_path() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                let (client_conn, client_handle) =
+                    FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                handle.spawn(async move {
+                    let mut transport = Framed::new(server_conn, LinesCodec::new());
+                    while transport.send(String::from("Hello Future!")).await.is_ok() {}
+                });
+                let mut transport = Framed::new(client_conn, LinesCodec::new());
+                transport.send(String::from("ping")).await.unwrap();
+                assert!(transport.next().await.unwrap().is_ok());
+                assert!(client_handle.inner.is_pristine());
+                {
+                    let lock = client_handle.inner.state.lock().unwrap();
+                    assert!(lock.send_delay.is_none() && lock.receive_delay.is_none());
+                }
+
+                client_handle.set_receive_latency(time::Duration::from_secs(1));
+                assert!(!client_handle.inner.is_pristine());
+                assert!(transport.next().await.unwrap().is_ok());
+                let lock = client_handle.inner.state.lock().unwrap();
+                assert!(lock.send_delay.is_none() && lock.receive_delay.is_some());
+            })
+            .unwrap();
     }
 
     #[test]
     /// Test that injecting a disconnect fault unblocks poll.
     fn disconnect_unblocks() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let server_addr = "127.0.0.1:9092".parse().unwrap();
-            let client_addr = "127.0.0.1:35255".parse().unwrap();
-            // need to keep _server_conn in scope so that actual disconnects due to drop are not confused with injected ones
-            let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
-            let (client_conn, client_handle) =
-                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
-
-            let mut transport = Framed::new(client_conn, LinesCodec::new());
-            // ensure the transport returns nothing
-            tokio_test::assert_pending!(futures::poll!(transport.next()));
-            // spawn a future to inject a disconnect fault.
-            client_handle.disconnect();
-            let result = transport.next().await.unwrap();
-            assert!(
-                result.is_err(),
-                "expected future to resolve in disconnect error"
-            );
-        });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                // need to keep _server_conn in scope so that actual disconnects due to drop are not confused with injected ones
+                let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+                let (client_conn, client_handle) =
+                    FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+
+                let mut transport = Framed::new(client_conn, LinesCodec::new());
+                // ensure the transport returns nothing
+                tokio_test::assert_pending!(futures::poll!(transport.next()));
+                // spawn a future to inject a disconnect fault.
+                client_handle.disconnect();
+                let result = transport.next().await.unwrap();
+                assert!(
+                    result.is_err(),
+                    "expected future to resolve in disconnect error"
+                );
+            })
+            .unwrap();
+    }
+
+    /// Returns the bytes of a stream made of a write of each size in `writes`, numbered so
+    /// that bytes which are lost, duplicated or moved are told apart.
+    #[cfg(feature = "quickcheck")]
+    fn stream_bytes(writes: &[u16]) -> Vec<Vec<u8>> {
+        let mut next = 0u32;
+        writes
+            .iter()
+            .map(|size| {
+                (0..size % 4097)
+                    .map(|_| {
+                        next += 1;
+                        (next % 251) as u8
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Write `writes` to `client` and read from `server` in reads of the sizes in `reads` until
+    /// the end of the stream, returning true if exactly the bytes written were read.
+    #[cfg(feature = "quickcheck")]
+    async fn transfer<S>(mut client: S, mut server: S, writes: Vec<u16>, reads: Vec<u16>) -> bool
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let writes = stream_bytes(&writes);
+        let write = async move {
+            for bytes in &writes {
+                client.write_all(bytes).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+            writes.concat()
+        };
+        let read = async move {
+            let mut read = vec![];
+            let mut sizes = reads
+                .iter()
+                .map(|size| usize::from(*size) % 4096 + 1)
+                .cycle();
+            loop {
+                let mut buf = vec![0; sizes.next().unwrap_or(1024)];
+                match server.read(&mut buf).await.unwrap() {
+                    0 => return read,
+                    n => read.extend_from_slice(&buf[..n]),
+                }
+            }
+        };
+        let (written, read) = future::join(write, read).await;
+        written == read
+    }
+
+    #[test]
+    #[cfg(feature = "quickcheck")]
+    /// Test that changing the latency of either end and clogging and unclogging it while bytes
+    /// are in flight never loses, duplicates or reorders them, whatever the sizes of the writes
+    /// and reads.
+    fn latency_and_clogs_keep_bytes() {
+        fn property(writes: Vec<u16>, reads: Vec<u16>, faults: Vec<(u8, u16)>) -> bool {
+            let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+                .build()
+                .unwrap();
+            runtime
+                .block_on(async {
+                    let server_addr = "127.0.0.1:9092".parse().unwrap();
+                    let client_addr = "127.0.0.1:35255".parse().unwrap();
+                    let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                    let (client_conn, client_handle) =
+                        FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                    let (server_conn, server_handle) =
+                        FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+                    let time_handle = handle.time_handle();
+                    let inject = async move {
+                        for (kind, millis) in faults {
+                            let duration = time::Duration::from_millis(u64::from(millis % 1000));
+                            match kind % 6 {
+                                0 => client_handle.set_send_latency(duration),
+                                1 => client_handle.set_receive_latency(duration),
+                                2 => server_handle.set_send_latency(duration),
+                                3 => server_handle.set_receive_latency(duration),
+                                4 => client_handle.clog_sends(),
+                                _ => server_handle.clog_receives(),
+                            }
+                            time_handle.delay_from(duration).await;
+                            client_handle.unclog_sends();
+                            server_handle.unclog_receives();
+                        }
+                    };
+                    let transfer = transfer(client_conn, server_conn, writes, reads);
+                    future::join(transfer, inject).await.0
+                })
+                .unwrap()
+        }
+        ::quickcheck::QuickCheck::new()
+            .tests(100)
+            .quickcheck(property as fn(Vec<u16>, Vec<u16>, Vec<(u8, u16)>) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "quickcheck")]
+    /// Test that connections between hosts deliver exactly the bytes written to them under the
+    /// latency and jitter of the network and the latency fault injector, whatever the seed and
+    /// the sizes of the writes and reads.
+    fn network_latency_keeps_bytes() {
+        fn property(seed: u64, latency: u8, writes: Vec<u16>, reads: Vec<u16>) -> bool {
+            let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+                .seed(seed)
+                .latency(time::Duration::from_millis(u64::from(latency)))
+                .jitter(time::Duration::from_millis(u64::from(latency) * 2))
+                .build()
+                .unwrap();
+            handle.spawn(runtime.latency_fault().run());
+            let server = runtime.handle("10.0.0.1".parse().unwrap());
+            let client = runtime.handle("10.0.0.2".parse().unwrap());
+            runtime
+                .block_on(async move {
+                    let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+                    let mut listener = server.bind(addr).await.unwrap();
+                    let (client_conn, accepted) =
+                        future::join(client.connect(addr), listener.accept()).await;
+                    let (server_conn, _) = accepted.unwrap();
+                    transfer(client_conn.unwrap(), server_conn, writes, reads).await
+                })
+                .unwrap()
+        }
+        ::quickcheck::QuickCheck::new()
+            .tests(50)
+            .quickcheck(property as fn(u64, u8, Vec<u16>, Vec<u16>) -> bool);
     }
 }
@@ -1,42 +1,87 @@
-use bytes::{Buf, Bytes, IntoBuf};
-use futures::{channel::mpsc, Future, Poll, Sink, SinkExt, Stream};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use super::capture::PacketCapture;
+use super::stats::{ConnectionCounters, DirectionCounters};
+use crate::deterministic::{escape, flow, EventKind, FlowStage, FlowTrace, Timeline, TimelineKind};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::Poll;
+use std::{
+    fmt, io, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 pub mod fault;
+mod pipe;
 pub use fault::{FaultyTcpStream, FaultyTcpStreamHandle};
+use pipe::Pipe;
 use tracing::{span, trace, Level};
 
+/// Size of the buffers writes are copied into. Each write takes a slice of the current buffer
+/// and passes it to the peer without copying, so a buffer is only allocated once every write
+/// in it has been read, rather than once per write.
+const WRITE_BUFFER: usize = 8 * 1024;
+
+/// What the peer of a connection sees once one of its halves is dropped without having been
+/// shut down. A half which was shut down first always closes cleanly, so the peer reads the
+/// end of the stream once it has read everything written to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CloseOnDrop {
+    /// Close cleanly, unless data written to the dropped half was left unread, in which case
+    /// the connection is reset, as TCP does.
+    #[default]
+    Tcp,
+    /// Always close cleanly.
+    Graceful,
+    /// Always reset the connection, as closing a socket with a linger timeout of zero does.
+    Reset,
+}
+
 /// Returns a client/server socket pair, along with a SocketHandle which can be used to close
 /// either side of the socket halfs.
 pub fn new_socket_pair(
     client_addr: net::SocketAddr,
     server_addr: net::SocketAddr,
 ) -> (SocketHalf, SocketHalf) {
-    let (client_tx, client_rx) = mpsc::channel(8);
-    let (server_tx, server_rx) = mpsc::channel(8);
-    let client_socket = SocketHalf::new(client_addr, server_addr, client_tx, server_rx);
-    let server_socket = SocketHalf::new(server_addr, client_addr, server_tx, client_rx);
+    let to_server = Pipe::default();
+    let to_client = Pipe::default();
+    let client_socket = SocketHalf::new(
+        client_addr,
+        server_addr,
+        to_server.clone(),
+        to_client.clone(),
+    );
+    let server_socket = SocketHalf::new(server_addr, client_addr, to_client, to_server);
     (client_socket, server_socket)
 }
 
 pub struct SocketHalf {
-    tx: mpsc::Sender<Bytes>,
-    rx: mpsc::Receiver<Bytes>,
-    staged: Option<Bytes>,
+    outgoing: Pipe,
+    incoming: Pipe,
+    write_buf: BytesMut,
     shutdown: bool,
+    /// Set once the connection has been reset by this half.
+    reset: bool,
+    close_on_drop: CloseOnDrop,
     local_addr: net::SocketAddr,
     peer_addr: net::SocketAddr,
+    capture: Option<PacketCapture>,
+    timeline: Option<Timeline>,
+    stats: Option<sync::Arc<ConnectionCounters>>,
+    flows: Option<FlowTrace>,
+    /// The flow the connection was opened in, until the half is accepted.
+    accept_flow: Option<u64>,
+    /// Set if writes are cut short to fit the peer's buffer rather than overfilling it.
+    bounded: bool,
+    /// Set while a bounded write is waiting for room, so the wait is only recorded once.
+    blocked: bool,
 }
 
 impl fmt::Debug for SocketHalf {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "SocketHalf {{ local_addr: {}, peer_addr: {}, shutdown: {}, staged: {:?} }}",
-            self.local_addr,
-            self.peer_addr,
-            self.shutdown,
-            self.staged.as_ref().map(|b| b.len())
+            "SocketHalf {{ local_addr: {}, peer_addr: {}, shutdown: {} }}",
+            self.local_addr, self.peer_addr, self.shutdown,
         )
     }
 }
@@ -45,16 +90,25 @@ impl SocketHalf {
     fn new(
         local_addr: net::SocketAddr,
         peer_addr: net::SocketAddr,
-        tx: mpsc::Sender<Bytes>,
-        rx: mpsc::Receiver<Bytes>,
+        outgoing: Pipe,
+        incoming: Pipe,
     ) -> Self {
         Self {
-            tx,
-            rx,
-            staged: None,
+            outgoing,
+            incoming,
+            write_buf: BytesMut::new(),
             shutdown: false,
+            reset: false,
+            close_on_drop: CloseOnDrop::default(),
             local_addr,
             peer_addr,
+            capture: None,
+            timeline: None,
+            stats: None,
+            flows: None,
+            accept_flow: None,
+            bounded: false,
+            blocked: false,
         }
     }
     pub fn local_addr(&self) -> net::SocketAddr {
@@ -63,55 +117,211 @@ impl SocketHalf {
     pub fn peer_addr(&self) -> net::SocketAddr {
         self.peer_addr
     }
+    /// Close the connection as `close` describes if this half is dropped without being shut
+    /// down.
+    pub(crate) fn set_close_on_drop(&mut self, close: CloseOnDrop) {
+        self.close_on_drop = close;
+    }
+    /// Reset the connection, discarding anything either half has not read, so that further
+    /// reads and writes of the peer fail with `ConnectionReset`.
+    pub(crate) fn reset(&mut self) {
+        if self.reset {
+            return;
+        }
+        self.reset = true;
+        self.outgoing.reset();
+        self.incoming.reset();
+        if let Some(capture) = &self.capture {
+            capture.reset(self.local_addr, self.peer_addr);
+        }
+    }
+    /// Record the bytes written to this half in `capture`.
+    pub(crate) fn set_capture(&mut self, capture: PacketCapture) {
+        self.capture = Some(capture);
+    }
+    /// Record the closing of this half in `timeline`, and the chunks it sends in its event
+    /// log.
+    pub(crate) fn set_timeline(&mut self, timeline: Timeline) {
+        self.timeline = Some(timeline);
+    }
+    /// Never buffer more than the peer's buffer holds: writes are cut short to fit, and are
+    /// recorded in the timeline when they are cut short or have to wait for room.
+    pub(crate) fn set_bounded(&mut self) {
+        self.bounded = true;
+    }
+    /// Record the flows of the chunks this half sends and reads in `flows`.
+    pub(crate) fn set_flows(&mut self, flows: FlowTrace) {
+        self.flows = Some(flows);
+    }
+    /// Note that the connection was opened in `flow`, which the task accepting this half joins.
+    pub(crate) fn set_accept_flow(&mut self, flow: Option<u64>) {
+        self.accept_flow = flow;
+    }
+    /// Record that this half was accepted by a listener, joining the flow the connection was
+    /// opened in.
+    pub(crate) fn accepted(&mut self) {
+        if let (Some(flows), Some(flow)) = (&self.flows, self.accept_flow.take()) {
+            let stage = FlowStage::Accepted {
+                source: self.peer_addr,
+                dest: self.local_addr,
+            };
+            flows.record_for(flow, self.local_addr.ip(), stage);
+            flow::adopt(flow);
+        }
+    }
+    /// Record that this half began reading a chunk written in `flow`, joining the flow.
+    fn received(&self, flow: Option<u64>, bytes: usize) {
+        if let (Some(flows), Some(flow)) = (&self.flows, flow) {
+            let stage = FlowStage::Received {
+                source: self.peer_addr,
+                dest: self.local_addr,
+                bytes,
+            };
+            flows.record_for(flow, self.local_addr.ip(), stage);
+            flow::adopt(flow);
+        }
+    }
+    /// Count the bytes written by this half which the peer has not read in `memory`.
+    pub(crate) fn set_memory(&mut self, memory: sync::Arc<atomic::AtomicUsize>) {
+        self.outgoing.set_memory(memory);
+    }
+    /// Count the traffic written by this half in `outgoing`, and note when it is closed in
+    /// `connection`.
+    pub(crate) fn set_stats(
+        &mut self,
+        connection: sync::Arc<ConnectionCounters>,
+        outgoing: sync::Arc<DirectionCounters>,
+    ) {
+        self.outgoing.set_stats(outgoing);
+        self.stats = Some(connection);
+    }
     pub(crate) fn connected(&self) -> bool {
-        !self.tx.is_closed()
-    }
-    /// Attempt to read any staged bytes into `dst`. Returns the number of bytes read, or None if
-    /// no bytes were staged.
-    fn read_staged(&mut self, dst: &mut [u8]) -> Option<usize> {
-        if let Some(mut bytes) = self.staged.take() {
-            debug_assert!(!bytes.is_empty(), "staged bytes should not be empty");
-            let to_write = std::cmp::min(dst.len(), bytes.len());
-            let b = bytes.split_to(to_write);
-            let mut b = b.into_buf();
-            b.copy_to_slice(&mut dst[..to_write]);
-            if !bytes.is_empty() {
-                self.staged.replace(bytes);
-            }
-            Some(to_write)
-        } else {
-            None
+        !self.outgoing.is_closed()
+    }
+    /// Panic unless polled by the runtime of the connection.
+    fn check_executor(&self) {
+        escape::check_executor(|| {
+            format!("connection from {} to {}", self.local_addr, self.peer_addr)
+        });
+    }
+    /// Attempt to take up to `max` bytes written by the peer, slicing them off the chunk it
+    /// wrote rather than copying them.
+    fn poll_read_bytes(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<io::Result<Bytes>> {
+        self.check_executor();
+        let (bytes, flow) = futures::ready!(self.incoming.poll_read_bytes(cx, max))?;
+        self.received(flow, bytes.len());
+        Poll::Ready(Ok(bytes))
+    }
+    /// Wait until there is room to send a chunk to the peer.
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_executor();
+        let poll = self.outgoing.poll_write_ready(cx);
+        if poll.is_pending() && self.bounded && !self.blocked {
+            self.blocked = true;
+            self.buffer_full();
+        } else if poll.is_ready() {
+            self.blocked = false;
+        }
+        poll
+    }
+    /// Returns how many of `len` bytes can be sent to the peer, which is all of them unless
+    /// writes are bounded and the peer's buffer has less room.
+    fn fit(&self, len: usize) -> usize {
+        if !self.bounded {
+            return len;
+        }
+        let room = self.outgoing.room();
+        if len > room {
+            self.buffer_full();
+        }
+        std::cmp::min(len, room)
+    }
+    fn buffer_full(&self) {
+        trace!("buffer to {} is full", self.peer_addr);
+        if let Some(timeline) = &self.timeline {
+            timeline.record(TimelineKind::BufferFull {
+                local: self.local_addr,
+                peer: self.peer_addr,
+            });
+        }
+    }
+    /// Hand `bytes` to the peer, which reads from them directly. Must only be called once
+    /// `poll_send_ready` has returned ready.
+    fn send(&mut self, bytes: Bytes) -> io::Result<()> {
+        let captured = self.capture.as_ref().map(|_| bytes.clone());
+        let len = bytes.len();
+        let flow = self.flows.as_ref().and_then(|_| flow::current());
+        self.outgoing.write(bytes, flow)?;
+        if let (Some(flows), Some(flow)) = (&self.flows, flow) {
+            let stage = FlowStage::Delivered {
+                source: self.local_addr,
+                dest: self.peer_addr,
+                bytes: len,
+            };
+            flows.record_for(flow, self.peer_addr.ip(), stage);
+        }
+        if let Some(timeline) = &self.timeline {
+            timeline.events().record(EventKind::Sent {
+                local: self.local_addr,
+                peer: self.peer_addr,
+                len,
+            });
+        }
+        if let (Some(capture), Some(bytes)) = (&self.capture, captured) {
+            capture.data(self.local_addr, self.peer_addr, &bytes);
+        }
+        Ok(())
+    }
+    /// Copy `buf` into the write buffer, returning it as a chunk which can be sent to the peer.
+    fn write_chunk(&mut self, buf: &[u8]) -> Bytes {
+        if self.write_buf.remaining_mut() < buf.len() {
+            // reclaims the current buffer if every chunk taken from it has been dropped.
+            let additional = std::cmp::max(buf.len(), WRITE_BUFFER);
+            self.write_buf.reserve(additional);
+        }
+        self.write_buf.put_slice(buf);
+        self.write_buf.take().freeze()
+    }
+}
+
+impl Drop for SocketHalf {
+    fn drop(&mut self) {
+        let reset = !self.shutdown
+            && match self.close_on_drop {
+                CloseOnDrop::Tcp => self.incoming.has_unread(),
+                CloseOnDrop::Graceful => false,
+                CloseOnDrop::Reset => true,
+            };
+        if reset {
+            self.reset();
+        }
+        self.outgoing.close_write();
+        self.incoming.close_read();
+        if let Some(stats) = &self.stats {
+            stats.close();
+        }
+        if let Some(timeline) = &self.timeline {
+            timeline.record(TimelineKind::Closed {
+                local: self.local_addr,
+                peer: self.peer_addr,
+            });
         }
     }
 }
 
 impl AsyncRead for SocketHalf {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         dst: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        // span! macro seems to trip up clippy here
-        #![allow(clippy::cognitive_complexity)]
-        span!(Level::TRACE, "AsyncRead::poll_read", "{:?}", self).in_scope(|| loop {
+        span!(Level::TRACE, "AsyncRead::poll_read", "{:?}", self).in_scope(|| {
             trace!("attempting to read {} bytes", dst.len());
-            if let Some(bytes_read) = self.read_staged(dst) {
-                trace!("read {} bytes", bytes_read);
-                return Poll::Ready(Ok(bytes_read));
-            }
-
-            trace!("no bytes staged");
-            let stream = Pin::new(&mut self.rx);
-            match futures::ready!(stream.poll_next(cx)) {
-                Some(new_bytes) => {
-                    trace!("found staged bytes");
-                    self.staged.replace(new_bytes)
-                }
-                None => {
-                    trace!("socket disconnected");
-                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-                }
-            };
+            self.check_executor();
+            let (bytes_read, flow) = futures::ready!(self.incoming.poll_read(cx, dst))?;
+            self.received(flow, bytes_read);
+            trace!("read {} bytes", bytes_read);
+            Poll::Ready(Ok(bytes_read))
         })
     }
 }
@@ -123,36 +333,30 @@ impl AsyncWrite for SocketHalf {
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         span!(Level::TRACE, "AsyncWrite::poll_write", "{:?}", self).in_scope(|| {
-            let size = buf.len();
-            let bytes: Bytes = buf.into();
-            trace!("writing {} bytes", size);
-            let send = self.tx.send(bytes);
-            futures::pin_mut!(send);
-            match futures::ready!(send.poll(cx)) {
-                Ok(()) => Poll::Ready(Ok(size)),
-                Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
-            }
+            trace!("writing {} bytes", buf.len());
+            // wait for room before copying, so pending writes don't allocate.
+            futures::ready!(self.poll_send_ready(cx))?;
+            let size = self.fit(buf.len());
+            let bytes = self.write_chunk(&buf[..size]);
+            self.send(bytes)?;
+            Poll::Ready(Ok(size))
         })
     }
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        span!(Level::TRACE, "AsyncWrite::poll_flush", "{:?}", self).in_scope(|| {
-            trace!("flushing");
-            let stream = &mut self.tx;
-            futures::pin_mut!(stream);
-            stream
-                .poll_flush(cx)
-                .map_err(|_| io::ErrorKind::BrokenPipe.into())
-        })
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        // writes are handed to the peer as soon as they are accepted.
+        Poll::Ready(Ok(()))
     }
-    fn poll_shutdown(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Result<(), io::Error>> {
-        span!(Level::TRACE, "AsyncWrite::poll_flush", "{:?}", self).in_scope(|| {
+    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        span!(Level::TRACE, "AsyncWrite::poll_shutdown", "{:?}", self).in_scope(|| {
             trace!("shutting down");
-            Pin::new(&mut self.tx)
-                .poll_close(cx)
-                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+            self.outgoing.close_write();
+            if !self.shutdown {
+                self.shutdown = true;
+                if let Some(capture) = &self.capture {
+                    capture.shutdown(self.local_addr, self.peer_addr);
+                }
+            }
+            Poll::Ready(Ok(()))
         })
     }
 }
@@ -169,8 +373,9 @@ impl crate::TcpStream for SocketHalf {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Environment;
+    use crate::SpawnEnv;
     use futures::{FutureExt, SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     async fn pong_server(
         server: SocketHalf,
@@ -196,29 +401,33 @@ mod tests {
     #[test]
     /// Tests that messages can be sent and received using a pair of MemoryStreams.
     fn test_ping_pong() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
-        runtime.block_on(async {
-            let server_addr = "127.0.0.1:9092".parse().unwrap();
-            let client_addr = "127.0.0.1:35255".parse().unwrap();
-            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
-            handle.spawn(pong_server(server_conn, None).map(|_| ()));
-            let mut transport =
-                tokio::codec::Framed::new(client_conn, tokio::codec::LinesCodec::new());
-            for _ in 0..100usize {
-                transport.send(String::from("ping")).await.unwrap();
-                let result = transport.next().await.unwrap().unwrap();
-                assert_eq!(result, String::from("pong"));
-            }
-        });
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                handle.spawn(pong_server(server_conn, None).map(|_| ()));
+                let mut transport =
+                    tokio::codec::Framed::new(client_conn, tokio::codec::LinesCodec::new());
+                for _ in 0..100usize {
+                    transport.send(String::from("ping")).await.unwrap();
+                    let result = transport.next().await.unwrap().unwrap();
+                    assert_eq!(result, String::from("pong"));
+                }
+            })
+            .unwrap();
     }
 
     #[test]
-    /// Tests that disconnecting the server and client will cause both the server and client to fail further
-    /// reads/writes with an error.
+    /// Tests that the server closing its half will cause the client to reach the end of the stream, and
+    /// fail writes after the first one with an error.
     fn test_disconnect() {
-        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
-        let handle = runtime.localhost_handle();
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
         runtime.block_on(async {
             let server_addr = "127.0.0.1:9092".parse().unwrap();
             let client_addr = "127.0.0.1:35255".parse().unwrap();
@@ -240,14 +449,185 @@ mod tests {
                     }
                     num if num == 2 => {
                         assert!(send_result.is_ok(), "expected send to succeed");
-                        assert!(transport.next().await.unwrap().is_err(), "msg num 2 should cause the server to close, resulting in an err returned by the receive")
+                        assert!(transport.next().await.is_none(), "msg num 2 should cause the server to close, ending the stream of the receive")
+                    }
+                    3 => {
+                        assert!(send_result.is_ok(), "the first send after the server closed is accepted, as the server only answers it with a reset");
                     }
                     _ => {
-                        assert!(send_result.is_err(), "now that the server is closed, sends should always fail");
+                        assert!(send_result.is_err(), "now that the server has answered with a reset, sends should always fail");
                     }
                 }
             }
             server_status.await.unwrap();
-        });
+        }).unwrap();
+    }
+
+    #[test]
+    /// Tests that a stream of writes of varying sizes is read back intact through reads of a
+    /// different size, as chunks are taken from and returned to the write buffer.
+    fn test_large_transfer() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+                let expected = data.clone();
+                handle.spawn(async move {
+                    let mut written = 0;
+                    for size in [1, 7, 100, 4096, 20_000].iter().cycle() {
+                        let end = std::cmp::min(written + size, data.len());
+                        client_conn.write_all(&data[written..end]).await.unwrap();
+                        written = end;
+                        if written == data.len() {
+                            break;
+                        }
+                    }
+                });
+                let mut received = vec![0; expected.len()];
+                for chunk in received.chunks_mut(3000) {
+                    server_conn.read_exact(chunk).await.unwrap();
+                }
+                assert_eq!(received, expected);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Tests that buffers written with `write_bytes` are read back with `read_bytes` as slices
+    /// of the same allocation, rather than copies of it.
+    fn test_zero_copy() {
+        let (mut runtime, handle) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                let (mut client_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+                let (mut server_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+                let data = Bytes::from(vec![7; 64 * 1024]);
+                let start = data.as_ptr() as usize;
+                client_conn.write_bytes(data.clone()).await.unwrap();
+
+                let mut offset = 0;
+                while offset < data.len() {
+                    let chunk = server_conn.read_bytes(10_000).await.unwrap();
+                    assert_eq!(chunk.as_ptr() as usize, start + offset);
+                    assert_eq!(&chunk[..], &data[offset..offset + chunk.len()]);
+                    offset += chunk.len();
+                }
+                assert_eq!(offset, data.len());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Tests that the peer of a half which was shut down reads the end of the stream once it has
+    /// read everything written, while a reset or a half dropped with data left unread fails the
+    /// reads and writes of its peer, unless configured to close cleanly.
+    fn close_on_drop() {
+        let (mut runtime, _) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                client_conn.write_all(b"bye").await.unwrap();
+                client_conn.shutdown().await.unwrap();
+                drop(client_conn);
+                let mut received = vec![];
+                server_conn.read_to_end(&mut received).await.unwrap();
+                assert_eq!(received, b"bye");
+                assert_eq!(server_conn.read(&mut [0; 8]).await.unwrap(), 0);
+
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                server_conn.write_all(b"unread").await.unwrap();
+                client_conn.write_all(b"lost").await.unwrap();
+                drop(client_conn);
+                let error = server_conn.read(&mut [0; 8]).await.unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+                let error = server_conn.write_all(b"more").await.unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                server_conn.write_all(b"unread").await.unwrap();
+                client_conn.write_all(b"kept").await.unwrap();
+                client_conn.set_close_on_drop(CloseOnDrop::Graceful);
+                drop(client_conn);
+                let mut received = vec![];
+                server_conn.read_to_end(&mut received).await.unwrap();
+                assert_eq!(received, b"kept");
+
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                client_conn.reset();
+                let error = server_conn.read(&mut [0; 8]).await.unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Tests that writes to a peer which has gone away fail with the same sequence of errors a
+    /// kernel returns: after a clean close, the first write is accepted and later ones fail with
+    /// `BrokenPipe`, while after a reset, the first fails with `ConnectionReset` instead.
+    fn write_after_close() {
+        let (mut runtime, _) = crate::deterministic::DeterministicRuntime::builder()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(async {
+                let server_addr = "127.0.0.1:9092".parse().unwrap();
+                let client_addr = "127.0.0.1:35255".parse().unwrap();
+                let kinds = |results: Vec<io::Result<()>>| -> Vec<_> {
+                    results
+                        .into_iter()
+                        .map(|result| result.map_err(|error| error.kind()))
+                        .collect()
+                };
+
+                let (mut client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+                drop(server_conn);
+                let mut results = vec![];
+                for _ in 0..3 {
+                    results.push(client_conn.write_all(b"ping").await);
+                }
+                assert_eq!(
+                    kinds(results),
+                    vec![
+                        Ok(()),
+                        Err(io::ErrorKind::BrokenPipe),
+                        Err(io::ErrorKind::BrokenPipe)
+                    ]
+                );
+
+                let (mut client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+                server_conn.reset();
+                let mut results = vec![];
+                for _ in 0..2 {
+                    results.push(client_conn.write_all(b"ping").await);
+                }
+                assert_eq!(
+                    kinds(results),
+                    vec![
+                        Err(io::ErrorKind::ConnectionReset),
+                        Err(io::ErrorKind::BrokenPipe)
+                    ]
+                );
+
+                let (mut client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+                client_conn.shutdown().await.unwrap();
+                let error = client_conn.write_all(b"ping").await.unwrap_err();
+                assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+            })
+            .unwrap();
     }
 }
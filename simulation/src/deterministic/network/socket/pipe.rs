@@ -0,0 +1,333 @@
+//! One direction of a simulated connection.
+use super::super::stats::DirectionCounters;
+use bytes::Bytes;
+use futures::Poll;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{self, atomic},
+    task::Context,
+    task::Waker,
+};
+
+/// Number of bytes a pipe buffers before writes wait for the reader to catch up.
+const CAPACITY: usize = 64 * 1024;
+
+/// A write queued for the reader.
+#[derive(Debug)]
+struct Chunk {
+    bytes: Bytes,
+    /// The flow the write was made in, until the reader starts reading it.
+    flow: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    chunks: VecDeque<Chunk>,
+    buffered: usize,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    /// Set once the writing half has shut down or been dropped.
+    write_closed: bool,
+    /// Set once the reading half has been dropped.
+    read_closed: bool,
+    /// Set once the connection has been reset, failing reads and writes alike.
+    reset: bool,
+    /// Set once a write has been accepted after the reading half was dropped.
+    answered: bool,
+    /// Set once a write has failed, after which every write fails with `BrokenPipe`.
+    failed: bool,
+    /// Counts the bytes buffered by every pipe of the network.
+    memory: Option<sync::Arc<atomic::AtomicUsize>>,
+    /// Counts the traffic through the pipe.
+    stats: Option<sync::Arc<DirectionCounters>>,
+}
+
+impl State {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    /// Account for `len` bytes taken by the reader, waking a writer waiting for room once half
+    /// of the pipe has been drained.
+    fn consumed(&mut self, len: usize) {
+        self.buffered -= len;
+        if let Some(memory) = &self.memory {
+            memory.fetch_sub(len, atomic::Ordering::Relaxed);
+        }
+        if let Some(stats) = &self.stats {
+            stats.consumed(len);
+        }
+        if self.buffered <= CAPACITY / 2 {
+            self.wake_writer();
+        }
+    }
+
+    /// Remove the front chunk, which has been read in full.
+    fn pop_delivered(&mut self) -> Option<Bytes> {
+        if let Some(stats) = &self.stats {
+            stats.delivered();
+        }
+        self.chunks.pop_front().map(|chunk| chunk.bytes)
+    }
+
+    /// Returns the error the next write fails with, if it fails. As with a kernel, the first
+    /// write after the reader has gone away is accepted, as the peer only answers it with a
+    /// reset, and later writes fail with `BrokenPipe`. A reset fails the first write with
+    /// `ConnectionReset` instead, and later ones with `BrokenPipe` too.
+    fn write_error(&mut self) -> Option<io::Error> {
+        let kind = if self.write_closed || self.failed {
+            io::ErrorKind::BrokenPipe
+        } else if self.reset {
+            io::ErrorKind::ConnectionReset
+        } else if self.read_closed && self.answered {
+            io::ErrorKind::BrokenPipe
+        } else {
+            return None;
+        };
+        self.failed = true;
+        Some(kind.into())
+    }
+
+    /// Discard the queued chunks, which will never be read.
+    fn discard(&mut self) {
+        if let Some(stats) = &self.stats {
+            stats.dropped(self.chunks.len());
+        }
+        self.chunks.clear();
+        let buffered = self.buffered;
+        self.consumed(buffered);
+    }
+
+    /// Wait for a chunk to be written to the empty pipe, returning the end of the stream once
+    /// the writer has closed it, or failing if the connection was reset.
+    fn poll_empty<T>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>>
+    where
+        T: Default,
+    {
+        if self.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.write_closed {
+            return Poll::Ready(Ok(T::default()));
+        }
+        self.reader.replace(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// One direction of a simulated connection, carrying the chunks written to one half to the
+/// reads of the other.
+///
+/// Chunks written before the reader runs again are delivered together: the reader is woken
+/// once for all of them, and reads them in turn without waiting to be woken again. A writer
+/// waiting for room is only woken once the reader has drained half of the pipe, so a stream
+/// of small writes does not wake either side once per write.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Pipe {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Pipe {
+    /// Count the bytes buffered by the pipe in `memory`.
+    pub(crate) fn set_memory(&self, memory: sync::Arc<atomic::AtomicUsize>) {
+        let mut state = self.state.lock().unwrap();
+        memory.fetch_add(state.buffered, atomic::Ordering::Relaxed);
+        state.memory = Some(memory);
+    }
+
+    /// Count the traffic through the pipe in `stats`.
+    pub(crate) fn set_stats(&self, stats: sync::Arc<DirectionCounters>) {
+        self.state.lock().unwrap().stats = Some(stats);
+    }
+
+    /// Returns true if either end of the pipe has been closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.write_closed || state.read_closed || state.reset
+    }
+
+    /// Returns true if chunks are queued which the reader has not read.
+    pub(crate) fn has_unread(&self) -> bool {
+        !self.state.lock().unwrap().chunks.is_empty()
+    }
+
+    /// Wait until there is room in the pipe for another chunk.
+    pub(crate) fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = state.write_error() {
+            return Poll::Ready(Err(error));
+        }
+        if state.buffered >= CAPACITY {
+            state.writer.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the number of bytes which can be queued before the pipe is full.
+    pub(crate) fn room(&self) -> usize {
+        CAPACITY.saturating_sub(self.state.lock().unwrap().buffered)
+    }
+
+    /// Queue `bytes`, written in `flow`, for the reader. Must only be called once
+    /// `poll_write_ready` has returned ready, but never waits for room itself. Once the reader
+    /// has gone away, the one write still accepted is discarded rather than queued.
+    pub(crate) fn write(&self, bytes: Bytes, flow: Option<u64>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(error) = state.write_error() {
+            return Err(error);
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if state.read_closed {
+            state.answered = true;
+            if let Some(stats) = &state.stats {
+                stats.written(bytes.len());
+                stats.consumed(bytes.len());
+                stats.dropped(1);
+            }
+            return Ok(());
+        }
+        state.buffered += bytes.len();
+        if let Some(memory) = &state.memory {
+            memory.fetch_add(bytes.len(), atomic::Ordering::Relaxed);
+        }
+        if let Some(stats) = &state.stats {
+            stats.written(bytes.len());
+        }
+        state.chunks.push_back(Chunk { bytes, flow });
+        state.wake_reader();
+        Ok(())
+    }
+
+    /// Copy as many bytes of the front chunk as fit into `dst`, along with the flow it was
+    /// written in if this is the first read of it. Reads never span chunks, so the boundaries
+    /// between writes are preserved unless a read is shorter than a write.
+    pub(crate) fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<(usize, Option<u64>)>> {
+        let mut state = self.state.lock().unwrap();
+        let chunk = match state.chunks.front_mut() {
+            Some(chunk) => chunk,
+            None => return state.poll_empty(cx),
+        };
+        let flow = chunk.flow.take();
+        let len = std::cmp::min(dst.len(), chunk.bytes.len());
+        dst[..len].copy_from_slice(&chunk.bytes[..len]);
+        chunk.bytes.advance(len);
+        if chunk.bytes.is_empty() {
+            state.pop_delivered();
+        }
+        state.consumed(len);
+        Poll::Ready(Ok((len, flow)))
+    }
+
+    /// Take up to `max` queued bytes from the front chunk, slicing rather than copying them,
+    /// along with the flow it was written in if this is the first read of it.
+    pub(crate) fn poll_read_bytes(
+        &self,
+        cx: &mut Context<'_>,
+        max: usize,
+    ) -> Poll<io::Result<(Bytes, Option<u64>)>> {
+        let mut state = self.state.lock().unwrap();
+        let (bytes, flow) = match state.chunks.front_mut() {
+            Some(chunk) if chunk.bytes.len() > max => {
+                (chunk.bytes.split_to(max), chunk.flow.take())
+            }
+            Some(chunk) => {
+                let flow = chunk.flow.take();
+                (state.pop_delivered().unwrap(), flow)
+            }
+            None => return state.poll_empty(cx),
+        };
+        state.consumed(bytes.len());
+        Poll::Ready(Ok((bytes, flow)))
+    }
+
+    /// Close the writing end, so the reader reaches the end of the stream once it has read every
+    /// queued chunk.
+    pub(crate) fn close_write(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.write_closed = true;
+        state.wake_reader();
+    }
+
+    /// Close the reading end, discarding queued chunks and failing further writes.
+    pub(crate) fn close_read(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.read_closed = true;
+        state.discard();
+    }
+
+    /// Reset the pipe, discarding queued chunks and failing further reads and writes.
+    pub(crate) fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reset = true;
+        state.discard();
+        state.wake_reader();
+        state.wake_writer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+
+    impl ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    /// Test that the reader is woken once for a batch of writes, and a full pipe only wakes the
+    /// writer once half of it has been read.
+    fn batched_wakeups() {
+        let pipe = Pipe::default();
+        let reader = sync::Arc::new(CountWakes::default());
+        let writer = sync::Arc::new(CountWakes::default());
+        let reader_waker = waker(reader.clone());
+        let writer_waker = waker(writer.clone());
+        let mut reader_cx = Context::from_waker(&reader_waker);
+        let mut writer_cx = Context::from_waker(&writer_waker);
+        let mut buf = [0; 1024];
+
+        assert!(pipe.poll_read(&mut reader_cx, &mut buf).is_pending());
+        let mut writes = 0;
+        while pipe.poll_write_ready(&mut writer_cx).is_ready() {
+            pipe.write(Bytes::from(vec![writes as u8; 1024]), None)
+                .unwrap();
+            writes += 1;
+        }
+        assert_eq!(writes, CAPACITY / 1024);
+        assert_eq!(reader.0.load(Ordering::SeqCst), 1);
+
+        for read in 0..writes {
+            match pipe.poll_read(&mut reader_cx, &mut buf) {
+                Poll::Ready(Ok((1024, None))) => assert_eq!(buf[0], read as u8),
+                other => panic!("unexpected read {:?}", other),
+            }
+            let woken = if read + 1 < writes / 2 { 0 } else { 1 };
+            assert_eq!(writer.0.load(Ordering::SeqCst), woken);
+        }
+        assert!(pipe.poll_read(&mut reader_cx, &mut buf).is_pending());
+        assert_eq!(reader.0.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,270 @@
+//! Statistics of simulated connections.
+//!
+//! Every connection established with [`NetEnv::connect`] counts the bytes and chunks sent each way,
+//! how many chunks were delivered, how many were discarded unread when the reading end was
+//! closed, and how many were held back by latency or a clog, along with when the connection
+//! was established and closed. Statistics are kept after the connection is closed, so tests
+//! can assert over the traffic between two hosts during a run, such as replication traffic
+//! staying under a budget while a partition was in place. Those of a closed connection are
+//! kept until a new connection is established between the same addresses, which happens once
+//! its source port is reused, so the statistics kept are bounded by the number of connections
+//! open at once rather than growing with every connection of the run.
+//!
+//! [`NetEnv::connect`]:crate::NetEnv::connect
+use crate::deterministic::{InFlight, TimeReader};
+use std::{
+    net,
+    sync::{self, atomic},
+    time,
+};
+
+/// Traffic in one direction of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectionStats {
+    /// Bytes written.
+    pub bytes: u64,
+    /// Chunks written which the reading end read in full. Each write is sent as one chunk.
+    pub delivered: u64,
+    /// Chunks discarded without being read in full, because the reading end was closed.
+    pub dropped: u64,
+    /// Writes and reads which had to wait for latency to pass or a clog to clear.
+    pub delayed: u64,
+}
+
+/// Statistics of a connection from `source` to `dest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub source: net::SocketAddr,
+    pub dest: net::SocketAddr,
+    /// Simulated time elapsed since the runtime was created when the connection was
+    /// established.
+    pub established: time::Duration,
+    /// Simulated time elapsed since the runtime was created when either end of the connection
+    /// was closed, if it has been.
+    pub closed: Option<time::Duration>,
+    /// Traffic from `source` to `dest`.
+    pub sent: DirectionStats,
+    /// Traffic from `dest` to `source`.
+    pub received: DirectionStats,
+}
+
+impl ConnectionStats {
+    /// Returns how long the connection was open for, if it has been closed.
+    pub fn lifetime(&self) -> Option<time::Duration> {
+        self.closed.map(|closed| closed - self.established)
+    }
+}
+
+/// Counters of one direction of a connection, shared by the pipe carrying it and the ends
+/// writing and reading it.
+#[derive(Debug, Default)]
+pub(crate) struct DirectionCounters {
+    bytes: atomic::AtomicU64,
+    delivered: atomic::AtomicU64,
+    dropped: atomic::AtomicU64,
+    delayed: atomic::AtomicU64,
+    /// Chunks written.
+    chunks: atomic::AtomicU64,
+    /// Bytes read or discarded.
+    consumed: atomic::AtomicU64,
+}
+
+impl DirectionCounters {
+    pub(crate) fn written(&self, len: usize) {
+        self.bytes.fetch_add(len as u64, atomic::Ordering::Relaxed);
+        self.chunks.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn consumed(&self, len: usize) {
+        self.consumed
+            .fetch_add(len as u64, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn delivered(&self) {
+        self.delivered.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self, chunks: usize) {
+        self.dropped
+            .fetch_add(chunks as u64, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn delayed(&self) {
+        self.delayed.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    /// Returns the chunks and bytes written which have been neither read nor discarded.
+    fn in_flight(&self) -> (u64, u64) {
+        let load = |count: &atomic::AtomicU64| count.load(atomic::Ordering::Relaxed);
+        let chunks = load(&self.chunks) - load(&self.delivered) - load(&self.dropped);
+        (chunks, load(&self.bytes) - load(&self.consumed))
+    }
+
+    fn stats(&self) -> DirectionStats {
+        DirectionStats {
+            bytes: self.bytes.load(atomic::Ordering::Relaxed),
+            delivered: self.delivered.load(atomic::Ordering::Relaxed),
+            dropped: self.dropped.load(atomic::Ordering::Relaxed),
+            delayed: self.delayed.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters of a connection, kept by the network after the connection is closed until a new
+/// connection between the same addresses replaces them.
+#[derive(Debug)]
+pub(crate) struct ConnectionCounters {
+    source: net::SocketAddr,
+    dest: net::SocketAddr,
+    time: TimeReader,
+    established: time::Duration,
+    closed: sync::Mutex<Option<time::Duration>>,
+    pub(crate) sent: sync::Arc<DirectionCounters>,
+    pub(crate) received: sync::Arc<DirectionCounters>,
+}
+
+impl ConnectionCounters {
+    pub(crate) fn new(source: net::SocketAddr, dest: net::SocketAddr, time: TimeReader) -> Self {
+        Self {
+            source,
+            dest,
+            established: time.elapsed(),
+            time,
+            closed: sync::Mutex::new(None),
+            sent: sync::Arc::default(),
+            received: sync::Arc::default(),
+        }
+    }
+
+    /// Returns true if the connection runs between `a` and `b`, in either direction.
+    pub(crate) fn is_between(&self, a: net::IpAddr, b: net::IpAddr) -> bool {
+        let (source, dest) = (self.source.ip(), self.dest.ip());
+        (source == a && dest == b) || (source == b && dest == a)
+    }
+
+    /// Note that an end of the connection was closed, unless the other already was.
+    pub(crate) fn close(&self) {
+        let mut closed = self.closed.lock().unwrap();
+        if closed.is_none() {
+            *closed = Some(self.time.elapsed());
+        }
+    }
+
+    /// Returns the messages in flight in each direction of the connection.
+    pub(crate) fn in_flight(&self) -> Vec<InFlight> {
+        let directions = [
+            (self.source, self.dest, &self.sent),
+            (self.dest, self.source, &self.received),
+        ];
+        directions
+            .iter()
+            .filter_map(|(source, dest, counters)| {
+                let (chunks, bytes) = counters.in_flight();
+                if chunks == 0 {
+                    return None;
+                }
+                Some(InFlight {
+                    source: *source,
+                    dest: *dest,
+                    chunks,
+                    bytes,
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            source: self.source,
+            dest: self.dest,
+            established: self.established,
+            closed: *self.closed.lock().unwrap(),
+            sent: self.sent.stats(),
+            received: self.received.stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that bytes and chunks sent each way, delays, drops and the lifetime of a connection
+    /// are counted, and kept once the connection is closed.
+    fn connection_stats() {
+        let (mut runtime, handle) = DeterministicRuntime::builder()
+            .latency(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let server = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let mut socket = client.connect(addr).await.unwrap();
+                let (mut accepted, source) = listener.accept().await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+                socket.write_all(b"world").await.unwrap();
+                accepted.read_exact(&mut [0; 5]).await.unwrap();
+                accepted.write_all(b"ack").await.unwrap();
+                client.delay_from(Duration::from_secs(1)).await;
+                drop(accepted);
+                drop(socket);
+
+                let stats = client.connection_stats(source, addr).unwrap();
+                assert_eq!(
+                    stats.sent,
+                    DirectionStats {
+                        bytes: 10,
+                        delivered: 1,
+                        dropped: 1,
+                        delayed: 2,
+                    }
+                );
+                assert_eq!(stats.received.bytes, 3);
+                assert_eq!(stats.received.dropped, 1);
+                assert_eq!(stats.lifetime(), Some(Duration::from_millis(1200)));
+                assert_eq!(
+                    server.connection_stats_between(server.local_addr(), client.local_addr()),
+                    vec![stats]
+                );
+                assert!(client.connection_stats(addr, source).is_none());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that the statistics of closed connections are replaced by those of newer
+    /// connections between the same addresses, while the run still counts all of them.
+    fn replaced_stats() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let server = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                for _ in 0..100 {
+                    let mut socket = client.connect(addr).await.unwrap();
+                    let (accepted, _) = listener.accept().await.unwrap();
+                    socket.write_all(b"hello").await.unwrap();
+                    drop(accepted);
+                    drop(socket);
+                }
+                let kept =
+                    server.connection_stats_between(server.local_addr(), client.local_addr());
+                assert_eq!(kept.len(), 1);
+                assert_eq!(kept[0].sent.bytes, 5);
+            })
+            .unwrap();
+        let summary = runtime.summary();
+        assert_eq!(summary.connections, 100);
+        assert_eq!(summary.bytes, 500);
+    }
+}
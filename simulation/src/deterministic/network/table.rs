@@ -0,0 +1,107 @@
+use super::fault::Connection;
+use std::{collections::BTreeMap, net};
+
+/// Connections below this count are never collected on insertion.
+const MIN_COLLECT: usize = 64;
+
+/// The live connections of a simulated network, in the order they were established.
+///
+/// Connections are indexed by their source address, so that establishing a connection costs
+/// `O(log n)` rather than a scan of every connection. Dropped connections are collected once the
+/// table has doubled in size since the last collection, keeping the cost amortized constant.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTable {
+    connections: BTreeMap<u64, Connection>,
+    sources: BTreeMap<net::SocketAddr, u64>,
+    next_id: u64,
+    collected: usize,
+    /// For each address, the lowest of the ports up to `u16::MAX` which were all found taken by
+    /// live connections.
+    taken: BTreeMap<net::IpAddr, u16>,
+}
+
+impl ConnectionTable {
+    /// Returns true if a connection from `source` is in the table.
+    pub(crate) fn contains_source(&self, source: net::SocketAddr) -> bool {
+        self.sources.contains_key(&source)
+    }
+
+    /// Insert `connection`, collecting dropped connections first if the table has grown enough.
+    pub(crate) fn insert(&mut self, connection: Connection) {
+        if self.connections.len() >= MIN_COLLECT.max(self.collected * 2) {
+            self.gc_dropped();
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sources.insert(connection.source(), id);
+        self.connections.insert(id, connection);
+    }
+
+    /// Find the highest port on `addr` which no live connection uses as its source, releasing
+    /// the port of a dropped connection if that is the highest. Returns `None` if every port is
+    /// used by a live connection.
+    ///
+    /// The ports above the last one handed out are remembered as taken, so handing out ports
+    /// one after another doesn't scan them again. A connection on one of those ports which is
+    /// dropped in the meantime only releases its port once dropped connections are collected.
+    pub(crate) fn unused_port(&mut self, addr: net::IpAddr) -> Option<u16> {
+        if let Some(port) = self.scan_port(addr) {
+            return Some(port);
+        }
+        // the ports remembered as taken may have been released since.
+        self.gc_dropped();
+        self.scan_port(addr)
+    }
+
+    fn scan_port(&mut self, addr: net::IpAddr) -> Option<u16> {
+        let mut port = match self.taken.get(&addr) {
+            Some(0) => return None,
+            Some(taken) => taken - 1,
+            None => u16::MAX,
+        };
+        let range = net::SocketAddr::new(addr, 0)..=net::SocketAddr::new(addr, port);
+        let mut released = None;
+        for (source, id) in self.sources.range(range).rev() {
+            if source.port() != port {
+                break;
+            }
+            if self.connections[id].is_dropped() {
+                released = Some((*source, *id));
+                break;
+            }
+            self.taken.insert(addr, port);
+            // every port of the address is taken by a live connection.
+            if port == 0 {
+                return None;
+            }
+            port -= 1;
+        }
+        if let Some((source, id)) = released {
+            self.sources.remove(&source);
+            self.connections.remove(&id);
+        }
+        Some(port)
+    }
+
+    /// Remove all connections which have been dropped by either end.
+    pub(crate) fn gc_dropped(&mut self) {
+        let sources = &mut self.sources;
+        self.connections.retain(|_, connection| {
+            let dropped = connection.is_dropped();
+            if dropped {
+                sources.remove(&connection.source());
+            }
+            !dropped
+        });
+        self.taken.clear();
+        self.collected = self.connections.len();
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.values()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.connections.values_mut()
+    }
+}
@@ -0,0 +1,428 @@
+//! Extension point for simulating transports which are not shaped like TCP.
+//!
+//! A [`Transport`] creates both ends of each connection made over it, such as the queue pairs
+//! of a simulated RDMA link or a ring buffer shared between hosts. The network takes care of
+//! the rest: listeners are bound to and connected to by address, connections are recorded in the
+//! timeline and subject to the same faults as TCP connections, and all of them are
+//! disconnected when either host is killed. Endpoints observe faults through their
+//! [`TransportGate`], which each operation must pass through before taking effect.
+//!
+//! Listeners and both ends of each connection hold a descriptor of their host, as those of TCP
+//! do, so connecting, binding and accepting fail with `EMFILE` once a host is at its
+//! descriptor limit. As operations over a transport carry no bytes the network can see,
+//! transport connections have no [`ConnectionStats`], are left out of packet captures and
+//! flows, and do not count towards the traffic of the network.
+//!
+//! Transports have their own address space per transport type, so a transport listener and
+//! a TCP listener can be bound to the same address.
+//!
+//! [`Transport`]:Transport
+//! [`TransportGate`]:TransportGate
+//! [`ConnectionStats`]:super::ConnectionStats
+use super::{listen::Place, socket::FaultyTcpStream};
+use crate::deterministic::{Descriptor, DescriptorTable, Resource};
+use futures::{channel::mpsc, future, lock, StreamExt};
+use std::{
+    any, fmt, io, net,
+    sync::{self, atomic},
+};
+
+/// A user implemented transport which can be plugged into the simulated network.
+pub trait Transport: Send + 'static {
+    /// One end of a connection over this transport.
+    type Endpoint: Send + 'static;
+
+    /// Create both ends of a new connection. The client end must pass its operations through
+    /// `client`, and the server end through `server`, for faults to be injected into them.
+    ///
+    /// This is called while the network is locked, so it must not use the simulated network.
+    fn pair(
+        &mut self,
+        client: TransportGate,
+        server: TransportGate,
+    ) -> (Self::Endpoint, Self::Endpoint);
+}
+
+/// One end of a connection over a [`Transport`]. Operations performed through the gate are
+/// delayed by injected latency, held back while the connection is clogged and fail once it is
+/// disconnected. Operations in each direction pass through the gate one at a time, in order.
+///
+/// Cloned gates share the same end of the connection.
+///
+/// [`Transport`]:Transport
+#[derive(Debug, Clone)]
+pub struct TransportGate {
+    local_addr: net::SocketAddr,
+    peer_addr: net::SocketAddr,
+    stream: sync::Arc<FaultyTcpStream<()>>,
+    send: sync::Arc<lock::Mutex<()>>,
+    receive: sync::Arc<lock::Mutex<()>>,
+    descriptor: sync::Arc<sync::Mutex<Option<Descriptor>>>,
+}
+
+impl TransportGate {
+    pub(crate) fn new(
+        local_addr: net::SocketAddr,
+        peer_addr: net::SocketAddr,
+        stream: FaultyTcpStream<()>,
+    ) -> Self {
+        Self {
+            local_addr,
+            peer_addr,
+            stream: sync::Arc::new(stream),
+            send: sync::Arc::new(lock::Mutex::new(())),
+            receive: sync::Arc::new(lock::Mutex::new(())),
+            descriptor: sync::Arc::default(),
+        }
+    }
+
+    /// Hold `descriptor` open until every clone of this end has been dropped.
+    pub(crate) fn set_descriptor(&self, descriptor: Descriptor) {
+        self.descriptor.lock().unwrap().replace(descriptor);
+    }
+
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.peer_addr
+    }
+
+    /// Wait until an operation sending data from this end may take effect.
+    pub async fn send(&self) -> io::Result<()> {
+        let _send = self.send.lock().await;
+        future::poll_fn(|cx| self.stream.poll_send_delay(cx)).await
+    }
+
+    /// Wait until an operation receiving data at this end may take effect.
+    pub async fn receive(&self) -> io::Result<()> {
+        let _receive = self.receive.lock().await;
+        future::poll_fn(|cx| self.stream.poll_receive_delay(cx)).await
+    }
+}
+
+/// Listener for connections over a [`Transport`], returned by
+/// [`DeterministicRuntimeHandle::bind_transport`].
+///
+/// [`Transport`]:Transport
+/// [`DeterministicRuntimeHandle::bind_transport`]:crate::deterministic::DeterministicRuntimeHandle::bind_transport
+pub struct TransportListener<T>
+where
+    T: Transport,
+{
+    local_addr: net::SocketAddr,
+    rx: mpsc::UnboundedReceiver<Arrival<T>>,
+    _descriptor: Descriptor,
+    descriptors: DescriptorTable,
+}
+
+impl<T> fmt::Debug for TransportListener<T>
+where
+    T: Transport,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransportListener {{ local_addr: {} }}", self.local_addr)
+    }
+}
+
+impl<T> TransportListener<T>
+where
+    T: Transport,
+{
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Accept the server end of the next connection, along with the address of the client.
+    /// Fails once the host the listener is bound on has been killed, and with `EMFILE` if the
+    /// host is at its descriptor limit, in which case the connection is dropped.
+    pub async fn accept(&mut self) -> io::Result<(T::Endpoint, net::SocketAddr)> {
+        match self.rx.next().await {
+            Some((endpoint, gate, addr, _place)) => {
+                let resource = Resource::Connection {
+                    local_addr: self.local_addr,
+                    peer_addr: addr,
+                };
+                let descriptor = self.descriptors.allocate(self.local_addr.ip(), resource)?;
+                gate.set_descriptor(descriptor);
+                Ok((endpoint, addr))
+            }
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+/// A transport bound to an address, stored by the network regardless of its type.
+pub(crate) trait Bound: Send {
+    /// Returns true if the listener has been dropped.
+    fn is_closed(&self) -> bool;
+    /// Returns the number of connections to the listener which it has not accepted yet.
+    fn queued(&self) -> usize;
+    fn as_any(&self) -> &dyn any::Any;
+    fn as_any_mut(&mut self) -> &mut dyn any::Any;
+}
+
+impl fmt::Debug for dyn Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bound {{ closed: {} }}", self.is_closed())
+    }
+}
+
+/// A connection on its way to a transport listener: its server end, the gate the server end
+/// holds its descriptor through once accepted, the address of the client and its place in the
+/// listener's queue.
+pub(crate) type Arrival<T> = (
+    <T as Transport>::Endpoint,
+    TransportGate,
+    net::SocketAddr,
+    Place,
+);
+
+pub(crate) struct Registration<T>
+where
+    T: Transport,
+{
+    pub(crate) transport: T,
+    pub(crate) tx: mpsc::UnboundedSender<Arrival<T>>,
+    /// Counts the connections to the listener which it has not accepted yet.
+    pub(crate) queued: sync::Arc<atomic::AtomicUsize>,
+}
+
+impl<T> Registration<T>
+where
+    T: Transport,
+{
+    pub(crate) fn new(
+        local_addr: net::SocketAddr,
+        transport: T,
+        descriptor: Descriptor,
+        descriptors: DescriptorTable,
+    ) -> (Self, TransportListener<T>) {
+        let (tx, rx) = mpsc::unbounded();
+        let registration = Self {
+            transport,
+            tx,
+            queued: sync::Arc::default(),
+        };
+        let listener = TransportListener {
+            local_addr,
+            rx,
+            _descriptor: descriptor,
+            descriptors,
+        };
+        (registration, listener)
+    }
+}
+
+impl<T> Bound for Registration<T>
+where
+    T: Transport,
+{
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(atomic::Ordering::Relaxed)
+    }
+
+    fn as_any(&self) -> &dyn any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::{DeterministicRuntime, TimelineKind},
+        SpawnEnv,
+    };
+
+    /// A transport of byte messages, queued in memory.
+    struct Queue;
+
+    #[derive(Debug)]
+    struct QueueEndpoint {
+        gate: TransportGate,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl QueueEndpoint {
+        async fn send(&self, message: Vec<u8>) -> io::Result<()> {
+            self.gate.send().await?;
+            self.tx
+                .unbounded_send(message)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        async fn recv(&mut self) -> io::Result<Vec<u8>> {
+            let message = self.rx.next().await.ok_or(io::ErrorKind::BrokenPipe)?;
+            self.gate.receive().await?;
+            Ok(message)
+        }
+    }
+
+    impl Transport for Queue {
+        type Endpoint = QueueEndpoint;
+
+        fn pair(
+            &mut self,
+            client: TransportGate,
+            server: TransportGate,
+        ) -> (QueueEndpoint, QueueEndpoint) {
+            let (client_tx, server_rx) = mpsc::unbounded();
+            let (server_tx, client_rx) = mpsc::unbounded();
+            let client = QueueEndpoint {
+                gate: client,
+                tx: client_tx,
+                rx: client_rx,
+            };
+            let server = QueueEndpoint {
+                gate: server,
+                tx: server_tx,
+                rx: server_rx,
+            };
+            (client, server)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            io::Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A transport without any connections.
+    struct Other;
+
+    impl Transport for Other {
+        type Endpoint = ();
+
+        fn pair(&mut self, _: TransportGate, _: TransportGate) -> ((), ()) {
+            ((), ())
+        }
+    }
+
+    #[test]
+    /// Test that connections over a custom transport are established by address, and are
+    /// disconnected when the server is killed.
+    fn custom_transport() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 4791);
+        runtime
+            .block_on(async {
+                let mut listener = server.bind_transport(addr, Queue).unwrap();
+                assert!(server.bind_transport(addr, Queue).is_err());
+                server.spawn(async move {
+                    while let Ok((mut endpoint, _)) = listener.accept().await {
+                        while let Ok(message) = endpoint.recv().await {
+                            endpoint.send(message).await.unwrap();
+                        }
+                    }
+                });
+
+                let mut endpoint = client.connect_transport::<Queue>(addr).unwrap();
+                assert_eq!(endpoint.gate.peer_addr(), addr);
+                endpoint.send(b"ping".to_vec()).await.unwrap();
+                assert_eq!(endpoint.recv().await.unwrap(), b"ping");
+
+                let unbound = net::SocketAddr::new(server.local_addr(), 4792);
+                let refused = client.connect_transport::<Queue>(unbound).unwrap_err();
+                assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+                let refused = client.connect_transport::<Other>(addr).unwrap_err();
+                assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+
+                server.kill(server.local_addr());
+                assert!(endpoint.send(b"ping".to_vec()).await.is_err());
+                assert!(client.connect_transport::<Queue>(addr).is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that transport listeners and both ends of transport connections hold descriptors
+    /// of their hosts, so connecting and accepting fail with `EMFILE` at the limit.
+    fn transport_descriptors() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 4791);
+        runtime
+            .block_on(async {
+                server.set_descriptor_limit(server.local_addr(), Some(1));
+                client.set_descriptor_limit(client.local_addr(), Some(1));
+                let mut listener = server.bind_transport(addr, Queue).unwrap();
+                let other = net::SocketAddr::new(server.local_addr(), 4792);
+                let err = server.bind_transport(other, Queue).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(24));
+
+                let endpoint = client.connect_transport::<Queue>(addr).unwrap();
+                let err = client.connect_transport::<Queue>(addr).unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(24));
+                // the server is out of descriptors, so the connection is dropped.
+                let err = listener.accept().await.unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(24));
+
+                drop(endpoint);
+                assert_eq!(client.open_descriptors(client.local_addr()), 0);
+                server.set_descriptor_limit(server.local_addr(), None);
+                let _endpoint = client.connect_transport::<Queue>(addr).unwrap();
+                let (accepted, _) = listener.accept().await.unwrap();
+                assert_eq!(server.open_descriptors(server.local_addr()), 2);
+                drop(accepted);
+                assert_eq!(server.open_descriptors(server.local_addr()), 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that transport connections are recorded in the timeline, but have no statistics
+    /// and are left out of packet captures.
+    fn transport_bookkeeping() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let buffer = Buffer::default();
+        runtime.capture_packets(buffer.clone()).unwrap();
+        let timeline = runtime.record_timeline();
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 4791);
+        let source = runtime
+            .block_on(async {
+                let mut listener = server.bind_transport(addr, Queue).unwrap();
+                let endpoint = client.connect_transport::<Queue>(addr).unwrap();
+                let (mut accepted, _) = listener.accept().await.unwrap();
+                endpoint.send(b"ping".to_vec()).await.unwrap();
+                assert_eq!(accepted.recv().await.unwrap(), b"ping");
+                endpoint.gate.local_addr()
+            })
+            .unwrap();
+
+        let handle = runtime.handle(source.ip());
+        assert_eq!(handle.connection_stats(source, addr), None);
+        assert!(handle
+            .connection_stats_between(source.ip(), addr.ip())
+            .is_empty());
+        // the capture holds nothing but its header.
+        assert_eq!(buffer.0.lock().unwrap().len(), 24);
+        let connected = TimelineKind::Connected { source, dest: addr };
+        assert!(timeline
+            .entries()
+            .iter()
+            .any(|entry| entry.kind == connected));
+    }
+}
@@ -0,0 +1,413 @@
+//! Exporting a run as OpenTelemetry traces.
+//!
+//! [`to_otlp_json`] turns the events kept in memory into an OTLP/JSON trace export request,
+//! the body an OpenTelemetry collector accepts on `/v1/traces`, so a run can be browsed in
+//! Jaeger or Tempo alongside traces from production. The run is a single trace, whose id is
+//! derived from the seed, rooted at a span covering the whole run which holds the faults
+//! injected as events. Each host is a resource of its own, named after its hostname if it has
+//! one, with a span for the host holding a span for the lifetime of each of its tasks, each of
+//! its timers which fired and each connection it established. Polls of a task and chunks sent
+//! over a connection are events on their span.
+//!
+//! Timestamps are the simulated wall clock time, which starts at the same instant in every
+//! run. Spans still open when the export is taken end at the last event, marked with the
+//! `simulation.unfinished` attribute.
+//!
+//! [`to_otlp_json`]:crate::deterministic::DeterministicRuntime::to_otlp_json
+use super::{time::EPOCH, timeline, EventKind, EventRecord, TimelineKind};
+use std::{collections, fmt::Write as _, net, time};
+
+/// Id of the span covering the whole run.
+const RUN_SPAN: u64 = 1;
+
+/// An attribute of a span, resource or event, with its value as an OTLP `AnyValue`.
+type Attribute = (&'static str, String);
+
+fn string(value: impl ToString) -> String {
+    format!(
+        "{{\"stringValue\":\"{}\"}}",
+        timeline::escape(&value.to_string())
+    )
+}
+
+fn int(value: impl Into<i64>) -> String {
+    format!("{{\"intValue\":\"{}\"}}", value.into())
+}
+
+fn boolean(value: bool) -> String {
+    format!("{{\"boolValue\":{}}}", value)
+}
+
+/// Returns simulated time `at` as nanoseconds since the Unix epoch.
+fn unix_nanos(at: time::Duration) -> u128 {
+    (EPOCH + at).as_nanos()
+}
+
+fn write_attributes(json: &mut String, attributes: &[Attribute]) {
+    json.push_str("\"attributes\":[");
+    for (i, (key, value)) in attributes.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "{{\"key\":\"{}\",\"value\":{}}}", key, value);
+    }
+    json.push(']');
+}
+
+#[derive(Debug)]
+struct Event {
+    at: time::Duration,
+    name: &'static str,
+    attributes: Vec<Attribute>,
+}
+
+#[derive(Debug)]
+struct Span {
+    id: u64,
+    parent: Option<u64>,
+    name: String,
+    start: time::Duration,
+    end: Option<time::Duration>,
+    attributes: Vec<Attribute>,
+    events: Vec<Event>,
+}
+
+impl Span {
+    fn new(id: u64, parent: Option<u64>, name: String, start: time::Duration) -> Self {
+        Self {
+            id,
+            parent,
+            name,
+            start,
+            end: None,
+            attributes: vec![],
+            events: vec![],
+        }
+    }
+
+    /// Write the span to `json`, ending it at `last` if it has not ended.
+    fn write(&self, json: &mut String, trace_id: &str, last: time::Duration) {
+        let _ = write!(
+            json,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{:016x}\",",
+            trace_id, self.id
+        );
+        if let Some(parent) = self.parent {
+            let _ = write!(json, "\"parentSpanId\":\"{:016x}\",", parent);
+        }
+        let _ = write!(
+            json,
+            "\"name\":\"{}\",\"kind\":1,\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",",
+            timeline::escape(&self.name),
+            unix_nanos(self.start),
+            unix_nanos(self.end.unwrap_or(last))
+        );
+        let mut attributes = self.attributes.clone();
+        if self.end.is_none() {
+            attributes.push(("simulation.unfinished", boolean(true)));
+        }
+        write_attributes(json, &attributes);
+        json.push_str(",\"events\":[");
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"timeUnixNano\":\"{}\",\"name\":\"{}\",",
+                unix_nanos(event.at),
+                event.name
+            );
+            write_attributes(json, &event.attributes);
+            json.push('}');
+        }
+        json.push_str("]}");
+    }
+}
+
+/// What a span which has not ended covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Task(net::IpAddr, u64),
+    Timer(net::IpAddr, u64),
+    Connection(net::SocketAddr, net::SocketAddr),
+}
+
+/// Builds the spans of each host from the events of a run.
+#[derive(Debug, Default)]
+struct Export {
+    /// Spans of each host, starting with the span of the host itself.
+    hosts: collections::BTreeMap<net::IpAddr, Vec<Span>>,
+    /// Spans which have not ended, with their host and index among its spans.
+    open: collections::HashMap<Key, (net::IpAddr, usize)>,
+    faults: Vec<Event>,
+    next_id: u64,
+    last: time::Duration,
+}
+
+impl Export {
+    /// Returns the spans of `host`, starting the span of the host on its first appearance.
+    fn host(&mut self, host: net::IpAddr, at: time::Duration) -> &mut Vec<Span> {
+        if !self.hosts.contains_key(&host) {
+            let id = self.id();
+            let span = Span::new(id, Some(RUN_SPAN), format!("host {}", host), at);
+            self.hosts.insert(host, vec![span]);
+        }
+        self.hosts.get_mut(&host).unwrap()
+    }
+
+    fn id(&mut self) -> u64 {
+        self.next_id += 1;
+        RUN_SPAN + self.next_id
+    }
+
+    fn begin(
+        &mut self,
+        key: Key,
+        host: net::IpAddr,
+        name: String,
+        at: time::Duration,
+        attributes: Vec<Attribute>,
+    ) {
+        let id = self.id();
+        let spans = self.host(host, at);
+        let mut span = Span::new(id, Some(spans[0].id), name, at);
+        span.attributes = attributes;
+        spans.push(span);
+        let index = spans.len() - 1;
+        self.open.insert(key, (host, index));
+    }
+
+    fn span(&mut self, key: Key) -> Option<&mut Span> {
+        let (host, index) = *self.open.get(&key)?;
+        self.hosts.get_mut(&host).map(|spans| &mut spans[index])
+    }
+
+    fn end(&mut self, key: Key, at: time::Duration) {
+        if let Some(span) = self.span(key) {
+            span.end = Some(at);
+        }
+        self.open.remove(&key);
+    }
+
+    fn add(&mut self, event: &EventRecord) {
+        let at = event.at;
+        self.last = at;
+        match &event.kind {
+            EventKind::Timeline(TimelineKind::TaskSpawned { host, task }) => self.begin(
+                Key::Task(*host, *task),
+                *host,
+                format!("task {}", task),
+                at,
+                vec![("simulation.task", int(*task as i64))],
+            ),
+            EventKind::Timeline(TimelineKind::TaskExited {
+                host,
+                task,
+                cancelled,
+            }) => {
+                let key = Key::Task(*host, *task);
+                if let Some(span) = self.span(key) {
+                    span.attributes
+                        .push(("simulation.cancelled", boolean(*cancelled)));
+                }
+                self.end(key, at);
+            }
+            EventKind::TaskPolled { host, task } => {
+                if let Some(span) = self.span(Key::Task(*host, *task)) {
+                    span.events.push(Event {
+                        at,
+                        name: "poll",
+                        attributes: vec![],
+                    });
+                }
+            }
+            EventKind::TimerSet { host, timer } => self.begin(
+                Key::Timer(*host, *timer),
+                *host,
+                format!("timer {}", timer),
+                at,
+                vec![("simulation.timer", int(*timer as i64))],
+            ),
+            EventKind::TimerFired { host, timer } => self.end(Key::Timer(*host, *timer), at),
+            EventKind::Timeline(TimelineKind::Connected { source, dest }) => self.begin(
+                Key::Connection(*source, *dest),
+                source.ip(),
+                format!("{} -> {}", source, dest),
+                at,
+                vec![
+                    ("simulation.source", string(source)),
+                    ("simulation.dest", string(dest)),
+                ],
+            ),
+            EventKind::Timeline(TimelineKind::Closed { local, peer }) => {
+                // the span ends when either half is closed.
+                self.end(Key::Connection(*local, *peer), at);
+                self.end(Key::Connection(*peer, *local), at);
+            }
+            EventKind::Sent { local, peer, len } => {
+                let sent = Event {
+                    at,
+                    name: "sent",
+                    attributes: vec![
+                        ("simulation.source", string(local)),
+                        ("simulation.bytes", int(*len as i64)),
+                    ],
+                };
+                let mut key = Key::Connection(*local, *peer);
+                if !self.open.contains_key(&key) {
+                    key = Key::Connection(*peer, *local);
+                }
+                if let Some(span) = self.span(key) {
+                    span.events.push(sent);
+                }
+            }
+            EventKind::Timeline(TimelineKind::HostExited { host, code }) => {
+                self.host(*host, at)[0].events.push(Event {
+                    at,
+                    name: "exited",
+                    attributes: vec![("simulation.exit_code", int(*code))],
+                })
+            }
+            EventKind::Timeline(TimelineKind::Fault { kind, target }) => self.faults.push(Event {
+                at,
+                name: "fault",
+                attributes: vec![
+                    ("simulation.fault.kind", string(kind)),
+                    ("simulation.fault.target", string(target)),
+                ],
+            }),
+            EventKind::Timeline(TimelineKind::TimeAdvanced)
+            | EventKind::Timeline(TimelineKind::BacklogFull { .. })
+            | EventKind::Timeline(TimelineKind::BufferFull { .. })
+            | EventKind::RandomDraw { .. } => {}
+        }
+    }
+}
+
+/// Returns `events` of the run seeded with `seed` as an OTLP/JSON trace export request, with
+/// the resource of each host named after its entry in `hostnames`, if any.
+pub(crate) fn otlp_json(
+    seed: u64,
+    hostnames: &collections::HashMap<net::IpAddr, String>,
+    events: &[EventRecord],
+) -> String {
+    let mut export = Export::default();
+    for event in events {
+        export.add(event);
+    }
+    let trace_id = format!("{:016x}{:016x}", seed, RUN_SPAN);
+    let last = export.last;
+    for spans in export.hosts.values_mut() {
+        spans[0].end = Some(last);
+    }
+    let mut run = Span::new(RUN_SPAN, None, "simulation".to_string(), Default::default());
+    run.end = Some(last);
+    run.attributes = vec![("simulation.seed", int(seed as i64))];
+    run.events = export.faults;
+
+    let mut json = String::from("{\"resourceSpans\":[");
+    let resources = std::iter::once((None, vec![run])).chain(
+        export
+            .hosts
+            .into_iter()
+            .map(|(host, spans)| (Some(host), spans)),
+    );
+    for (i, (host, spans)) in resources.enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let mut attributes = vec![("simulation.seed", int(seed as i64))];
+        match host {
+            Some(host) => {
+                let hostname = hostnames.get(&host);
+                let service = hostname.cloned().unwrap_or_else(|| host.to_string());
+                attributes.push(("service.name", string(service)));
+                if let Some(hostname) = hostname {
+                    attributes.push(("host.name", string(hostname)));
+                }
+                attributes.push(("host.ip", string(host)));
+            }
+            None => attributes.push(("service.name", string("simulation"))),
+        }
+        json.push_str("\n{\"resource\":{");
+        write_attributes(&mut json, &attributes);
+        json.push_str("},\"scopeSpans\":[{\"scope\":{\"name\":\"simulation\"},\"spans\":[");
+        for (i, span) in spans.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push('\n');
+            span.write(&mut json, &trace_id, last);
+        }
+        json.push_str("\n]}]}");
+    }
+    json.push_str("\n]}\n");
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::{net, time::Duration};
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    /// Test that hosts are exported as resources holding spans for their tasks, timers and
+    /// connections, in a single trace with simulated timestamps.
+    fn export() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(5).build().unwrap();
+        runtime.record_events(1000);
+        let server = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                server.set_hostname(server.local_addr(), "server");
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let connector = client.clone();
+                client.spawn(async move {
+                    connector.delay_from(Duration::from_secs(1)).await;
+                    let mut socket = connector.connect(addr).await.unwrap();
+                    socket.write_all(b"hello").await.unwrap();
+                });
+                let (_socket, _) = listener.accept().await.unwrap();
+                server.delay_from(Duration::from_secs(1)).await;
+                handle.kill(client.local_addr());
+            })
+            .unwrap();
+
+        let json = runtime.to_otlp_json();
+        assert!(json.starts_with("{\"resourceSpans\":[\n{\"resource\":{\"attributes\":["));
+        assert!(json.ends_with("\n]}]}\n]}\n"));
+        let trace = "{\"traceId\":\"00000000000000050000000000000001\",";
+        for fragment in &[
+            "{\"key\":\"service.name\",\"value\":{\"stringValue\":\"server\"}},\
+             {\"key\":\"host.name\",\"value\":{\"stringValue\":\"server\"}},\
+             {\"key\":\"host.ip\",\"value\":{\"stringValue\":\"10.0.0.1\"}}",
+            "{\"key\":\"service.name\",\"value\":{\"stringValue\":\"10.0.0.2\"}},\
+             {\"key\":\"host.ip\",\"value\":{\"stringValue\":\"10.0.0.2\"}}",
+            "\"spanId\":\"0000000000000001\",\"name\":\"simulation\",\"kind\":1,\
+             \"startTimeUnixNano\":\"1577836800000000000\",\
+             \"endTimeUnixNano\":\"1577836802000000000\"",
+            "{\"timeUnixNano\":\"1577836802000000000\",\"name\":\"fault\",\"attributes\":[\
+             {\"key\":\"simulation.fault.kind\",\"value\":{\"stringValue\":\"Kill\"}},",
+            "\"spanId\":\"0000000000000003\",\"parentSpanId\":\"0000000000000001\",\
+             \"name\":\"host 10.0.0.2\"",
+            "\"spanId\":\"0000000000000002\",\"parentSpanId\":\"0000000000000003\",\
+             \"name\":\"task 0\",\"kind\":1,\"startTimeUnixNano\":\"1577836800000000000\",\
+             \"endTimeUnixNano\":\"1577836801000000000\"",
+            "\"name\":\"timer 0\",\"kind\":1,\"startTimeUnixNano\":\"1577836800000000000\",\
+             \"endTimeUnixNano\":\"1577836801000000000\"",
+            "\"name\":\"10.0.0.2:65535 -> 10.0.0.1:9092\"",
+            "{\"timeUnixNano\":\"1577836801000000000\",\"name\":\"sent\",\"attributes\":[\
+             {\"key\":\"simulation.source\",\"value\":{\"stringValue\":\"10.0.0.2:65535\"}},\
+             {\"key\":\"simulation.bytes\",\"value\":{\"intValue\":\"5\"}}]}",
+        ] {
+            assert!(json.contains(fragment), "{} not in {}", fragment, json);
+        }
+        assert_eq!(json.matches(trace).count(), 7);
+        assert!(!json.contains("simulation.unfinished"));
+    }
+}
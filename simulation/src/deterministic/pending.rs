@@ -0,0 +1,155 @@
+//! Listing what a simulation is waiting on.
+//!
+//! A run which stalls, or which a test wants to step through, is easiest to reason about in
+//! terms of the work still outstanding. [`pending_work`] takes a snapshot of the delays which
+//! have not fired, the tasks which are runnable and those blocked until something wakes them,
+//! and the messages written to connections which have not yet been read. Once no task is
+//! runnable and no message is in flight, the only way the run can make progress is for time to
+//! advance to the next timer.
+//!
+//! [`pending_work`]:crate::deterministic::DeterministicRuntimeHandle::pending_work
+use std::{net, time};
+
+/// A delay which has not yet fired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingTimer {
+    /// The host which started the delay.
+    pub host: net::IpAddr,
+    /// Identifies the delay, as in the event log.
+    pub id: u64,
+    pub deadline: time::Instant,
+}
+
+/// A task which has not yet completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingTask {
+    /// The host the task was spawned on behalf of.
+    pub host: net::IpAddr,
+    /// Identifies the task, as in the event log.
+    pub id: u64,
+    /// The name the task was spawned with, if any.
+    pub name: Option<String>,
+    /// True if the task has been woken, or never polled, and is waiting for the executor to
+    /// poll it. False if it is blocked until something wakes it.
+    pub runnable: bool,
+}
+
+/// Messages written to one direction of a connection which have not yet been read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlight {
+    /// The end which wrote the messages.
+    pub source: net::SocketAddr,
+    /// The end which is yet to read them.
+    pub dest: net::SocketAddr,
+    /// Writes which have not been read in full.
+    pub chunks: u64,
+    /// Bytes which have not been read.
+    pub bytes: u64,
+}
+
+/// A snapshot of the work a simulation is waiting on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingWork {
+    /// Delays which have not fired, earliest deadline first.
+    pub timers: Vec<PendingTimer>,
+    /// Tasks which have not completed, ordered by host and then by id.
+    pub tasks: Vec<PendingTask>,
+    /// Messages in flight, in the order their connections were established.
+    pub messages: Vec<InFlight>,
+}
+
+impl PendingWork {
+    /// Returns the tasks which are waiting for the executor to poll them.
+    pub fn runnable(&self) -> impl Iterator<Item = &PendingTask> {
+        self.tasks.iter().filter(|task| task.runnable)
+    }
+
+    /// Returns the tasks which are blocked until something wakes them.
+    pub fn blocked(&self) -> impl Iterator<Item = &PendingTask> {
+        self.tasks.iter().filter(|task| !task.runnable)
+    }
+
+    /// Returns true if no task is runnable and no message is in flight, so the run can only
+    /// progress by advancing time.
+    pub fn is_quiescent(&self) -> bool {
+        self.runnable().next().is_none() && self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::{net, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that pending timers, runnable and blocked tasks and messages in flight are listed.
+    fn pending_work() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let sleeper = client.clone();
+                client.spawn_named("sleeper", async move {
+                    sleeper.delay_from(Duration::from_secs(10)).await;
+                });
+                // give the sleeper a chance to start its delay.
+                let mut yielded = false;
+                futures::future::poll_fn(|cx| {
+                    if yielded {
+                        return futures::Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    futures::Poll::Pending
+                })
+                .await;
+                let mut socket = client.connect(addr).await.unwrap();
+                let (mut accepted, source) = listener.accept().await.unwrap();
+                let (tx, rx) = futures::channel::oneshot::channel();
+                server.spawn(async move {
+                    let mut buf = [0; 2];
+                    accepted.read_exact(&mut buf).await.unwrap();
+                    let _ = tx.send((buf, accepted));
+                });
+
+                let pending = client.pending_work();
+                assert_eq!(pending.tasks.len(), 2);
+                assert!(pending
+                    .runnable()
+                    .all(|task| task.host == server.local_addr()));
+                assert_eq!(pending.runnable().count(), 1);
+                assert_eq!(pending.blocked().count(), 1);
+                let sleeper = pending.blocked().next().unwrap();
+                assert_eq!(sleeper.name.as_deref(), Some("sleeper"));
+                assert_eq!(pending.timers.len(), 1);
+                assert_eq!(pending.timers[0].host, client.local_addr());
+                assert_eq!(
+                    pending.timers[0].deadline,
+                    client.now() + Duration::from_secs(10)
+                );
+
+                socket.write_all(b"a").await.unwrap();
+                socket.write_all(b"bcd").await.unwrap();
+                let (buf, _accepted) = rx.await.unwrap();
+                assert_eq!(buf, *b"ab");
+                let pending = client.pending_work();
+                assert_eq!(pending.blocked().count(), 1);
+                assert_eq!(
+                    pending.messages,
+                    vec![super::InFlight {
+                        source,
+                        dest: addr,
+                        chunks: 1,
+                        bytes: 2,
+                    }]
+                );
+                assert!(!pending.is_quiescent());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Fault plans, describing ahead of time when each host of a cluster is crashed or restarted.
+//!
+//! Fault injectors which make random decisions as they run are hard to minimize when a seed
+//! fails, since a change to the seed changes every decision. A `FaultPlan` is a plain list of
+//! faults, so property testing libraries can generate plans and shrink failing ones down to
+//! the few faults needed to reproduce a failure. Hosts are referred to by their index in the
+//! cluster, wrapping around if the cluster has fewer hosts than the plan refers to.
+use crate::{
+    deterministic::{Cluster, MAX_DELAY},
+    Error, TimeEnv,
+};
+use std::{collections, net, time::Duration};
+use tracing::debug;
+
+/// A fault injected into a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HostFault {
+    /// Kill the host, booting it again once `downtime` has passed.
+    Crash { downtime: Duration },
+    /// Kill the host and immediately boot it again.
+    Restart,
+}
+
+/// A fault injected at a point in a plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlannedFault {
+    /// Time since the plan started at which the fault is injected.
+    pub after: Duration,
+    /// Index of the host in the cluster.
+    pub host: usize,
+    pub fault: HostFault,
+}
+
+/// A list of faults to inject into a cluster.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FaultPlan {
+    faults: Vec<PlannedFault>,
+}
+
+impl FaultPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `fault` on the host at index `host`, injected `after` the plan starts.
+    pub fn fault(mut self, after: Duration, host: usize, fault: HostFault) -> Self {
+        self.faults.push(PlannedFault { after, host, fault });
+        self
+    }
+
+    /// Returns the faults in the plan, in the order they were added.
+    pub fn faults(&self) -> &[PlannedFault] {
+        &self.faults
+    }
+
+    /// Check that every fault, and every crashed host booting again, is at most [`MAX_DELAY`]
+    /// after the plan starts, returning [`Error::TimeOverflow`] naming the first which is not.
+    ///
+    /// [`MAX_DELAY`]:crate::deterministic::MAX_DELAY
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    pub fn check(&self) -> Result<(), Error> {
+        for (index, fault) in self.faults.iter().enumerate() {
+            if fault.after > MAX_DELAY {
+                return Err(Error::TimeOverflow {
+                    operation: format!("scheduling fault {} after {:?}", index, fault.after),
+                });
+            }
+            if let HostFault::Crash { downtime } = fault.fault {
+                let boot = fault.after.checked_add(downtime);
+                if boot.is_none_or(|boot| boot > MAX_DELAY) {
+                    return Err(Error::TimeOverflow {
+                        operation: format!(
+                            "scheduling the boot after fault {}, {:?} + {:?}",
+                            index, fault.after, downtime
+                        ),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Inject each fault into `cluster` at its planned time. Faults targeting a host which is
+    /// still down from an earlier crash are skipped. Completes once every crashed host has
+    /// booted again.
+    ///
+    /// Returns the error from [`check`], without injecting any fault, if the plan does not fit
+    /// in simulated time.
+    ///
+    /// [`check`]:FaultPlan::check
+    pub async fn run(self, cluster: Cluster) -> Result<(), Error> {
+        self.check()?;
+        let handle = cluster.handle(net::Ipv4Addr::LOCALHOST.into());
+        let start = handle.now();
+        let mut faults: collections::VecDeque<_> = {
+            let mut faults = self.faults;
+            faults.sort_by_key(|fault| fault.after);
+            faults.into()
+        };
+        // hosts which are down, along with the time at which they boot again.
+        let mut down: Vec<(Duration, net::IpAddr)> = vec![];
+        loop {
+            let next_boot = down.iter().map(|(at, _)| *at).min();
+            let next_fault = faults.front().map(|fault| fault.after);
+            let boot_first = match (next_boot, next_fault) {
+                (None, None) => return Ok(()),
+                (Some(boot), Some(fault)) => boot <= fault,
+                (boot, _) => boot.is_some(),
+            };
+            if boot_first {
+                let boot = next_boot.unwrap();
+                handle.delay(start + boot).await;
+                let index = down.iter().position(|(at, _)| *at == boot).unwrap();
+                let (_, addr) = down.remove(index);
+                cluster.boot_host(addr);
+                continue;
+            }
+            let PlannedFault { after, host, fault } = faults.pop_front().unwrap();
+            handle.delay(start + after).await;
+            let hosts = cluster.hosts();
+            if hosts.is_empty() {
+                continue;
+            }
+            let addr = hosts[host % hosts.len()];
+            if down.iter().any(|(_, down)| *down == addr) {
+                debug!("skipping {:?} of host {}, which is down", fault, addr);
+                continue;
+            }
+            match fault {
+                HostFault::Crash { downtime } => {
+                    cluster.kill_host(addr);
+                    down.push((after + downtime, addr));
+                }
+                HostFault::Restart => cluster.restart_host(addr),
+            }
+        }
+    }
+}
+
+impl std::iter::FromIterator<PlannedFault> for FaultPlan {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = PlannedFault>,
+    {
+        Self {
+            faults: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{ChaosKind, DeterministicRuntime, DeterministicRuntimeHandle};
+
+    async fn idle(handle: DeterministicRuntimeHandle) {
+        handle.delay_from(Duration::from_secs(3600)).await;
+    }
+
+    #[test]
+    /// Test that faults are injected at their planned time, skipping hosts which are down.
+    fn run_plan() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                for _ in 0..2 {
+                    cluster.add_host(idle).await;
+                }
+                let start = handle.now();
+                FaultPlan::new()
+                    .fault(
+                        Duration::from_secs(5),
+                        0,
+                        HostFault::Crash {
+                            downtime: Duration::from_secs(10),
+                        },
+                    )
+                    // wraps around to the first host, which is still down.
+                    .fault(Duration::from_secs(6), 2, HostFault::Restart)
+                    .fault(Duration::from_secs(7), 1, HostFault::Restart)
+                    .run(cluster.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(handle.now() - start, Duration::from_secs(15));
+
+                let log = handle.chaos_log_handle().query();
+                let first = cluster.hosts()[0];
+                assert_eq!(log.clone().kind(ChaosKind::Kill).host(first).count(), 1);
+                assert_eq!(log.clone().kind(ChaosKind::Boot).host(first).count(), 1);
+                assert_eq!(log.kind(ChaosKind::Kill).count(), 2);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a plan which does not fit in simulated time fails without injecting faults.
+    fn run_overflow() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let cluster = Cluster::new(handle.clone());
+                cluster.add_host(idle).await;
+                let result = FaultPlan::new()
+                    .fault(Duration::from_secs(1), 0, HostFault::Restart)
+                    .fault(MAX_DELAY + Duration::from_secs(1), 0, HostFault::Restart)
+                    .run(cluster)
+                    .await;
+                match result {
+                    Err(Error::TimeOverflow { operation }) => {
+                        assert!(operation.starts_with("scheduling fault 1"), "{}", operation)
+                    }
+                    other => panic!("unexpected result {:?}", other),
+                }
+                let log = handle.chaos_log_handle().query();
+                assert_eq!(log.kind(ChaosKind::Kill).count(), 0);
+            })
+            .unwrap();
+    }
+}
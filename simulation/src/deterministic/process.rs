@@ -0,0 +1,278 @@
+//! Tracking of the tasks belonging to each simulated host.
+//!
+//! Every task spawned through a [`DeterministicRuntimeHandle`] is registered against the
+//! address the handle is scoped to. This allows all tasks for a host to be cancelled at once
+//! when the host is killed, mimicking a process crash, or when the process running on the host
+//! exits.
+//!
+//! A panic in a task aborts the whole simulation by default. With [`PanicPolicy::CrashHost`],
+//! the panic only crashes the host of the task instead: the host is killed as a fault would
+//! kill it, so the rest of the cluster observes a node dying of a bug, and the panic message
+//! is kept for the test to inspect.
+//!
+//! [`DeterministicRuntimeHandle`]:crate::deterministic::DeterministicRuntimeHandle
+//! [`PanicPolicy::CrashHost`]:PanicPolicy::CrashHost
+use super::{escape, flow, topology::Hosts, EventKind, PendingTask, Timeline, TimelineKind};
+use futures::future::{self, AbortHandle};
+use futures::task::{waker_ref, ArcWake};
+use futures::{Future, FutureExt};
+use std::{
+    cell::Cell,
+    collections, net,
+    sync::{self, atomic},
+    task::{Context, Waker},
+};
+use tracing::{field, info_span, trace};
+
+thread_local! {
+    static CURRENT_TASK: Cell<Option<(net::IpAddr, u64)>> = const { Cell::new(None) };
+}
+
+/// Returns the host and id of the task being polled on the current thread, if any.
+pub(crate) fn current_task() -> Option<(net::IpAddr, u64)> {
+    CURRENT_TASK.try_with(Cell::get).ok().flatten()
+}
+
+/// Restores the task which was being polled before when dropped.
+struct Polling(Option<(net::IpAddr, u64)>);
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        CURRENT_TASK.with(|current| current.set(self.0));
+    }
+}
+
+/// What a panic in a task running on behalf of a host does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Unwind out of the runtime, aborting the whole simulation.
+    #[default]
+    Abort,
+    /// Kill the host of the panicking task, recording the kill in the chaos log, and carry on
+    /// running the rest of the simulation.
+    CrashHost,
+}
+
+/// A task running on behalf of a host.
+#[derive(Debug)]
+struct Task {
+    abort: AbortHandle,
+    name: Option<String>,
+    /// Set when the task is woken, and cleared when it is polled.
+    runnable: sync::Arc<atomic::AtomicBool>,
+}
+
+/// Wakes a task through the executor's waker, noting that it is runnable.
+struct TaskWaker {
+    waker: Waker,
+    runnable: sync::Arc<atomic::AtomicBool>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+        arc_self.runnable.store(true, atomic::Ordering::SeqCst);
+        arc_self.waker.wake_by_ref();
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_task: u64,
+    tasks: collections::BTreeMap<net::IpAddr, collections::BTreeMap<u64, Task>>,
+    /// Code passed to the most recent exit of each host.
+    exit_codes: collections::BTreeMap<net::IpAddr, i32>,
+    panic_policy: PanicPolicy,
+    /// Message of the most recent panic which crashed each host.
+    panics: collections::BTreeMap<net::IpAddr, String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ProcessTable {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    timeline: Timeline,
+}
+
+impl ProcessTable {
+    pub(crate) fn new(timeline: Timeline) -> Self {
+        Self {
+            inner: sync::Arc::default(),
+            timeline,
+        }
+    }
+
+    /// Wrap `future` so that it can be cancelled by killing `addr`. The returned future
+    /// records each time it is polled in the event log, and removes itself from the table
+    /// once complete.
+    ///
+    /// Each poll enters a `task` span carrying the address and `hostname` of the host, the id of
+    /// the task and its `name`, if any, so every event emitted by the task is attributed to it
+    /// without the task instrumenting itself.
+    pub(crate) fn register<F>(
+        &self,
+        addr: net::IpAddr,
+        hostname: &str,
+        name: Option<&str>,
+        future: F,
+    ) -> impl Future<Output = ()> + Send + 'static
+    where
+        F: Future<Output = ()> + Send + Unpin + 'static,
+    {
+        let (mut future, abort) = future::abortable(future);
+        // spawned tasks are runnable until their first poll.
+        let runnable = sync::Arc::new(atomic::AtomicBool::new(true));
+        let id = {
+            let mut lock = self.inner.lock().unwrap();
+            let id = lock.next_task;
+            lock.next_task += 1;
+            let task = Task {
+                abort,
+                name: name.map(String::from),
+                runnable: sync::Arc::clone(&runnable),
+            };
+            lock.tasks.entry(addr).or_default().insert(id, task);
+            id
+        };
+        self.timeline.record(TimelineKind::TaskSpawned {
+            host: addr,
+            task: id,
+        });
+        let events = self.timeline.events().clone();
+        events.tally().task_spawned();
+        let span = info_span!(
+            "task",
+            host = %addr,
+            hostname,
+            task = id,
+            name = field::Empty
+        );
+        if let Some(name) = name {
+            span.record("name", name);
+        }
+        let mut task_waker: Option<sync::Arc<TaskWaker>> = None;
+        // tasks join the flow of the task spawning them.
+        let mut flow = flow::current();
+        let future = future::poll_fn(move |cx| {
+            events.tally().polled();
+            events.record(EventKind::TaskPolled {
+                host: addr,
+                task: id,
+            });
+            let _span = span.enter();
+            let _polling = Polling(CURRENT_TASK.with(|current| current.replace(Some((addr, id)))));
+            let _flow = flow::enter(&mut flow);
+            runnable.store(false, atomic::Ordering::SeqCst);
+            // the waker is only rebuilt when the executor hands the task a different one.
+            let stale = match &task_waker {
+                Some(task_waker) => !task_waker.waker.will_wake(cx.waker()),
+                None => true,
+            };
+            if stale {
+                task_waker = Some(sync::Arc::new(TaskWaker {
+                    waker: cx.waker().clone(),
+                    runnable: sync::Arc::clone(&runnable),
+                }));
+            }
+            let waker = waker_ref(task_waker.as_ref().unwrap());
+            let armed = escape::arm();
+            let poll = future.poll_unpin(&mut Context::from_waker(&waker));
+            drop(armed);
+            escape::check(|| format!("task {} of {}", id, addr));
+            poll
+        });
+        let inner = sync::Arc::clone(&self.inner);
+        let timeline = self.timeline.clone();
+        future.map(move |result| {
+            {
+                let mut lock = inner.lock().unwrap();
+                if let Some(tasks) = lock.tasks.get_mut(&addr) {
+                    tasks.remove(&id);
+                }
+            }
+            timeline.events().tally().task_exited(result.is_err());
+            timeline.record(TimelineKind::TaskExited {
+                host: addr,
+                task: id,
+                cancelled: result.is_err(),
+            });
+        })
+    }
+
+    /// Cancel all tasks running on behalf of `addr`.
+    pub(crate) fn kill(&self, addr: net::IpAddr) {
+        let tasks = self.inner.lock().unwrap().tasks.remove(&addr);
+        if let Some(tasks) = tasks {
+            trace!("cancelling {} tasks for {}", tasks.len(), addr);
+            for (_, task) in tasks {
+                task.abort.abort();
+            }
+        }
+    }
+
+    /// Cancel all tasks running on behalf of `addr`, recording that its process exited with
+    /// `code`.
+    pub(crate) fn exit(&self, addr: net::IpAddr, code: i32) {
+        self.inner.lock().unwrap().exit_codes.insert(addr, code);
+        self.timeline
+            .record(TimelineKind::HostExited { host: addr, code });
+        self.kill(addr);
+    }
+
+    /// Returns the code passed to the most recent exit of `addr`, if it has exited.
+    pub(crate) fn exit_code(&self, addr: net::IpAddr) -> Option<i32> {
+        self.inner.lock().unwrap().exit_codes.get(&addr).copied()
+    }
+
+    /// Returns every running task, ordered by host and then by id.
+    pub(crate) fn pending(&self) -> Vec<PendingTask> {
+        let lock = self.inner.lock().unwrap();
+        lock.tasks
+            .iter()
+            .flat_map(|(host, tasks)| {
+                tasks.iter().map(move |(id, task)| PendingTask {
+                    host: *host,
+                    id: *id,
+                    name: task.name.clone(),
+                    runnable: task.runnable.load(atomic::Ordering::SeqCst),
+                })
+            })
+            .collect()
+    }
+
+    /// Add every host running tasks or which has exited to `hosts`, with its task count and exit
+    /// code.
+    pub(crate) fn add_hosts(&self, hosts: &mut Hosts) {
+        let lock = self.inner.lock().unwrap();
+        for (addr, tasks) in &lock.tasks {
+            hosts.host(*addr).tasks = tasks.len();
+        }
+        for (addr, code) in &lock.exit_codes {
+            hosts.host(*addr).exit_code = Some(*code);
+        }
+    }
+
+    /// Make panics in tasks act as `policy` describes.
+    pub(crate) fn set_panic_policy(&self, policy: PanicPolicy) {
+        self.inner.lock().unwrap().panic_policy = policy;
+    }
+
+    pub(crate) fn panic_policy(&self) -> PanicPolicy {
+        self.inner.lock().unwrap().panic_policy
+    }
+
+    /// Record that a task of `addr` panicked with `message`, crashing the host.
+    pub(crate) fn panicked(&self, addr: net::IpAddr, message: String) {
+        trace!("{} crashed by a panic: {}", addr, message);
+        self.inner.lock().unwrap().panics.insert(addr, message);
+    }
+
+    /// Returns the message of the most recent panic which crashed `addr`, if any.
+    pub(crate) fn panic_message(&self, addr: net::IpAddr) -> Option<String> {
+        self.inner.lock().unwrap().panics.get(&addr).cloned()
+    }
+
+    /// Returns the number of running tasks for `addr`.
+    pub(crate) fn task_count(&self, addr: net::IpAddr) -> usize {
+        let lock = self.inner.lock().unwrap();
+        lock.tasks.get(&addr).map(|tasks| tasks.len()).unwrap_or(0)
+    }
+}
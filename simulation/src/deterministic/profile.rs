@@ -0,0 +1,265 @@
+//! Profiling where a simulation spends real time.
+//!
+//! A scenario which covers days of simulated time in seconds of real time can still be slow,
+//! and it is rarely obvious whether the time goes to the simulated network, the timer wheel,
+//! the tasks under test or the executor driving them. Once [`profile`] is called, the real
+//! time spent driving the runtime is attributed to each of these, and the [`Profile`] can be
+//! printed as a summary at the end of the run.
+//!
+//! Time is only counted toward the innermost subsystem, so a task writing to a socket counts
+//! the write toward the network rather than the task. Executor bookkeeping is whatever is not
+//! counted toward any other subsystem.
+//!
+//! [`profile`]:crate::deterministic::DeterministicRuntime::profile
+//! [`Profile`]:Profile
+use super::escape;
+use std::{
+    cell::RefCell,
+    fmt, marker,
+    sync::{self, atomic},
+    time::{Duration, Instant},
+};
+
+/// A part of the runtime whose real time is profiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Subsystem {
+    /// Simulated connections and listeners.
+    Network,
+    /// Advancing time and firing timers.
+    Timers,
+    /// Polling tasks spawned on simulated hosts, and the future passed to `block_on`.
+    Tasks,
+    /// Everything else the executor does to drive tasks.
+    Executor,
+}
+
+/// The real time spent in each subsystem of a runtime since profiling started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Time spent on simulated connections and listeners.
+    pub network: Duration,
+    /// Time spent advancing time and firing timers.
+    pub timers: Duration,
+    /// Time spent polling tasks, excluding the time they spent in the network.
+    pub tasks: Duration,
+    /// Time spent by the executor scheduling tasks.
+    pub executor: Duration,
+}
+
+impl Profile {
+    /// Returns the total real time profiled.
+    pub fn total(&self) -> Duration {
+        self.network + self.timers + self.tasks + self.executor
+    }
+
+    fn add(&mut self, subsystem: Subsystem, duration: Duration) {
+        let total = match subsystem {
+            Subsystem::Network => &mut self.network,
+            Subsystem::Timers => &mut self.timers,
+            Subsystem::Tasks => &mut self.tasks,
+            Subsystem::Executor => &mut self.executor,
+        };
+        *total += duration;
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().as_secs_f64();
+        let rows = [
+            ("network", self.network),
+            ("timers", self.timers),
+            ("tasks", self.tasks),
+            ("executor", self.executor),
+        ];
+        writeln!(f, "{:<10}{:>14}{:>8}", "subsystem", "real time", "share")?;
+        for (name, duration) in rows.iter() {
+            let share = if total > 0.0 {
+                duration.as_secs_f64() / total * 100.0
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "{:<10}{:>14}{:>7.1}%",
+                name,
+                format!("{:.3?}", duration),
+                share
+            )?;
+        }
+        write!(f, "{:<10}{:>14}", "total", format!("{:.3?}", self.total()))
+    }
+}
+
+/// Attributes the real time spent by a runtime to its subsystems, once enabled.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    enabled: sync::Arc<atomic::AtomicBool>,
+    totals: sync::Arc<sync::Mutex<Profile>>,
+}
+
+impl Profiler {
+    /// Start profiling.
+    pub(crate) fn enable(&self) {
+        self.enabled.store(true, atomic::Ordering::SeqCst);
+    }
+
+    /// Returns the time spent in each subsystem so far.
+    pub fn report(&self) -> Profile {
+        *self.totals.lock().unwrap()
+    }
+
+    /// Profile the current thread until the returned guard is dropped, if profiling is enabled.
+    /// Time not spent in any other subsystem meanwhile is counted toward the executor.
+    pub(crate) fn install(&self) -> Option<Installed> {
+        if !self.enabled.load(atomic::Ordering::SeqCst) {
+            return None;
+        }
+        let active = Active {
+            totals: sync::Arc::clone(&self.totals),
+            frames: vec![],
+        };
+        let previous = ACTIVE.with(|current| current.replace(Some(active)));
+        Some(Installed {
+            span: span(Subsystem::Executor),
+            previous,
+        })
+    }
+}
+
+/// A subsystem entered on the current thread, and the time spent in those entered from it.
+#[derive(Debug)]
+struct Frame {
+    subsystem: Subsystem,
+    started: Instant,
+    children: Duration,
+}
+
+#[derive(Debug)]
+struct Active {
+    totals: sync::Arc<sync::Mutex<Profile>>,
+    frames: Vec<Frame>,
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Option<Active>> = const { RefCell::new(None) };
+}
+
+/// Stops profiling the current thread when dropped.
+#[derive(Debug)]
+pub(crate) struct Installed {
+    span: Option<Span>,
+    previous: Option<Active>,
+}
+
+impl Drop for Installed {
+    fn drop(&mut self) {
+        self.span.take();
+        let previous = self.previous.take();
+        ACTIVE.with(|current| current.replace(previous));
+    }
+}
+
+/// Counts the time until it is dropped toward a subsystem. Must be dropped on the thread it
+/// was entered on, before returning to the executor.
+#[derive(Debug)]
+pub(crate) struct Span {
+    _thread: marker::PhantomData<*const ()>,
+}
+
+/// Count the time until the returned span is dropped toward `subsystem`, if the current thread
+/// is being profiled.
+pub(crate) fn span(subsystem: Subsystem) -> Option<Span> {
+    ACTIVE
+        .try_with(|current| {
+            let mut current = current.borrow_mut();
+            let active = current.as_mut()?;
+            active.frames.push(Frame {
+                subsystem,
+                started: escape::unguarded(Instant::now),
+                children: Duration::from_secs(0),
+            });
+            Some(Span {
+                _thread: marker::PhantomData,
+            })
+        })
+        .ok()
+        .flatten()
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let _ = ACTIVE.try_with(|current| {
+            let mut current = current.borrow_mut();
+            let active = match current.as_mut() {
+                Some(active) => active,
+                None => return,
+            };
+            let frame = match active.frames.pop() {
+                Some(frame) => frame,
+                None => return,
+            };
+            let elapsed = escape::unguarded(|| frame.started.elapsed());
+            let exclusive = elapsed.checked_sub(frame.children).unwrap_or_default();
+            active
+                .totals
+                .lock()
+                .unwrap()
+                .add(frame.subsystem, exclusive);
+            if let Some(parent) = active.frames.last_mut() {
+                parent.children += elapsed;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that time spent on the network, timers and tasks is attributed to each, and that
+    /// nothing is attributed before profiling is enabled.
+    fn profile() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let workload = |handle: crate::deterministic::DeterministicRuntimeHandle| async move {
+            let addr = net::SocketAddr::new(handle.local_addr(), 9092);
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 64];
+                while socket.read_exact(&mut buf).await.is_ok() {
+                    socket.write_all(&buf).await.unwrap();
+                }
+            });
+            let mut client = handle.connect(addr).await.unwrap();
+            for _ in 0..100 {
+                client.write_all(&[1; 64]).await.unwrap();
+                client.read_exact(&mut [0; 64]).await.unwrap();
+                handle.delay_from(Duration::from_secs(1)).await;
+            }
+        };
+        runtime.block_on(workload(handle.clone())).unwrap();
+        let profiler = runtime.profile();
+        assert_eq!(profiler.report(), Profile::default());
+
+        let started = Instant::now();
+        runtime
+            .block_on(workload(
+                handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into()),
+            ))
+            .unwrap();
+        let elapsed = started.elapsed();
+        let profile = profiler.report();
+        assert!(profile.network > Duration::from_secs(0), "{}", profile);
+        assert!(profile.timers > Duration::from_secs(0), "{}", profile);
+        assert!(profile.tasks > Duration::from_secs(0), "{}", profile);
+        assert!(profile.executor > Duration::from_secs(0), "{}", profile);
+        assert!(profile.total() <= elapsed, "{}", profile);
+        assert!(profile.to_string().starts_with("subsystem"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Listing the resources simulated hosts hold open.
+//!
+//! Tests can take a snapshot of the files, listeners, connections, timers and tasks a host has
+//! open at any point during a run with [`open_resources`], and assert invariants over them,
+//! such as a connection pool never holding more than a fixed number of connections to a peer.
+//!
+//! [`open_resources`]:crate::deterministic::DeterministicRuntimeHandle::open_resources
+use std::{net, path, time};
+
+/// A file, listener or connection held open by a simulated host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// A file opened at `path`.
+    File { path: path::PathBuf },
+    /// A listener bound to `local_addr`.
+    Listener { local_addr: net::SocketAddr },
+    /// One end of a connection, either established by the host or accepted by one of its
+    /// listeners.
+    Connection {
+        local_addr: net::SocketAddr,
+        peer_addr: net::SocketAddr,
+    },
+}
+
+/// The resources a simulated host held open when the snapshot was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenResources {
+    /// Files, listeners and connections, in the order they were opened.
+    pub descriptors: Vec<Resource>,
+    /// Deadlines of the delays started by the host which have not completed, earliest first.
+    pub timers: Vec<time::Instant>,
+    /// Number of tasks running on behalf of the host.
+    pub tasks: usize,
+}
+
+impl OpenResources {
+    /// Returns the paths of the open files.
+    pub fn files(&self) -> impl Iterator<Item = &path::Path> {
+        self.descriptors
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::File { path } => Some(path.as_path()),
+                _ => None,
+            })
+    }
+
+    /// Returns the addresses of the open listeners.
+    pub fn listeners(&self) -> impl Iterator<Item = net::SocketAddr> + '_ {
+        self.descriptors
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::Listener { local_addr } => Some(*local_addr),
+                _ => None,
+            })
+    }
+
+    /// Returns the local and peer addresses of the open connections.
+    pub fn connections(&self) -> impl Iterator<Item = (net::SocketAddr, net::SocketAddr)> + '_ {
+        self.descriptors
+            .iter()
+            .filter_map(|resource| match resource {
+                Resource::Connection {
+                    local_addr,
+                    peer_addr,
+                } => Some((*local_addr, *peer_addr)),
+                _ => None,
+            })
+    }
+
+    /// Returns the number of open connections to the host `peer`.
+    pub fn connections_to(&self, peer: net::IpAddr) -> usize {
+        self.connections()
+            .filter(|(_, peer_addr)| peer_addr.ip() == peer)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{FsEnv, NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that open files, listeners, connections, timers and tasks are listed for each
+    /// host, and removed once they are closed.
+    fn open_resources() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let server_addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(server_addr).await.unwrap();
+                let file = server.create("data").await.unwrap();
+                let socket = client.connect(server_addr).await.unwrap();
+                let (accepted, client_addr) = listener.accept().await.unwrap();
+                let now = client.now();
+                let timer = client.delay_from(Duration::from_secs(10));
+                client.spawn(client.delay_from(Duration::from_secs(5)));
+
+                let resources = server.open_resources(server.local_addr());
+                assert_eq!(
+                    resources.files().collect::<Vec<_>>(),
+                    vec![path::Path::new("data")]
+                );
+                assert_eq!(resources.listeners().collect::<Vec<_>>(), vec![server_addr]);
+                assert_eq!(
+                    resources.connections().collect::<Vec<_>>(),
+                    vec![(server_addr, client_addr)]
+                );
+                assert!(resources.timers.is_empty());
+                assert_eq!(resources.tasks, 0);
+
+                let resources = client.open_resources(client.local_addr());
+                assert_eq!(resources.connections_to(server.local_addr()), 1);
+                assert_eq!(resources.connections_to(client.local_addr()), 0);
+                assert_eq!(
+                    resources.timers,
+                    vec![now + Duration::from_secs(5), now + Duration::from_secs(10)]
+                );
+                assert_eq!(resources.tasks, 1);
+
+                drop((file, socket, accepted, listener, timer));
+                client.delay_from(Duration::from_secs(6)).await;
+                let empty = OpenResources {
+                    descriptors: vec![],
+                    timers: vec![],
+                    tasks: 0,
+                };
+                assert_eq!(server.open_resources(server.local_addr()), empty);
+                assert_eq!(client.open_resources(client.local_addr()), empty);
+            })
+            .unwrap();
+    }
+}
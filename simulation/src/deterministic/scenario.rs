@@ -0,0 +1,220 @@
+//! Phased scenario execution.
+//!
+//! Simulations are commonly structured as a series of phases. A cluster is first set up,
+//! then faults are injected while a workload runs, then the faults are healed and finally
+//! the state of the system is verified. `Scenario` encodes this structure explicitly. Each
+//! phase has a simulated time budget, a set of tasks which must complete within the budget,
+//! and a fault plan made up of fault injectors which run for the duration of the phase.
+//!
+//! The current phase is available from [`DeterministicRuntimeHandle::current_phase`], and
+//! every phase transition is published on the event bus.
+//!
+//! [`DeterministicRuntimeHandle::current_phase`]:crate::deterministic::DeterministicRuntimeHandle::current_phase
+use crate::{deterministic::DeterministicRuntimeHandle, Error, SpawnEnv, TimeEnv};
+use futures::{future, Future, FutureExt};
+use std::{fmt, pin::Pin, time::Duration};
+use tracing::debug;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Start the system under test and bring it to a steady state.
+    Setup,
+    /// Run the workload while faults are injected.
+    Chaos,
+    /// Allow the system under test to recover from injected faults.
+    Heal,
+    /// Check that the system under test is in a correct state.
+    Verification,
+}
+
+struct PhasePlan {
+    phase: Phase,
+    budget: Duration,
+    faults: Vec<BoxFuture>,
+    tasks: Vec<BoxFuture>,
+}
+
+impl fmt::Debug for PhasePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PhasePlan {{ phase: {:?}, budget: {:?}, faults: {}, tasks: {} }}",
+            self.phase,
+            self.budget,
+            self.faults.len(),
+            self.tasks.len()
+        )
+    }
+}
+
+/// A sequence of phases to execute in order.
+#[derive(Debug, Default)]
+pub struct Scenario {
+    phases: Vec<PhasePlan>,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self { phases: vec![] }
+    }
+
+    /// Append a new phase with the provided time budget. Calls to [`fault`] and [`task`]
+    /// apply to the most recently appended phase.
+    ///
+    /// A phase without tasks lasts for its entire budget. A phase with tasks completes as
+    /// soon as all of its tasks complete, and fails if they do not complete within the budget.
+    ///
+    /// [`fault`]:Scenario::fault
+    /// [`task`]:Scenario::task
+    pub fn phase(mut self, phase: Phase, budget: Duration) -> Self {
+        self.phases.push(PhasePlan {
+            phase,
+            budget,
+            faults: vec![],
+            tasks: vec![],
+        });
+        self
+    }
+
+    /// Add a fault injector to the current phase. The injector is spawned when the phase
+    /// begins, and cancelled when the phase ends.
+    pub fn fault<F>(mut self, fault: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.current().faults.push(Box::pin(fault));
+        self
+    }
+
+    /// Add a task to the current phase. The phase is complete once all of its tasks complete.
+    pub fn task<F>(mut self, task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.current().tasks.push(Box::pin(task));
+        self
+    }
+
+    fn current(&mut self) -> &mut PhasePlan {
+        self.phases
+            .last_mut()
+            .expect("a phase must be added before adding faults or tasks")
+    }
+
+    /// Execute each phase in order, returning an error if any phase exceeds its time budget.
+    pub async fn run(self, handle: &DeterministicRuntimeHandle) -> Result<(), Error> {
+        for plan in self.phases {
+            let PhasePlan {
+                phase,
+                budget,
+                faults,
+                tasks,
+            } = plan;
+            debug!("entering phase {:?} with budget {:?}", phase, budget);
+            handle.set_phase(Some(phase));
+            let faults: Vec<future::AbortHandle> = faults
+                .into_iter()
+                .map(|fault| {
+                    let (fault, abort) = future::abortable(fault);
+                    handle.spawn(fault.map(|_| ()));
+                    abort
+                })
+                .collect();
+            let result = if tasks.is_empty() {
+                handle.delay_from(budget).await;
+                Ok(())
+            } else {
+                handle
+                    .timeout(future::join_all(tasks), budget)
+                    .await
+                    .map(|_| ())
+                    .map_err(|_| Error::PhaseBudgetExceeded { phase })
+            };
+            for fault in faults {
+                fault.abort();
+            }
+            if result.is_err() {
+                handle.set_phase(None);
+            }
+            result?;
+        }
+        handle.set_phase(None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    /// Test that phases run in order, and that faults only run during their phase.
+    fn phases() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let faults = Arc::new(AtomicUsize::new(0));
+                let start = handle.now();
+
+                let fault_handle = handle.clone();
+                let fault_count = Arc::clone(&faults);
+                let setup_handle = handle.clone();
+                let verify_handle = handle.clone();
+                let scenario = Scenario::new()
+                    .phase(Phase::Setup, Duration::from_secs(10))
+                    .task(async move {
+                        assert_eq!(setup_handle.current_phase(), Some(Phase::Setup));
+                    })
+                    .phase(Phase::Chaos, Duration::from_secs(60))
+                    .fault(async move {
+                        loop {
+                            fault_handle.delay_from(Duration::from_secs(1)).await;
+                            fault_count.fetch_add(1, Ordering::SeqCst);
+                        }
+                    })
+                    .phase(Phase::Verification, Duration::from_secs(10))
+                    .task(async move {
+                        assert_eq!(verify_handle.current_phase(), Some(Phase::Verification));
+                    });
+                scenario.run(&handle).await.unwrap();
+                let injected = faults.load(Ordering::SeqCst);
+                assert!((59..=60).contains(&injected), "injected {}", injected);
+                assert_eq!(handle.now() - start, Duration::from_secs(60));
+                handle.delay_from(Duration::from_secs(10)).await;
+                assert_eq!(faults.load(Ordering::SeqCst), injected);
+                assert_eq!(handle.current_phase(), None);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that exceeding a phase budget results in an error, and leaves no phase current.
+    fn budget_exceeded() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let task_handle = handle.clone();
+                let result = Scenario::new()
+                    .phase(Phase::Setup, Duration::from_secs(10))
+                    .task(async move {
+                        task_handle.delay_from(Duration::from_secs(20)).await;
+                    })
+                    .run(&handle)
+                    .await;
+                match result {
+                    Err(Error::PhaseBudgetExceeded { phase }) => assert_eq!(phase, Phase::Setup),
+                    other => panic!("unexpected result {:?}", other),
+                }
+                // the run is over, so the failed phase is no longer current.
+                assert_eq!(handle.current_phase(), None);
+            })
+            .unwrap();
+    }
+}
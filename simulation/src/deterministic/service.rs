@@ -0,0 +1,173 @@
+//! In-process transport for tower services, bypassing the simulated TCP stack.
+//!
+//! Large simulations of services which exchange structured requests and responses spend most
+//! of their time encoding them, shoveling the bytes through simulated sockets and decoding them
+//! again. A [`ServiceChannel`] instead wires a client directly to a [`Service`] on another
+//! simulated host, passing requests and responses as values.
+//!
+//! The channel is registered with the simulated network like a TCP connection, so faults still
+//! apply to each message: injected latency delays it, clogging the connection holds it back
+//! until it is unclogged, and killing either host fails the calls in flight and any made
+//! afterwards. Requests are handled by tasks spawned on the server host, so they are cancelled
+//! along with the rest of its software when it is killed. Like a TCP connection, a channel is
+//! not reestablished once it has failed.
+//!
+//! [`ServiceChannel`]:ServiceChannel
+//! [`Service`]:tower_service::Service
+use crate::{
+    deterministic::{DeterministicRuntimeHandle, TransportGate},
+    SpawnEnv,
+};
+use futures::{channel::oneshot, future, Future};
+use std::{
+    error, fmt, io, net,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower_service::Service;
+
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// A [`Service`] which passes requests to a service on another simulated host, subject to
+/// the faults injected into the simulated network.
+///
+/// Cloned channels share the same simulated connection.
+///
+/// [`Service`]:tower_service::Service
+#[derive(Clone)]
+pub struct ServiceChannel<S> {
+    server: DeterministicRuntimeHandle,
+    service: S,
+    client_gate: TransportGate,
+    server_gate: TransportGate,
+}
+
+impl<S> fmt::Debug for ServiceChannel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ServiceChannel {{ server: {} }}",
+            self.server.local_addr()
+        )
+    }
+}
+
+impl<S> ServiceChannel<S> {
+    /// Connect the host of `client` to `service`, which is served by the host of `addr`. Fails
+    /// with `AddrNotAvailable` if the host of `client` has no unused port to connect from.
+    pub fn new(
+        client: &DeterministicRuntimeHandle,
+        addr: net::SocketAddr,
+        service: S,
+    ) -> io::Result<Self> {
+        let (client_gate, server_gate) = client.shared.network_handle.connect_gates(addr)?;
+        Ok(Self {
+            server: client.scoped(addr.ip()),
+            service,
+            client_gate,
+            server_gate,
+        })
+    }
+}
+
+impl<S, Request> Service<Request> for ServiceChannel<S>
+where
+    S: Service<Request> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Response: Send + 'static,
+    S::Error: Into<BoxError> + Send + 'static,
+    Request: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the server waits for the service to be ready before calling it.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut service = self.service.clone();
+        let server = self.server.clone();
+        let client_gate = self.client_gate.clone();
+        let server_gate = self.server_gate.clone();
+        Box::pin(async move {
+            client_gate.send().await?;
+            server_gate.receive().await?;
+            let (tx, rx) = oneshot::channel();
+            server.spawn(async move {
+                let response = match future::poll_fn(|cx| service.poll_ready(cx)).await {
+                    Ok(()) => service.call(request).await,
+                    Err(e) => Err(e),
+                };
+                let _ = tx.send(response);
+            });
+            // the task is cancelled if the server is killed.
+            let response = rx
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?;
+            server_gate.send().await?;
+            client_gate.receive().await?;
+            response.map_err(Into::into)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TimeEnv};
+    use std::time::Duration;
+
+    /// Doubles each request after a second has passed.
+    #[derive(Clone)]
+    struct Double {
+        handle: DeterministicRuntimeHandle,
+    }
+
+    impl Service<u64> for Double {
+        type Response = u64;
+        type Error = io::Error;
+        type Future = Pin<Box<dyn Future<Output = io::Result<u64>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: u64) -> Self::Future {
+            let handle = self.handle.clone();
+            Box::pin(async move {
+                handle.delay_from(Duration::from_secs(1)).await;
+                Ok(request * 2)
+            })
+        }
+    }
+
+    #[test]
+    /// Test that requests are handled on the server host, and fail once it has been killed.
+    fn call_service() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 9092);
+        runtime
+            .block_on(async {
+                let double = Double {
+                    handle: server.clone(),
+                };
+                let mut channel = ServiceChannel::new(&client, addr, double).unwrap();
+                let start = client.now();
+                assert_eq!(channel.call(21).await.unwrap(), 42);
+                assert!(client.now() - start >= Duration::from_secs(1));
+
+                let in_flight = crate::spawn_with_result(&client, channel.call(1));
+                client.delay_from(Duration::from_millis(500)).await;
+                assert_eq!(server.task_count(server.local_addr()), 1);
+                server.kill(server.local_addr());
+                assert!(in_flight.await.is_err());
+                assert!(channel.call(2).await.is_err());
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+//! A turmoil style facade over the `DeterministicRuntime`.
+//!
+//! Simulations written for turmoil declare named hosts, which run server software that can
+//! be restarted, and named clients, which drive the test and whose completion ends the run.
+//! `Sim` offers the same shape, so those tests can be ported by swapping the imports:
+//!
+//! ```ignore
+//! let mut sim = SimBuilder::new().build()?;
+//! sim.host("server", || async {
+//!     let mut listener = simulation::net::TcpListener::bind(([0, 0, 0, 0], 9000)).await?;
+//!     // ...
+//!     Ok(())
+//! });
+//! sim.client("client", async {
+//!     let addr = (simulation::deterministic::lookup("server"), 9000);
+//!     let socket = simulation::net::TcpStream::connect(addr).await?;
+//!     // ...
+//!     Ok(())
+//! });
+//! sim.run()?;
+//! ```
+//!
+//! Host and client software runs in the context of its host, so it uses the simulated network
+//! through [`simulation::net`] and schedules work through the free functions in the crate root.
+//! Host names are registered with the [`DeterministicDiscoveryHandle`], and resolved with
+//! [`lookup`]. Software can find the name of the host it runs on with
+//! [`Environment::hostname`].
+//!
+//! [`simulation::net`]:crate::net
+//! [`DeterministicDiscoveryHandle`]:crate::deterministic::DeterministicDiscoveryHandle
+//! [`Environment::hostname`]:crate::Environment::hostname
+use crate::{
+    deterministic::{DeterministicRuntime, DeterministicRuntimeHandle},
+    Error, SpawnEnv, TimeEnv,
+};
+use futures::{channel::mpsc, Future, StreamExt};
+use std::{collections, error, fmt, net, pin::Pin, sync, time::Duration};
+use tracing::debug;
+
+/// The result returned by host and client software.
+pub type SimResult = Result<(), Box<dyn error::Error + Send + Sync>>;
+
+type Software =
+    sync::Arc<dyn Fn() -> Pin<Box<dyn Future<Output = SimResult> + Send>> + Send + Sync>;
+
+/// Builds a [`Sim`].
+#[derive(Debug)]
+pub struct SimBuilder {
+    seed: u64,
+    duration: Duration,
+}
+
+impl Default for SimBuilder {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl SimBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the simulation's source of randomness.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Limit the simulated time a run may take before it fails. Defaults to 10 seconds.
+    pub fn simulation_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn build(self) -> Result<Sim, Error> {
+        let (runtime, _) = DeterministicRuntime::builder().seed(self.seed).build()?;
+        let (results_tx, results_rx) = mpsc::unbounded();
+        Ok(Sim {
+            runtime,
+            duration: self.duration,
+            names: collections::BTreeMap::new(),
+            hosts: collections::BTreeMap::new(),
+            clients: collections::BTreeSet::new(),
+            results_tx,
+            results_rx,
+        })
+    }
+}
+
+enum Role {
+    Host,
+    Client,
+}
+
+/// A simulation made up of named hosts and clients.
+pub struct Sim {
+    runtime: DeterministicRuntime,
+    duration: Duration,
+    names: collections::BTreeMap<String, net::IpAddr>,
+    hosts: collections::BTreeMap<String, Software>,
+    clients: collections::BTreeSet<String>,
+    results_tx: mpsc::UnboundedSender<(String, Role, SimResult)>,
+    results_rx: mpsc::UnboundedReceiver<(String, Role, SimResult)>,
+}
+
+impl fmt::Debug for Sim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sim {{ names: {:?} }}", self.names)
+    }
+}
+
+impl Sim {
+    /// Add a host named `name`, which runs the software returned by `software`. The software
+    /// is started again each time the host is bounced.
+    pub fn host<F, U>(&mut self, name: &str, software: F)
+    where
+        F: Fn() -> U + Send + Sync + 'static,
+        U: Future<Output = SimResult> + Send + 'static,
+    {
+        self.allocate(name);
+        let software: Software = sync::Arc::new(move || Box::pin(software()));
+        self.hosts.insert(name.to_string(), software);
+        self.start(name);
+    }
+
+    /// Add a client named `name`, which runs `software` once. A run completes once every
+    /// client has completed.
+    pub fn client<U>(&mut self, name: &str, software: U)
+    where
+        U: Future<Output = SimResult> + Send + 'static,
+    {
+        let handle = self.allocate(name);
+        let results_tx = self.results_tx.clone();
+        let name = name.to_string();
+        self.clients.insert(name.clone());
+        handle.spawn(async move {
+            let result = software.await;
+            let _ = results_tx.unbounded_send((name, Role::Client, result));
+        });
+    }
+
+    /// Returns the address of the host or client `name`.
+    pub fn lookup(&self, name: &str) -> net::IpAddr {
+        match self.names.get(name) {
+            Some(addr) => *addr,
+            None => panic!("no host named {}", name),
+        }
+    }
+
+    /// Returns a handle scoped to the host or client `name`, for injecting faults.
+    pub fn handle(&self, name: &str) -> DeterministicRuntimeHandle {
+        self.runtime.handle(self.lookup(name))
+    }
+
+    /// Crash the host `name`, stopping its software until it is bounced.
+    pub fn crash(&mut self, name: &str) {
+        let handle = self.handle(name);
+        handle.kill(handle.local_addr());
+    }
+
+    /// Crash the host `name` and start its software again.
+    pub fn bounce(&mut self, name: &str) {
+        self.crash(name);
+        self.start(name);
+    }
+
+    /// Run the simulation until every client completes. Fails if any host or client returns
+    /// an error, or if the clients do not complete within the simulation duration.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let handle = self.runtime.localhost_handle();
+        let duration = self.duration;
+        let mut clients = std::mem::take(&mut self.clients);
+        let results_rx = &mut self.results_rx;
+        self.runtime.block_on(async move {
+            let results = async {
+                while !clients.is_empty() {
+                    let (name, role, result) = results_rx.next().await.expect("sender dropped");
+                    // clients left over from a previous run which timed out are ignored.
+                    if let Role::Client = role {
+                        if !clients.remove(&name) {
+                            continue;
+                        }
+                    }
+                    if let Err(source) = result {
+                        return Err(match role {
+                            Role::Host => Error::SimHost { name, source },
+                            Role::Client => Error::SimClient { name, source },
+                        });
+                    }
+                    debug!("{} completed", name);
+                }
+                Ok(())
+            };
+            handle
+                .timeout(results, duration)
+                .await
+                .map_err(|_| Error::SimDurationExceeded { duration })?
+        })?
+    }
+
+    /// Allocate the next address for `name`, registering it with discovery.
+    fn allocate(&mut self, name: &str) -> DeterministicRuntimeHandle {
+        assert!(
+            !self.names.contains_key(name),
+            "a host named {} already exists",
+            name
+        );
+        let addr = net::Ipv4Addr::from(
+            u32::from(net::Ipv4Addr::new(192, 168, 0, 1)) + self.names.len() as u32,
+        );
+        let addr = net::IpAddr::from(addr);
+        self.names.insert(name.to_string(), addr);
+        let handle = self.runtime.handle(addr);
+        handle.set_hostname(addr, name);
+        handle
+            .discovery_handle()
+            .register(name, net::SocketAddr::new(addr, 0));
+        handle
+    }
+
+    /// Start the software of the host `name`.
+    fn start(&mut self, name: &str) {
+        let handle = self.handle(name);
+        let software = sync::Arc::clone(&self.hosts[name]);
+        let results_tx = self.results_tx.clone();
+        let name = name.to_string();
+        handle.spawn(async move {
+            let result = software().await;
+            let _ = results_tx.unbounded_send((name, Role::Host, result));
+        });
+    }
+}
+
+/// Returns the address of the host or client `name` in the current simulation.
+///
+/// # Panics
+///
+/// Panics if called outside of a simulation, or if no host is named `name`.
+pub fn lookup(name: &str) -> net::IpAddr {
+    let handle = super::current().expect("lookup called outside of a simulation");
+    match handle.discovery_handle().lookup(name).first() {
+        Some(addr) => addr.ip(),
+        None => panic!("no host named {}", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{TcpListener, TcpStream};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve a single byte response to every connection.
+    async fn server() -> SimResult {
+        let mut listener = TcpListener::bind(([0, 0, 0, 0], 9000)).await?;
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+            socket.write_all(b"!").await?;
+        }
+    }
+
+    async fn request() -> SimResult {
+        let mut socket = TcpStream::connect((lookup("server"), 9000)).await?;
+        let mut buf = [0; 1];
+        socket.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"!");
+        Ok(())
+    }
+
+    #[test]
+    /// Test that clients can reach hosts by name, and that failures of clients and crashed
+    /// hosts fail the run.
+    fn hosts_and_clients() {
+        let mut sim = SimBuilder::new().build().unwrap();
+        sim.host("server", server);
+        sim.client("client", request());
+        sim.run().unwrap();
+
+        // connections to a crashed host are refused rather than left waiting.
+        sim.crash("server");
+        sim.client("client-2", request());
+        match sim.run().unwrap_err() {
+            Error::SimClient { name, source } => {
+                assert_eq!(name, "client-2");
+                let err = source.downcast::<std::io::Error>().unwrap();
+                assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            }
+            e => panic!("unexpected error {}", e),
+        }
+
+        sim.bounce("server");
+        sim.client("client-3", request());
+        sim.run().unwrap();
+
+        sim.client("client-4", async { Err("failed".into()) });
+        match sim.run().unwrap_err() {
+            Error::SimClient { name, .. } => assert_eq!(name, "client-4"),
+            e => panic!("unexpected error {}", e),
+        }
+    }
+}
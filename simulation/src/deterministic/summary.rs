@@ -0,0 +1,223 @@
+//! Summary of a simulation run.
+//!
+//! Once a run is over, [`summary`] reports how much simulated and real time it took and how
+//! much work happened in it: the tasks spawned and how they ended, the timers fired, the
+//! connections opened and the bytes sent over them, the faults injected and the invariants
+//! checked with [`sim_assert!`]. A run whose summary shows far less work than usual likely
+//! stopped exercising what it was meant to, and the summary as JSON is small enough to archive
+//! alongside each seed run in CI.
+//!
+//! [`summary`]:crate::deterministic::DeterministicRuntime::summary
+//! [`sim_assert!`]:crate::sim_assert
+use std::{fmt, io, sync::atomic, time::Duration};
+
+/// Counts of work done during a run which are not kept anywhere else, maintained whether or
+/// not events are being recorded.
+#[derive(Debug, Default)]
+pub(crate) struct Tally {
+    tasks_spawned: atomic::AtomicU64,
+    tasks_completed: atomic::AtomicU64,
+    tasks_cancelled: atomic::AtomicU64,
+    timers_fired: atomic::AtomicU64,
+    invariants: atomic::AtomicU64,
+    /// Polls of spawned tasks and of the futures passed to `block_on`.
+    polls: atomic::AtomicU64,
+}
+
+impl Tally {
+    pub(crate) fn task_spawned(&self) {
+        self.tasks_spawned.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn task_exited(&self, cancelled: bool) {
+        let count = if cancelled {
+            &self.tasks_cancelled
+        } else {
+            &self.tasks_completed
+        };
+        count.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn timer_fired(&self) {
+        self.timers_fired.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn invariant_checked(&self) {
+        self.invariants.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn polled(&self) {
+        self.polls.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn polls(&self) -> u64 {
+        self.polls.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Summary of a run so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Summary {
+    pub seed: u64,
+    /// Simulated time elapsed since the runtime was created.
+    pub simulated: Duration,
+    /// Real time spent driving the runtime.
+    pub wall: Duration,
+    pub tasks_spawned: u64,
+    /// Tasks which ran to completion.
+    pub tasks_completed: u64,
+    /// Tasks which were cancelled by their host being killed or exiting.
+    pub tasks_cancelled: u64,
+    pub timers_fired: u64,
+    pub connections: u64,
+    /// Bytes written to connections, in both directions.
+    pub bytes: u64,
+    /// Actions recorded in the chaos log.
+    pub faults: u64,
+    /// Assertions evaluated by [`sim_assert!`] and [`sim_assert_eq!`].
+    ///
+    /// [`sim_assert!`]:crate::sim_assert
+    /// [`sim_assert_eq!`]:crate::sim_assert_eq
+    pub invariants: u64,
+}
+
+impl Summary {
+    pub(crate) fn new(
+        seed: u64,
+        simulated: Duration,
+        wall: Duration,
+        tally: &Tally,
+        (connections, bytes): (u64, u64),
+        faults: u64,
+    ) -> Self {
+        let load = |count: &atomic::AtomicU64| count.load(atomic::Ordering::Relaxed);
+        Self {
+            seed,
+            simulated,
+            wall,
+            tasks_spawned: load(&tally.tasks_spawned),
+            tasks_completed: load(&tally.tasks_completed),
+            tasks_cancelled: load(&tally.tasks_cancelled),
+            timers_fired: load(&tally.timers_fired),
+            connections,
+            bytes,
+            faults,
+            invariants: load(&tally.invariants),
+        }
+    }
+
+    /// Returns the summary as a single line JSON object. Durations are in microseconds.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"seed\":{},\"simulated_us\":{},\"wall_us\":{},\"tasks_spawned\":{},\
+             \"tasks_completed\":{},\"tasks_cancelled\":{},\"timers_fired\":{},\
+             \"connections\":{},\"bytes\":{},\"faults\":{},\"invariants\":{}}}",
+            self.seed,
+            self.simulated.as_micros(),
+            self.wall.as_micros(),
+            self.tasks_spawned,
+            self.tasks_completed,
+            self.tasks_cancelled,
+            self.timers_fired,
+            self.connections,
+            self.bytes,
+            self.faults,
+            self.invariants
+        )
+    }
+
+    /// Write the summary as JSON to `writer`, followed by a newline.
+    pub fn write_json<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all((self.to_json() + "\n").as_bytes())
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed            {}", self.seed)?;
+        writeln!(f, "simulated time  {:?}", self.simulated)?;
+        writeln!(f, "wall time       {:.3?}", self.wall)?;
+        writeln!(
+            f,
+            "tasks           {} spawned, {} completed, {} cancelled",
+            self.tasks_spawned, self.tasks_completed, self.tasks_cancelled
+        )?;
+        writeln!(f, "timers fired    {}", self.timers_fired)?;
+        writeln!(
+            f,
+            "connections     {} opened, {} bytes sent",
+            self.connections, self.bytes
+        )?;
+        writeln!(f, "faults          {}", self.faults)?;
+        write!(f, "invariants      {}", self.invariants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::net;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    /// Test that the summary counts the tasks, timers, connections, bytes, faults and
+    /// invariants of a run.
+    fn summary() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(4).build().unwrap();
+        let server = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let connector = client.clone();
+                client.spawn(async move {
+                    connector.delay_from(Duration::from_secs(3)).await;
+                    let mut socket = connector.connect(addr).await.unwrap();
+                    socket.write_all(b"hello").await.unwrap();
+                });
+                server.spawn(async {
+                    futures::future::pending::<()>().await;
+                });
+                let _ = listener.accept().await.unwrap();
+                crate::sim_assert!(handle.elapsed() >= Duration::from_secs(3));
+                crate::sim_assert_eq!(handle.elapsed(), Duration::from_secs(3));
+                handle.kill(server.local_addr());
+                handle.delay_from(Duration::from_secs(1)).await;
+            })
+            .unwrap();
+
+        let summary = runtime.summary();
+        assert!(summary.wall > Duration::from_secs(0));
+        assert_eq!(
+            summary,
+            Summary {
+                seed: 4,
+                simulated: Duration::from_secs(4),
+                wall: summary.wall,
+                tasks_spawned: 2,
+                tasks_completed: 1,
+                tasks_cancelled: 1,
+                timers_fired: 2,
+                connections: 1,
+                bytes: 5,
+                faults: 1,
+                invariants: 2,
+            }
+        );
+        assert!(summary
+            .to_json()
+            .starts_with("{\"seed\":4,\"simulated_us\":4000000,\"wall_us\":"));
+        assert!(summary
+            .to_json()
+            .ends_with("\"connections\":1,\"bytes\":5,\"faults\":1,\"invariants\":2}"));
+        assert!(summary
+            .to_string()
+            .contains("tasks           2 spawned, 1 completed, 1 cancelled"));
+    }
+}
@@ -1,29 +1,365 @@
 //! A mock source of time, allowing for determinstic control of the progress
 //! of time.
-use std::{sync, time};
+//!
+//! How far time moves once every task is idle is decided by a [`Clock`]. By default time jumps
+//! straight to the next timer, but a custom clock can model time differently, for instance
+//! advancing in discrete ticks, or waiting for another system before time moves when
+//! co-simulating with it.
+//!
+//! Pending delays are scheduled on the timing wheel of `tokio_timer`, which wakes them once
+//! time reaches their deadline. Once every task is idle, the wheel finds the next deadline and
+//! time is advanced straight to it. Timers far in the future are moved down the levels of the
+//! wheel on the way, without returning to the executor, so a long stretch of idle time costs
+//! no more than a short one. The time source also keeps its own index of the delays started
+//! by hosts, in a `BTreeSet` ordered by deadline, which lists them, so starting or completing
+//! a delay costs `O(log n)` in the number outstanding.
+//!
+//! Delays started through a host's handle are returned as a [`Delay`], which is counted among
+//! the open timers of that host until it completes or is dropped. The wheel fires delays
+//! sharing a deadline in an order which falls out of how its slots are linked, so a [`Delay`]
+//! is not woken by the wheel directly: the time source collects the delays which fired while it
+//! was parked, and wakes their tasks earliest deadline first, in the order the delays were
+//! started among those sharing a deadline.
+//!
+//! The wheel reaches [`MAX_DELAY`] ahead of the current time. Setting a delay further ahead
+//! than that, or a deadline which does not fit in an `Instant`, fails with
+//! [`Error::TimeOverflow`] naming the operation when the delay is set, rather than inside the
+//! wheel once it is polled. The `try_` methods of [`DeterministicTimeHandle`] return the error
+//! to the caller. The methods which return a delay, as [`TimeEnv`] and Tokio's timer do,
+//! record the error instead and return a delay which never completes: time stops advancing,
+//! and [`DeterministicRuntime::block_on`] returns the error. Advancing the clock further than
+//! an `Instant` reaches is recorded in the same way.
+//!
+//! The most recent advances of time are kept as [`ClockAdvance`]s, and included when a run
+//! panics or exceeds its time limit, so a run which stalls shows whether time stopped moving
+//! and which timers it last moved forward for.
+//!
+//! [`Clock`]:Clock
+//! [`Delay`]:Delay
+//! [`ClockAdvance`]:ClockAdvance
+//! [`MAX_DELAY`]:MAX_DELAY
+//! [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+//! [`DeterministicTimeHandle`]:DeterministicTimeHandle
+//! [`TimeEnv`]:crate::TimeEnv
+//! [`DeterministicRuntime::block_on`]:crate::deterministic::DeterministicRuntime::block_on
+use super::profile::{self, Subsystem};
+use super::{escape, EventKind, EventLog, PendingTimer, Timeline, TimelineKind};
+use crate::Error;
+use futures::task::{waker_ref, ArcWake};
+use futures::{FutureExt, Poll};
+use std::{
+    collections, fmt,
+    future::Future,
+    mem, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::{Context, Waker},
+    time,
+};
+
+/// A source of simulated time for the [`DeterministicRuntime`].
+///
+/// [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+pub trait Clock: fmt::Debug + Send + 'static {
+    /// Returns the simulated time which has passed since the runtime was created. This must
+    /// never decrease.
+    fn elapsed(&self) -> time::Duration;
+    /// Called when every task is idle and the next timer fires `duration` from now. The clock
+    /// may advance by less than `duration`, in which case the runtime checks for work and calls
+    /// it again with the remaining duration.
+    fn advance(&mut self, duration: time::Duration);
+}
+
+/// The default [`Clock`], which advances straight to the next timer.
+///
+/// [`Clock`]:Clock
+#[derive(Debug, Default)]
+pub struct AutoAdvanceClock {
+    elapsed: time::Duration,
+}
+
+impl Clock for AutoAdvanceClock {
+    fn elapsed(&self) -> time::Duration {
+        self.elapsed
+    }
+    fn advance(&mut self, duration: time::Duration) {
+        self.elapsed += duration;
+    }
+}
+
+/// A [`Clock`] which advances in whole ticks, so that every timer fires at the end of the
+/// tick containing its deadline.
+///
+/// [`Clock`]:Clock
+#[derive(Debug)]
+pub struct TickClock {
+    tick: time::Duration,
+    elapsed: time::Duration,
+}
+
+impl TickClock {
+    /// Create a clock which advances `tick` at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick` is zero.
+    pub fn new(tick: time::Duration) -> Self {
+        assert!(
+            tick > time::Duration::from_millis(0),
+            "tick must be nonzero"
+        );
+        Self {
+            tick,
+            elapsed: time::Duration::from_millis(0),
+        }
+    }
+}
+
+impl Clock for TickClock {
+    fn elapsed(&self) -> time::Duration {
+        self.elapsed
+    }
+    fn advance(&mut self, duration: time::Duration) {
+        // round up to the end of the tick containing the deadline.
+        let tick = self.tick.as_nanos();
+        let mut nanos = duration.as_nanos();
+        let remainder = nanos % tick;
+        if remainder > 0 {
+            nanos += tick - remainder;
+        }
+        self.elapsed += time::Duration::from_nanos(nanos as u64);
+    }
+}
+
+/// The furthest ahead of the current time a delay can be set: the whole seconds within the
+/// 2^36 milliseconds the timing wheel reaches, a little over two years.
+pub const MAX_DELAY: time::Duration = time::Duration::from_secs((1 << 36) / 1000);
+
+/// Returns the instant `duration` after `base`, or [`Error::TimeOverflow`] naming `operation`
+/// if it does not fit in an `Instant` or is further ahead than a delay can be set.
+///
+/// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+pub(crate) fn checked_add<F>(
+    base: time::Instant,
+    duration: time::Duration,
+    operation: F,
+) -> Result<time::Instant, Error>
+where
+    F: FnOnce() -> String,
+{
+    match base.checked_add(duration) {
+        Some(deadline) if duration <= MAX_DELAY => Ok(deadline),
+        _ => Err(Error::TimeOverflow {
+            operation: operation(),
+        }),
+    }
+}
+
+/// Number of advances of time kept in the history.
+const HISTORY: usize = 64;
+
+/// What moved simulated time forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdvanceCause {
+    /// Every task was idle, so time moved forward until a timer fired. `timer` is the earliest
+    /// delay started by a host which was due by then, as its host and id, or `None` if only
+    /// timers outside of any host were, such as the time limit of the runtime.
+    Idle { timer: Option<(net::IpAddr, u64)> },
+    /// Time was advanced directly, rather than by waiting for a timer.
+    Explicit,
+}
+
+/// An advance of simulated time, from and to the time elapsed since the runtime was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClockAdvance {
+    pub from: time::Duration,
+    pub to: time::Duration,
+    pub cause: AdvanceCause,
+}
+
+impl fmt::Display for ClockAdvance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} -> {:?}", self.from, self.to)?;
+        match self.cause {
+            AdvanceCause::Idle {
+                timer: Some((host, timer)),
+            } => write!(f, ", idle until timer {} of {}", timer, host),
+            AdvanceCause::Idle { timer: None } => {
+                write!(f, ", idle until a timer outside of any host")
+            }
+            AdvanceCause::Explicit => write!(f, ", advanced explicitly"),
+        }
+    }
+}
 
 #[derive(Debug)]
 struct Inner {
     /// Time basis for which mock time is derived.
     base: time::Instant,
-    /// The amount of mock time which has elapsed.
-    advance: time::Duration,
+    /// Decides how much mock time has elapsed.
+    clock: Box<dyn Clock>,
+    /// The most recent advances of time, oldest first.
+    history: collections::VecDeque<ClockAdvance>,
+    /// The first overflow of time, which stops time advancing until the runtime returns it.
+    overflow: Option<Error>,
 }
 
+/// Wall clock time at which the simulation starts, fixed so that timestamps are the same in
+/// every run: midnight UTC on the first of January 2020.
+pub(crate) const EPOCH: time::Duration = time::Duration::from_secs(1_577_836_800);
+
 impl Inner {
-    fn new() -> Self {
+    fn new(clock: Box<dyn Clock>) -> Self {
         Self {
             base: time::Instant::now(),
-            advance: time::Duration::from_millis(0),
+            clock,
+            history: collections::VecDeque::with_capacity(HISTORY),
+            overflow: None,
         }
     }
 
-    fn advance(&mut self, duration: time::Duration) {
-        self.advance += duration;
+    fn advance(&mut self, duration: time::Duration) -> Result<(), Error> {
+        let elapsed = self.elapsed().checked_add(duration);
+        let representable = elapsed.is_some_and(|elapsed| {
+            self.base.checked_add(elapsed).is_some()
+                && time::UNIX_EPOCH.checked_add(EPOCH + elapsed).is_some()
+        });
+        if !representable {
+            return Err(Error::TimeOverflow {
+                operation: format!(
+                    "advancing the clock by {:?} after {:?}",
+                    duration,
+                    self.elapsed()
+                ),
+            });
+        }
+        self.clock.advance(duration);
+        Ok(())
+    }
+
+    /// Record `error`, unless an earlier overflow is yet to be returned.
+    fn overflowed(&mut self, error: Error) {
+        self.overflow.get_or_insert(error);
+    }
+
+    /// Add an advance from `from` to now to the history, if time moved.
+    fn record(&mut self, from: time::Duration, cause: AdvanceCause) {
+        let to = self.elapsed();
+        if to > from {
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(ClockAdvance { from, to, cause });
+        }
+    }
+
+    fn elapsed(&self) -> time::Duration {
+        self.clock.elapsed()
     }
 
     fn now(&self) -> time::Instant {
-        self.base + self.advance
+        self.base + self.elapsed()
+    }
+
+    fn system_time(&self) -> time::SystemTime {
+        time::UNIX_EPOCH + EPOCH + self.elapsed()
+    }
+}
+
+/// The pending delays started by every host, indexed by deadline.
+#[derive(Debug, Default)]
+struct Timers {
+    /// Every pending delay by deadline, and then by id, which is the order they were started.
+    deadlines: collections::BTreeSet<(time::Instant, u64)>,
+    /// The host which started each pending delay, and its deadline, by id.
+    owners: collections::BTreeMap<u64, (net::IpAddr, time::Instant)>,
+    next_id: u64,
+    /// The task waiting on each pending delay, by id.
+    wakers: collections::HashMap<u64, Waker>,
+    /// Delays which fired and whose tasks have not been woken yet, in the order to wake them.
+    fired: collections::BTreeSet<(time::Instant, u64)>,
+    /// Records each delay which is started and completes.
+    events: Option<EventLog>,
+}
+
+impl Timers {
+    fn add(&mut self, addr: net::IpAddr, deadline: time::Instant) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.deadlines.insert((deadline, id));
+        self.owners.insert(id, (addr, deadline));
+        if let Some(events) = &self.events {
+            events.record(EventKind::TimerSet {
+                host: addr,
+                timer: id,
+            });
+        }
+        id
+    }
+
+    /// Returns the host and id of the earliest delay due by `now`, if any.
+    fn due(&self, now: time::Instant) -> Option<(net::IpAddr, u64)> {
+        // the first delay in the index is the earliest, and fired first if any did.
+        let (deadline, id) = self.deadlines.iter().next()?;
+        if *deadline > now {
+            return None;
+        }
+        Some((self.owners[id].0, *id))
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some((_, deadline)) = self.owners.remove(&id) {
+            self.deadlines.remove(&(deadline, id));
+            self.fired.remove(&(deadline, id));
+        }
+        self.wakers.remove(&id);
+    }
+
+    /// Returns the pending delays started by `addr`, or by every host, earliest deadline
+    /// first.
+    fn list(&self, addr: Option<net::IpAddr>) -> impl Iterator<Item = PendingTimer> + '_ {
+        self.deadlines
+            .iter()
+            .map(move |(deadline, id)| PendingTimer {
+                host: self.owners[id].0,
+                id: *id,
+                deadline: *deadline,
+            })
+            .filter(move |timer| addr.is_none_or(|addr| addr == timer.host))
+    }
+
+    /// Note that the delay `id` fired, leaving its task to be woken by [`take_fired`].
+    ///
+    /// [`take_fired`]:Timers::take_fired
+    fn fire(&mut self, id: u64) {
+        if let Some((_, deadline)) = self.owners.get(&id) {
+            self.fired.insert((*deadline, id));
+        }
+    }
+
+    /// Returns the wakers of the tasks waiting on delays which fired, earliest deadline first
+    /// and in the order the delays were started among those sharing a deadline.
+    fn take_fired(&mut self) -> Vec<Waker> {
+        let fired = mem::take(&mut self.fired);
+        fired
+            .into_iter()
+            .filter_map(|(_, id)| self.wakers.remove(&id))
+            .collect()
+    }
+}
+
+/// Passed to the wheel in place of the waker of the task waiting on a [`Delay`], so the time
+/// source decides the order in which the tasks of delays which fired together are woken.
+///
+/// [`Delay`]:Delay
+struct TimerWaker {
+    id: u64,
+    timers: sync::Arc<sync::Mutex<Timers>>,
+}
+
+impl ArcWake for TimerWaker {
+    fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+        arc_self.timers.lock().unwrap().fire(arc_self.id);
     }
 }
 
@@ -33,6 +369,9 @@ pub struct DeterministicTime<P> {
     park: tokio_timer::Timer<DeterministicPark<P>, Now>,
     inner: sync::Arc<sync::Mutex<Inner>>,
     timer_handle: tokio_timer::timer::Handle,
+    timers: sync::Arc<sync::Mutex<Timers>>,
+    timeline: Option<Timeline>,
+    unparked: sync::Arc<atomic::AtomicBool>,
 }
 
 impl<P> DeterministicTime<P>
@@ -44,16 +383,40 @@ where
     ///
     /// [`Park`]:[tokio_executor::park::Park]
     pub fn new_with_park(park: P) -> Self {
-        let inner = Inner::new();
+        Self::new_with_clock(park, Box::new(AutoAdvanceClock::default()))
+    }
+
+    /// Wrap the provided `Park` instance, advancing time as decided by `clock`.
+    pub fn new_with_clock(park: P, clock: Box<dyn Clock>) -> Self {
+        let inner = Inner::new(clock);
         let inner = sync::Arc::new(sync::Mutex::new(inner));
         let now = Now::new(sync::Arc::clone(&inner));
         let inner_park = DeterministicPark::new(park, sync::Arc::clone(&inner));
+        let unparked = sync::Arc::clone(&inner_park.unparked);
         let timer = tokio_timer::Timer::new_with_now(inner_park, now);
         let timer_handle = timer.handle();
         Self {
             inner,
             park: timer,
             timer_handle,
+            timers: sync::Arc::default(),
+            timeline: None,
+            unparked,
+        }
+    }
+
+    /// Record each advance of time in `timeline`, and each delay which is started and completes
+    /// in its event log.
+    pub(crate) fn set_timeline(&mut self, timeline: Timeline) {
+        self.timers.lock().unwrap().events = Some(timeline.events().clone());
+        self.timeline = Some(timeline);
+    }
+
+    /// Wake the tasks waiting on the delays which fired while the wheel was turned.
+    fn wake_fired(&self) {
+        let wakers = self.timers.lock().unwrap().take_fired();
+        for waker in wakers {
+            waker.wake();
         }
     }
 
@@ -62,6 +425,7 @@ where
         DeterministicTimeHandle {
             inner,
             timer_handle: self.timer_handle.clone(),
+            timers: sync::Arc::clone(&self.timers),
         }
     }
 }
@@ -70,17 +434,34 @@ where
 pub struct DeterministicTimeHandle {
     inner: sync::Arc<sync::Mutex<Inner>>,
     timer_handle: tokio_timer::timer::Handle,
+    timers: sync::Arc<sync::Mutex<Timers>>,
 }
 
 impl DeterministicTimeHandle {
     /// Advances the internal clock for the provided duration.
-    pub(crate) fn advance(&self, duration: time::Duration) {
-        self.inner.lock().unwrap().advance(duration);
+    pub(crate) fn advance(&self, duration: time::Duration) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let from = inner.elapsed();
+        inner.advance(duration)?;
+        inner.record(from, AdvanceCause::Explicit);
+        Ok(())
     }
     /// Return time now.
     pub(crate) fn now(&self) -> time::Instant {
         self.inner.lock().unwrap().now()
     }
+    /// Return the simulated wall clock time now.
+    pub(crate) fn system_time(&self) -> time::SystemTime {
+        self.inner.lock().unwrap().system_time()
+    }
+    /// Return the amount of mock time which has elapsed.
+    pub(crate) fn elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().elapsed()
+    }
+    /// Return the most recent advances of time, oldest first.
+    pub(crate) fn history(&self) -> Vec<ClockAdvance> {
+        self.inner.lock().unwrap().history.iter().copied().collect()
+    }
 
     /// Creates an instance of `Now` from this deterministic time source.
     ///
@@ -95,32 +476,338 @@ impl DeterministicTimeHandle {
         tokio_timer::clock::Clock::new_with_now(self.clone_now())
     }
 
+    /// Returns a delay which completes at `deadline`, or [`Error::TimeOverflow`] if `deadline`
+    /// is more than [`MAX_DELAY`] ahead.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    pub fn try_delay(&self, deadline: time::Instant) -> Result<tokio_timer::Delay, Error> {
+        self.check_deadline(deadline, "setting a delay")?;
+        Ok(self.timer_handle.delay(deadline))
+    }
+
+    /// Returns a delay which completes at `deadline`.
+    ///
+    /// If `deadline` is more than [`MAX_DELAY`] ahead, the delay never completes, and the
+    /// overflow is returned by the runtime. [`try_delay`] returns it here instead.
+    ///
+    /// [`MAX_DELAY`]:MAX_DELAY
+    /// [`try_delay`]:DeterministicTimeHandle::try_delay
     pub fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
-        self.timer_handle.delay(deadline)
+        self.try_delay(deadline).unwrap_or_else(|error| {
+            self.overflowed(error);
+            self.timer_handle.delay(self.never())
+        })
+    }
+
+    /// Returns a delay which completes once `duration` has passed, or [`Error::TimeOverflow`]
+    /// if `duration` is longer than [`MAX_DELAY`].
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    pub fn try_delay_from(&self, duration: time::Duration) -> Result<tokio_timer::Delay, Error> {
+        let deadline = checked_add(self.now(), duration, || {
+            format!("setting a delay for {:?}", duration)
+        })?;
+        Ok(self.timer_handle.delay(deadline))
     }
 
+    /// Returns a delay which completes once `duration` has passed.
+    ///
+    /// If `duration` is longer than [`MAX_DELAY`], the delay never completes, and the overflow
+    /// is returned by the runtime. [`try_delay_from`] returns it here instead.
+    ///
+    /// [`MAX_DELAY`]:MAX_DELAY
+    /// [`try_delay_from`]:DeterministicTimeHandle::try_delay_from
     pub fn delay_from(&self, duration: time::Duration) -> tokio_timer::Delay {
-        self.timer_handle.delay(self.now() + duration)
+        self.try_delay_from(duration).unwrap_or_else(|error| {
+            self.overflowed(error);
+            self.timer_handle.delay(self.never())
+        })
+    }
+
+    /// Requires `value` to complete before `timeout` has passed, or returns
+    /// [`Error::TimeOverflow`] if `timeout` is longer than [`MAX_DELAY`].
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    pub fn try_timeout<T>(
+        &self,
+        value: T,
+        timeout: time::Duration,
+    ) -> Result<tokio_timer::Timeout<T>, Error> {
+        checked_add(self.now(), timeout, || {
+            format!("setting a timeout of {:?}", timeout)
+        })?;
+        Ok(self.timer_handle.timeout(value, timeout))
     }
 
+    /// Requires `value` to complete before `timeout` has passed.
+    ///
+    /// If `timeout` is longer than [`MAX_DELAY`], the timeout never elapses, and the overflow
+    /// is returned by the runtime. [`try_timeout`] returns it here instead.
+    ///
+    /// [`MAX_DELAY`]:MAX_DELAY
+    /// [`try_timeout`]:DeterministicTimeHandle::try_timeout
     pub fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T> {
+        if let Err(error) = checked_add(self.now(), timeout, || {
+            format!("setting a timeout of {:?}", timeout)
+        }) {
+            self.overflowed(error);
+            let never = self.never().saturating_duration_since(self.now());
+            return self.timer_handle.timeout(value, never);
+        }
         self.timer_handle.timeout(value, timeout)
     }
 
+    /// Returns [`Error::TimeOverflow`] naming `operation` if `deadline` is further ahead than a
+    /// delay can be set.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    fn check_deadline(&self, deadline: time::Instant, operation: &str) -> Result<(), Error> {
+        let ahead = deadline.saturating_duration_since(self.now());
+        if ahead > MAX_DELAY {
+            return Err(Error::TimeOverflow {
+                operation: format!("{} {:?} ahead", operation, ahead),
+            });
+        }
+        Ok(())
+    }
+
+    /// Record `error`, to be returned by the runtime once it next polls.
+    fn overflowed(&self, error: Error) {
+        self.inner.lock().unwrap().overflowed(error);
+    }
+
+    /// Take the overflow recorded since the runtime last returned one, letting time advance
+    /// again.
+    pub(crate) fn take_overflow(&self) -> Option<Error> {
+        self.inner.lock().unwrap().overflow.take()
+    }
+
+    /// Returns the deadline given to delays which overflowed: as far ahead as a delay can be
+    /// set, which is not reached before the runtime returns the overflow, as time stops
+    /// advancing until then.
+    fn never(&self) -> time::Instant {
+        let now = self.now();
+        now.checked_add(MAX_DELAY).unwrap_or(now)
+    }
+
     pub fn clone_timer_handle(&self) -> tokio_timer::timer::Handle {
         self.timer_handle.clone()
     }
+
+    /// Returns a delay which completes at `deadline`, counted among the timers of `addr`
+    /// until then, or [`Error::TimeOverflow`] if `deadline` is more than [`MAX_DELAY`] ahead.
+    ///
+    /// [`Error::TimeOverflow`]:crate::Error::TimeOverflow
+    /// [`MAX_DELAY`]:MAX_DELAY
+    pub(crate) fn try_host_delay(
+        &self,
+        addr: net::IpAddr,
+        deadline: time::Instant,
+    ) -> Result<Delay, Error> {
+        self.check_deadline(deadline, &format!("setting a delay of {}", addr))?;
+        Ok(self.host_delay(addr, deadline))
+    }
+
+    /// Record `error`, and return a delay of `addr` which never completes in place of the one
+    /// which overflowed.
+    pub(crate) fn overflowed_delay(&self, addr: net::IpAddr, error: Error) -> Delay {
+        self.overflowed(error);
+        self.host_delay(addr, self.never())
+    }
+
+    fn host_delay(&self, addr: net::IpAddr, deadline: time::Instant) -> Delay {
+        let timer = self.timers.lock().unwrap().add(addr, deadline);
+        Delay {
+            inner: self.timer_handle.delay(deadline),
+            addr,
+            timer: Some(timer),
+            waker: sync::Arc::new(TimerWaker {
+                id: timer,
+                timers: sync::Arc::clone(&self.timers),
+            }),
+            timers: sync::Arc::clone(&self.timers),
+        }
+    }
+
+    /// Returns the deadlines of the pending delays started by `addr`, earliest first.
+    pub(crate) fn timers(&self, addr: net::IpAddr) -> Vec<time::Instant> {
+        let lock = self.timers.lock().unwrap();
+        lock.list(Some(addr)).map(|timer| timer.deadline).collect()
+    }
+
+    /// Returns the pending delays started by every host, earliest deadline first.
+    pub(crate) fn pending(&self) -> Vec<PendingTimer> {
+        self.timers.lock().unwrap().list(None).collect()
+    }
+
+    /// Returns a read only view of this time source.
+    pub(crate) fn reader(&self) -> TimeReader {
+        TimeReader {
+            inner: sync::Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// A read only view of simulated time, which can be moved to threads outside of the runtime,
+/// such as a thread writing logs with simulated timestamps.
+///
+/// Readings never run ahead of the simulation, but which reading a thread gets depends on when
+/// the operating system schedules it, so they are not deterministic and must not be fed back
+/// into the simulation.
+#[derive(Debug, Clone)]
+pub struct TimeReader {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl TimeReader {
+    /// Return the simulated time now.
+    pub fn now(&self) -> time::Instant {
+        self.inner.lock().unwrap().now()
+    }
+    /// Return the simulated wall clock time now.
+    pub fn system_time(&self) -> time::SystemTime {
+        self.inner.lock().unwrap().system_time()
+    }
+    /// Return the simulated time which has passed since the runtime was created.
+    pub fn elapsed(&self) -> time::Duration {
+        self.inner.lock().unwrap().elapsed()
+    }
+    /// Return the simulated time which has passed, unless the time source is locked.
+    pub(crate) fn try_elapsed(&self) -> Option<time::Duration> {
+        self.inner.try_lock().ok().map(|inner| inner.elapsed())
+    }
+    /// Return the most recent advances of time, unless the time source is locked.
+    pub(crate) fn try_history(&self) -> Option<Vec<ClockAdvance>> {
+        self.inner
+            .try_lock()
+            .ok()
+            .map(|inner| inner.history.iter().copied().collect())
+    }
+}
+
+/// A delay started by a simulated host, which counts as one of the host's open timers until it
+/// completes or is dropped.
+pub struct Delay {
+    inner: tokio_timer::Delay,
+    addr: net::IpAddr,
+    /// Registration of the delay with the time source while it is pending.
+    timer: Option<u64>,
+    waker: sync::Arc<TimerWaker>,
+    timers: sync::Arc<sync::Mutex<Timers>>,
+}
+
+impl Delay {
+    /// Returns the instant at which the delay completes.
+    pub fn deadline(&self) -> time::Instant {
+        self.inner.deadline()
+    }
+
+    /// Returns true if the delay has completed.
+    pub fn is_elapsed(&self) -> bool {
+        self.inner.is_elapsed()
+    }
+
+    /// Reset the delay to complete at `deadline` instead, even if it has already completed.
+    pub fn reset(&mut self, deadline: time::Instant) {
+        self.inner.reset(deadline);
+        let mut lock = self.timers.lock().unwrap();
+        if let Some(id) = self.timer.take() {
+            lock.remove(id);
+        }
+        let id = lock.add(self.addr, deadline);
+        self.timer = Some(id);
+        self.waker = sync::Arc::new(TimerWaker {
+            id,
+            timers: sync::Arc::clone(&self.timers),
+        });
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delay")
+            .field("inner", &self.inner)
+            .field("addr", &self.addr)
+            .field("timer", &self.timer)
+            .finish()
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        escape::check_executor(|| format!("delay of {}", self.addr));
+        let this = &mut *self;
+        let poll = match this.timer {
+            Some(id) => {
+                let waker = cx.waker().clone();
+                this.timers.lock().unwrap().wakers.insert(id, waker);
+                let waker = waker_ref(&this.waker);
+                this.inner.poll_unpin(&mut Context::from_waker(&waker))
+            }
+            None => this.inner.poll_unpin(cx),
+        };
+        futures::ready!(poll);
+        if let Some(id) = self.timer.take() {
+            let mut lock = self.timers.lock().unwrap();
+            lock.remove(id);
+            if let Some(events) = &lock.events {
+                events.tally().timer_fired();
+                events.record(EventKind::TimerFired {
+                    host: self.addr,
+                    timer: id,
+                });
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            self.timers.lock().unwrap().remove(id);
+        }
+    }
 }
 
 #[derive(Debug)]
 struct DeterministicPark<P> {
     park: P,
     inner: sync::Arc<sync::Mutex<Inner>>,
+    /// Set when the executor is unparked, for instance when a task is spawned through a
+    /// handle, so that time is not advanced past work which is ready to run.
+    unparked: sync::Arc<atomic::AtomicBool>,
 }
 
 impl<P> DeterministicPark<P> {
     fn new(park: P, inner: sync::Arc<sync::Mutex<Inner>>) -> Self {
-        Self { park, inner }
+        Self {
+            park,
+            inner,
+            unparked: sync::Arc::new(atomic::AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Unparks the executor, noting that it was unparked.
+#[derive(Debug)]
+pub struct DeterministicUnpark<U> {
+    unpark: U,
+    unparked: sync::Arc<atomic::AtomicBool>,
+}
+
+impl<U> tokio_executor::park::Unpark for DeterministicUnpark<U>
+where
+    U: tokio_executor::park::Unpark,
+{
+    fn unpark(&self) {
+        self.unparked.store(true, atomic::Ordering::SeqCst);
+        // the reactor is woken through a pipe, which is not simulated code blocking.
+        escape::unguarded(|| self.unpark.unpark());
     }
 }
 
@@ -128,17 +815,31 @@ impl<P> tokio_executor::park::Park for DeterministicPark<P>
 where
     P: tokio_executor::park::Park,
 {
-    type Unpark = P::Unpark;
+    type Unpark = DeterministicUnpark<P::Unpark>;
     type Error = P::Error;
     fn unpark(&self) -> Self::Unpark {
-        self.park.unpark()
+        DeterministicUnpark {
+            unpark: self.park.unpark(),
+            unparked: sync::Arc::clone(&self.unparked),
+        }
     }
     fn park(&mut self) -> Result<(), Self::Error> {
+        if self.unparked.load(atomic::Ordering::SeqCst) {
+            return self.park.park_timeout(time::Duration::from_millis(0));
+        }
         self.park.park()
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
-        let mut lock = self.inner.lock().unwrap();
-        lock.advance(duration);
+        if self.unparked.load(atomic::Ordering::SeqCst) {
+            // the executor may have work to do, so return to it without advancing time.
+            return self.park.park_timeout(time::Duration::from_millis(0));
+        }
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Err(error) = inner.advance(duration) {
+                inner.overflowed(error);
+            }
+        }
         self.park.park_timeout(time::Duration::from_millis(0))
     }
 }
@@ -147,16 +848,49 @@ impl<P> tokio_executor::park::Park for DeterministicTime<P>
 where
     P: tokio_executor::park::Park,
 {
-    type Unpark = P::Unpark;
+    type Unpark = DeterministicUnpark<P::Unpark>;
     type Error = P::Error;
     fn unpark(&self) -> Self::Unpark {
         self.park.unpark()
     }
     fn park(&mut self) -> Result<(), Self::Error> {
-        self.park.park()
+        let _span = profile::span(Subsystem::Timers);
+        // the wheel only moves timers down a level when time reaches the slot holding them, so
+        // keep stepping it until a timer fires or the executor is otherwise unparked, rather
+        // than returning to an executor with nothing to run at every step.
+        let before = self.inner.lock().unwrap().elapsed();
+        // once time overflows it stops, so return to the runtime to report it.
+        while self.inner.lock().unwrap().overflow.is_none() {
+            self.park.park()?;
+            self.wake_fired();
+            if self.unparked.swap(false, atomic::Ordering::SeqCst) {
+                break;
+            }
+        }
+        let (elapsed, now) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.elapsed(), inner.now())
+        };
+        if elapsed > before {
+            // delays are only deregistered once polled, so those which just fired are still
+            // among the timers.
+            let timer = self.timers.lock().unwrap().due(now);
+            self.inner
+                .lock()
+                .unwrap()
+                .record(before, AdvanceCause::Idle { timer });
+            if let Some(timeline) = &self.timeline {
+                timeline.record(TimelineKind::TimeAdvanced);
+            }
+        }
+        Ok(())
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
-        self.park.park_timeout(duration)
+        let _span = profile::span(Subsystem::Timers);
+        self.park.park_timeout(duration)?;
+        self.wake_fired();
+        self.unparked.store(false, atomic::Ordering::SeqCst);
+        Ok(())
     }
 }
 
@@ -177,8 +911,7 @@ impl Now {
 
 impl tokio_timer::clock::Now for Now {
     fn now(&self) -> time::Instant {
-        let l = self.inner.lock().unwrap();
-        l.base + l.advance
+        self.inner.lock().unwrap().now()
     }
 }
 
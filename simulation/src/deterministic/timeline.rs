@@ -0,0 +1,335 @@
+//! Structured timeline of a simulation run, for rendering in a visualizer.
+//!
+//! Once recording is enabled with [`DeterministicRuntime::record_timeline`], the runtime notes
+//! when each task is spawned and exits, when time is advanced to fire timers, and when each
+//! connection is established and closed. Together with the actions in the chaos log, these
+//! are exported as a JSON document of entries ordered by simulated time:
+//!
+//! ```text
+//! {"entries":[
+//! {"at_us":0,"kind":"task_spawned","host":"10.0.0.1","task":0},
+//! {"at_us":1500000,"kind":"time_advanced"},
+//! {"at_us":1500000,"kind":"connected","source":"10.0.0.2:65535","dest":"10.0.0.1:9092"},
+//! {"at_us":2000000,"kind":"fault","fault":"Kill","target":"Host(10.0.0.1)"}
+//! ]}
+//! ```
+//!
+//! [`DeterministicRuntime::record_timeline`]:crate::deterministic::DeterministicRuntime::record_timeline
+use crate::deterministic::{
+    DeterministicChaosLogHandle, DeterministicTimeHandle, EventKind, EventLog,
+};
+use std::{fmt, fmt::Write as _, io, mem, net, sync, time};
+
+/// What happened at a point in the timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimelineKind {
+    /// A task was spawned on behalf of `host`.
+    TaskSpawned { host: net::IpAddr, task: u64 },
+    /// A task exited, either by completing or by being cancelled when its host was killed.
+    TaskExited {
+        host: net::IpAddr,
+        task: u64,
+        cancelled: bool,
+    },
+    /// The process running on `host` exited with `code`, cancelling all of its tasks.
+    HostExited { host: net::IpAddr, code: i32 },
+    /// Time was advanced while every task was idle, to fire the next timer. Timers with
+    /// distant deadlines may be reached in several steps.
+    TimeAdvanced,
+    /// A connection was established from `source` to `dest`.
+    Connected {
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    },
+    /// The `local` half of a connection to `peer` was closed.
+    Closed {
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+    /// A fault was injected, as recorded in the chaos log.
+    Fault { kind: String, target: String },
+    /// A connection from `source` to `dest` was refused because the listener's queue of
+    /// connections it has not accepted was full. Only recorded with strict queues.
+    BacklogFull {
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+    },
+    /// A write by the `local` half of a connection to `peer` was cut short or blocked because
+    /// its send buffer was full. Only recorded with strict queues.
+    BufferFull {
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+}
+
+/// An entry in the timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineEntry {
+    /// Simulated time elapsed since the runtime was created.
+    pub at: time::Duration,
+    pub kind: TimelineKind,
+}
+
+/// Handle for recording and exporting the timeline of a run.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    time: DeterministicTimeHandle,
+    chaos_log: DeterministicChaosLogHandle,
+    events: EventLog,
+    /// Entries recorded so far, or `None` if recording is not enabled.
+    entries: sync::Arc<sync::Mutex<Option<Vec<TimelineEntry>>>>,
+}
+
+impl Timeline {
+    pub(crate) fn new(
+        time: DeterministicTimeHandle,
+        chaos_log: DeterministicChaosLogHandle,
+        events: EventLog,
+    ) -> Self {
+        Self {
+            time,
+            chaos_log,
+            events,
+            entries: sync::Arc::new(sync::Mutex::new(None)),
+        }
+    }
+
+    /// Start recording entries.
+    pub(crate) fn enable(&self) {
+        let mut lock = self.entries.lock().unwrap();
+        if lock.is_none() {
+            *lock = Some(vec![]);
+        }
+    }
+
+    /// Returns the event log, which every entry is also recorded in.
+    pub(crate) fn events(&self) -> &EventLog {
+        &self.events
+    }
+
+    /// Record that `kind` happened now, if recording is enabled, in the timeline and the event
+    /// log.
+    pub(crate) fn record(&self, kind: TimelineKind) {
+        self.events
+            .record_with(|| EventKind::Timeline(kind.clone()));
+        if let Some(entries) = self.entries.lock().unwrap().as_mut() {
+            entries.push(TimelineEntry {
+                at: self.time.elapsed(),
+                kind,
+            });
+        }
+    }
+
+    /// Returns the approximate number of bytes held by the entries recorded so far, not
+    /// counting the chaos log.
+    pub(crate) fn memory(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.as_ref().map_or(0, Vec::len) * mem::size_of::<TimelineEntry>()
+    }
+
+    /// Returns every entry recorded so far, along with every action in the chaos log, ordered
+    /// by simulated time.
+    pub fn entries(&self) -> Vec<TimelineEntry> {
+        let mut entries = self.entries.lock().unwrap().clone().unwrap_or_default();
+        let start = self.time.now() - self.time.elapsed();
+        entries.extend(
+            self.chaos_log
+                .actions()
+                .into_iter()
+                .map(|action| TimelineEntry {
+                    at: action.at - start,
+                    kind: TimelineKind::Fault {
+                        kind: format!("{:?}", action.kind),
+                        target: format!("{:?}", action.target),
+                    },
+                }),
+        );
+        // the sort is stable, so entries recorded at the same time keep their order.
+        entries.sort_by_key(|entry| entry.at);
+        entries
+    }
+
+    /// Returns the timeline as a JSON document.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"entries\":[");
+        for (i, entry) in self.entries().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push('\n');
+            let _ = write!(json, "{{\"at_us\":{}", entry.at.as_micros());
+            let _ = write_kind(&mut json, &entry.kind);
+            json.push('}');
+        }
+        json.push_str("\n]}\n");
+        json
+    }
+
+    /// Write the timeline as a JSON document to `writer`.
+    pub fn write_json<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all(self.to_json().as_bytes())
+    }
+}
+
+/// Write the fields describing `kind` to `json`, each preceded by a comma.
+pub(crate) fn write_kind(json: &mut String, kind: &TimelineKind) -> fmt::Result {
+    match kind {
+        TimelineKind::TaskSpawned { host, task } => write!(
+            json,
+            ",\"kind\":\"task_spawned\",\"host\":\"{}\",\"task\":{}",
+            host, task
+        ),
+        TimelineKind::TaskExited {
+            host,
+            task,
+            cancelled,
+        } => write!(
+            json,
+            ",\"kind\":\"task_exited\",\"host\":\"{}\",\"task\":{},\"cancelled\":{}",
+            host, task, cancelled
+        ),
+        TimelineKind::HostExited { host, code } => write!(
+            json,
+            ",\"kind\":\"host_exited\",\"host\":\"{}\",\"code\":{}",
+            host, code
+        ),
+        TimelineKind::TimeAdvanced => write!(json, ",\"kind\":\"time_advanced\""),
+        TimelineKind::Connected { source, dest } => write!(
+            json,
+            ",\"kind\":\"connected\",\"source\":\"{}\",\"dest\":\"{}\"",
+            source, dest
+        ),
+        TimelineKind::Closed { local, peer } => write!(
+            json,
+            ",\"kind\":\"closed\",\"local\":\"{}\",\"peer\":\"{}\"",
+            local, peer
+        ),
+        TimelineKind::Fault { kind, target } => write!(
+            json,
+            ",\"kind\":\"fault\",\"fault\":\"{}\",\"target\":\"{}\"",
+            escape(kind),
+            escape(target)
+        ),
+        TimelineKind::BacklogFull { source, dest } => write!(
+            json,
+            ",\"kind\":\"backlog_full\",\"source\":\"{}\",\"dest\":\"{}\"",
+            source, dest
+        ),
+        TimelineKind::BufferFull { local, peer } => write!(
+            json,
+            ",\"kind\":\"buffer_full\",\"local\":\"{}\",\"peer\":\"{}\"",
+            local, peer
+        ),
+    }
+}
+
+/// Escape `value` for use inside a JSON string.
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{ChaosKind, ChaosTarget, DeterministicRuntime};
+    use crate::NetEnv;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that tasks, time, connections and faults are recorded in order of simulated time.
+    fn record_and_export() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let timeline = runtime.record_timeline();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 9092);
+        runtime
+            .block_on(async {
+                let mut listener = server.bind(addr).await.unwrap();
+                let connector = client.clone();
+                let task = crate::spawn_with_result(&client, async move {
+                    crate::delay_for(Duration::from_secs(1)).await;
+                    connector.connect(addr).await.unwrap()
+                });
+                let (_socket, _) = crate::TcpListener::accept(&mut listener).await.unwrap();
+                drop(task.await);
+                server.chaos_log_handle().record(
+                    ChaosKind::Custom("partition".to_string()),
+                    ChaosTarget::Network,
+                );
+            })
+            .unwrap();
+
+        let entries = timeline.entries();
+        assert!(entries
+            .iter()
+            .any(|entry| entry.kind == TimelineKind::TimeAdvanced && entry.at.as_secs() == 1));
+        let kinds: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| entry.kind != TimelineKind::TimeAdvanced)
+            .map(|entry| (entry.at.as_secs(), entry.kind))
+            .collect();
+        let local = net::SocketAddr::new(net::Ipv4Addr::new(10, 0, 0, 2).into(), 65535);
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    0,
+                    TimelineKind::TaskSpawned {
+                        host: local.ip(),
+                        task: 0
+                    }
+                ),
+                (
+                    1,
+                    TimelineKind::Connected {
+                        source: local,
+                        dest: addr
+                    }
+                ),
+                (
+                    1,
+                    TimelineKind::TaskExited {
+                        host: local.ip(),
+                        task: 0,
+                        cancelled: false
+                    }
+                ),
+                (1, TimelineKind::Closed { local, peer: addr }),
+                (
+                    1,
+                    TimelineKind::Closed {
+                        local: addr,
+                        peer: local
+                    }
+                ),
+                (
+                    1,
+                    TimelineKind::Fault {
+                        kind: "Custom(\"partition\")".to_string(),
+                        target: "Network".to_string()
+                    }
+                ),
+            ]
+        );
+        let json = timeline.to_json();
+        assert!(json.contains(
+            "{\"at_us\":1000000,\"kind\":\"connected\",\"source\":\"10.0.0.2:65535\",\"dest\":\"10.0.0.1:9092\"}"
+        ));
+        assert!(json.contains("\"fault\":\"Custom(\\\"partition\\\")\""));
+    }
+}
@@ -0,0 +1,264 @@
+//! Exporting the shape of a simulated cluster.
+//!
+//! [`topology`] takes a snapshot of the hosts of a simulation, the addresses they listen on,
+//! the connections open between them and the partitions in place, at the simulated instant it
+//! is called. [`Topology::to_dot`] renders the snapshot as a Graphviz graph, with a node for
+//! each host and an edge for each connection, so the shape of the cluster when a test fails
+//! can be seen at a glance rather than pieced together from the log.
+//!
+//! [`topology`]:crate::deterministic::DeterministicRuntimeHandle::topology
+//! [`Topology::to_dot`]:Topology::to_dot
+use super::timeline;
+use std::{collections, fmt::Write, io, net, time};
+
+/// A host of a simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopologyHost {
+    pub addr: net::IpAddr,
+    /// The name given to the host, if any.
+    pub hostname: Option<String>,
+    /// Ports the host has a listener bound to.
+    pub listening: Vec<u16>,
+    /// Tasks running on behalf of the host.
+    pub tasks: usize,
+    /// Code passed to the most recent exit of the host, if it has exited.
+    pub exit_code: Option<i32>,
+}
+
+/// A connection which neither end has closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveConnection {
+    pub source: net::SocketAddr,
+    pub dest: net::SocketAddr,
+    /// True if traffic over the connection is held back in both directions.
+    pub clogged: bool,
+}
+
+/// A snapshot of the hosts, listeners, connections and partitions of a simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    /// Simulated time elapsed since the runtime was created when the snapshot was taken.
+    pub at: time::Duration,
+    /// Hosts which are named, listening, connected, running tasks or have exited, ordered by
+    /// address.
+    pub hosts: Vec<TopologyHost>,
+    /// Live connections, in the order they were established.
+    pub connections: Vec<LiveConnection>,
+    /// Pairs of hosts, from source to destination, between which connections are clogged.
+    pub partitions: Vec<(net::IpAddr, net::IpAddr)>,
+}
+
+/// Collects the hosts of a topology from the parts of the simulation which know of them.
+#[derive(Debug, Default)]
+pub(crate) struct Hosts {
+    hosts: collections::BTreeMap<net::IpAddr, TopologyHost>,
+}
+
+impl Hosts {
+    pub(crate) fn host(&mut self, addr: net::IpAddr) -> &mut TopologyHost {
+        self.hosts.entry(addr).or_insert_with(|| TopologyHost {
+            addr,
+            hostname: None,
+            listening: vec![],
+            tasks: 0,
+            exit_code: None,
+        })
+    }
+}
+
+impl Topology {
+    pub(crate) fn new(
+        at: time::Duration,
+        mut hosts: Hosts,
+        listeners: Vec<net::SocketAddr>,
+        connections: Vec<LiveConnection>,
+        partitions: Vec<(net::IpAddr, net::IpAddr)>,
+    ) -> Self {
+        for listener in listeners {
+            hosts.host(listener.ip()).listening.push(listener.port());
+        }
+        for connection in &connections {
+            hosts.host(connection.source.ip());
+            hosts.host(connection.dest.ip());
+        }
+        for (source, dest) in &partitions {
+            hosts.host(*source);
+            hosts.host(*dest);
+        }
+        let hosts = hosts
+            .hosts
+            .into_values()
+            .map(|mut host| {
+                host.listening.sort();
+                host
+            })
+            .collect();
+        Self {
+            at,
+            hosts,
+            connections,
+            partitions,
+        }
+    }
+
+    /// Returns the topology as a Graphviz graph in the DOT language. Hosts which have exited
+    /// are drawn dashed, clogged connections in red, and partitions as dashed red edges.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph topology {\n");
+        let _ = writeln!(
+            dot,
+            "  label=\"topology at {}\";",
+            timeline::escape(&format!("{:?}", self.at))
+        );
+        dot.push_str("  node [shape=box];\n");
+        for host in &self.hosts {
+            let mut label = match &host.hostname {
+                Some(hostname) => format!("{}\\n{}", timeline::escape(hostname), host.addr),
+                None => host.addr.to_string(),
+            };
+            if !host.listening.is_empty() {
+                let ports: Vec<_> = host.listening.iter().map(u16::to_string).collect();
+                let _ = write!(label, "\\nlistening on {}", ports.join(", "));
+            }
+            let _ = write!(label, "\\n{} tasks", host.tasks);
+            let style = match host.exit_code {
+                Some(code) => {
+                    let _ = write!(label, "\\nexited with {}", code);
+                    ",style=dashed"
+                }
+                None => "",
+            };
+            let _ = writeln!(dot, "  \"{}\" [label=\"{}\"{}];", host.addr, label, style);
+        }
+        for connection in &self.connections {
+            let style = if connection.clogged { ",color=red" } else { "" };
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"{}\" [label=\"{} -> {}\"{}];",
+                connection.source.ip(),
+                connection.dest.ip(),
+                connection.source.port(),
+                connection.dest.port(),
+                style
+            );
+        }
+        for (source, dest) in &self.partitions {
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"{}\" [label=\"partitioned\",style=dashed,color=red];",
+                source, dest
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Write the topology as a Graphviz graph to `writer`.
+    pub fn write_dot<W>(&self, mut writer: W) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        writer.write_all(self.to_dot().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::network::fault::CloggedConnection;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that the topology lists hosts with their listeners and tasks, live connections and
+    /// partitions, and renders them as a graph.
+    fn topology() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        let exited = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 3).into());
+        runtime
+            .network
+            .clone_inner()
+            .lock()
+            .unwrap()
+            .clog_connection(CloggedConnection::new(
+                client.local_addr(),
+                exited.local_addr(),
+            ));
+        runtime
+            .block_on(async {
+                server.set_hostname(server.local_addr(), "server");
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let _other = server
+                    .bind(net::SocketAddr::new(server.local_addr(), 80))
+                    .await
+                    .unwrap();
+                let _socket = client.connect(addr).await.unwrap();
+                let (_accepted, _) = listener.accept().await.unwrap();
+                let closed = client.connect(addr).await.unwrap();
+                drop(closed);
+                server.spawn(futures::future::pending());
+                let exiting = exited.clone();
+                exited.spawn(async move { exiting.exit(1).await });
+                server.delay_from(Duration::from_secs(2)).await;
+
+                let topology = server.topology();
+                assert_eq!(topology.at, Duration::from_secs(2));
+                assert_eq!(
+                    topology.hosts,
+                    vec![
+                        TopologyHost {
+                            addr: server.local_addr(),
+                            hostname: Some("server".to_string()),
+                            listening: vec![80, 9092],
+                            tasks: 1,
+                            exit_code: None,
+                        },
+                        TopologyHost {
+                            addr: client.local_addr(),
+                            hostname: None,
+                            listening: vec![],
+                            tasks: 0,
+                            exit_code: None,
+                        },
+                        TopologyHost {
+                            addr: exited.local_addr(),
+                            hostname: None,
+                            listening: vec![],
+                            tasks: 0,
+                            exit_code: Some(1),
+                        },
+                    ]
+                );
+                assert_eq!(
+                    topology.connections,
+                    vec![LiveConnection {
+                        source: net::SocketAddr::new(client.local_addr(), 65535),
+                        dest: addr,
+                        clogged: false,
+                    }]
+                );
+                assert_eq!(
+                    topology.partitions,
+                    vec![(client.local_addr(), exited.local_addr())]
+                );
+
+                let dot = topology.to_dot();
+                assert!(dot.starts_with("digraph topology {\n  label=\"topology at 2s\";\n"));
+                for line in &[
+                "  \"10.0.0.1\" [label=\"server\\n10.0.0.1\\nlistening on 80, 9092\\n1 tasks\"];",
+                "  \"10.0.0.2\" [label=\"10.0.0.2\\n0 tasks\"];",
+                "  \"10.0.0.3\" [label=\"10.0.0.3\\n0 tasks\\nexited with 1\",style=dashed];",
+                "  \"10.0.0.2\" -> \"10.0.0.1\" [label=\"65535 -> 9092\"];",
+                "  \"10.0.0.2\" -> \"10.0.0.3\" [label=\"partitioned\",style=dashed,color=red];",
+            ] {
+                assert!(dot.contains(line), "{} not in {}", line, dot);
+            }
+                assert!(dot.ends_with("}\n"));
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Exporting a run in the Chrome trace event format.
+//!
+//! [`EventLog::to_chrome_trace`] turns the events kept in memory into a JSON document which
+//! `about://tracing` in Chrome, or Perfetto, opens as a zoomable view of the run. Each host is
+//! shown as a process of its own, holding a span for the lifetime of each of its tasks, each of
+//! its timers which fired and each connection it established, along with a mark at each poll of
+//! a task. Faults are marked across every host. Timestamps are in simulated time.
+//!
+//! [`EventLog::to_chrome_trace`]:crate::deterministic::EventLog::to_chrome_trace
+use super::{timeline, EventKind, EventRecord, TimelineKind};
+use std::{collections, net};
+
+/// Builds a trace, numbering hosts in the order they first appear.
+#[derive(Debug, Default)]
+struct Trace {
+    json: String,
+    hosts: collections::HashMap<net::IpAddr, usize>,
+    /// Connections which are open, by source and destination, with the id of their span.
+    connections: collections::HashMap<(net::SocketAddr, net::SocketAddr), u64>,
+    next_connection: u64,
+}
+
+impl Trace {
+    /// Returns the process id of `host`, naming the process on its first appearance.
+    fn pid(&mut self, host: net::IpAddr) -> usize {
+        if let Some(pid) = self.hosts.get(&host) {
+            return *pid;
+        }
+        let pid = self.hosts.len() + 1;
+        self.hosts.insert(host, pid);
+        self.push(format!(
+            "{{\"ph\":\"M\",\"name\":\"process_name\",\"pid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+            pid, host
+        ));
+        pid
+    }
+
+    fn push(&mut self, event: String) {
+        if !self.json.is_empty() {
+            self.json.push(',');
+        }
+        self.json.push('\n');
+        self.json.push_str(&event);
+    }
+
+    /// Begin or end (`phase` of `b` or `e`) the span `name` with `id` in `category` on `host`.
+    fn span(
+        &mut self,
+        phase: char,
+        category: &str,
+        name: &str,
+        id: u64,
+        host: net::IpAddr,
+        at: u128,
+    ) {
+        let pid = self.pid(host);
+        self.push(format!(
+            "{{\"ph\":\"{}\",\"cat\":\"{}\",\"name\":\"{}\",\"id\":\"{}-{}\",\"pid\":{},\"tid\":0,\"ts\":{}}}",
+            phase,
+            category,
+            timeline::escape(name),
+            category,
+            id,
+            pid,
+            at
+        ));
+    }
+
+    fn add(&mut self, event: &EventRecord) {
+        let at = event.at.as_micros();
+        match &event.kind {
+            EventKind::Timeline(TimelineKind::TaskSpawned { host, task }) => {
+                self.span('b', "task", &format!("task {}", task), *task, *host, at)
+            }
+            EventKind::Timeline(TimelineKind::TaskExited { host, task, .. }) => {
+                self.span('e', "task", &format!("task {}", task), *task, *host, at)
+            }
+            EventKind::TaskPolled { host, task } => {
+                let pid = self.pid(*host);
+                self.push(format!(
+                    "{{\"ph\":\"i\",\"s\":\"t\",\"cat\":\"poll\",\"name\":\"poll task {}\",\"pid\":{},\"tid\":0,\"ts\":{}}}",
+                    task, pid, at
+                ));
+            }
+            EventKind::TimerSet { host, timer } => {
+                self.span('b', "timer", &format!("timer {}", timer), *timer, *host, at)
+            }
+            EventKind::TimerFired { host, timer } => {
+                self.span('e', "timer", &format!("timer {}", timer), *timer, *host, at)
+            }
+            EventKind::Timeline(TimelineKind::Connected { source, dest }) => {
+                let id = self.next_connection;
+                self.next_connection += 1;
+                self.connections.insert((*source, *dest), id);
+                let name = format!("{} -> {}", source, dest);
+                self.span('b', "connection", &name, id, source.ip(), at)
+            }
+            EventKind::Timeline(TimelineKind::Closed { local, peer }) => {
+                // the span ends when either half is closed.
+                for (source, dest) in [(*local, *peer), (*peer, *local)].iter() {
+                    if let Some(id) = self.connections.remove(&(*source, *dest)) {
+                        let name = format!("{} -> {}", source, dest);
+                        self.span('e', "connection", &name, id, source.ip(), at);
+                    }
+                }
+            }
+            EventKind::Timeline(TimelineKind::HostExited { host, code }) => {
+                let pid = self.pid(*host);
+                self.push(format!(
+                    "{{\"ph\":\"i\",\"s\":\"p\",\"cat\":\"host\",\"name\":\"exited with {}\",\"pid\":{},\"tid\":0,\"ts\":{}}}",
+                    code, pid, at
+                ));
+            }
+            EventKind::Timeline(TimelineKind::Fault { kind, target }) => self.push(format!(
+                "{{\"ph\":\"i\",\"s\":\"g\",\"cat\":\"fault\",\"name\":\"{} on {}\",\"pid\":0,\"tid\":0,\"ts\":{}}}",
+                timeline::escape(kind),
+                timeline::escape(target),
+                at
+            )),
+            EventKind::Timeline(TimelineKind::BacklogFull { source, dest }) => {
+                let pid = self.pid(*source);
+                self.push(format!(
+                    "{{\"ph\":\"i\",\"s\":\"p\",\"cat\":\"backpressure\",\"name\":\"backlog of {} full\",\"pid\":{},\"tid\":0,\"ts\":{}}}",
+                    dest, pid, at
+                ));
+            }
+            EventKind::Timeline(TimelineKind::BufferFull { local, peer }) => {
+                let pid = self.pid(local.ip());
+                self.push(format!(
+                    "{{\"ph\":\"i\",\"s\":\"p\",\"cat\":\"backpressure\",\"name\":\"buffer to {} full\",\"pid\":{},\"tid\":0,\"ts\":{}}}",
+                    peer, pid, at
+                ));
+            }
+            EventKind::Timeline(TimelineKind::TimeAdvanced)
+            | EventKind::Sent { .. }
+            | EventKind::RandomDraw { .. } => {}
+        }
+    }
+}
+
+/// Returns `events` as a JSON document in the Chrome trace event format.
+pub(crate) fn chrome_trace(events: &[EventRecord]) -> String {
+    let mut trace = Trace::default();
+    for event in events {
+        trace.add(event);
+    }
+    let mut json = String::from("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[");
+    json.push_str(&trace.json);
+    json.push_str("\n]}\n");
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that tasks, timers and connections are exported as spans on the track of their
+    /// host, in simulated time.
+    fn export() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let events = runtime.record_events(1000);
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let mut listener = server.bind(addr).await.unwrap();
+                let connector = client.clone();
+                client.spawn(async move {
+                    connector.delay_from(Duration::from_secs(1)).await;
+                    let _socket = connector.connect(addr).await.unwrap();
+                    connector.delay_from(Duration::from_secs(1)).await;
+                });
+                let (_socket, _) = listener.accept().await.unwrap();
+                server.delay_from(Duration::from_secs(5)).await;
+            })
+            .unwrap();
+
+        let trace = events.to_chrome_trace();
+        assert!(trace.starts_with("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n"));
+        assert!(trace.ends_with("\n]}\n"));
+        for event in &[
+            "{\"ph\":\"M\",\"name\":\"process_name\",\"pid\":1,\"args\":{\"name\":\"10.0.0.2\"}}",
+            "{\"ph\":\"b\",\"cat\":\"task\",\"name\":\"task 0\",\"id\":\"task-0\",\"pid\":1,\"tid\":0,\"ts\":0}",
+            "{\"ph\":\"b\",\"cat\":\"timer\",\"name\":\"timer 0\",\"id\":\"timer-0\",\"pid\":1,\"tid\":0,\"ts\":0}",
+            "{\"ph\":\"e\",\"cat\":\"timer\",\"name\":\"timer 0\",\"id\":\"timer-0\",\"pid\":1,\"tid\":0,\"ts\":1000000}",
+            "{\"ph\":\"b\",\"cat\":\"connection\",\"name\":\"10.0.0.2:65535 -> 10.0.0.1:9092\",\"id\":\"connection-0\",\"pid\":1,\"tid\":0,\"ts\":1000000}",
+            "{\"ph\":\"e\",\"cat\":\"connection\",\"name\":\"10.0.0.2:65535 -> 10.0.0.1:9092\",\"id\":\"connection-0\",\"pid\":1,\"tid\":0,\"ts\":2000000}",
+            "{\"ph\":\"e\",\"cat\":\"task\",\"name\":\"task 0\",\"id\":\"task-0\",\"pid\":1,\"tid\":0,\"ts\":2000000}",
+            "{\"ph\":\"M\",\"name\":\"process_name\",\"pid\":2,\"args\":{\"name\":\"10.0.0.1\"}}",
+        ] {
+            assert!(trace.contains(event), "{} not in {}", event, trace);
+        }
+        assert!(trace.contains("\"name\":\"poll task 0\",\"pid\":1"));
+        assert!(trace.contains("\"pid\":2,\"tid\":0,\"ts\":6000000}"));
+    }
+}
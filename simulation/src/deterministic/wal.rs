@@ -0,0 +1,264 @@
+//! Crash testing for write ahead logs.
+//!
+//! `WalChecker` packages the most common storage test: records are appended to a log one at a
+//! time, the host crashes, and the log is recovered. Every record whose append was
+//! acknowledged before the crash must be recovered, and nothing which was never appended may
+//! appear.
+//!
+//! Rather than crashing at random, the checker first counts the filesystem operations needed
+//! to append every record. It then replays the appends once for each of those operations,
+//! freezing the disk after that many operations have started and crashing the host, so every
+//! intermediate disk state is tested. Each replay uses a fresh [`DeterministicRuntime`], so
+//! state shared between appends must live on the simulated filesystem.
+//!
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+use crate::{
+    deterministic::{DeterministicFsHandle, DeterministicRuntime, DeterministicRuntimeHandle},
+    Error, SpawnEnv, TimeEnv,
+};
+use futures::{channel::oneshot, Future};
+use std::{fmt, io, net, ops, pin::Pin, sync, time::Duration};
+use tracing::debug;
+
+type Append = sync::Arc<
+    dyn Fn(
+            DeterministicRuntimeHandle,
+            Vec<u8>,
+        ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send>>
+        + Send
+        + Sync,
+>;
+type Recover = sync::Arc<
+    dyn Fn(
+            DeterministicRuntimeHandle,
+        ) -> Pin<Box<dyn Future<Output = io::Result<Vec<Vec<u8>>>> + Send>>
+        + Send
+        + Sync,
+>;
+type Configure = sync::Arc<dyn Fn(&DeterministicFsHandle) + Send + Sync>;
+
+/// Simulated time allowed for every append to complete before the host is crashed.
+const APPEND_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Checks that a write ahead log never loses an acknowledged record when its host crashes.
+pub struct WalChecker {
+    append: Append,
+    recover: Recover,
+    records: Vec<Vec<u8>>,
+    seeds: ops::Range<u64>,
+    configure: Option<Configure>,
+}
+
+impl fmt::Debug for WalChecker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "WalChecker {{ records: {}, seeds: {:?} }}",
+            self.records.len(),
+            self.seeds
+        )
+    }
+}
+
+impl WalChecker {
+    /// Create a checker for the log implemented by `append` and `recover`. `append` writes a
+    /// single record, and returns `Ok` once the record is acknowledged. `recover` reads back
+    /// every record in the log after a crash.
+    pub fn new<A, AF, R, RF>(append: A, recover: R) -> Self
+    where
+        A: Fn(DeterministicRuntimeHandle, Vec<u8>) -> AF + Send + Sync + 'static,
+        AF: Future<Output = io::Result<()>> + Send + 'static,
+        R: Fn(DeterministicRuntimeHandle) -> RF + Send + Sync + 'static,
+        RF: Future<Output = io::Result<Vec<Vec<u8>>>> + Send + 'static,
+    {
+        Self {
+            append: sync::Arc::new(move |handle, record| Box::pin(append(handle, record))),
+            recover: sync::Arc::new(move |handle| Box::pin(recover(handle))),
+            records: (0..8)
+                .map(|i| format!("record {}", i).into_bytes())
+                .collect(),
+            seeds: 0..1,
+            configure: None,
+        }
+    }
+
+    /// Append `records`, in order, instead of the default of eight short records.
+    pub fn records(mut self, records: Vec<Vec<u8>>) -> Self {
+        self.records = records;
+        self
+    }
+
+    /// Repeat every crash point with each seed in `seeds`, varying which unsynced writes
+    /// survive each crash.
+    pub fn seeds(mut self, seeds: ops::Range<u64>) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    /// Configure the filesystem faults of the host before the records are appended, for
+    /// example to enable torn or reordered writes.
+    pub fn configure<F>(mut self, configure: F) -> Self
+    where
+        F: Fn(&DeterministicFsHandle) + Send + Sync + 'static,
+    {
+        self.configure.replace(sync::Arc::new(configure));
+        self
+    }
+
+    /// Run the checker, returning the first violation found.
+    pub fn run(&self) -> Result<(), Error> {
+        for seed in self.seeds.clone() {
+            let operations = self.count_operations(seed)?;
+            debug!(
+                "checking {} crash points with seed {}",
+                operations + 1,
+                seed
+            );
+            for crash_point in 0..=operations {
+                self.check(seed, crash_point)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn runtime(
+        &self,
+        seed: u64,
+    ) -> Result<(DeterministicRuntime, DeterministicRuntimeHandle), Error> {
+        let (runtime, _) = DeterministicRuntime::builder().seed(seed).build()?;
+        let handle = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        if let Some(configure) = self.configure.as_ref() {
+            configure(&handle.fs_handle());
+        }
+        Ok((runtime, handle))
+    }
+
+    /// Returns the number of filesystem operations needed to append every record.
+    fn count_operations(&self, seed: u64) -> Result<u64, Error> {
+        let (mut runtime, handle) = self.runtime(seed)?;
+        let append = sync::Arc::clone(&self.append);
+        let records = self.records.clone();
+        runtime.block_on(async move {
+            for record in records {
+                // failed appends are not acknowledged, so are permitted.
+                let _ = append(handle.clone(), record).await;
+            }
+            Ok(handle.fs_handle().operations())
+        })?
+    }
+
+    /// Append every record, crashing the host once `crash_point` filesystem operations have
+    /// started, then check the recovered records.
+    fn check(&self, seed: u64, crash_point: u64) -> Result<(), Error> {
+        let (mut runtime, handle) = self.runtime(seed)?;
+        let append = sync::Arc::clone(&self.append);
+        let recover = sync::Arc::clone(&self.recover);
+        let records = self.records.clone();
+        let acknowledged = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async move {
+            handle.fs_handle().freeze_after(Some(crash_point));
+            let (done_tx, done_rx) = oneshot::channel();
+            let appender = handle.clone();
+            let appended = sync::Arc::clone(&acknowledged);
+            handle.spawn(async move {
+                for record in records {
+                    if append(appender.clone(), record.clone()).await.is_ok() {
+                        appended.lock().unwrap().push(record);
+                    }
+                }
+                let _ = done_tx.send(());
+            });
+            let _ = handle.timeout(done_rx, APPEND_TIMEOUT).await;
+            handle.kill(handle.local_addr());
+            handle.fs_handle().freeze_after(None);
+
+            let recovered = recover(handle.clone())
+                .await
+                .map_err(|source| Error::WalRecovery {
+                    seed,
+                    crash_point,
+                    source,
+                })?;
+            for record in acknowledged.lock().unwrap().iter() {
+                if !recovered.contains(record) {
+                    return Err(Error::WalRecordLost {
+                        seed,
+                        crash_point,
+                        record: record.clone(),
+                    });
+                }
+            }
+            for record in recovered {
+                if !self.records.contains(&record) {
+                    return Err(Error::WalUnexpectedRecord {
+                        seed,
+                        crash_point,
+                        record,
+                    });
+                }
+            }
+            Ok(())
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{File, FsEnv};
+
+    const LOG: &str = "/data/wal";
+
+    /// Append a newline terminated record to the log, syncing it if `sync` is set.
+    async fn append(
+        handle: DeterministicRuntimeHandle,
+        record: Vec<u8>,
+        sync: bool,
+    ) -> io::Result<()> {
+        let mut file = match handle.open(LOG).await {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => handle.create(LOG).await?,
+            Err(e) => return Err(e),
+        };
+        let mut line = record;
+        line.push(b'\n');
+        let offset = file.size().await?;
+        file.write_at(&line, offset).await?;
+        if sync {
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Read every complete record in the log.
+    async fn recover(handle: DeterministicRuntimeHandle) -> io::Result<Vec<Vec<u8>>> {
+        let data = match handle.read(LOG).await {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut records: Vec<Vec<u8>> = data.split(|b| *b == b'\n').map(|r| r.to_vec()).collect();
+        // the final record is incomplete unless the log ends with a newline.
+        records.pop();
+        Ok(records)
+    }
+
+    #[test]
+    /// Test that a log which syncs each record before acknowledging it passes, and one which
+    /// does not fails.
+    fn wal_checker() {
+        WalChecker::new(|handle, record| append(handle, record, true), recover)
+            .seeds(0..5)
+            .run()
+            .unwrap();
+
+        let err = WalChecker::new(|handle, record| append(handle, record, false), recover)
+            .seeds(0..5)
+            .run()
+            .unwrap_err();
+        match err {
+            Error::WalRecordLost { .. } => {}
+            e => panic!("unexpected error {}", e),
+        }
+    }
+}
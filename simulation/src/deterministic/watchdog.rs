@@ -0,0 +1,190 @@
+//! Turning a hung simulation into a report.
+//!
+//! A simulation which stops making progress, such as one with a task spinning inside a single
+//! poll or blocked on real I/O, hangs without a word, and in CI only ends once the job times
+//! out. With a [`watchdog`] configured, a thread watches the runtime while it runs, and once
+//! neither simulated time has advanced nor any task been polled for the configured amount of
+//! real time, prints the seed, the most recent advances of time and events, and the work the
+//! simulation is waiting on, then aborts the process.
+//!
+//! [`watchdog`]:crate::deterministic::DeterministicRuntimeBuilder::watchdog
+use super::{dump::SimContext, DeterministicRuntimeHandle, PendingWork};
+use std::{
+    fmt::Write as _,
+    process,
+    sync::{self, atomic},
+    thread, time,
+};
+
+/// Called with the report once the watchdog fires.
+pub(crate) type OnStall = sync::Arc<dyn Fn(String) + Send + Sync>;
+
+/// Returns the action of the watchdog outside of tests, printing the report and aborting.
+pub(crate) fn abort() -> OnStall {
+    sync::Arc::new(|report| {
+        eprintln!("{}", report);
+        process::abort();
+    })
+}
+
+/// Watches a running simulation for progress until dropped.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    stop: sync::Arc<atomic::AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Watch the simulation described by `context` on a thread of its own, calling `on_stall`
+    /// with a report once it makes no progress for `limit` of real time.
+    pub(crate) fn start(
+        limit: time::Duration,
+        context: SimContext,
+        handle: DeterministicRuntimeHandle,
+        on_stall: OnStall,
+    ) -> Self {
+        let stop = sync::Arc::new(atomic::AtomicBool::new(false));
+        let stopped = sync::Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let progress = || (context.time.try_elapsed(), context.events.tally().polls());
+            let mut last = progress();
+            let mut since = time::Instant::now();
+            while !stopped.load(atomic::Ordering::SeqCst) {
+                thread::park_timeout(limit / 4);
+                let now = progress();
+                if now != last {
+                    last = now;
+                    since = time::Instant::now();
+                } else if since.elapsed() >= limit {
+                    on_stall(report(limit, &context, &handle));
+                    break;
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, atomic::Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Describe a simulation which made no progress for `limit`.
+fn report(
+    limit: time::Duration,
+    context: &SimContext,
+    handle: &DeterministicRuntimeHandle,
+) -> String {
+    let mut report = format!(
+        "simulation made no progress for {:?} of real time: {}",
+        limit,
+        context.describe_outside()
+    );
+    let start = handle.now() - handle.elapsed();
+    let PendingWork {
+        timers,
+        tasks,
+        messages,
+    } = handle.pending_work();
+    let _ = write!(report, "\npending timers: {}", timers.len());
+    for timer in timers {
+        let _ = write!(
+            report,
+            "\n  timer {} of {} due at {:?}",
+            timer.id,
+            timer.host,
+            timer.deadline - start
+        );
+    }
+    let _ = write!(report, "\npending tasks: {}", tasks.len());
+    for task in tasks {
+        let _ = write!(report, "\n  task {} of {}", task.id, task.host);
+        if let Some(name) = task.name {
+            let _ = write!(report, " ({})", name);
+        }
+        report.push_str(if task.runnable {
+            ", runnable"
+        } else {
+            ", blocked"
+        });
+    }
+    let _ = write!(report, "\nmessages in flight: {}", messages.len());
+    for message in messages {
+        let _ = write!(
+            report,
+            "\n  {} -> {}: {} writes, {} bytes",
+            message.source, message.dest, message.chunks, message.bytes
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TimeEnv;
+    use std::{net, sync::mpsc};
+
+    #[test]
+    #[cfg_attr(
+        feature = "blocking-guard",
+        ignore = "sleeps the thread to stall the simulation"
+    )]
+    /// Test that the watchdog reports a simulation stuck inside a poll, with the work it is
+    /// waiting on, and stays quiet while the simulation makes progress.
+    fn stalled() {
+        let (tx, rx) = mpsc::channel();
+        let tx = sync::Mutex::new(tx);
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(9).build().unwrap();
+        runtime.watchdog = Some((
+            time::Duration::from_millis(100),
+            sync::Arc::new(move |report| tx.lock().unwrap().send(report).unwrap()),
+        ));
+        let host = handle.scoped(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        runtime
+            .block_on(async {
+                for _ in 0..50 {
+                    host.delay_from(time::Duration::from_secs(1)).await;
+                    thread::sleep(time::Duration::from_millis(5));
+                }
+            })
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        runtime
+            .block_on(async {
+                host.spawn_named("waiting", host.delay_from(time::Duration::from_secs(10)));
+                host.delay_from(time::Duration::from_secs(1)).await;
+                thread::sleep(time::Duration::from_millis(500));
+            })
+            .unwrap();
+        let report = rx.try_recv().unwrap();
+        assert!(
+            report.starts_with(
+                "simulation made no progress for 100ms of real time: seed 9 after 51s of \
+                 simulated time\nlast 20 clock advances:"
+            ),
+            "{}",
+            report
+        );
+        assert!(
+            report.ends_with(
+                "\npending timers: 1\n  timer 50 of 10.0.0.1 due at 60s\
+                 \npending tasks: 1\n  task 0 of 10.0.0.1 (waiting), blocked\
+                 \nmessages in flight: 0"
+            ),
+            "{}",
+            report
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Running the same test against the deterministic and production runtimes.
+//!
+//! A test body written against [`Environment`] should behave the same in simulation as it does
+//! with real sockets, files and timers. Running it under both runtimes catches divergence
+//! between the simulated implementation of the [`Environment`] contract and the real one, which
+//! would otherwise let a simulation pass for code which is broken in production.
+//!
+//! Test bodies are async functions which take the environment and an address on which they may
+//! bind a listener. [`dual_runtime_test!`] declares a test which runs a body under the
+//! [`DeterministicRuntime`] and then on localhost under the [`NaturalRuntime`]:
+//!
+//! ```ignore
+//! async fn echo<E: Environment>(env: E, addr: SocketAddr) {
+//!     let mut listener = env.bind(addr).await.unwrap();
+//!     // ...
+//! }
+//!
+//! simulation::dual_runtime_test!(echo_both, echo);
+//! ```
+//!
+//! [`Environment`]:crate::Environment
+//! [`dual_runtime_test!`]:crate::dual_runtime_test
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`NaturalRuntime`]:crate::production::NaturalRuntime
+use crate::{
+    deterministic::{DeterministicRuntime, DeterministicRuntimeHandle},
+    production::{NaturalRuntime, NaturalRuntimeHandle},
+};
+use futures::Future;
+use std::{net, panic};
+
+/// Port bound by test bodies in simulation, where every port is free.
+const DETERMINISTIC_PORT: u16 = 9092;
+
+/// Run `f` under a [`DeterministicRuntime`] seeded with `seed`, passing it a handle scoped to
+/// localhost and an address on localhost which it may bind.
+///
+/// [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+pub fn run_deterministic<F, U>(seed: u64, f: F) -> U::Output
+where
+    F: FnOnce(DeterministicRuntimeHandle, net::SocketAddr) -> U,
+    U: Future,
+{
+    let (mut runtime, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+    let addr = net::SocketAddr::new(handle.local_addr(), DETERMINISTIC_PORT);
+    runtime.block_on(f(handle, addr)).unwrap()
+}
+
+/// Run `f` under a [`NaturalRuntime`], passing it a handle and an address on localhost with a
+/// port which was free when the test started.
+///
+/// [`NaturalRuntime`]:crate::production::NaturalRuntime
+pub fn run_natural<F, U>(f: F) -> U::Output
+where
+    F: FnOnce(NaturalRuntimeHandle, net::SocketAddr) -> U,
+    U: Future,
+{
+    let mut runtime = NaturalRuntime::new().unwrap();
+    let handle = runtime.handle();
+    let localhost = net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), 0);
+    let addr = net::TcpListener::bind(localhost)
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    runtime.block_on(f(handle, addr))
+}
+
+/// Run `f`, reporting which runtime it was run under if it panics.
+#[doc(hidden)]
+pub fn report<F, T>(runtime: &str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(output) => output,
+        Err(payload) => {
+            eprintln!("test failed under the {} runtime", runtime);
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+/// Declare a test named `$name` which runs the async function `$body` under the deterministic
+/// runtime, and then under the production runtime. `$body` must be generic over the
+/// `Environment` it is passed, along with an address it may bind.
+///
+/// The deterministic runtime is seeded with `0`, unless a seed is provided after the body.
+/// Attributes and doc comments before the name are applied to the test.
+#[macro_export]
+macro_rules! dual_runtime_test {
+    ($(#[$meta:meta])* $name:ident, $body:path) => {
+        $crate::dual_runtime_test!($(#[$meta])* $name, $body, 0);
+    };
+    ($(#[$meta:meta])* $name:ident, $body:path, $seed:expr) => {
+        #[test]
+        $(#[$meta])*
+        fn $name() {
+            $crate::dual::report("deterministic", || {
+                $crate::dual::run_deterministic($seed, |env, addr| $body(env, addr))
+            });
+            $crate::dual::report("production", || {
+                $crate::dual::run_natural(|env, addr| $body(env, addr))
+            });
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Echo a message over a connection, then check that time advances across a delay.
+    async fn echo<E>(env: E, addr: net::SocketAddr)
+    where
+        E: NetEnv + SpawnEnv + TimeEnv,
+    {
+        let mut listener = env.bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        env.spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+        let mut socket = env.connect(addr).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        socket.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+
+        let start = env.now();
+        env.delay_from(time::Duration::from_millis(10)).await;
+        assert!(env.now() - start >= time::Duration::from_millis(10));
+    }
+
+    crate::dual_runtime_test!(
+        /// Test that a body runs to completion under both runtimes.
+        dual_echo,
+        echo
+    );
+}
@@ -0,0 +1,542 @@
+//! A type-erased [`Environment`], for code which cannot be generic over it.
+//!
+//! [`DynEnvironment`] wraps any [`Environment`] and implements [`Environment`] itself, with
+//! sockets, listeners, files and delays boxed. Applications can store a `DynEnvironment` in
+//! structs and trait objects instead of threading an `E: Environment` parameter through every
+//! layer, and still run under the [`DeterministicRuntime`] in tests.
+//!
+//! ```ignore
+//! struct Server {
+//!     env: DynEnvironment,
+//! }
+//!
+//! let server = Server {
+//!     env: DynEnvironment::new(runtime.localhost_handle()),
+//! };
+//! ```
+//!
+//! Each operation costs an extra allocation and dynamic dispatch compared to using the wrapped
+//! environment directly.
+//!
+//! [`Environment`]:crate::Environment
+//! [`DynEnvironment`]:DynEnvironment
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+#[cfg(feature = "sim")]
+use crate::deterministic::DeterministicRuntimeHandle;
+use crate::{
+    sync::{broadcast, mpsc, oneshot, Faults},
+    Environment, File, FsEnv, HostEnv, NetEnv, RandEnv, RandomHandle, SpawnEnv, TcpListener,
+    TcpStream, TimeEnv,
+};
+use async_trait::async_trait;
+use futures::{future::BoxFuture, Future, Stream, StreamExt};
+use std::{
+    any, fmt, io,
+    net::{IpAddr, SocketAddr},
+    path,
+    pin::Pin,
+    sync, time,
+};
+
+/// A boxed delay future, as returned by [`DynEnvironment`].
+///
+/// [`DynEnvironment`]:DynEnvironment
+pub type BoxDelay = Pin<Box<dyn Future<Output = ()> + Send>>;
+/// A boxed socket, as returned by [`DynEnvironment`].
+///
+/// [`DynEnvironment`]:DynEnvironment
+pub type BoxTcpStream = Box<dyn TcpStream>;
+/// A boxed file, as returned by [`DynEnvironment`].
+///
+/// [`DynEnvironment`]:DynEnvironment
+pub type BoxFile = Box<dyn File>;
+
+impl TcpStream for BoxTcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+#[async_trait]
+impl File for BoxFile {
+    async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        (**self).seek(pos).await
+    }
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset).await
+    }
+    async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        (**self).write_at(buf, offset).await
+    }
+    async fn size(&mut self) -> io::Result<u64> {
+        (**self).size().await
+    }
+    async fn sync_all(&mut self) -> io::Result<()> {
+        (**self).sync_all().await
+    }
+    async fn sync_data(&mut self) -> io::Result<()> {
+        (**self).sync_data().await
+    }
+}
+
+/// Object safe counterpart of [`TcpListener`], which boxes the sockets it accepts.
+///
+/// [`TcpListener`]:crate::TcpListener
+trait ErasedListener: Send {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxTcpStream, SocketAddr)>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn ttl(&self) -> io::Result<u32>;
+    fn set_ttl(&self, ttl: u32) -> io::Result<()>;
+    fn into_stream(self: Box<Self>)
+        -> Pin<Box<dyn Stream<Item = io::Result<BoxTcpStream>> + Send>>;
+}
+
+impl<L> ErasedListener for L
+where
+    L: TcpListener + Send + 'static,
+{
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<(BoxTcpStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (socket, addr) = TcpListener::accept(self).await?;
+            Ok((Box::new(socket) as BoxTcpStream, addr))
+        })
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        TcpListener::ttl(self)
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        TcpListener::set_ttl(self, ttl)
+    }
+    fn into_stream(
+        self: Box<Self>,
+    ) -> Pin<Box<dyn Stream<Item = io::Result<BoxTcpStream>> + Send>> {
+        let stream = TcpListener::into_stream(*self);
+        Box::pin(stream.map(|socket| socket.map(|socket| Box::new(socket) as BoxTcpStream)))
+    }
+}
+
+/// A boxed listener, as returned by [`DynEnvironment`].
+///
+/// [`DynEnvironment`]:DynEnvironment
+pub struct BoxTcpListener {
+    inner: Box<dyn ErasedListener>,
+}
+
+impl BoxTcpListener {
+    pub fn new<L>(listener: L) -> Self
+    where
+        L: TcpListener + Send + 'static,
+    {
+        Self {
+            inner: Box::new(listener),
+        }
+    }
+}
+
+impl fmt::Debug for BoxTcpListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BoxTcpListener {{ local_addr: {:?} }}",
+            self.inner.local_addr()
+        )
+    }
+}
+
+#[async_trait]
+impl TcpListener for BoxTcpListener {
+    type Stream = BoxTcpStream;
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)> {
+        self.inner.accept().await
+    }
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+    fn into_stream(self) -> Pin<Box<dyn Stream<Item = io::Result<Self::Stream>> + Send>> {
+        self.inner.into_stream()
+    }
+}
+
+/// Object safe counterpart of [`Environment`], which boxes everything it returns.
+///
+/// [`Environment`]:crate::Environment
+trait ErasedEnvironment: Send + Sync {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+    fn now(&self) -> time::Instant;
+    fn now_system_time(&self) -> time::SystemTime;
+    fn delay(&self, deadline: time::Instant) -> BoxDelay;
+    fn hostname(&self) -> String;
+    fn host_id(&self) -> u64;
+    fn local_addrs(&self) -> Vec<IpAddr>;
+    fn random(&self) -> RandomHandle;
+    fn config(&self, key: &str) -> Option<String>;
+    /// Returns the faults to inject into channels created through the environment.
+    fn channel_faults(&self) -> Option<sync::Arc<dyn Faults>>;
+    fn exit(&self, code: i32) -> BoxFuture<'_, ()>;
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxTcpListener>>;
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxTcpStream>>;
+    fn open(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<BoxFile>>;
+    fn create(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<BoxFile>>;
+    fn read(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+    fn write(&self, path: path::PathBuf, contents: Vec<u8>) -> BoxFuture<'_, io::Result<()>>;
+    fn rename(&self, from: path::PathBuf, to: path::PathBuf) -> BoxFuture<'_, io::Result<()>>;
+    fn remove(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>>;
+    fn create_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>>;
+    fn remove_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>>;
+    fn read_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<Vec<path::PathBuf>>>;
+    fn sync_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>>;
+}
+
+impl<E> ErasedEnvironment for E
+where
+    E: Environment + Sync,
+{
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        SpawnEnv::spawn(self, future)
+    }
+    fn now(&self) -> time::Instant {
+        TimeEnv::now(self)
+    }
+    fn now_system_time(&self) -> time::SystemTime {
+        TimeEnv::now_system_time(self)
+    }
+    fn delay(&self, deadline: time::Instant) -> BoxDelay {
+        Box::pin(TimeEnv::delay(self, deadline))
+    }
+    fn hostname(&self) -> String {
+        HostEnv::hostname(self)
+    }
+    fn host_id(&self) -> u64 {
+        HostEnv::host_id(self)
+    }
+    fn local_addrs(&self) -> Vec<IpAddr> {
+        HostEnv::local_addrs(self)
+    }
+    fn random(&self) -> RandomHandle {
+        RandEnv::random(self)
+    }
+    fn config(&self, key: &str) -> Option<String> {
+        HostEnv::config(self, key)
+    }
+    fn channel_faults(&self) -> Option<sync::Arc<dyn Faults>> {
+        // channels are generic over their messages, so the faults of the deterministic runtime
+        // are looked up here rather than creating channels through the wrapped environment.
+        let env: &dyn any::Any = self;
+        #[cfg(feature = "sim")]
+        if let Some(handle) = env.downcast_ref::<DeterministicRuntimeHandle>() {
+            return Some(handle.channel_handle().faults());
+        }
+        env.downcast_ref::<DynEnvironment>()
+            .and_then(|env| env.inner.channel_faults())
+    }
+    fn exit(&self, code: i32) -> BoxFuture<'_, ()> {
+        SpawnEnv::exit(self, code)
+    }
+    fn bind(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxTcpListener>> {
+        Box::pin(async move { Ok(BoxTcpListener::new(NetEnv::bind(self, addr).await?)) })
+    }
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxTcpStream>> {
+        Box::pin(async move {
+            let socket = NetEnv::connect(self, addr).await?;
+            Ok(Box::new(socket) as BoxTcpStream)
+        })
+    }
+    fn open(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<BoxFile>> {
+        Box::pin(async move { Ok(Box::new(FsEnv::open(self, path).await?) as BoxFile) })
+    }
+    fn create(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<BoxFile>> {
+        Box::pin(async move { Ok(Box::new(FsEnv::create(self, path).await?) as BoxFile) })
+    }
+    fn read(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        FsEnv::read(self, path)
+    }
+    fn write(&self, path: path::PathBuf, contents: Vec<u8>) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::write(self, path, contents)
+    }
+    fn rename(&self, from: path::PathBuf, to: path::PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::rename(self, from, to)
+    }
+    fn remove(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::remove(self, path)
+    }
+    fn create_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::create_dir(self, path)
+    }
+    fn remove_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::remove_dir(self, path)
+    }
+    fn read_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<Vec<path::PathBuf>>> {
+        FsEnv::read_dir(self, path)
+    }
+    fn sync_dir(&self, path: path::PathBuf) -> BoxFuture<'_, io::Result<()>> {
+        FsEnv::sync_dir(self, path)
+    }
+}
+
+/// An [`Environment`] which wraps any other environment behind a trait object, so it can be
+/// stored without making the containing type generic.
+///
+/// [`Environment`]:crate::Environment
+#[derive(Clone)]
+pub struct DynEnvironment {
+    inner: sync::Arc<dyn ErasedEnvironment>,
+}
+
+impl DynEnvironment {
+    pub fn new<E>(env: E) -> Self
+    where
+        E: Environment + Sync,
+    {
+        Self {
+            inner: sync::Arc::new(env),
+        }
+    }
+}
+
+impl fmt::Debug for DynEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DynEnvironment {{ hostname: {:?} }}",
+            self.inner.hostname()
+        )
+    }
+}
+
+#[async_trait]
+impl SpawnEnv for DynEnvironment {
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.spawn(Box::pin(future))
+    }
+    fn channel<T>(&self, buffer: usize) -> (mpsc::Sender<T>, mpsc::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::channel_with_faults(buffer, self.inner.channel_faults())
+    }
+    fn unbounded_channel<T>(&self) -> (mpsc::UnboundedSender<T>, mpsc::UnboundedReceiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::unbounded_channel_with_faults(self.inner.channel_faults(), false)
+    }
+    fn lossy_channel<T>(&self) -> (mpsc::UnboundedSender<T>, mpsc::UnboundedReceiver<T>)
+    where
+        T: Send + 'static,
+    {
+        mpsc::unbounded_channel_with_faults(self.inner.channel_faults(), true)
+    }
+    fn oneshot<T>(&self) -> (oneshot::Sender<T>, oneshot::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        oneshot::channel_with_faults(self.inner.channel_faults())
+    }
+    fn broadcast<T>(&self) -> (broadcast::Sender<T>, broadcast::Receiver<T>)
+    where
+        T: Clone + Send + 'static,
+    {
+        broadcast::channel_with_faults(self.inner.channel_faults())
+    }
+    async fn exit(&self, code: i32) {
+        self.inner.exit(code).await
+    }
+}
+
+impl TimeEnv for DynEnvironment {
+    type Delay = BoxDelay;
+    fn now(&self) -> time::Instant {
+        self.inner.now()
+    }
+    fn now_system_time(&self) -> time::SystemTime {
+        self.inner.now_system_time()
+    }
+    fn delay(&self, deadline: time::Instant) -> BoxDelay {
+        self.inner.delay(deadline)
+    }
+}
+
+#[async_trait]
+impl NetEnv for DynEnvironment {
+    type TcpStream = BoxTcpStream;
+    type TcpListener = BoxTcpListener;
+    async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        self.inner.bind(addr.into()).await
+    }
+    async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        self.inner.connect(addr.into()).await
+    }
+}
+
+impl RandEnv for DynEnvironment {
+    fn random(&self) -> RandomHandle {
+        self.inner.random()
+    }
+}
+
+impl HostEnv for DynEnvironment {
+    fn hostname(&self) -> String {
+        self.inner.hostname()
+    }
+    fn host_id(&self) -> u64 {
+        self.inner.host_id()
+    }
+    fn local_addrs(&self) -> Vec<IpAddr> {
+        self.inner.local_addrs()
+    }
+    fn config(&self, key: &str) -> Option<String> {
+        self.inner.config(key)
+    }
+}
+
+#[async_trait]
+impl FsEnv for DynEnvironment {
+    type File = BoxFile;
+    async fn open<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.open(path.as_ref().to_path_buf()).await
+    }
+    async fn create<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.create(path.as_ref().to_path_buf()).await
+    }
+    async fn read<P>(&self, path: P) -> io::Result<Vec<u8>>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.read(path.as_ref().to_path_buf()).await
+    }
+    async fn write<P, C>(&self, path: P, contents: C) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync,
+    {
+        let contents = contents.as_ref().to_vec();
+        self.inner
+            .write(path.as_ref().to_path_buf(), contents)
+            .await
+    }
+    async fn rename<P, Q>(&self, from: P, to: Q) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync,
+    {
+        let from = from.as_ref().to_path_buf();
+        self.inner.rename(from, to.as_ref().to_path_buf()).await
+    }
+    async fn remove<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.remove(path.as_ref().to_path_buf()).await
+    }
+    async fn create_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.create_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn remove_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.remove_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn read_dir<P>(&self, path: P) -> io::Result<Vec<path::PathBuf>>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.read_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn sync_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        self.inner.sync_dir(path.as_ref().to_path_buf()).await
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    // the erased traits are not imported, as the wrapper implements them as well.
+    use super::DynEnvironment;
+    use crate::{deterministic::DeterministicRuntime, File, FsEnv, NetEnv, SpawnEnv, TcpListener};
+    use std::{io, net, net::SocketAddr};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A server which is not generic over its environment.
+    struct Echo {
+        env: DynEnvironment,
+    }
+
+    impl Echo {
+        async fn serve(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+            let mut listener = self.env.bind(addr).await?;
+            let addr = listener.local_addr()?;
+            self.env.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+            });
+            Ok(addr)
+        }
+    }
+
+    #[test]
+    /// Test that a type-erased environment provides sockets, files and channels from the
+    /// environment it wraps.
+    fn dyn_environment() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        handle.channel_handle().set_drop_probability(1.0);
+        let echo = Echo {
+            env: DynEnvironment::new(handle),
+        };
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), 9092);
+                let addr = echo.serve(addr).await.unwrap();
+                let mut socket = echo.env.connect(addr).await.unwrap();
+                socket.write_all(b"ping").await.unwrap();
+                let mut reply = [0; 4];
+                socket.read_exact(&mut reply).await.unwrap();
+                assert_eq!(&reply, b"ping");
+
+                let mut file = echo.env.create("data").await.unwrap();
+                file.write_at(b"hello", 0).await.unwrap();
+                file.sync_all().await.unwrap();
+                assert_eq!(echo.env.read("data").await.unwrap(), b"hello");
+
+                let (tx, mut rx) = echo.env.lossy_channel();
+                tx.send(1).unwrap();
+                drop(tx);
+                assert_eq!(rx.recv().await, None);
+            })
+            .unwrap();
+    }
+}
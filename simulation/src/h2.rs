@@ -0,0 +1,143 @@
+//! Helpers for testing [h2] HTTP/2 connections over simulated sockets.
+//!
+//! HTTP/2 multiplexes many streams over a single connection, each with its own flow control
+//! window, so latency and loss on the connection surface as stalls and resets on individual
+//! streams. [`client`] and [`server`] perform the connection handshakes over any socket, and
+//! [`send_until_stalled`] and [`assert_reset`] make it easy to assert on how streams behave
+//! under the faults injected by the [`DeterministicRuntime`].
+//!
+//! [h2]: https://docs.rs/h2
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+use crate::{SpawnEnv, TimeEnv};
+use ::h2::{client::SendRequest, server::Connection, Reason, SendStream};
+use bytes::Bytes;
+use futures::future;
+use std::{fmt, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Perform a client handshake over `io`, spawning the connection onto `env` and returning the
+/// handle used to send requests.
+pub async fn client<E, S>(env: &E, io: S) -> Result<SendRequest<Bytes>, ::h2::Error>
+where
+    E: SpawnEnv,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (send_request, connection) = ::h2::client::handshake(io).await?;
+    env.spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(send_request)
+}
+
+/// Perform a server handshake over `io`, returning the connection which yields incoming
+/// requests.
+pub async fn server<S>(io: S) -> Result<Connection<S, Bytes>, ::h2::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ::h2::server::handshake(io).await
+}
+
+/// Send `data` on `stream` as send capacity becomes available, ending the stream once all of
+/// it is sent. Returns the number of bytes sent, which is less than the length of `data` if no
+/// capacity was assigned to the stream for `stall`.
+pub async fn send_until_stalled<E>(
+    env: &E,
+    stream: &mut SendStream<Bytes>,
+    mut data: Bytes,
+    stall: Duration,
+) -> Result<usize, ::h2::Error>
+where
+    E: TimeEnv,
+{
+    let mut sent = 0;
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        if stream.capacity() == 0 {
+            let capacity = future::poll_fn(|cx| stream.poll_capacity(cx));
+            match env.timeout(capacity, stall).await {
+                Ok(Some(capacity)) => {
+                    capacity?;
+                }
+                Ok(None) | Err(_) => return Ok(sent),
+            }
+            continue;
+        }
+        let chunk = data.split_to(stream.capacity().min(data.len()));
+        sent += chunk.len();
+        stream.send_data(chunk, data.is_empty())?;
+    }
+    Ok(sent)
+}
+
+/// Assert that `result` failed because the stream was reset with `reason`.
+pub fn assert_reset<T>(result: &Result<T, ::h2::Error>, reason: Reason)
+where
+    T: fmt::Debug,
+{
+    match result {
+        Err(e) if e.reason() == Some(reason) => {}
+        Err(e) => panic!(
+            "expected the stream to be reset with {:?}, got {}",
+            reason, e
+        ),
+        Ok(value) => panic!(
+            "expected the stream to be reset with {:?}, got {:?}",
+            reason, value
+        ),
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener};
+    use std::net;
+
+    #[test]
+    #[cfg_attr(feature = "clock-guard", ignore = "h2 reads the real clock")]
+    /// Test that a stream stalls once its flow control window is exhausted, and that resets
+    /// sent by the server are visible to the client.
+    fn flow_control_and_reset() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server_handle = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client_handle = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server_handle.local_addr(), 8443);
+                let mut listener = server_handle.bind(addr).await.unwrap();
+                server_handle.spawn(async move {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    let mut connection = ::h2::server::Builder::new()
+                        .initial_window_size(1024)
+                        .handshake::<_, Bytes>(socket)
+                        .await
+                        .unwrap();
+                    let (_, mut respond) = connection.accept().await.unwrap().unwrap();
+                    respond.send_reset(Reason::REFUSED_STREAM);
+                    // hold the body of the upload without reading it.
+                    let (_upload, _) = connection.accept().await.unwrap().unwrap();
+                    while connection.accept().await.is_some() {}
+                });
+
+                let socket = client_handle.connect(addr).await.unwrap();
+                let mut send_request = client(&client_handle, socket).await.unwrap();
+                let request = http::Request::get("/refused").body(()).unwrap();
+                let (response, _) = send_request.send_request(request, true).unwrap();
+                assert_reset(&response.await, Reason::REFUSED_STREAM);
+
+                // the refusal is only received once the server's settings have been applied.
+                let request = http::Request::post("/upload").body(()).unwrap();
+                let mut send_request = send_request.ready().await.unwrap();
+                let (_response, mut upload) = send_request.send_request(request, false).unwrap();
+                let data = Bytes::from(vec![0; 4096]);
+                let sent =
+                    send_until_stalled(&client_handle, &mut upload, data, Duration::from_secs(1))
+                        .await
+                        .unwrap();
+                assert_eq!(sent, 1024);
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,620 @@
+//! Recording of client operation histories and checking them for linearizability.
+//!
+//! Simulation is good at finding interesting schedules, but deciding whether the results
+//! observed by clients are legal requires a model of the system under test. Clients record
+//! each operation they perform into a [`History`] as an invocation followed by either a
+//! completion or a failure, timestamped by the [`Environment`]. Once the run is complete,
+//! the history can be checked against a sequential [`Model`] such as [`Register`] or [`Kv`].
+//!
+//! Operations which were invoked but never completed (for instance because the connection
+//! was dropped) are indeterminate. They may or may not have taken effect, and the checker
+//! considers both possibilities.
+//!
+//! [`History`]:History
+//! [`Environment`]:crate::Environment
+//! [`Model`]:Model
+//! [`Register`]:Register
+//! [`Kv`]:Kv
+use crate::TimeEnv;
+use std::{collections, fmt, hash, sync, time};
+
+/// Identifies an operation recorded in a [`History`].
+///
+/// [`History`]:History
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OperationId(usize);
+
+/// A single entry in a [`History`].
+///
+/// [`History`]:History
+#[derive(Debug, Clone)]
+pub enum Event<I, O> {
+    /// A client process began an operation.
+    Invoke {
+        id: OperationId,
+        process: usize,
+        input: I,
+        at: time::Instant,
+    },
+    /// The operation completed, returning `output`.
+    Ok {
+        id: OperationId,
+        output: O,
+        at: time::Instant,
+    },
+    /// The operation definitely did not take effect.
+    Fail { id: OperationId, at: time::Instant },
+}
+
+/// A sequential specification of the system under test.
+pub trait Model {
+    type State: Clone + Eq + hash::Hash;
+    type Input;
+    type Output: PartialEq;
+
+    /// Returns the initial state of the model.
+    fn init(&self) -> Self::State;
+
+    /// Applies `input` to `state`, returning the resulting state and the output a client
+    /// would observe.
+    fn step(&self, state: &Self::State, input: &Self::Input) -> (Self::State, Self::Output);
+}
+
+#[derive(Debug)]
+struct Log<I, O> {
+    events: Vec<Event<I, O>>,
+    invocations: usize,
+}
+
+/// Recorder for the operations performed by clients against a system under test.
+///
+/// Cloned histories share the same underlying log.
+pub struct History<I, O> {
+    log: sync::Arc<sync::Mutex<Log<I, O>>>,
+}
+
+impl<I, O> Clone for History<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            log: sync::Arc::clone(&self.log),
+        }
+    }
+}
+
+impl<I, O> fmt::Debug for History<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = self.log.lock().unwrap().events.len();
+        write!(f, "History {{ events: {} }}", len)
+    }
+}
+
+impl<I, O> Default for History<I, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I, O> History<I, O> {
+    pub fn new() -> Self {
+        Self {
+            log: sync::Arc::new(sync::Mutex::new(Log {
+                events: vec![],
+                invocations: 0,
+            })),
+        }
+    }
+
+    /// Record that `process` invoked an operation with the provided input.
+    pub fn invoke<E>(&self, env: &E, process: usize, input: I) -> OperationId
+    where
+        E: TimeEnv,
+    {
+        let mut lock = self.log.lock().unwrap();
+        let id = OperationId(lock.invocations);
+        lock.invocations += 1;
+        lock.events.push(Event::Invoke {
+            id,
+            process,
+            input,
+            at: env.now(),
+        });
+        id
+    }
+
+    /// Record that the operation `id` completed with the provided output. An `id` which was not
+    /// returned by [`invoke`] on this history is ignored.
+    ///
+    /// [`invoke`]:History::invoke
+    pub fn ok<E>(&self, env: &E, id: OperationId, output: O)
+    where
+        E: TimeEnv,
+    {
+        let at = env.now();
+        let mut lock = self.log.lock().unwrap();
+        lock.events.push(Event::Ok { id, output, at });
+    }
+
+    /// Record that the operation `id` failed without taking effect. An `id` which was not
+    /// returned by [`invoke`] on this history is ignored.
+    ///
+    /// [`invoke`]:History::invoke
+    pub fn fail<E>(&self, env: &E, id: OperationId)
+    where
+        E: TimeEnv,
+    {
+        let at = env.now();
+        let mut lock = self.log.lock().unwrap();
+        lock.events.push(Event::Fail { id, at });
+    }
+}
+
+impl<I, O> History<I, O>
+where
+    I: Clone,
+    O: Clone,
+{
+    /// Returns all recorded events in the order they were recorded, suitable for exporting
+    /// to an external checker.
+    pub fn events(&self) -> Vec<Event<I, O>> {
+        self.log.lock().unwrap().events.clone()
+    }
+
+    /// Search for a linearization of the recorded history against `model`, returning the
+    /// operations in linearization order if one exists. Indeterminate operations which are
+    /// not part of the returned order are considered to have never taken effect.
+    pub fn linearize<M>(&self, model: &M) -> Option<Vec<OperationId>>
+    where
+        M: Model<Input = I, Output = O>,
+    {
+        let operations = self.operations();
+        let mut search = Search::new(model, &operations);
+        if search.search(model.init()) {
+            Some(search.order.iter().map(|i| operations[*i].id).collect())
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the recorded history is linearizable with respect to `model`.
+    pub fn is_linearizable<M>(&self, model: &M) -> bool
+    where
+        M: Model<Input = I, Output = O>,
+    {
+        self.linearize(model).is_some()
+    }
+
+    /// Pair up invocations with their completions, dropping failed operations.
+    fn operations(&self) -> Vec<Operation<I, O>> {
+        let lock = self.log.lock().unwrap();
+        // operation ids are assigned sequentially, so they can be used as indices.
+        let mut operations: Vec<Option<Operation<I, O>>> = vec![];
+        for event in lock.events.iter() {
+            match event {
+                Event::Invoke { id, input, at, .. } => {
+                    operations.push(Some(Operation {
+                        id: *id,
+                        input: input.clone(),
+                        output: None,
+                        call: *at,
+                        ret: None,
+                    }));
+                }
+                // ids which were not invoked on this history are ignored.
+                Event::Ok { id, output, at } => {
+                    if let Some(operation) = operations.get_mut(id.0).and_then(Option::as_mut) {
+                        operation.output.replace(output.clone());
+                        operation.ret.replace(*at);
+                    }
+                }
+                Event::Fail { id, .. } => {
+                    if let Some(operation) = operations.get_mut(id.0) {
+                        operation.take();
+                    }
+                }
+            }
+        }
+        operations.into_iter().flatten().collect()
+    }
+}
+
+#[derive(Debug)]
+struct Operation<I, O> {
+    id: OperationId,
+    input: I,
+    /// Output of the operation, or None if the operation is indeterminate.
+    output: Option<O>,
+    call: time::Instant,
+    /// Completion time of the operation, or None if the operation is indeterminate.
+    ret: Option<time::Instant>,
+}
+
+/// Depth first search over candidate linearizations, following Wing & Gong with the
+/// state caching described by Lowe.
+///
+/// The search keeps its own stack rather than recursing, as it goes one level deeper for each
+/// operation linearized, and remembers the states it visited alongside a bitset of the
+/// operations linearized to reach them.
+///
+/// An operation can be linearized before another only if it was invoked no later than the
+/// other returned. Operations whose invocation and return share an instant are treated as
+/// concurrent, as simulated time does not advance while tasks run, so either may have
+/// happened first.
+struct Search<'a, M: Model> {
+    model: &'a M,
+    operations: &'a [Operation<M::Input, M::Output>],
+    /// One bit per operation, set once it is linearized.
+    linearized: Vec<u64>,
+    order: Vec<usize>,
+    visited: collections::HashSet<(Vec<u64>, M::State)>,
+}
+
+/// A state reached by the search, and the next operation to try linearizing from it.
+struct Frame<S> {
+    state: S,
+    next: usize,
+}
+
+impl<'a, M: Model> Search<'a, M> {
+    fn new(model: &'a M, operations: &'a [Operation<M::Input, M::Output>]) -> Self {
+        Self {
+            model,
+            operations,
+            linearized: vec![0; operations.len().div_ceil(64)],
+            order: vec![],
+            visited: collections::HashSet::new(),
+        }
+    }
+
+    fn is_linearized(&self, i: usize) -> bool {
+        self.linearized[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn toggle(&mut self, i: usize) {
+        self.linearized[i / 64] ^= 1 << (i % 64);
+    }
+
+    /// Returns true once every operation which completed has been linearized.
+    fn is_complete(&self) -> bool {
+        (0..self.operations.len())
+            .all(|i| self.is_linearized(i) || self.operations[i].ret.is_none())
+    }
+
+    /// Returns the first operation from `from` onwards which can be linearized next from
+    /// `state`, along with the state it leads to.
+    fn candidate(&self, state: &M::State, from: usize) -> Option<(usize, M::State)> {
+        // An operation can only be linearized next if it was invoked before every other
+        // remaining operation returned.
+        let min_ret = (0..self.operations.len())
+            .filter(|i| !self.is_linearized(*i))
+            .filter_map(|i| self.operations[i].ret)
+            .min();
+        (from..self.operations.len()).find_map(|i| {
+            let op = &self.operations[i];
+            if self.is_linearized(i) || min_ret.is_some_and(|min_ret| op.call > min_ret) {
+                return None;
+            }
+            let (next, output) = self.model.step(state, &op.input);
+            match op.output.as_ref() {
+                Some(expected) if *expected != output => None,
+                _ => Some((i, next)),
+            }
+        })
+    }
+
+    fn search(&mut self, init: M::State) -> bool {
+        if self.is_complete() {
+            return true;
+        }
+        self.visited.insert((self.linearized.clone(), init.clone()));
+        let mut stack = vec![Frame {
+            state: init,
+            next: 0,
+        }];
+        while let Some(frame) = stack.last_mut() {
+            match self.candidate(&frame.state, frame.next) {
+                Some((i, next)) => {
+                    frame.next = i + 1;
+                    self.toggle(i);
+                    self.order.push(i);
+                    if self.is_complete() {
+                        return true;
+                    }
+                    if self.visited.insert((self.linearized.clone(), next.clone())) {
+                        stack.push(Frame {
+                            state: next,
+                            next: 0,
+                        });
+                    } else {
+                        self.toggle(i);
+                        self.order.pop();
+                    }
+                }
+                None => {
+                    // every operation was tried from this state, so backtrack.
+                    stack.pop();
+                    if let Some(i) = self.order.pop() {
+                        self.toggle(i);
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Operations supported by the [`Register`] model.
+///
+/// [`Register`]:Register
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterInput<T> {
+    Read,
+    Write(T),
+    /// Compare and set, writing the second value if the register holds the first.
+    Cas(T, T),
+}
+
+/// Results of operations supported by the [`Register`] model.
+///
+/// [`Register`]:Register
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterOutput<T> {
+    Read(Option<T>),
+    Write,
+    Cas(bool),
+}
+
+/// Model of a single register supporting reads, writes and compare and set.
+#[derive(Debug, Default)]
+pub struct Register<T> {
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> Register<T> {
+    pub fn new() -> Self {
+        Self {
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T> Model for Register<T>
+where
+    T: Clone + Eq + hash::Hash,
+{
+    type State = Option<T>;
+    type Input = RegisterInput<T>;
+    type Output = RegisterOutput<T>;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(&self, state: &Self::State, input: &Self::Input) -> (Self::State, Self::Output) {
+        match input {
+            RegisterInput::Read => (state.clone(), RegisterOutput::Read(state.clone())),
+            RegisterInput::Write(value) => (Some(value.clone()), RegisterOutput::Write),
+            RegisterInput::Cas(expected, value) => {
+                if state.as_ref() == Some(expected) {
+                    (Some(value.clone()), RegisterOutput::Cas(true))
+                } else {
+                    (state.clone(), RegisterOutput::Cas(false))
+                }
+            }
+        }
+    }
+}
+
+/// Operations supported by the [`Kv`] model.
+///
+/// [`Kv`]:Kv
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvInput<K, V> {
+    Get(K),
+    Put(K, V),
+    Delete(K),
+}
+
+/// Results of operations supported by the [`Kv`] model.
+///
+/// [`Kv`]:Kv
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvOutput<V> {
+    Get(Option<V>),
+    Put,
+    Delete,
+}
+
+/// Model of a key value store.
+#[derive(Debug, Default)]
+pub struct Kv<K, V> {
+    _marker: std::marker::PhantomData<(K, V)>,
+}
+
+impl<K, V> Kv<K, V> {
+    pub fn new() -> Self {
+        Self {
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<K, V> Model for Kv<K, V>
+where
+    K: Clone + Ord + hash::Hash,
+    V: Clone + Eq + hash::Hash,
+{
+    type State = collections::BTreeMap<K, V>;
+    type Input = KvInput<K, V>;
+    type Output = KvOutput<V>;
+
+    fn init(&self) -> Self::State {
+        collections::BTreeMap::new()
+    }
+
+    fn step(&self, state: &Self::State, input: &Self::Input) -> (Self::State, Self::Output) {
+        match input {
+            KvInput::Get(key) => (state.clone(), KvOutput::Get(state.get(key).cloned())),
+            KvInput::Put(key, value) => {
+                let mut next = state.clone();
+                next.insert(key.clone(), value.clone());
+                (next, KvOutput::Put)
+            }
+            KvInput::Delete(key) => {
+                let mut next = state.clone();
+                next.remove(key);
+                (next, KvOutput::Delete)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TimeEnv};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that a read observing a concurrent write is linearizable.
+    fn concurrent_register() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let history = History::new();
+                let write = history.invoke(&handle, 0, RegisterInput::Write(1));
+                let read = history.invoke(&handle, 1, RegisterInput::Read);
+                handle.delay_from(Duration::from_secs(1)).await;
+                history.ok(&handle, read, RegisterOutput::Read(Some(1)));
+                history.ok(&handle, write, RegisterOutput::Write);
+                assert_eq!(history.linearize(&Register::new()), Some(vec![write, read]));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a read which misses a completed write is rejected.
+    fn stale_read() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let history = History::new();
+                let write = history.invoke(&handle, 0, RegisterInput::Write(1));
+                handle.delay_from(Duration::from_secs(1)).await;
+                history.ok(&handle, write, RegisterOutput::Write);
+                handle.delay_from(Duration::from_secs(1)).await;
+                let read = history.invoke(&handle, 1, RegisterInput::Read);
+                handle.delay_from(Duration::from_secs(1)).await;
+                history.ok(&handle, read, RegisterOutput::Read(None));
+                assert!(!history.is_linearizable(&Register::new()));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that indeterminate and failed operations are handled.
+    fn indeterminate_kv() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let history = History::new();
+                // this put never completes, but a later read observes it.
+                history.invoke(&handle, 0, KvInput::Put("a", 1));
+                let failed = history.invoke(&handle, 1, KvInput::Put("a", 2));
+                history.fail(&handle, failed);
+                handle.delay_from(Duration::from_secs(1)).await;
+                let get = history.invoke(&handle, 2, KvInput::Get("a"));
+                history.ok(&handle, get, KvOutput::Get(Some(1)));
+                assert!(history.is_linearizable(&Kv::new()));
+
+                let get = history.invoke(&handle, 2, KvInput::Get("a"));
+                history.ok(&handle, get, KvOutput::Get(Some(2)));
+                assert!(!history.is_linearizable(&Kv::new()));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that a history far longer than the stack could hold one frame per operation for is
+    /// checked.
+    fn long_history() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let history = History::new();
+                let mut writes = vec![];
+                for i in 0..5_000 {
+                    let write = history.invoke(&handle, 0, RegisterInput::Write(i));
+                    handle.delay_from(Duration::from_millis(1)).await;
+                    history.ok(&handle, write, RegisterOutput::Write);
+                    handle.delay_from(Duration::from_millis(1)).await;
+                    writes.push(write);
+                }
+                let read = history.invoke(&handle, 1, RegisterInput::Read);
+                history.ok(&handle, read, RegisterOutput::Read(Some(4_999)));
+                writes.push(read);
+                assert_eq!(history.linearize(&Register::new()), Some(writes));
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that completing an operation invoked on a different history is ignored.
+    fn foreign_operation() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let other = History::new();
+                let _ = other.invoke(&handle, 0, RegisterInput::Write(1));
+                let foreign = other.invoke(&handle, 0, RegisterInput::Write(2));
+
+                let history: History<RegisterInput<u64>, _> = History::new();
+                let read = history.invoke(&handle, 0, RegisterInput::Read);
+                history.ok(&handle, read, RegisterOutput::Read(None));
+                history.ok(&handle, foreign, RegisterOutput::Write);
+                history.fail(&handle, foreign);
+                assert_eq!(history.linearize(&Register::new()), Some(vec![read]));
+                assert!(other.is_linearizable(&Register::new()));
+            })
+            .unwrap();
+    }
+
+    /// A clock which only advances when told to, and provides no other capabilities.
+    #[derive(Debug, Clone)]
+    struct ManualClock {
+        now: sync::Arc<sync::Mutex<time::Instant>>,
+    }
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl TimeEnv for ManualClock {
+        type Delay = futures::future::Ready<()>;
+
+        fn now(&self) -> time::Instant {
+            *self.now.lock().unwrap()
+        }
+        fn delay(&self, _: time::Instant) -> Self::Delay {
+            futures::future::ready(())
+        }
+    }
+
+    #[test]
+    /// Test that a history can be recorded with an environment which only implements
+    /// `TimeEnv`.
+    fn time_only_environment() {
+        let clock = ManualClock {
+            now: sync::Arc::new(sync::Mutex::new(time::Instant::now())),
+        };
+        let history = History::new();
+        let write = history.invoke(&clock, 0, RegisterInput::Write(1));
+        clock.advance(Duration::from_secs(1));
+        history.ok(&clock, write, RegisterOutput::Write);
+        clock.advance(Duration::from_secs(1));
+        let read = history.invoke(&clock, 1, RegisterInput::Read);
+        history.ok(&clock, read, RegisterOutput::Read(None));
+        assert!(!history.is_linearizable(&Register::new()));
+    }
+}
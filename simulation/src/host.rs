@@ -0,0 +1,36 @@
+//! Identity of the host the process is running on, for environments backed by the operating
+//! system.
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    net,
+};
+
+pub(crate) fn hostname() -> String {
+    hostname::get_hostname().unwrap_or_else(|| String::from("localhost"))
+}
+
+/// Returns the machine id where the operating system provides one, which unlike the hostname
+/// does not change once the machine is installed. Otherwise the hostname is hashed.
+pub(crate) fn host_id() -> u64 {
+    if let Ok(machine_id) = fs::read_to_string("/etc/machine-id") {
+        let machine_id = machine_id.trim();
+        if let Some(Ok(id)) = machine_id.get(..16).map(|id| u64::from_str_radix(id, 16)) {
+            return id;
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    hostname().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the addresses of every network interface, in sorted order.
+pub(crate) fn local_addrs() -> Vec<net::IpAddr> {
+    let mut addrs: Vec<_> = get_if_addrs::get_if_addrs()
+        .map(|interfaces| interfaces.iter().map(|interface| interface.ip()).collect())
+        .unwrap_or_default();
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
@@ -0,0 +1,244 @@
+//! Adapters for running [hyper] clients and servers on an [`Environment`].
+//!
+//! Hyper needs three things from a runtime: a stream of accepted connections for its server,
+//! a connector for its client, and an executor to spawn connection tasks onto. This module
+//! provides each of them on top of any [`Environment`], so the same service can be tested
+//! under the [`DeterministicRuntime`] and deployed on the [`SingleThreadedRuntime`].
+//!
+//! ```ignore
+//! let listener = handle.bind(addr).await?;
+//! let server = hyper::Server::builder(HyperAccept::new(listener))
+//!     .executor(HyperExecutor::new(handle.clone()))
+//!     .serve(make_service);
+//! handle.spawn(async move { server.await.unwrap() });
+//!
+//! let client = hyper::Client::builder()
+//!     .executor(HyperExecutor::new(handle.clone()))
+//!     .build::<_, hyper::Body>(HyperConnect::new(handle.clone()));
+//! ```
+//!
+//! The simulated network has no name resolution, so [`HyperConnect`] only connects to URIs
+//! whose host is an IP address.
+//!
+//! [hyper]: https://docs.rs/hyper
+//! [`Environment`]:crate::Environment
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`SingleThreadedRuntime`]:crate::singlethread::SingleThreadedRuntime
+use crate::{NetEnv, SpawnEnv, TcpListener};
+use ::hyper::client::connect::{Connect, Connected, Destination};
+use ::hyper::server::accept::Accept;
+use futures::{Future, Stream};
+use std::{
+    fmt, io, net,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A stream of connections accepted by a [`TcpListener`], which can be passed to
+/// `hyper::Server::builder`.
+pub struct HyperAccept<L>
+where
+    L: TcpListener,
+{
+    incoming: Pin<Box<dyn Stream<Item = io::Result<L::Stream>> + Send>>,
+}
+
+impl<L> HyperAccept<L>
+where
+    L: TcpListener,
+{
+    pub fn new(listener: L) -> Self {
+        Self {
+            incoming: listener.into_stream(),
+        }
+    }
+}
+
+impl<L> fmt::Debug for HyperAccept<L>
+where
+    L: TcpListener,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HyperAccept")
+    }
+}
+
+impl<L> Accept for HyperAccept<L>
+where
+    L: TcpListener,
+{
+    type Conn = L::Stream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.incoming.as_mut().poll_next(cx)
+    }
+}
+
+/// A hyper client connector which opens connections using a [`NetEnv`].
+#[derive(Debug, Clone)]
+pub struct HyperConnect<E> {
+    env: E,
+}
+
+impl<E> HyperConnect<E>
+where
+    E: NetEnv,
+{
+    pub fn new(env: E) -> Self {
+        Self { env }
+    }
+}
+
+impl<E> Connect for HyperConnect<E>
+where
+    E: NetEnv + Sync,
+{
+    type Transport = E::TcpStream;
+    type Error = io::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = io::Result<(Self::Transport, Connected)>> + Send + 'static>>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let env = self.env.clone();
+        Box::pin(async move {
+            let addr = socket_addr(dst.scheme(), dst.host(), dst.port())?;
+            let socket = env.connect(addr).await?;
+            Ok((socket, Connected::new()))
+        })
+    }
+}
+
+/// Returns the socket address of a URI with the given `scheme`, `host` and `port`, using the
+/// default port for its scheme if none is set.
+pub(crate) fn socket_addr(
+    scheme: &str,
+    host: &str,
+    port: Option<u16>,
+) -> io::Result<net::SocketAddr> {
+    let ip: net::IpAddr = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not an IP address", host),
+            )
+        })?;
+    let port = match (port, scheme) {
+        (Some(port), _) => port,
+        (None, "https") => 443,
+        (None, _) => 80,
+    };
+    Ok(net::SocketAddr::new(ip, port))
+}
+
+/// An executor which spawns hyper connection tasks onto a [`SpawnEnv`].
+#[derive(Debug, Clone)]
+pub struct HyperExecutor<E> {
+    env: E,
+}
+
+impl<E> HyperExecutor<E>
+where
+    E: SpawnEnv,
+{
+    pub fn new(env: E) -> Self {
+        Self { env }
+    }
+}
+
+impl<E> tokio_executor::Executor for HyperExecutor<E>
+where
+    E: SpawnEnv,
+{
+    fn spawn(
+        &mut self,
+        future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) -> Result<(), tokio_executor::SpawnError> {
+        self.env.spawn(future);
+        Ok(())
+    }
+}
+
+impl<E> tokio_executor::Executor for &HyperExecutor<E>
+where
+    E: SpawnEnv,
+{
+    fn spawn(
+        &mut self,
+        future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) -> Result<(), tokio_executor::SpawnError> {
+        self.env.spawn(future);
+        Ok(())
+    }
+}
+
+impl<E, T> tokio_executor::TypedExecutor<T> for HyperExecutor<E>
+where
+    E: SpawnEnv,
+    T: Future<Output = ()> + Send + 'static,
+{
+    fn spawn(&mut self, future: T) -> Result<(), tokio_executor::SpawnError> {
+        self.env.spawn(future);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, NetEnv, SpawnEnv};
+    use ::hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Client, Request, Response, Server,
+    };
+
+    #[test]
+    #[cfg_attr(feature = "clock-guard", ignore = "hyper reads the real clock")]
+    /// Test that a hyper client can make requests to a hyper server over the simulated network.
+    fn request_response() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 8080);
+                let listener = server.bind(addr).await.unwrap();
+                let make_service = make_service_fn(|_| async {
+                    Ok::<_, io::Error>(service_fn(|request: Request<Body>| async move {
+                        let path = request.uri().path().to_string();
+                        Ok::<_, io::Error>(Response::new(Body::from(path)))
+                    }))
+                });
+                let http = Server::builder(HyperAccept::new(listener))
+                    .executor(HyperExecutor::new(server.clone()))
+                    .serve(make_service);
+                server.spawn(async move {
+                    http.await.unwrap();
+                });
+
+                let client = Client::builder()
+                    .executor(HyperExecutor::new(client.clone()))
+                    .build::<_, Body>(HyperConnect::new(client.clone()));
+                for path in &["/a", "/b"] {
+                    let uri = format!("http://{}{}", addr, path).parse().unwrap();
+                    let response = client.get(uri).await.unwrap();
+                    let mut body = response.into_body();
+                    let mut received = vec![];
+                    while let Some(chunk) = body.next().await {
+                        received.extend_from_slice(&chunk.unwrap());
+                    }
+                    assert_eq!(&received[..], path.as_bytes());
+                }
+
+                let uri = "http://localhost:8080/".parse().unwrap();
+                assert!(client.get(uri).await.is_err());
+            })
+            .unwrap();
+    }
+}
@@ -4,7 +4,25 @@
 //!
 //! Simulation is an abstraction over [Tokio], allowing application developers to write
 //! applications which are generic over sources of nondeterminism. Additionally, Simulation
-//! provides deterministic analogues to time, scheduling, network and eventually disk IO.
+//! provides deterministic analogues to time, scheduling, network and disk IO.
+//!
+//! The same applications are run in production on the [`production::NaturalRuntime`], which
+//! provides real sockets, files, timers and randomness.
+//!
+//! Simulation itself is built on the Tokio 0.2 alphas. With the `tokio1` feature, applications
+//! written against `Environment` can also be run in production on a Tokio 1.x runtime, using
+//! the handle in the `tokio1` module. The deterministic runtime is not available on Tokio 1.x:
+//! it still drives its executor, timer and network through the 0.2 alphas.
+//!
+//! Where threading an `Environment` type parameter through an application is impractical,
+//! [`DynEnvironment`] wraps any environment behind a trait object.
+//!
+//! The deterministic runtime and everything built on it is behind the `sim` feature, which is
+//! enabled by default. Production builds can depend on Simulation with `default-features =
+//! false`, leaving only `Environment` and the runtimes backed by the operating system. The
+//! drop-in [`net`] types and [`sync`] primitives then no longer check for a simulation on each
+//! call, and production handles implement `Environment` directly, so code generic over it
+//! compiles to the same calls it would make to Tokio.
 //!
 //! # Scheduling and Time
 //!
@@ -18,11 +36,10 @@
 //! This can be used to naturally express ordering between tasks
 //!
 //! ```rust
-//!    use simulation::{Environment};
+//!    use simulation::{Environment, TimeEnv};
 //!    #[test]
 //!    fn ordering() {
-//!        let mut runtime = DeterministicRuntime::new().unwrap();
-//!        let handle = runtime.localhost_handle();
+//!        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
 //!        runtime.block_on(async {
 //!            let delay1 = handle.delay_from(Duration::from_secs(10));
 //!            let delay2 = handle.delay_from(Duration::from_secs(30));
@@ -41,19 +58,29 @@
 //!            })
 //!            .await;
 //!            assert!(completed_at1 < completed_at2)
-//!        });
+//!        }).unwrap();
 //!    }
 //! ```
 //!
 //! # Network
 //!
-//! Simulation includes an in-memory network. Applications can use `Environment::bind` and `Environment::connect`
+//! Simulation includes an in-memory network. Applications can use `NetEnv::bind` and `NetEnv::connect`
 //! to create in-memory connections between components. The in-memory connections will automatically have delays
 //! and disconnect faults injected, dependent on an initial seed value.
 //!
 //! [`DeterministicRuntime`] supports both a [`DeterministicRuntime::localhost_handle`] as well as creating a handle
 //! scoped to a particular [`std::net:IpAddr`] with [`DeterministicRuntime::handle`].
 //!
+//! # Filesystem
+//!
+//! Simulation includes an in-memory filesystem. Applications can use `Environment::open`,
+//! `Environment::create` and the other file operations on `Environment` to store data. Each
+//! simulated host has its own filesystem, so hosts scoped to different addresses never observe
+//! each other's files.
+//!
+//! Like `tokio::fs::File`, files implement `AsyncRead` and `AsyncWrite` from a cursor which
+//! can be moved with `File::seek`, so existing storage code can be ported with few changes.
+//!
 //! # Faults
 //!
 //! Faults are injected based on a seedable RNG, causing IO delays and disconnects.
@@ -69,7 +96,7 @@
 //!
 //! Fault injection is handled by spawned tasks. Currently there is one fault injector which will inject
 //! determinstic latency changes to socket read/write sides based on the initial seed value passed to
-//! [`DeterministicRuntime::new_with_seed`]. Launching the fault injector involves spawning it at startup.
+//! [`DeterministicRuntimeBuilder::seed`]. Launching the fault injector involves spawning it at startup.
 //!
 //! # Example
 //! The following example demonstrates a simple client server app which has latency faults injected.
@@ -87,7 +114,7 @@
 //!        E: Environment,
 //!    {
 //!        // delay the response, in deterministic mode this will immediately progress time.
-//!        env.delay_from(time::Duration::from_secs(1));
+//!        env.delay_from(time::Duration::from_secs(1)).await;
 //!        println!("handling connection from {:?}", addr);
 //!        let mut transport = Framed::new(socket, LinesCodec::new());
 //!        if let Err(e) = transport.send(String::from("Hello World!")).await {
@@ -135,10 +162,12 @@
 //!    }
 //!    #[test]
 //!    fn test() {
-//!        // Various seed values can be supplied to `DeterministicRuntime::new_with_seed` to find a seed
+//!        // Various seed values can be supplied to `DeterministicRuntimeBuilder::seed` to find a seed
 //!        // value for which this example terminates incorrectly.
-//!        let mut runtime = simulation::deterministic::DeterministicRuntime::new_with_seed(1).unwrap();
-//!        let handle = runtime.handle();
+//!        let (mut runtime, handle) = simulation::deterministic::DeterministicRuntime::builder()
+//!            .seed(1)
+//!            .build()
+//!            .unwrap();
 //!        runtime.block_on(async {
 //!            handle.spawn(runtime.latency_fault().run());
 //!            let bind_addr: net::SocketAddr = "127.0.0.1:8080".parse().unwrap();
@@ -147,21 +176,57 @@
 //!                server.await.unwrap();
 //!            });
 //!            client(handle, bind_addr).await.unwrap();
-//!        })
+//!        }).unwrap()
 //!    }
 //! ```
 //!
 //! [Tokio]: https://github.com/tokio-rs
 //! [CurrentThread]:[tokio_executor::current_thread::CurrentThread]
 //! [Delay]:[tokio_timer::Delay]
-//! [Timeout]:[tokio_timer::Timeout]
+//! [Timeout]:[Timeout]
 use async_trait::async_trait;
 use futures::{Future, FutureExt, Stream};
-use std::{io, net, fmt, error, pin::Pin, time};
+use std::{
+    error, fmt, io,
+    net::{IpAddr, SocketAddr},
+    path,
+    pin::Pin,
+    time,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+#[cfg(feature = "sim")]
 pub mod deterministic;
+#[cfg(feature = "sim")]
+pub mod dual;
+mod dynamic;
+#[cfg(feature = "h2")]
+pub mod h2;
+pub mod history;
+mod host;
+#[cfg(feature = "hyper")]
+pub mod hyper;
+pub mod net;
+pub mod production;
+#[cfg(all(feature = "quickcheck", feature = "sim"))]
+pub mod quickcheck;
+mod random;
+pub mod servers;
 pub mod singlethread;
+#[cfg(feature = "subscriber")]
+pub mod subscriber;
+pub mod sync;
+mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "tokio1")]
+pub mod tokio1;
+#[cfg(feature = "tonic")]
+pub mod tonic;
+
+pub use dynamic::{BoxDelay, BoxFile, BoxTcpListener, BoxTcpStream, DynEnvironment};
+pub use random::RandomHandle;
+pub use timeout::{Elapsed, Timeout};
 
 #[derive(Debug)]
 pub enum Error {
@@ -174,6 +239,56 @@ pub enum Error {
     CurrentThreadRun {
         source: tokio_executor::current_thread::RunError,
     },
+    #[cfg(feature = "sim")]
+    PhaseBudgetExceeded {
+        phase: deterministic::Phase,
+    },
+    #[cfg(feature = "sim")]
+    WalRecordLost {
+        seed: u64,
+        crash_point: u64,
+        record: Vec<u8>,
+    },
+    #[cfg(feature = "sim")]
+    WalUnexpectedRecord {
+        seed: u64,
+        crash_point: u64,
+        record: Vec<u8>,
+    },
+    #[cfg(feature = "sim")]
+    WalRecovery {
+        seed: u64,
+        crash_point: u64,
+        source: io::Error,
+    },
+    #[cfg(feature = "sim")]
+    Certificate {
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    #[cfg(feature = "sim")]
+    SimHost {
+        name: String,
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    #[cfg(feature = "sim")]
+    SimClient {
+        name: String,
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    #[cfg(feature = "sim")]
+    SimDurationExceeded {
+        duration: time::Duration,
+    },
+    #[cfg(feature = "sim")]
+    Unhealthy {
+        elapsed: time::Duration,
+        healthy: usize,
+        min: usize,
+    },
+    #[cfg(feature = "sim")]
+    TimeOverflow {
+        operation: String,
+    },
 }
 
 impl fmt::Display for Error {
@@ -182,64 +297,211 @@ impl fmt::Display for Error {
             Error::Spawn { source } => write!(f, "Spawn error: {:?}", source),
             Error::RuntimeBuild { source } => write!(f, "Construction error: {:?}", source),
             Error::CurrentThreadRun { source } => write!(f, "Error: {:?}", source),
+            #[cfg(feature = "sim")]
+            Error::PhaseBudgetExceeded { phase } => {
+                write!(f, "Phase {:?} exceeded its time budget", phase)
+            }
+            #[cfg(feature = "sim")]
+            Error::WalRecordLost {
+                seed,
+                crash_point,
+                record,
+            } => write!(
+                f,
+                "Acknowledged record {:?} lost by a crash after {} operations with seed {}",
+                String::from_utf8_lossy(record),
+                crash_point,
+                seed
+            ),
+            #[cfg(feature = "sim")]
+            Error::WalUnexpectedRecord {
+                seed,
+                crash_point,
+                record,
+            } => write!(
+                f,
+                "Record {:?} was never appended but recovered after a crash after {} operations with seed {}",
+                String::from_utf8_lossy(record),
+                crash_point,
+                seed
+            ),
+            #[cfg(feature = "sim")]
+            Error::WalRecovery {
+                seed,
+                crash_point,
+                source,
+            } => write!(
+                f,
+                "Recovery failed after a crash after {} operations with seed {}: {:?}",
+                crash_point, seed, source
+            ),
+            #[cfg(feature = "sim")]
+            Error::Certificate { source } => write!(f, "Certificate error: {}", source),
+            #[cfg(feature = "sim")]
+            Error::SimHost { name, source } => write!(f, "Host {} failed: {}", name, source),
+            #[cfg(feature = "sim")]
+            Error::SimClient { name, source } => write!(f, "Client {} failed: {}", name, source),
+            #[cfg(feature = "sim")]
+            Error::SimDurationExceeded { duration } => {
+                write!(f, "Clients did not complete within {:?}", duration)
+            }
+            #[cfg(feature = "sim")]
+            Error::Unhealthy {
+                elapsed,
+                healthy,
+                min,
+            } => write!(
+                f,
+                "Only {} targets were healthy {:?} into the health check, expected at least {}",
+                healthy, elapsed, min
+            ),
+            #[cfg(feature = "sim")]
+            Error::TimeOverflow { operation } => {
+                write!(f, "Simulated time overflowed while {}", operation)
+            }
         }
     }
 }
 
 impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> { 
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             Error::Spawn { source } => Some(source),
             Error::RuntimeBuild { source } => Some(source),
             Error::CurrentThreadRun { source } => Some(source),
+            #[cfg(feature = "sim")]
+            Error::PhaseBudgetExceeded { .. } => None,
+            #[cfg(feature = "sim")]
+            Error::WalRecordLost { .. } => None,
+            #[cfg(feature = "sim")]
+            Error::WalUnexpectedRecord { .. } => None,
+            #[cfg(feature = "sim")]
+            Error::WalRecovery { source, .. } => Some(source),
+            #[cfg(feature = "sim")]
+            Error::Certificate { source } => Some(source.as_ref()),
+            #[cfg(feature = "sim")]
+            Error::SimHost { source, .. } => Some(source.as_ref()),
+            #[cfg(feature = "sim")]
+            Error::SimClient { source, .. } => Some(source.as_ref()),
+            #[cfg(feature = "sim")]
+            Error::SimDurationExceeded { .. } => None,
+            #[cfg(feature = "sim")]
+            Error::Unhealthy { .. } => None,
+            #[cfg(feature = "sim")]
+            Error::TimeOverflow { .. } => None,
         }
-     }
+    }
 }
 
-#[async_trait]
-pub trait Network {
-    type TcpStream: TcpStream + Send + 'static + Unpin;
-    type TcpListener: TcpListener + Send + 'static + Unpin;
+#[cfg(feature = "sim")]
+impl Error {
+    /// Returns the fault injected by the deterministic runtime which caused this error, if
+    /// any, so tests can check that a failure is the one they injected.
+    pub fn injected_fault(&self) -> Option<&deterministic::InjectedFault> {
+        deterministic::InjectedFault::find(self)
+    }
+}
 
-    /// Binds and returns a listener which can be used to listen for new connections.
-    async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
-    where
-        A: Into<net::SocketAddr> + Send + Sync;
+/// The former name of [`NetEnv`], implemented for every type which implements it.
+pub trait Network: NetEnv {}
 
-    /// Connects to the specified addr, returning a [`TcpStream`] which can be
-    /// used to send and receive bytes.
-    ///
-    /// [`TcpStream`]:`TcpStream`
-    async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
-    where
-        A: Into<net::SocketAddr> + Send + Sync;
-}
+impl<T> Network for T where T: NetEnv {}
 
+/// Spawning tasks, and the channels they communicate over.
 #[async_trait]
-pub trait Environment: Unpin + Sized + Clone + Send + 'static {
-    type TcpStream: TcpStream + Send + 'static + Unpin;
-    type TcpListener: TcpListener + Send + 'static + Unpin;
-
-    /// Spawn a task on the runtime provided by this [`Environment`].
+pub trait SpawnEnv: Unpin + Sized + Clone + Send + 'static {
+    /// Spawn a task on the runtime provided by this environment.
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static;
+
+    /// Exits the process this environment runs in with `code`. Under the deterministic runtime
+    /// only the simulated host is stopped, cancelling its tasks and closing its sockets, and the
+    /// returned future never completes.
+    async fn exit(&self, code: i32);
+
+    /// Creates a channel which buffers up to `buffer` messages for each sender.
+    fn channel<T>(&self, buffer: usize) -> (sync::mpsc::Sender<T>, sync::mpsc::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        sync::mpsc::channel(buffer)
+    }
+    /// Creates a channel with an unbounded buffer.
+    fn unbounded_channel<T>(
+        &self,
+    ) -> (
+        sync::mpsc::UnboundedSender<T>,
+        sync::mpsc::UnboundedReceiver<T>,
+    )
+    where
+        T: Send + 'static,
+    {
+        sync::mpsc::unbounded_channel()
+    }
+    /// Creates a channel with an unbounded buffer, which may drop messages under the
+    /// deterministic runtime, like a datagram socket.
+    fn lossy_channel<T>(
+        &self,
+    ) -> (
+        sync::mpsc::UnboundedSender<T>,
+        sync::mpsc::UnboundedReceiver<T>,
+    )
+    where
+        T: Send + 'static,
+    {
+        sync::mpsc::unbounded_channel()
+    }
+    /// Creates a channel for sending a single message.
+    fn oneshot<T>(&self) -> (sync::oneshot::Sender<T>, sync::oneshot::Receiver<T>)
+    where
+        T: Send + 'static,
+    {
+        sync::oneshot::channel()
+    }
+    /// Creates a channel which delivers each message to every receiver.
+    fn broadcast<T>(&self) -> (sync::broadcast::Sender<T>, sync::broadcast::Receiver<T>)
+    where
+        T: Clone + Send + 'static,
+    {
+        sync::broadcast::channel()
+    }
+}
+
+/// Time and timers.
+pub trait TimeEnv: Unpin + Sized + Clone + Send + 'static {
+    /// Delay future provided by the timer of the runtime.
+    type Delay: Future<Output = ()> + Send + Unpin + 'static;
+
     /// Return the time now according to the executor.
     fn now(&self) -> time::Instant;
+    /// Return the wall clock time now, for timestamps which are stored or sent to other hosts.
+    fn now_system_time(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
     /// Returns a delay future which completes after the provided instant.
-    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay;
+    fn delay(&self, deadline: time::Instant) -> Self::Delay;
     /// Returns a delay future which completes at some time from now.
-    fn delay_from(&self, from_now: time::Duration) -> tokio_timer::Delay {
+    fn delay_from(&self, from_now: time::Duration) -> Self::Delay {
         let now = self.now();
         self.delay(now + from_now)
     }
     /// Creates a timeout future which which will execute T until the timeout elapses.
-    fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T>;
+    fn timeout<T>(&self, value: T, timeout: time::Duration) -> Timeout<T, Self::Delay> {
+        Timeout::new(value, self.delay_from(timeout))
+    }
+}
+
+/// TCP sockets.
+#[async_trait]
+pub trait NetEnv: Unpin + Sized + Clone + Send + 'static {
+    type TcpStream: TcpStream + Send + 'static + Unpin;
+    type TcpListener: TcpListener + Send + 'static + Unpin;
 
     /// Binds and returns a listener which can be used to listen for new connections.
     async fn bind<A>(&self, addr: A) -> io::Result<Self::TcpListener>
     where
-        A: Into<net::SocketAddr> + Send + Sync;
+        A: Into<SocketAddr> + Send + Sync;
 
     /// Connects to the specified addr, returning a [`TcpStream`] which can be
     /// used to send and receive bytes.
@@ -247,31 +509,258 @@ pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     /// [`TcpStream`]:`TcpStream`
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
-        A: Into<net::SocketAddr> + Send + Sync;
+        A: Into<SocketAddr> + Send + Sync;
 }
 
+/// A source of randomness.
+pub trait RandEnv: Unpin + Sized + Clone + Send + 'static {
+    /// Returns a source of randomness, which is seeded by the runtime in simulation so that
+    /// random choices are reproducible.
+    fn random(&self) -> RandomHandle {
+        RandomHandle::natural(production::NaturalRandomHandle::default())
+    }
+}
+
+/// Identity and configuration of the host.
+pub trait HostEnv: Unpin + Sized + Clone + Send + 'static {
+    /// Returns the name of the host this environment runs on.
+    fn hostname(&self) -> String;
+    /// Returns an identifier for the host this environment runs on, which does not change when
+    /// it restarts.
+    fn host_id(&self) -> u64;
+    /// Returns the addresses assigned to the host this environment runs on.
+    fn local_addrs(&self) -> Vec<IpAddr>;
+    /// Returns the configuration value for `key`, standing in for environment variables and
+    /// configuration files. Unless overridden, this reads the environment of the process.
+    fn config(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok()
+    }
+}
+
+/// Files and directories.
+#[async_trait]
+pub trait FsEnv: Unpin + Sized + Clone + Send + 'static {
+    type File: File + Send + 'static + Unpin;
+
+    /// Opens the existing file at `path` for reading and writing.
+    async fn open<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Opens the file at `path` for reading and writing, creating it if it does not exist
+    /// and truncating it if it does.
+    async fn create<P>(&self, path: P) -> io::Result<Self::File>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Reads the entire contents of the file at `path`.
+    async fn read<P>(&self, path: P) -> io::Result<Vec<u8>>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Replaces the contents of the file at `path` with `contents`, creating it if required.
+    async fn write<P, C>(&self, path: P, contents: C) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync;
+
+    /// Renames the file at `from` to `to`, replacing any file already at `to`.
+    async fn rename<P, Q>(&self, from: P, to: Q) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync;
+
+    /// Removes the file at `path`.
+    async fn remove<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Creates a directory at `path`. The directory containing it must already exist.
+    async fn create_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Removes the empty directory at `path`.
+    async fn remove_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Returns the paths of the entries within the directory at `path`, in sorted order.
+    async fn read_dir<P>(&self, path: P) -> io::Result<Vec<path::PathBuf>>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+
+    /// Flushes the entries of the directory at `path` to disk, so that files created, renamed
+    /// or removed within it survive a crash.
+    async fn sync_dir<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<path::Path> + Send + Sync;
+}
+
+/// Every capability a simulated application may depend on. Implemented for any type which
+/// implements each of the capability traits, so libraries which need only some capabilities
+/// can depend on those instead, and test doubles only need to implement what is used.
+pub trait Environment: SpawnEnv + TimeEnv + NetEnv + RandEnv + HostEnv + FsEnv {}
+
+impl<T> Environment for T where T: SpawnEnv + TimeEnv + NetEnv + RandEnv + HostEnv + FsEnv {}
+
 pub trait TcpStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
-    fn local_addr(&self) -> io::Result<net::SocketAddr>;
-    fn peer_addr(&self) -> io::Result<net::SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
 }
 
 #[async_trait]
 pub trait TcpListener {
     type Stream: TcpStream + Send + 'static;
-    async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error>;
-    fn local_addr(&self) -> Result<net::SocketAddr, io::Error>;
+    async fn accept(&mut self) -> Result<(Self::Stream, SocketAddr), io::Error>;
+    fn local_addr(&self) -> Result<SocketAddr, io::Error>;
     fn ttl(&self) -> io::Result<u32>;
     fn set_ttl(&self, ttl: u32) -> io::Result<()>;
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>>;
 }
 
+#[async_trait]
+pub trait File: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Seeks to `pos`, returning the new position of the cursor used by `AsyncRead` and
+    /// `AsyncWrite`.
+    async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64>;
+    /// Reads bytes starting at `offset` into `buf`, returning the number of bytes read. A
+    /// return value of 0 indicates that `offset` is at or beyond the end of the file. The
+    /// cursor is not moved.
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    /// Writes all of `buf` starting at `offset`, extending the file if required. The cursor is
+    /// not moved.
+    async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Returns the length of the file in bytes.
+    async fn size(&mut self) -> io::Result<u64>;
+    /// Flushes all data and metadata for this file to disk.
+    async fn sync_all(&mut self) -> io::Result<()>;
+    /// Flushes all data for this file to disk, without necessarily flushing metadata.
+    async fn sync_data(&mut self) -> io::Result<()>;
+}
+
 pub fn spawn_with_result<F, E, U>(env: &E, future: F) -> impl Future<Output = U>
 where
     F: Future<Output = U> + Send + 'static,
     U: Send + 'static,
-    E: Environment,
+    E: SpawnEnv,
 {
     let (remote, handle) = future.remote_handle();
     env.spawn(remote);
     Box::new(handle)
 }
+
+/// Spawn a task on the current runtime. Inside a [`DeterministicRuntime`], the task runs on
+/// behalf of the simulated host of the calling task, otherwise it is spawned onto the default
+/// Tokio executor.
+///
+/// [`DeterministicRuntime`]:deterministic::DeterministicRuntime
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    #[cfg(feature = "sim")]
+    {
+        if let Some(handle) = deterministic::current() {
+            return handle.spawn(future);
+        }
+    }
+    tokio_executor::spawn(future)
+}
+
+/// Return the time now according to the current runtime.
+pub fn now() -> time::Instant {
+    #[cfg(feature = "sim")]
+    {
+        if let Some(handle) = deterministic::current() {
+            return handle.now();
+        }
+    }
+    tokio_timer::clock::now()
+}
+
+/// Returns a delay future which completes after the provided instant, according to the
+/// current runtime. Unlike delays started through a simulated host's handle, these are not
+/// listed among the host's open timers.
+pub fn delay(deadline: time::Instant) -> tokio_timer::Delay {
+    #[cfg(feature = "sim")]
+    {
+        if let Some(handle) = deterministic::current() {
+            return handle.time_handle().delay(deadline);
+        }
+    }
+    tokio_timer::delay(deadline)
+}
+
+/// Returns a delay future which completes at some time from now, according to the current
+/// runtime.
+pub fn delay_for(duration: time::Duration) -> tokio_timer::Delay {
+    #[cfg(feature = "sim")]
+    {
+        if let Some(handle) = deterministic::current() {
+            return handle.time_handle().delay_from(duration);
+        }
+    }
+    delay(now() + duration)
+}
+
+/// Requires `value` to complete before `timeout` has elapsed, according to the current runtime.
+pub fn timeout<T>(value: T, timeout: time::Duration) -> Timeout<T, tokio_timer::Delay> {
+    Timeout::new(value, delay_for(timeout))
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, production::NaturalRuntime};
+    use std::error::Error as _;
+
+    #[test]
+    /// Test that a delay until an instant waits on the simulated clock inside the
+    /// deterministic runtime.
+    fn delay_in_simulation() {
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        runtime
+            .block_on(async {
+                let deadline = crate::now() + time::Duration::from_secs(3);
+                crate::delay(deadline).await;
+                assert_eq!(handle.now(), deadline);
+                assert_eq!(crate::now(), handle.now());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that the free functions fall back to the real clock outside the deterministic
+    /// runtime.
+    fn real_outside_simulation() {
+        let mut runtime = NaturalRuntime::new().unwrap();
+        runtime.block_on(async {
+            let start = time::Instant::now();
+            crate::delay_for(time::Duration::from_millis(10)).await;
+            assert!(start.elapsed() >= time::Duration::from_millis(10));
+            assert!(crate::now() >= start + time::Duration::from_millis(10));
+        });
+    }
+
+    #[test]
+    /// Test that errors raised by the simulation describe themselves and keep their source.
+    fn simulation_errors() {
+        let overflow = Error::TimeOverflow {
+            operation: "scheduling a delay".to_string(),
+        };
+        assert_eq!(
+            overflow.to_string(),
+            "Simulated time overflowed while scheduling a delay"
+        );
+        assert!(overflow.source().is_none());
+
+        let host = Error::SimHost {
+            name: "server".to_string(),
+            source: "connection refused".into(),
+        };
+        assert_eq!(host.to_string(), "Host server failed: connection refused");
+        assert_eq!(host.source().unwrap().to_string(), "connection refused");
+        assert!(host.injected_fault().is_none());
+    }
+}
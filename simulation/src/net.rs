@@ -0,0 +1,308 @@
+//! Drop-in replacements for `tokio::net`.
+//!
+//! [`TcpStream`] and [`TcpListener`] mirror the methods of their Tokio counterparts, but when
+//! used from a task running inside a [`DeterministicRuntime`] they use the simulated network
+//! of the host the task belongs to. Everywhere else they use real sockets. Existing code can
+//! adopt simulation by changing its imports, rather than threading an [`Environment`] through
+//! every function which opens a connection. Without the `sim` feature, they only wrap the
+//! Tokio types.
+//!
+//! The simulated network has no name resolution, so addresses must already be resolved.
+//!
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`Environment`]:crate::Environment
+#[cfg(feature = "sim")]
+use crate::{deterministic, NetEnv};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use std::{
+    io, net,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+enum StreamInner {
+    #[cfg(feature = "sim")]
+    Simulated(Box<deterministic::Socket>),
+    Real(tokio::net::TcpStream),
+}
+
+/// A TCP stream between a local and a remote socket.
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: StreamInner,
+}
+
+impl TcpStream {
+    /// Opens a connection to `addr`.
+    pub async fn connect<A>(addr: A) -> io::Result<TcpStream>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        let addr = addr.into();
+        #[cfg(feature = "sim")]
+        {
+            if let Some(handle) = deterministic::current() {
+                let socket = handle.connect(addr).await?;
+                return Ok(TcpStream {
+                    inner: StreamInner::Simulated(Box::new(socket)),
+                });
+            }
+        }
+        let socket = tokio::net::TcpStream::connect(addr).await?;
+        Ok(TcpStream {
+            inner: StreamInner::Real(socket),
+        })
+    }
+
+    /// Returns the local address this stream is bound to.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match &self.inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => crate::TcpStream::local_addr(&**socket),
+            StreamInner::Real(socket) => socket.local_addr(),
+        }
+    }
+
+    /// Returns the remote address this stream is connected to.
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        match &self.inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => crate::TcpStream::peer_addr(&**socket),
+            StreamInner::Real(socket) => socket.peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => Pin::new(socket).poll_read(cx, buf),
+            StreamInner::Real(socket) => Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => Pin::new(socket).poll_write(cx, buf),
+            StreamInner::Real(socket) => Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => Pin::new(socket).poll_flush(cx),
+            StreamInner::Real(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            #[cfg(feature = "sim")]
+            StreamInner::Simulated(socket) => Pin::new(socket).poll_shutdown(cx),
+            StreamInner::Real(socket) => Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+}
+
+impl crate::TcpStream for TcpStream {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+#[derive(Debug)]
+enum ListenerInner {
+    #[cfg(feature = "sim")]
+    Simulated(deterministic::Listener),
+    Real(tokio::net::TcpListener),
+}
+
+/// A TCP socket server, listening for connections.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: ListenerInner,
+}
+
+impl TcpListener {
+    /// Creates a listener bound to `addr`.
+    pub async fn bind<A>(addr: A) -> io::Result<TcpListener>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        let addr = addr.into();
+        #[cfg(feature = "sim")]
+        {
+            if let Some(handle) = deterministic::current() {
+                let listener = handle.bind(addr).await?;
+                return Ok(TcpListener {
+                    inner: ListenerInner::Simulated(listener),
+                });
+            }
+        }
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        Ok(TcpListener {
+            inner: ListenerInner::Real(listener),
+        })
+    }
+
+    /// Accepts a new incoming connection, returning the stream and the remote address.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, net::SocketAddr)> {
+        let (inner, addr) = match &mut self.inner {
+            #[cfg(feature = "sim")]
+            ListenerInner::Simulated(listener) => {
+                let (socket, addr) = crate::TcpListener::accept(listener).await?;
+                (StreamInner::Simulated(Box::new(socket)), addr)
+            }
+            ListenerInner::Real(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                (StreamInner::Real(socket), addr)
+            }
+        };
+        Ok((TcpStream { inner }, addr))
+    }
+
+    /// Returns the local address this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        match &self.inner {
+            #[cfg(feature = "sim")]
+            ListenerInner::Simulated(listener) => crate::TcpListener::local_addr(listener),
+            ListenerInner::Real(listener) => listener.local_addr(),
+        }
+    }
+
+    /// Returns the value of the `IP_TTL` option for this listener.
+    pub fn ttl(&self) -> io::Result<u32> {
+        match &self.inner {
+            #[cfg(feature = "sim")]
+            ListenerInner::Simulated(listener) => crate::TcpListener::ttl(listener),
+            ListenerInner::Real(listener) => listener.ttl(),
+        }
+    }
+
+    /// Sets the value of the `IP_TTL` option for this listener.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match &self.inner {
+            #[cfg(feature = "sim")]
+            ListenerInner::Simulated(listener) => crate::TcpListener::set_ttl(listener, ttl),
+            ListenerInner::Real(listener) => listener.set_ttl(ttl),
+        }
+    }
+
+    /// Consumes the listener, returning a stream of the connections it accepts.
+    pub fn incoming(self) -> impl Stream<Item = io::Result<TcpStream>> + Send {
+        crate::TcpListener::into_stream(self)
+    }
+}
+
+#[async_trait]
+impl crate::TcpListener for TcpListener {
+    type Stream = TcpStream;
+    async fn accept(&mut self) -> io::Result<(Self::Stream, net::SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        TcpListener::ttl(self)
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        TcpListener::set_ttl(self, ttl)
+    }
+    fn into_stream(self) -> Pin<Box<dyn Stream<Item = io::Result<Self::Stream>> + Send>> {
+        match self.inner {
+            #[cfg(feature = "sim")]
+            ListenerInner::Simulated(listener) => crate::TcpListener::into_stream(listener)
+                .map(|socket| {
+                    socket.map(|socket| TcpStream {
+                        inner: StreamInner::Simulated(Box::new(socket)),
+                    })
+                })
+                .boxed(),
+            ListenerInner::Real(listener) => listener
+                .incoming()
+                .map(|socket| {
+                    socket.map(|socket| TcpStream {
+                        inner: StreamInner::Real(socket),
+                    })
+                })
+                .boxed(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, SpawnEnv};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that the shim uses the simulated network of the host a task is spawned on.
+    fn simulated_inside_runtime() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 9092);
+                let (bound_tx, bound_rx) = futures::channel::oneshot::channel();
+                server.spawn(async move {
+                    let mut listener = TcpListener::bind(addr).await.unwrap();
+                    bound_tx.send(()).unwrap();
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    socket.write_all(b"hello").await.unwrap();
+                });
+                bound_rx.await.unwrap();
+
+                let result = crate::spawn_with_result(&client, async move {
+                    let mut socket = TcpStream::connect(addr).await.unwrap();
+                    let mut buf = [0; 5];
+                    socket.read_exact(&mut buf).await.unwrap();
+                    (socket.local_addr().unwrap(), buf)
+                })
+                .await;
+                assert_eq!(result.0.ip(), client.local_addr());
+                assert_eq!(&result.1, b"hello");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that the shim falls back to real sockets outside the deterministic runtime, and
+    /// surfaces their errors.
+    fn real_outside_runtime() {
+        let mut runtime = crate::production::NaturalRuntime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let socket = TcpStream::connect(addr).await.unwrap();
+            assert_eq!(socket.peer_addr().unwrap(), addr);
+            drop(listener);
+
+            let err = TcpStream::connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+}
@@ -0,0 +1,287 @@
+//! An [`Environment`] for running applications in production.
+//!
+//! [`NaturalRuntime`] runs tasks on the Tokio thread pool, with real sockets, files and timers,
+//! and randomness provided by the operating system. Its handle mirrors the interface of
+//! [`DeterministicRuntimeHandle`] wherever it applies outside of a simulation, so code written
+//! against one can be moved to the other without changes.
+//!
+//! ```ignore
+//! let mut runtime = NaturalRuntime::new()?;
+//! let handle = runtime.handle();
+//! runtime.block_on(server(handle, addr))?;
+//! ```
+//!
+//! [`Environment`]:crate::Environment
+//! [`NaturalRuntime`]:NaturalRuntime
+//! [`DeterministicRuntimeHandle`]:crate::deterministic::DeterministicRuntimeHandle
+use crate::Error;
+use async_trait::async_trait;
+use futures::{Future, StreamExt};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path, time,
+};
+mod random;
+pub use random::NaturalRandomHandle;
+
+#[derive(Debug, Clone)]
+pub struct NaturalRuntimeHandle {
+    executor: tokio::runtime::TaskExecutor,
+    random_handle: NaturalRandomHandle,
+    start: time::Instant,
+}
+
+impl NaturalRuntimeHandle {
+    pub fn now(&self) -> time::Instant {
+        tokio_timer::clock::now()
+    }
+    /// Returns the time which has passed since the runtime was created.
+    pub fn elapsed(&self) -> time::Duration {
+        self.now() - self.start
+    }
+    pub fn random_handle(&self) -> NaturalRandomHandle {
+        self.random_handle.clone()
+    }
+}
+
+#[async_trait]
+impl crate::SpawnEnv for NaturalRuntimeHandle {
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.executor.spawn(future)
+    }
+    async fn exit(&self, code: i32) {
+        std::process::exit(code)
+    }
+}
+
+impl crate::TimeEnv for NaturalRuntimeHandle {
+    type Delay = tokio_timer::Delay;
+    fn now(&self) -> time::Instant {
+        tokio_timer::clock::now()
+    }
+    fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        // the delay is registered with the timer of the worker thread which first polls it.
+        tokio_timer::delay(deadline)
+    }
+}
+
+#[async_trait]
+impl crate::NetEnv for NaturalRuntimeHandle {
+    type TcpStream = tokio::net::TcpStream;
+    type TcpListener = tokio::net::TcpListener;
+    async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        tokio::net::TcpListener::bind(addr.into()).await
+    }
+    async fn connect<A>(&self, addr: A) -> Result<Self::TcpStream, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        tokio::net::TcpStream::connect(addr.into()).await
+    }
+}
+
+impl crate::RandEnv for NaturalRuntimeHandle {
+    fn random(&self) -> crate::RandomHandle {
+        crate::RandomHandle::natural(self.random_handle.clone())
+    }
+}
+
+impl crate::HostEnv for NaturalRuntimeHandle {
+    fn hostname(&self) -> String {
+        crate::host::hostname()
+    }
+    fn host_id(&self) -> u64 {
+        crate::host::host_id()
+    }
+    fn local_addrs(&self) -> Vec<IpAddr> {
+        crate::host::local_addrs()
+    }
+}
+
+#[async_trait]
+impl crate::FsEnv for NaturalRuntimeHandle {
+    type File = tokio::fs::File;
+    async fn open<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref().to_path_buf())
+            .await
+    }
+    async fn create<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref().to_path_buf())
+            .await
+    }
+    async fn read<P>(&self, path: P) -> Result<Vec<u8>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::read(path.as_ref().to_path_buf()).await
+    }
+    async fn write<P, C>(&self, path: P, contents: C) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync,
+    {
+        tokio::fs::write(path.as_ref().to_path_buf(), contents.as_ref().to_vec()).await
+    }
+    async fn rename<P, Q>(&self, from: P, to: Q) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::rename(from.as_ref().to_path_buf(), to.as_ref().to_path_buf()).await
+    }
+    async fn remove<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::remove_file(path.as_ref().to_path_buf()).await
+    }
+    async fn create_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::create_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn remove_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::remove_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn read_dir<P>(&self, path: P) -> Result<Vec<path::PathBuf>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let mut entries = tokio::fs::read_dir(path.as_ref().to_path_buf()).await?;
+        let mut paths = vec![];
+        while let Some(entry) = entries.next().await {
+            paths.push(entry?.path());
+        }
+        paths.sort();
+        Ok(paths)
+    }
+    async fn sync_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let mut dir = tokio::fs::File::open(path.as_ref().to_path_buf()).await?;
+        dir.sync_all().await
+    }
+}
+
+/// A runtime backed by the Tokio thread pool, reactor and timer.
+pub struct NaturalRuntime {
+    runtime: tokio::runtime::Runtime,
+    start: time::Instant,
+}
+
+impl NaturalRuntime {
+    pub fn new() -> Result<Self, Error> {
+        let runtime =
+            tokio::runtime::Runtime::new().map_err(|source| Error::RuntimeBuild { source })?;
+        Ok(NaturalRuntime {
+            runtime,
+            start: tokio_timer::clock::now(),
+        })
+    }
+
+    pub fn handle(&self) -> NaturalRuntimeHandle {
+        NaturalRuntimeHandle {
+            executor: self.runtime.executor(),
+            random_handle: NaturalRandomHandle::default(),
+            start: self.start,
+        }
+    }
+
+    pub fn spawn<F>(&mut self, future: F) -> &mut Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.runtime.spawn(future);
+        self
+    }
+
+    /// Run `f` to completion, while spawned tasks are driven by the thread pool.
+    pub fn block_on<F>(&mut self, f: F) -> F::Output
+    where
+        F: Future,
+    {
+        self.runtime.block_on(f)
+    }
+
+    /// Wait for all spawned tasks to complete, then shut down the runtime.
+    pub fn shutdown_on_idle(self) {
+        self.runtime.shutdown_on_idle()
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::DeterministicRuntime, HostEnv, NetEnv, SpawnEnv, TcpListener, TimeEnv,
+    };
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Bind a server to `addr` which echoes a single message, and return the reply to a ping
+    /// once a delay has elapsed.
+    async fn ping<E>(env: E, addr: SocketAddr) -> Vec<u8>
+    where
+        E: NetEnv + SpawnEnv + TimeEnv,
+    {
+        let mut listener = env.bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        env.spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+        let mut socket = env.connect(addr).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        let mut reply = vec![0; 4];
+        socket.read_exact(&mut reply).await.unwrap();
+        env.delay_from(time::Duration::from_millis(10)).await;
+        reply
+    }
+
+    #[test]
+    /// Test that the same code runs against real sockets and timers, and in simulation.
+    fn natural_and_deterministic() {
+        let mut runtime = NaturalRuntime::new().unwrap();
+        let handle = runtime.handle();
+        let localhost = net::IpAddr::V4(net::Ipv4Addr::LOCALHOST);
+        let reply = runtime.block_on(ping(handle.clone(), SocketAddr::new(localhost, 0)));
+        assert_eq!(reply, b"ping");
+        assert!(handle.elapsed() >= time::Duration::from_millis(10));
+        assert!(handle.random_handle().gen_range(5..10) >= 5);
+        assert!(handle.local_addrs().contains(&localhost));
+
+        let (mut runtime, handle) = DeterministicRuntime::builder().build().unwrap();
+        let reply = runtime
+            .block_on(ping(handle, SocketAddr::new(localhost, 9092)))
+            .unwrap();
+        assert_eq!(reply, b"ping");
+    }
+}
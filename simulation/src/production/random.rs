@@ -0,0 +1,42 @@
+use rand::{distributions::uniform::SampleUniform, rngs, Rng, RngCore};
+use rand_distr::{Distribution, Normal};
+use std::ops;
+
+/// Source of randomness provided by the operating system, with the same interface as
+/// [`DeterministicRandomHandle`].
+///
+/// [`DeterministicRandomHandle`]:crate::deterministic::DeterministicRandomHandle
+#[derive(Debug, Clone, Default)]
+pub struct NaturalRandomHandle {
+    _private: (),
+}
+
+impl NaturalRandomHandle {
+    pub fn normal_dist(&self, mean: f64, dev: f64) -> f64 {
+        let normal = Normal::new(mean, dev).unwrap_or_else(|_| {
+            panic!("illegal normal params, mean: {}, deviation: {}", mean, dev)
+        });
+        normal.sample(&mut rngs::OsRng)
+    }
+
+    pub fn should_fault(&self, probability: f64) -> bool {
+        rngs::OsRng.gen_bool(probability)
+    }
+
+    pub fn gen_range<T>(&self, range: ops::Range<T>) -> T
+    where
+        T: SampleUniform,
+    {
+        rngs::OsRng.gen_range(range.start, range.end)
+    }
+
+    /// Returns an index into a slice of `len` elements, which must not be empty.
+    pub fn gen_index(&self, len: usize) -> usize {
+        rngs::OsRng.gen_range(0, len)
+    }
+
+    /// Fill `dest` with random bytes.
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        rngs::OsRng.fill_bytes(dest)
+    }
+}
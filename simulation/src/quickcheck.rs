@@ -0,0 +1,221 @@
+//! A [quickcheck] harness which generates seeds and fault plans for simulations.
+//!
+//! [`Seed`] and [`FaultPlan`] implement `Arbitrary`, so they can be used as arguments of any
+//! quickcheck property. When a property fails, quickcheck shrinks the seed towards zero and the
+//! plan towards fewer, earlier faults on lower numbered hosts. [`Simulation`] wraps a property
+//! over a seed and a fault plan, and additionally treats a panic inside the property as a
+//! failure to be shrunk, which is how most assertions inside a simulation fail:
+//!
+//! ```ignore
+//! simulation::quickcheck::check(100, |seed: Seed, plan: FaultPlan| {
+//!     let mut runtime = seed.runtime().unwrap();
+//!     let handle = runtime.localhost_handle();
+//!     runtime.block_on(async move {
+//!         let cluster = Cluster::new(handle);
+//!         // add hosts and start a workload against them.
+//!         plan.run(cluster).await.unwrap();
+//!         // check the workload.
+//!     }).unwrap();
+//!     true
+//! });
+//! ```
+//!
+//! [quickcheck]: https://docs.rs/quickcheck
+use crate::{
+    deterministic::{DeterministicRuntime, FaultPlan, HostFault, PlannedFault},
+    Error,
+};
+use ::quickcheck::{Arbitrary, Gen, QuickCheck, TestResult, Testable};
+use rand::Rng;
+use std::{panic, time::Duration};
+
+/// The latest point in a plan at which a fault is generated, in milliseconds.
+const MAX_AFTER_MILLIS: u64 = 60_000;
+/// The longest generated downtime of a crashed host, in milliseconds.
+const MAX_DOWNTIME_MILLIS: u64 = 30_000;
+/// The number of distinct host indexes generated. Plans wrap around smaller clusters.
+const MAX_HOSTS: usize = 8;
+
+/// A seed for a [`DeterministicRuntime`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Seed(pub u64);
+
+impl Seed {
+    /// Create a runtime seeded with this seed.
+    pub fn runtime(self) -> Result<DeterministicRuntime, Error> {
+        DeterministicRuntime::builder()
+            .seed(self.0)
+            .build()
+            .map(|(runtime, _)| runtime)
+    }
+}
+
+impl Arbitrary for Seed {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        Seed(g.next_u64())
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.0.shrink().map(Seed))
+    }
+}
+
+impl Arbitrary for HostFault {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        if g.gen() {
+            HostFault::Crash {
+                downtime: Duration::from_millis(g.gen_range(0, MAX_DOWNTIME_MILLIS)),
+            }
+        } else {
+            HostFault::Restart
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        match *self {
+            HostFault::Crash { downtime } => Box::new((downtime.as_millis() as u64).shrink().map(
+                |millis| HostFault::Crash {
+                    downtime: Duration::from_millis(millis),
+                },
+            )),
+            HostFault::Restart => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+impl Arbitrary for PlannedFault {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        PlannedFault {
+            after: Duration::from_millis(g.gen_range(0, MAX_AFTER_MILLIS)),
+            host: g.gen_range(0, MAX_HOSTS),
+            fault: HostFault::arbitrary(g),
+        }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let parts = (self.after.as_millis() as u64, self.host, self.fault);
+        Box::new(parts.shrink().map(|(after, host, fault)| PlannedFault {
+            after: Duration::from_millis(after),
+            host,
+            fault,
+        }))
+    }
+}
+
+impl Arbitrary for FaultPlan {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        Vec::<PlannedFault>::arbitrary(g).into_iter().collect()
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(
+            self.faults()
+                .to_vec()
+                .shrink()
+                .map(|faults| faults.into_iter().collect()),
+        )
+    }
+}
+
+/// A property over a seed and a fault plan, which fails if it returns `false` or panics.
+///
+/// On failure, the seed and plan are shrunk to the smallest input which still fails, and the
+/// test fails with a message containing it.
+#[derive(Debug, Clone)]
+pub struct Simulation<F> {
+    property: F,
+}
+
+impl<F> Simulation<F>
+where
+    F: Fn(Seed, FaultPlan) -> bool + Send + 'static,
+{
+    pub fn new(property: F) -> Self {
+        Self { property }
+    }
+
+    fn passes(&self, (seed, plan): &(Seed, FaultPlan)) -> bool {
+        let property = &self.property;
+        let seed = *seed;
+        let plan = plan.clone();
+        panic::catch_unwind(panic::AssertUnwindSafe(|| property(seed, plan))).unwrap_or(false)
+    }
+}
+
+impl<F> Testable for Simulation<F>
+where
+    F: Fn(Seed, FaultPlan) -> bool + Send + 'static,
+{
+    fn result<G: Gen>(&self, g: &mut G) -> TestResult {
+        let mut input = (Seed::arbitrary(g), FaultPlan::arbitrary(g));
+        if self.passes(&input) {
+            return TestResult::passed();
+        }
+        // shrink greedily, moving to the first smaller input which still fails.
+        'shrink: loop {
+            for candidate in input.shrink() {
+                if !self.passes(&candidate) {
+                    input = candidate;
+                    continue 'shrink;
+                }
+            }
+            break;
+        }
+        let (seed, plan) = input;
+        TestResult::error(format!(
+            "simulation failed with seed {} and {:?}",
+            seed.0, plan
+        ))
+    }
+}
+
+/// Check `property` against `tests` generated seeds and fault plans, panicking with the
+/// smallest failing input if it fails.
+pub fn check<F>(tests: u64, property: F)
+where
+    F: Fn(Seed, FaultPlan) -> bool + Send + 'static,
+{
+    QuickCheck::new()
+        .tests(tests)
+        .quickcheck(Simulation::new(property))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{ChaosKind, Cluster, DeterministicRuntimeHandle};
+    use crate::TimeEnv;
+
+    async fn idle(handle: DeterministicRuntimeHandle) {
+        handle.delay_from(Duration::from_secs(3600)).await;
+    }
+
+    #[test]
+    /// Test that a failing simulation is shrunk to a single fault on the first host, at the
+    /// start of the plan.
+    fn shrink_failing_plan() {
+        let property = |seed: Seed, plan: FaultPlan| {
+            let mut runtime = seed.runtime().unwrap();
+            let handle = runtime.localhost_handle();
+            runtime
+                .block_on(async move {
+                    let cluster = Cluster::new(handle.clone());
+                    for _ in 0..3 {
+                        cluster.add_host(idle).await;
+                    }
+                    plan.run(cluster).await.unwrap();
+                    let kills = handle.chaos_log_handle().query().kind(ChaosKind::Kill);
+                    assert_eq!(kills.count(), 0);
+                })
+                .unwrap();
+            true
+        };
+        let result = QuickCheck::new()
+            .tests(20)
+            .quicktest(Simulation::new(property))
+            .unwrap_err();
+        let message = format!("{:?}", result);
+        assert!(message.contains("with seed 0 and FaultPlan"));
+        assert!(message.contains("faults: [PlannedFault { after: 0ns, host: 0"));
+    }
+}
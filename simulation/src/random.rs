@@ -0,0 +1,146 @@
+//! Randomness for code which is generic over its [`Environment`].
+//!
+//! [`RandomHandle`] draws from the seeded source of randomness of the [`DeterministicRuntime`]
+//! when running in simulation, so random choices made by application code are reproducible
+//! from the seed, and from the operating system otherwise.
+//!
+//! [`Environment`]:crate::Environment
+//! [`RandomHandle`]:RandomHandle
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+#[cfg(feature = "sim")]
+use crate::deterministic::DeterministicRandomHandle;
+use crate::production::NaturalRandomHandle;
+use rand::{distributions::uniform::SampleUniform, RngCore};
+use std::ops;
+
+#[derive(Debug, Clone)]
+enum Source {
+    #[cfg(feature = "sim")]
+    Deterministic(DeterministicRandomHandle),
+    Natural(NaturalRandomHandle),
+}
+
+/// Source of randomness provided by an [`Environment`]. Also implements `RngCore`, so it can
+/// be used with any of the functions of the `rand` crate.
+///
+/// [`Environment`]:crate::Environment
+#[derive(Debug, Clone)]
+pub struct RandomHandle {
+    source: Source,
+}
+
+impl RandomHandle {
+    #[cfg(feature = "sim")]
+    pub(crate) fn deterministic(handle: DeterministicRandomHandle) -> Self {
+        Self {
+            source: Source::Deterministic(handle),
+        }
+    }
+
+    pub(crate) fn natural(handle: NaturalRandomHandle) -> Self {
+        Self {
+            source: Source::Natural(handle),
+        }
+    }
+
+    pub fn normal_dist(&self, mean: f64, dev: f64) -> f64 {
+        match &self.source {
+            #[cfg(feature = "sim")]
+            Source::Deterministic(handle) => handle.normal_dist(mean, dev),
+            Source::Natural(handle) => handle.normal_dist(mean, dev),
+        }
+    }
+
+    pub fn should_fault(&self, probability: f64) -> bool {
+        match &self.source {
+            #[cfg(feature = "sim")]
+            Source::Deterministic(handle) => handle.should_fault(probability),
+            Source::Natural(handle) => handle.should_fault(probability),
+        }
+    }
+
+    pub fn gen_range<T>(&self, range: ops::Range<T>) -> T
+    where
+        T: SampleUniform,
+    {
+        match &self.source {
+            #[cfg(feature = "sim")]
+            Source::Deterministic(handle) => handle.gen_range(range),
+            Source::Natural(handle) => handle.gen_range(range),
+        }
+    }
+
+    /// Returns an index into a slice of `len` elements, which must not be empty. In
+    /// simulation the index is drawn the same way on every platform, unlike `gen_range` over
+    /// `usize`.
+    pub fn gen_index(&self, len: usize) -> usize {
+        match &self.source {
+            #[cfg(feature = "sim")]
+            Source::Deterministic(handle) => handle.gen_index(len),
+            Source::Natural(handle) => handle.gen_index(len),
+        }
+    }
+
+    /// Fill `dest` with random bytes.
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        match &self.source {
+            #[cfg(feature = "sim")]
+            Source::Deterministic(handle) => handle.fill_bytes(dest),
+            Source::Natural(handle) => handle.fill_bytes(dest),
+        }
+    }
+}
+
+impl RngCore for RandomHandle {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        RandomHandle::fill_bytes(self, &mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        RandomHandle::fill_bytes(self, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RandomHandle::fill_bytes(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RandomHandle::fill_bytes(self, dest);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, production::NaturalRuntime, RandEnv};
+    use rand::seq::SliceRandom;
+
+    /// Shuffle a list of peers, as generic code choosing the order to contact them in would.
+    fn shuffled<E>(env: &E) -> Vec<u32>
+    where
+        E: RandEnv,
+    {
+        let mut peers: Vec<u32> = (0..16).collect();
+        peers.shuffle(&mut env.random());
+        peers
+    }
+
+    #[test]
+    /// Test that random choices made through an environment are reproducible from the seed
+    /// in simulation.
+    fn seeded_choices() {
+        let handle = |seed| {
+            let (_, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+            handle
+        };
+        assert_eq!(shuffled(&handle(1)), shuffled(&handle(1)));
+        assert_ne!(shuffled(&handle(1)), shuffled(&handle(2)));
+
+        let natural = NaturalRuntime::new().unwrap().handle();
+        let mut peers = shuffled(&natural);
+        peers.sort();
+        assert_eq!(peers, (0..16).collect::<Vec<_>>());
+        assert!(natural.random().gen_range(5..10) >= 5);
+    }
+}
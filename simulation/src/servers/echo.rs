@@ -0,0 +1,67 @@
+//! An echo server, which writes every byte it reads from a connection back to it.
+use crate::{NetEnv, SpawnEnv, TcpListener};
+use std::{io, net};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+/// Bind to `addr` and echo every connection accepted until accepting a connection fails.
+pub async fn echo<E>(env: E, addr: net::SocketAddr) -> io::Result<()>
+where
+    E: NetEnv + SpawnEnv,
+{
+    let mut listener = env.bind(addr).await?;
+    loop {
+        let (mut socket, peer) = listener.accept().await?;
+        env.spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let n = match socket.read(&mut buf).await {
+                    Ok(0) => return,
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!("failed to read from {}: {}", peer, e);
+                        return;
+                    }
+                };
+                if let Err(e) = socket.write_all(&buf[..n]).await {
+                    debug!("failed to echo to {}: {}", peer, e);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, NetEnv, SpawnEnv};
+    use futures::FutureExt;
+
+    #[test]
+    /// Test that messages from concurrent clients are echoed back to the client which sent them.
+    fn echo_clients() {
+        let (mut runtime, _) = DeterministicRuntime::builder().seed(1).build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let addr = net::SocketAddr::new(server.local_addr(), 7);
+        let clients: Vec<_> = (1..4)
+            .map(|i| runtime.handle(net::Ipv4Addr::new(10, 0, 1, i).into()))
+            .collect();
+        runtime
+            .block_on(async {
+                server.spawn(echo(server.clone(), addr).map(Result::unwrap));
+                let clients = clients.into_iter().map(|client| {
+                    crate::spawn_with_result(&client.clone(), async move {
+                        let mut socket = client.connect(addr).await.unwrap();
+                        let message = format!("hello from {}", client.local_addr());
+                        socket.write_all(message.as_bytes()).await.unwrap();
+                        let mut echoed = vec![0; message.len()];
+                        socket.read_exact(&mut echoed).await.unwrap();
+                        assert_eq!(echoed, message.as_bytes());
+                    })
+                });
+                futures::future::join_all(clients).await;
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+//! A simple replicated key value store.
+//!
+//! A [`KvServer`] stores string keys and values in memory. A server configured with replicas
+//! acts as a primary: each write is forwarded to every replica and only applied and
+//! acknowledged once all of them have applied it, while reads are served from the primary's
+//! own state. Writes are replicated one at a time, so replicas apply them in the same order as
+//! the primary. A replica is a `KvServer` without replicas of its own, which can also be read
+//! from directly.
+//!
+//! If a replica can't be reached, the write fails and the client receives an error. Other
+//! replicas may already have applied it, so failed writes should be recorded as indeterminate
+//! rather than failed when checking a [`History`].
+//!
+//! Requests and responses are exchanged one per line:
+//!
+//! | request             | response                        |
+//! |---------------------|---------------------------------|
+//! | `GET <key>`         | `VALUE <value>` or `NONE`       |
+//! | `PUT <key> <value>` | `OK`                            |
+//! | `DEL <key>`         | `OK`                            |
+//!
+//! Any request can also be answered with `ERR <reason>`. Keys can't contain spaces or newlines,
+//! and values can't contain newlines.
+//!
+//! [`KvServer`]:KvServer
+//! [`History`]:crate::history::History
+use crate::{
+    history::{KvInput, KvOutput},
+    NetEnv, SpawnEnv, TcpListener,
+};
+use futures::{lock, SinkExt, StreamExt};
+use std::{collections, io, net, sync};
+use tokio::codec::{Framed, LinesCodec, LinesCodecError};
+use tracing::debug;
+
+/// A key value store server, optionally replicating writes to other servers.
+#[derive(Debug, Clone, Default)]
+pub struct KvServer {
+    replicas: Vec<net::SocketAddr>,
+}
+
+impl KvServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replicate writes to the server at `addr` before acknowledging them.
+    pub fn replica(mut self, addr: net::SocketAddr) -> Self {
+        self.replicas.push(addr);
+        self
+    }
+
+    /// Bind to `addr` and serve requests until accepting a connection fails.
+    pub async fn serve<E>(self, env: E, addr: net::SocketAddr) -> io::Result<()>
+    where
+        E: NetEnv + SpawnEnv,
+    {
+        let mut listener = env.bind(addr).await?;
+        let store = Store {
+            state: sync::Arc::default(),
+            replicas: sync::Arc::new(lock::Mutex::new(
+                self.replicas
+                    .into_iter()
+                    .map(|addr| Replica { addr, client: None })
+                    .collect(),
+            )),
+        };
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let store = store.clone();
+            let session = env.clone();
+            env.spawn(async move {
+                if let Err(e) = store.session(session, socket).await {
+                    debug!("session with {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+struct Replica<E>
+where
+    E: NetEnv,
+{
+    addr: net::SocketAddr,
+    client: Option<KvClient<E>>,
+}
+
+struct Store<E>
+where
+    E: NetEnv,
+{
+    state: sync::Arc<sync::Mutex<collections::BTreeMap<String, String>>>,
+    // writes hold this lock until they are applied, which orders them across sessions.
+    replicas: sync::Arc<lock::Mutex<Vec<Replica<E>>>>,
+}
+
+impl<E> Clone for Store<E>
+where
+    E: NetEnv,
+{
+    fn clone(&self) -> Self {
+        Self {
+            state: sync::Arc::clone(&self.state),
+            replicas: sync::Arc::clone(&self.replicas),
+        }
+    }
+}
+
+impl<E> Store<E>
+where
+    E: NetEnv,
+{
+    async fn session(
+        &self,
+        env: E,
+        socket: <E::TcpListener as TcpListener>::Stream,
+    ) -> io::Result<()> {
+        let mut transport = Framed::new(socket, LinesCodec::new());
+        while let Some(line) = transport.next().await {
+            let line = line.map_err(into_io_error)?;
+            let response = match decode_input(&line) {
+                Some(input) => match self.apply(env.clone(), &input).await {
+                    Ok(output) => encode_output(&output),
+                    Err(reason) => format!("ERR {}", reason),
+                },
+                None => format!("ERR invalid request {:?}", line),
+            };
+            transport.send(response).await.map_err(into_io_error)?;
+        }
+        Ok(())
+    }
+
+    async fn apply(
+        &self,
+        env: E,
+        input: &KvInput<String, String>,
+    ) -> Result<KvOutput<String>, String> {
+        if let KvInput::Get(key) = input {
+            let state = self.state.lock().unwrap();
+            return Ok(KvOutput::Get(state.get(key).cloned()));
+        }
+        let mut replicas = self.replicas.lock().await;
+        for replica in replicas.iter_mut() {
+            if let Err(e) = replica.replicate(env.clone(), input).await {
+                // reconnect on the next write.
+                replica.client = None;
+                return Err(format!("replica {} failed: {}", replica.addr, e));
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        match input {
+            KvInput::Put(key, value) => {
+                state.insert(key.clone(), value.clone());
+                Ok(KvOutput::Put)
+            }
+            KvInput::Delete(key) => {
+                state.remove(key);
+                Ok(KvOutput::Delete)
+            }
+            KvInput::Get(_) => unreachable!(),
+        }
+    }
+}
+
+impl<E> Replica<E>
+where
+    E: NetEnv,
+{
+    async fn replicate(&mut self, env: E, input: &KvInput<String, String>) -> io::Result<()> {
+        if self.client.is_none() {
+            self.client = Some(KvClient::connect(env, self.addr).await?);
+        }
+        self.client.as_mut().unwrap().call(input.clone()).await?;
+        Ok(())
+    }
+}
+
+/// A client of a [`KvServer`], sending one request at a time over a single connection.
+///
+/// [`KvServer`]:KvServer
+pub struct KvClient<E>
+where
+    E: NetEnv,
+{
+    transport: Framed<E::TcpStream, LinesCodec>,
+}
+
+impl<E> KvClient<E>
+where
+    E: NetEnv,
+{
+    /// Connect to the server at `addr`.
+    pub async fn connect(env: E, addr: net::SocketAddr) -> io::Result<Self> {
+        let socket = env.connect(addr).await?;
+        Ok(Self {
+            transport: Framed::new(socket, LinesCodec::new()),
+        })
+    }
+
+    /// Send `input` to the server, returning its output. Inputs and outputs are those of the
+    /// [`Kv`] model, so operations can be recorded to a [`History`] and checked against it.
+    ///
+    /// [`Kv`]:crate::history::Kv
+    /// [`History`]:crate::history::History
+    pub async fn call(&mut self, input: KvInput<String, String>) -> io::Result<KvOutput<String>> {
+        let request = encode_input(&input)?;
+        self.transport.send(request).await.map_err(into_io_error)?;
+        let response = match self.transport.next().await {
+            Some(response) => response.map_err(into_io_error)?,
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        decode_output(&input, &response)
+    }
+
+    /// Returns the value of `key`, if it is set.
+    pub async fn get(&mut self, key: &str) -> io::Result<Option<String>> {
+        match self.call(KvInput::Get(key.to_string())).await? {
+            KvOutput::Get(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set `key` to `value`.
+    pub async fn put(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.call(KvInput::Put(key.to_string(), value.to_string()))
+            .await
+            .map(drop)
+    }
+
+    /// Remove `key` if it is set.
+    pub async fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.call(KvInput::Delete(key.to_string())).await.map(drop)
+    }
+}
+
+fn into_io_error(e: LinesCodecError) -> io::Error {
+    match e {
+        LinesCodecError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+fn encode_input(input: &KvInput<String, String>) -> io::Result<String> {
+    let (key, request) = match input {
+        KvInput::Get(key) => (key, format!("GET {}", key)),
+        KvInput::Put(key, value) => {
+            if value.contains('\n') {
+                let reason = format!("value {:?} contains a newline", value);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+            }
+            (key, format!("PUT {} {}", key, value))
+        }
+        KvInput::Delete(key) => (key, format!("DEL {}", key)),
+    };
+    if key.is_empty() || key.contains(&[' ', '\n'][..]) {
+        let reason = format!("invalid key {:?}", key);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+    }
+    Ok(request)
+}
+
+fn decode_input(line: &str) -> Option<KvInput<String, String>> {
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(key), None) if !key.is_empty() => Some(KvInput::Get(key.to_string())),
+        (Some("PUT"), Some(key), Some(value)) if !key.is_empty() => {
+            Some(KvInput::Put(key.to_string(), value.to_string()))
+        }
+        (Some("DEL"), Some(key), None) if !key.is_empty() => Some(KvInput::Delete(key.to_string())),
+        _ => None,
+    }
+}
+
+fn encode_output(output: &KvOutput<String>) -> String {
+    match output {
+        KvOutput::Get(Some(value)) => format!("VALUE {}", value),
+        KvOutput::Get(None) => "NONE".to_string(),
+        KvOutput::Put | KvOutput::Delete => "OK".to_string(),
+    }
+}
+
+fn decode_output(input: &KvInput<String, String>, line: &str) -> io::Result<KvOutput<String>> {
+    if let Some(reason) = line.strip_prefix("ERR ") {
+        return Err(io::Error::other(reason));
+    }
+    match (input, line) {
+        (KvInput::Get(_), "NONE") => Ok(KvOutput::Get(None)),
+        (KvInput::Get(_), line) if line.starts_with("VALUE ") => {
+            Ok(KvOutput::Get(Some(line[6..].to_string())))
+        }
+        (KvInput::Put(..), "OK") => Ok(KvOutput::Put),
+        (KvInput::Delete(_), "OK") => Ok(KvOutput::Delete),
+        _ => {
+            let reason = format!("unexpected response {:?}", line);
+            Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::DeterministicRuntime,
+        history::{History, Kv},
+        SpawnEnv, TimeEnv,
+    };
+    use futures::FutureExt;
+    use rand::{Rng, SeedableRng};
+    use std::time::Duration;
+
+    #[test]
+    /// Test that writes acknowledged by the primary can be read from each of its replicas.
+    fn replicated_writes() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let hosts: Vec<_> = (1..4)
+            .map(|i| runtime.handle(net::Ipv4Addr::new(10, 0, 0, i).into()))
+            .collect();
+        let addrs: Vec<_> = hosts
+            .iter()
+            .map(|host| net::SocketAddr::new(host.local_addr(), 6379))
+            .collect();
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 1, 1).into());
+        runtime
+            .block_on(async {
+                let primary = KvServer::new().replica(addrs[1]).replica(addrs[2]);
+                let servers = vec![primary, KvServer::new(), KvServer::new()];
+                for ((server, host), addr) in servers.into_iter().zip(hosts).zip(addrs.clone()) {
+                    host.spawn(server.serve(host.clone(), addr).map(Result::unwrap));
+                }
+                // let the servers bind before connecting to them.
+                client.delay_from(Duration::from_millis(1)).await;
+
+                let mut primary = KvClient::connect(client.clone(), addrs[0]).await.unwrap();
+                primary.put("greeting", "hello world").await.unwrap();
+                primary.put("farewell", "goodbye").await.unwrap();
+                primary.delete("farewell").await.unwrap();
+                assert_eq!(
+                    primary.get("greeting").await.unwrap(),
+                    Some("hello world".to_string())
+                );
+                for addr in &addrs[1..] {
+                    let mut replica = KvClient::connect(client.clone(), *addr).await.unwrap();
+                    assert_eq!(
+                        replica.get("greeting").await.unwrap(),
+                        Some("hello world".to_string())
+                    );
+                    assert_eq!(replica.get("farewell").await.unwrap(), None);
+                }
+                assert!(primary.put("bad key", "value").await.is_err());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that concurrent clients observe a linearizable history while latency faults are
+    /// injected.
+    fn linearizable_clients() {
+        let (mut runtime, _) = DeterministicRuntime::builder().seed(3).build().unwrap();
+        let primary = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let replica = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        let primary_addr = net::SocketAddr::new(primary.local_addr(), 6379);
+        let replica_addr = net::SocketAddr::new(replica.local_addr(), 6379);
+        let clients: Vec<_> = (1..4)
+            .map(|i| runtime.handle(net::Ipv4Addr::new(10, 0, 1, i).into()))
+            .collect();
+        let latency_fault = runtime.latency_fault();
+        runtime
+            .block_on(async {
+                primary.spawn(latency_fault.run());
+                let replica_server = KvServer::new().serve(replica.clone(), replica_addr);
+                replica.spawn(replica_server.map(Result::unwrap));
+                let primary_server = KvServer::new()
+                    .replica(replica_addr)
+                    .serve(primary.clone(), primary_addr);
+                primary.spawn(primary_server.map(Result::unwrap));
+
+                let history = History::new();
+                let clients = clients.into_iter().enumerate().map(|(process, client)| {
+                    let history = history.clone();
+                    crate::spawn_with_result(&client.clone(), async move {
+                        let mut rng = rand::rngs::SmallRng::seed_from_u64(process as u64);
+                        let mut kv = KvClient::connect(client.clone(), primary_addr)
+                            .await
+                            .unwrap();
+                        for _ in 0..10 {
+                            let key = ["a", "b"][rng.gen_range(0, 2)].to_string();
+                            let input = match rng.gen_range(0, 3) {
+                                0 => KvInput::Get(key),
+                                1 => KvInput::Put(key, rng.gen_range(0, 10).to_string()),
+                                _ => KvInput::Delete(key),
+                            };
+                            let id = history.invoke(&client, process, input.clone());
+                            let output = kv.call(input).await.unwrap();
+                            history.ok(&client, id, output);
+                            client.delay_from(Duration::from_millis(10)).await;
+                        }
+                    })
+                });
+                futures::future::join_all(clients).await;
+                assert_eq!(history.events().len(), 60);
+                assert!(history.is_linearizable(&Kv::new()));
+            })
+            .unwrap();
+    }
+}
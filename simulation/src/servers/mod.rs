@@ -0,0 +1,22 @@
+//! Small reference services implemented against the [`Environment`] trait.
+//!
+//! These are useful as realistic peers for the system under test, and as a starting point
+//! when learning how to write services which can be simulated. Since they are generic over
+//! the [`Environment`], they run unchanged under a `DeterministicRuntime` or a
+//! `SingleThreadedRuntime`.
+//!
+//! * [`echo`] writes every byte it receives back to the sender.
+//! * [`KvServer`] is an in-memory key value store which replicates writes from a primary to
+//!   its replicas, and [`KvClient`] talks to it using the inputs and outputs of the
+//!   [`history::Kv`] model, so client histories can be checked for linearizability.
+//!
+//! [`Environment`]:crate::Environment
+//! [`echo`]:echo
+//! [`KvServer`]:KvServer
+//! [`KvClient`]:KvClient
+//! [`history::Kv`]:crate::history::Kv
+mod echo;
+mod kv;
+
+pub use echo::echo;
+pub use kv::{KvClient, KvServer};
@@ -0,0 +1,43 @@
+use async_trait::async_trait;
+use std::io::{self, SeekFrom};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+#[async_trait]
+impl crate::File for File {
+    async fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        File::seek(self, pos).await
+    }
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let cursor = File::seek(self, SeekFrom::Current(0)).await?;
+        File::seek(self, SeekFrom::Start(offset)).await?;
+        let mut read = 0;
+        while read < buf.len() {
+            match AsyncReadExt::read(self, &mut buf[read..]).await? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        File::seek(self, SeekFrom::Start(cursor)).await?;
+        Ok(read)
+    }
+    async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let cursor = File::seek(self, SeekFrom::Current(0)).await?;
+        File::seek(self, SeekFrom::Start(offset)).await?;
+        self.write_all(buf).await?;
+        self.flush().await?;
+        File::seek(self, SeekFrom::Start(cursor)).await?;
+        Ok(())
+    }
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.metadata().await?.len())
+    }
+    async fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self).await
+    }
+    async fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self).await
+    }
+}
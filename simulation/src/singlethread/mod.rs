@@ -1,10 +1,15 @@
 use crate::Error;
 use async_trait::async_trait;
-use futures::Future;
-use std::{io, net::SocketAddr, time};
+use futures::{Future, StreamExt};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path, time,
+};
 use tokio_executor::current_thread;
 use tokio_net::driver::Reactor;
 use tokio_timer::{clock::Clock, timer};
+mod fs;
 mod net;
 #[derive(Debug, Clone)]
 pub struct SingleThreadedRuntimeHandle {
@@ -14,9 +19,7 @@ pub struct SingleThreadedRuntimeHandle {
 }
 
 #[async_trait]
-impl crate::Environment for SingleThreadedRuntimeHandle {
-    type TcpStream = tokio::net::TcpStream;
-    type TcpListener = tokio::net::TcpListener;
+impl crate::SpawnEnv for SingleThreadedRuntimeHandle {
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
@@ -25,15 +28,25 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
             .spawn(future)
             .expect("failed to spawn task")
     }
+    async fn exit(&self, code: i32) {
+        std::process::exit(code)
+    }
+}
+
+impl crate::TimeEnv for SingleThreadedRuntimeHandle {
+    type Delay = tokio::timer::Delay;
     fn now(&self) -> time::Instant {
         self.clock_handle.now()
     }
     fn delay(&self, deadline: time::Instant) -> tokio::timer::Delay {
         self.timer_handle.delay(deadline)
     }
-    fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio::timer::Timeout<T> {
-        self.timer_handle.timeout(value, timeout)
-    }
+}
+
+#[async_trait]
+impl crate::NetEnv for SingleThreadedRuntimeHandle {
+    type TcpStream = tokio::net::TcpStream;
+    type TcpListener = tokio::net::TcpListener;
     async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
     where
         A: Into<SocketAddr> + Send + Sync,
@@ -48,6 +61,104 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     }
 }
 
+impl crate::RandEnv for SingleThreadedRuntimeHandle {}
+
+impl crate::HostEnv for SingleThreadedRuntimeHandle {
+    fn hostname(&self) -> String {
+        crate::host::hostname()
+    }
+    fn host_id(&self) -> u64 {
+        crate::host::host_id()
+    }
+    fn local_addrs(&self) -> Vec<IpAddr> {
+        crate::host::local_addrs()
+    }
+}
+
+#[async_trait]
+impl crate::FsEnv for SingleThreadedRuntimeHandle {
+    type File = tokio::fs::File;
+    async fn open<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref().to_path_buf())
+            .await
+    }
+    async fn create<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref().to_path_buf())
+            .await
+    }
+    async fn read<P>(&self, path: P) -> Result<Vec<u8>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::read(path.as_ref().to_path_buf()).await
+    }
+    async fn write<P, C>(&self, path: P, contents: C) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync,
+    {
+        tokio::fs::write(path.as_ref().to_path_buf(), contents.as_ref().to_vec()).await
+    }
+    async fn rename<P, Q>(&self, from: P, to: Q) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::rename(from.as_ref().to_path_buf(), to.as_ref().to_path_buf()).await
+    }
+    async fn remove<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::remove_file(path.as_ref().to_path_buf()).await
+    }
+    async fn create_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::create_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn remove_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        tokio::fs::remove_dir(path.as_ref().to_path_buf()).await
+    }
+    async fn read_dir<P>(&self, path: P) -> Result<Vec<path::PathBuf>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let mut entries = tokio::fs::read_dir(path.as_ref().to_path_buf()).await?;
+        let mut paths = vec![];
+        while let Some(entry) = entries.next().await {
+            paths.push(entry?.path());
+        }
+        paths.sort();
+        Ok(paths)
+    }
+    async fn sync_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let mut dir = tokio::fs::File::open(path.as_ref().to_path_buf()).await?;
+        dir.sync_all().await
+    }
+}
+
 pub struct SingleThreadedRuntime {
     reactor_handle: tokio_net::driver::Handle,
     timer_handle: tokio_timer::timer::Handle,
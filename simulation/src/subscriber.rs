@@ -0,0 +1,234 @@
+//! A [tracing-subscriber] layer which formats events with simulated time.
+//!
+//! Wall clock timestamps are meaningless inside a simulation, where hours of simulated time
+//! pass in milliseconds. [`SimTimeLayer`] instead stamps each event with the simulated time
+//! elapsed since the runtime was created, followed by the simulated host the event was emitted
+//! on behalf of and the spans the event was emitted in:
+//!
+//! ```text
+//! [   12.500s 10.0.0.2 task:request:retry] DEBUG app::client: connection refused attempt=3
+//! ```
+//!
+//! Events emitted outside of a [`DeterministicRuntime`] have no simulated time or host, and
+//! are stamped with `-` instead.
+//!
+//! [tracing-subscriber]: https://docs.rs/tracing-subscriber
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+use crate::deterministic;
+use std::{fmt, fmt::Write as _, io, sync};
+use tracing::{field, Event, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Writes events stamped with simulated time and host to a writer.
+pub struct SimTimeLayer {
+    writer: sync::Mutex<Box<dyn io::Write + Send>>,
+}
+
+impl fmt::Debug for SimTimeLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SimTimeLayer")
+    }
+}
+
+impl Default for SimTimeLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimTimeLayer {
+    /// Create a layer which writes events to stderr.
+    pub fn new() -> Self {
+        Self::with_writer(io::stderr())
+    }
+
+    /// Create a layer which writes events to `writer`.
+    pub fn with_writer<W>(writer: W) -> Self
+    where
+        W: io::Write + Send + 'static,
+    {
+        Self {
+            writer: sync::Mutex::new(Box::new(writer)),
+        }
+    }
+}
+
+impl<S> Layer<S> for SimTimeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = String::new();
+        match deterministic::current() {
+            Some(handle) => {
+                let elapsed = handle.elapsed();
+                let _ = write!(
+                    line,
+                    "[{:>5}.{:03}s {}",
+                    elapsed.as_secs(),
+                    elapsed.subsec_millis(),
+                    handle.local_addr()
+                );
+            }
+            None => line.push_str("[- -"),
+        }
+        if let Some(scope) = ctx.event_scope(event) {
+            let names: Vec<_> = scope.from_root().map(|span| span.name()).collect();
+            if !names.is_empty() {
+                let _ = write!(line, " {}", names.join(":"));
+            }
+        }
+        let metadata = event.metadata();
+        let _ = write!(line, "] {} {}:", metadata.level(), metadata.target());
+        event.record(&mut Fields { line: &mut line });
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap();
+        let _ = deterministic::unguarded(|| writer.write_all(line.as_bytes()));
+    }
+}
+
+/// Appends the message of an event, followed by its other fields as `name=value`.
+struct Fields<'a> {
+    line: &'a mut String,
+}
+
+impl field::Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &field::Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.line, " {:?}", value),
+            name => write!(self.line, " {}={:?}", name, value),
+        };
+    }
+
+    fn record_str(&mut self, field: &field::Field, value: &str) {
+        let _ = match field.name() {
+            "message" => write!(self.line, " {}", value),
+            name => write!(self.line, " {}={:?}", name, value),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::{net, time::Duration};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// Records the fields of every span named `task`.
+    #[derive(Clone, Default)]
+    struct TaskSpans(sync::Arc<sync::Mutex<Vec<String>>>);
+
+    impl<S> Layer<S> for TaskSpans
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: Context<'_, S>,
+        ) {
+            if attrs.metadata().name() == "task" {
+                let mut line = String::new();
+                attrs.record(&mut Fields { line: &mut line });
+                self.0.lock().unwrap().push(line);
+            }
+        }
+
+        fn on_record(
+            &self,
+            _: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _: Context<'_, S>,
+        ) {
+            let mut line = String::new();
+            values.record(&mut Fields { line: &mut line });
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(sync::Arc<sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Test that events are stamped with simulated time, host and span.
+    fn stamps_simulated_time() {
+        let buffer = Buffer::default();
+        let subscriber =
+            tracing_subscriber::registry().with(SimTimeLayer::with_writer(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+            let handle = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+            runtime
+                .block_on(async {
+                    crate::spawn_with_result(&handle, async {
+                        crate::delay_for(Duration::from_millis(1500)).await;
+                        let span = tracing::info_span!("request");
+                        let _enter = span.enter();
+                        tracing::info!(attempt = 3, "retrying");
+                    })
+                    .await;
+                })
+                .unwrap();
+            tracing::warn!("done");
+        });
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output
+            .lines()
+            .filter(|line| line.contains("retrying") || line.contains("done"))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "[    1.500s 10.0.0.1 task:request] INFO simulation::subscriber::tests: retrying attempt=3",
+                "[- -] WARN simulation::subscriber::tests: done",
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that events emitted by a task are in a span carrying its host, hostname, id and
+    /// name.
+    fn task_span() {
+        let spans = TaskSpans::default();
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(spans.clone())
+            .with(SimTimeLayer::with_writer(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+            let addr = net::Ipv4Addr::new(10, 0, 0, 1).into();
+            let handle = runtime.handle(addr);
+            handle.set_hostname(addr, "kafka-1");
+            runtime
+                .block_on(async {
+                    let (tx, rx) = futures::channel::oneshot::channel();
+                    handle.spawn_named("replicator", async move {
+                        tracing::info!("replicating");
+                        let _ = tx.send(());
+                    });
+                    rx.await.unwrap();
+                })
+                .unwrap();
+        });
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            vec![
+                " host=10.0.0.1 hostname=\"kafka-1\" task=0".to_string(),
+                " name=\"replicator\"".to_string(),
+            ]
+        );
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("10.0.0.1 task] INFO simulation::subscriber::tests: replicating"));
+    }
+}
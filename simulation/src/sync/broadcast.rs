@@ -0,0 +1,112 @@
+//! Channels which deliver each message to every receiver.
+//!
+//! Each receiver observes faults independently, so receivers may see the same message at
+//! different times.
+use super::{Delivery, Faults};
+use futures::{channel::mpsc, Stream, StreamExt};
+use std::{
+    fmt,
+    pin::Pin,
+    sync,
+    task::{Context, Poll},
+};
+
+/// Creates a channel which delivers messages without faults.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>)
+where
+    T: Clone,
+{
+    channel_with_faults(None)
+}
+
+pub(crate) fn channel_with_faults<T>(
+    faults: Option<sync::Arc<dyn Faults>>,
+) -> (Sender<T>, Receiver<T>)
+where
+    T: Clone,
+{
+    let shared = Shared {
+        faults,
+        subscribers: vec![],
+    };
+    let tx = Sender {
+        shared: sync::Arc::new(sync::Mutex::new(shared)),
+    };
+    let rx = tx.subscribe();
+    (tx, rx)
+}
+
+struct Shared<T> {
+    faults: Option<sync::Arc<dyn Faults>>,
+    subscribers: Vec<mpsc::UnboundedSender<T>>,
+}
+
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sender {{ receivers: {} }}", self.receiver_count())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `message` to every receiver, returning the number of receivers it was sent to.
+    pub fn send(&self, message: T) -> usize
+    where
+        T: Clone,
+    {
+        let mut lock = self.shared.lock().unwrap();
+        lock.subscribers
+            .retain(|tx| tx.unbounded_send(message.clone()).is_ok());
+        lock.subscribers.len()
+    }
+
+    /// Returns a receiver for every message sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut lock = self.shared.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        lock.subscribers.push(tx);
+        Receiver {
+            rx,
+            delivery: Delivery::new(lock.faults.clone(), false),
+        }
+    }
+
+    /// Returns the number of receivers which have not been dropped.
+    pub fn receiver_count(&self) -> usize {
+        let lock = self.shared.lock().unwrap();
+        lock.subscribers.iter().filter(|tx| !tx.is_closed()).count()
+    }
+}
+
+#[derive(Debug)]
+pub struct Receiver<T> {
+    rx: mpsc::UnboundedReceiver<T>,
+    delivery: Delivery<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next message, returning `None` once every sender has been dropped.
+    pub async fn recv(&mut self) -> Option<T> {
+        self.next().await
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let Self { rx, delivery } = self.get_mut();
+        delivery.poll_deliver(cx, |cx| rx.poll_next_unpin(cx))
+    }
+}
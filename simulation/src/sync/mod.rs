@@ -0,0 +1,110 @@
+//! Channels and synchronization primitives for tasks.
+//!
+//! Messages passed between tasks of the same process take part in the schedule just like
+//! messages passed over the network, so channels are created through an [`Environment`].
+//! Under the [`DeterministicRuntime`], messages can be held back for a random delay before
+//! they are received, and messages sent over a lossy channel can be dropped, as configured
+//! with the [`DeterministicChannelHandle`]. Delays apply to each message as it is received, so
+//! a channel still delivers the messages of each sender in order. Other environments deliver
+//! every message immediately.
+//!
+//! [`Mutex`], [`RwLock`], [`Semaphore`] and [`Notify`] choose which waiter to wake using the
+//! seed of the deterministic runtime they are used in, rather than the order the waiters
+//! happened to arrive in, so lock ordering and fairness bugs can be reproduced from the seed.
+//! Outside of a simulation, waiters are woken in the order they arrived.
+//!
+//! [`Environment`]:crate::Environment
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`DeterministicChannelHandle`]:crate::deterministic::DeterministicChannelHandle
+//! [`Mutex`]:Mutex
+//! [`RwLock`]:RwLock
+//! [`Semaphore`]:Semaphore
+//! [`Notify`]:Notify
+use futures::FutureExt;
+use std::{
+    fmt, sync,
+    task::{Context, Poll},
+};
+
+pub mod broadcast;
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+
+pub(crate) use crate::BoxDelay;
+
+/// Faults injected into messages as they are received.
+pub(crate) trait Faults: fmt::Debug + Send + Sync + 'static {
+    /// Returns a delay which must elapse before a message is received, if any.
+    fn delay(&self) -> Option<BoxDelay>;
+    /// Returns true if a message received over a lossy channel should be dropped.
+    fn should_drop(&self) -> bool;
+}
+
+/// Receiving side of the fault injection for a single channel.
+pub(crate) struct Delivery<T> {
+    faults: Option<sync::Arc<dyn Faults>>,
+    lossy: bool,
+    held: Option<(T, BoxDelay)>,
+}
+
+// messages are never pinned, only the delay holding them back.
+impl<T> Unpin for Delivery<T> {}
+
+impl<T> fmt::Debug for Delivery<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Delivery {{ faults: {:?}, lossy: {}, held: {} }}",
+            self.faults,
+            self.lossy,
+            self.held.is_some()
+        )
+    }
+}
+
+impl<T> Delivery<T> {
+    pub(crate) fn new(faults: Option<sync::Arc<dyn Faults>>, lossy: bool) -> Self {
+        Self {
+            faults,
+            lossy,
+            held: None,
+        }
+    }
+
+    /// Receive the next message from `next`, dropping it if the channel is lossy and holding
+    /// it back for any injected delay.
+    pub(crate) fn poll_deliver<F>(&mut self, cx: &mut Context<'_>, mut next: F) -> Poll<Option<T>>
+    where
+        F: FnMut(&mut Context<'_>) -> Poll<Option<T>>,
+    {
+        loop {
+            if let Some((_, delay)) = self.held.as_mut() {
+                futures::ready!(delay.poll_unpin(cx));
+                return Poll::Ready(self.held.take().map(|(message, _)| message));
+            }
+            let message = match futures::ready!(next(cx)) {
+                Some(message) => message,
+                None => return Poll::Ready(None),
+            };
+            let faults = match &self.faults {
+                Some(faults) => faults,
+                None => return Poll::Ready(Some(message)),
+            };
+            if self.lossy && faults.should_drop() {
+                continue;
+            }
+            match faults.delay() {
+                Some(delay) => self.held = Some((message, delay)),
+                None => return Poll::Ready(Some(message)),
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Multi-producer, single-consumer channels.
+use super::{Delivery, Faults};
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync,
+    task::{Context, Poll},
+};
+
+pub use futures::channel::mpsc::{SendError, TrySendError};
+
+/// Creates a channel which buffers up to `buffer` messages for each sender, and delivers
+/// messages without faults.
+pub fn channel<T>(buffer: usize) -> (Sender<T>, Receiver<T>) {
+    channel_with_faults(buffer, None)
+}
+
+/// Creates a channel with an unbounded buffer, which delivers messages without faults.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, UnboundedReceiver<T>) {
+    unbounded_channel_with_faults(None, false)
+}
+
+pub(crate) fn channel_with_faults<T>(
+    buffer: usize,
+    faults: Option<sync::Arc<dyn Faults>>,
+) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let rx = Receiver {
+        rx,
+        delivery: Delivery::new(faults, false),
+    };
+    (Sender { tx }, rx)
+}
+
+pub(crate) fn unbounded_channel_with_faults<T>(
+    faults: Option<sync::Arc<dyn Faults>>,
+    lossy: bool,
+) -> (UnboundedSender<T>, UnboundedReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded();
+    let rx = UnboundedReceiver {
+        rx,
+        delivery: Delivery::new(faults, lossy),
+    };
+    (UnboundedSender { tx }, rx)
+}
+
+#[derive(Debug)]
+pub struct Sender<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `message`, waiting for buffer space if the buffer is full.
+    pub async fn send(&mut self, message: T) -> Result<(), SendError> {
+        self.tx.send(message).await
+    }
+
+    /// Send `message` if there is buffer space available.
+    pub fn try_send(&mut self, message: T) -> Result<(), TrySendError<T>> {
+        self.tx.try_send(message)
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+#[derive(Debug)]
+pub struct Receiver<T> {
+    rx: mpsc::Receiver<T>,
+    delivery: Delivery<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next message, returning `None` once every sender has been dropped.
+    pub async fn recv(&mut self) -> Option<T> {
+        self.next().await
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let Self { rx, delivery } = self.get_mut();
+        delivery.poll_deliver(cx, |cx| rx.poll_next_unpin(cx))
+    }
+}
+
+#[derive(Debug)]
+pub struct UnboundedSender<T> {
+    tx: mpsc::UnboundedSender<T>,
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> UnboundedSender<T> {
+    /// Send `message`, failing only if the receiver has been dropped.
+    pub fn send(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.tx.unbounded_send(message)
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+#[derive(Debug)]
+pub struct UnboundedReceiver<T> {
+    rx: mpsc::UnboundedReceiver<T>,
+    delivery: Delivery<T>,
+}
+
+impl<T> UnboundedReceiver<T> {
+    /// Receive the next message, returning `None` once every sender has been dropped.
+    pub async fn recv(&mut self) -> Option<T> {
+        self.next().await
+    }
+}
+
+impl<T> Stream for UnboundedReceiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let Self { rx, delivery } = self.get_mut();
+        delivery.poll_deliver(cx, |cx| rx.poll_next_unpin(cx))
+    }
+}
@@ -0,0 +1,132 @@
+use super::{Semaphore, SemaphorePermit};
+use std::{fmt, ops, sync};
+
+/// An async mutual exclusion lock, which hands the lock to waiters in an order drawn from
+/// the seed under the deterministic runtime.
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    /// The value, which is moved into the guard while the lock is held.
+    value: sync::Mutex<Option<T>>,
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mutex {{ semaphore: {:?} }}", self.semaphore)
+    }
+}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(1),
+            value: sync::Mutex::new(Some(value)),
+        }
+    }
+
+    /// Lock the mutex, waiting until it is released by its current holder.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        self.guard(permit)
+    }
+
+    /// Lock the mutex if it is not held.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let permit = self.semaphore.try_acquire()?;
+        Some(self.guard(permit))
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut().unwrap().as_mut().unwrap()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner().unwrap().unwrap()
+    }
+
+    fn guard<'a>(&'a self, permit: SemaphorePermit<'a>) -> MutexGuard<'a, T> {
+        let value = self.value.lock().unwrap().take();
+        MutexGuard {
+            mutex: self,
+            value,
+            _permit: permit,
+        }
+    }
+}
+
+/// Holds the lock of a [`Mutex`] until dropped.
+///
+/// [`Mutex`]:Mutex
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    value: Option<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T> fmt::Debug for MutexGuard<'_, T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> ops::DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the value is returned before the permit, so the next holder always finds it.
+        *self.mutex.value.lock().unwrap() = self.value.take();
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TimeEnv;
+    use std::time::Duration;
+
+    /// Returns the order in which tasks queued on a mutex acquired it.
+    fn lock_order(seed: u64) -> Vec<usize> {
+        let (mut runtime, handle) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+        runtime
+            .block_on(async {
+                let mutex = sync::Arc::new(Mutex::new(vec![]));
+                let guard = mutex.lock().await;
+                let tasks: Vec<_> = (0..8)
+                    .map(|i| {
+                        let mutex = sync::Arc::clone(&mutex);
+                        crate::spawn_with_result(&handle, async move { mutex.lock().await.push(i) })
+                    })
+                    .collect();
+                // let every task queue on the lock before releasing it.
+                handle.delay_from(Duration::from_millis(1)).await;
+                drop(guard);
+                futures::future::join_all(tasks).await;
+                sync::Arc::try_unwrap(mutex).unwrap().into_inner()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    /// Test that the order waiters acquire the lock in is reproducible from the seed.
+    fn seeded_lock_order() {
+        let order = lock_order(1);
+        assert_eq!(order.len(), 8);
+        assert_eq!(lock_order(1), order);
+        assert!((2..10).any(|seed| lock_order(seed) != order));
+    }
+}
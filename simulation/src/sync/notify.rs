@@ -0,0 +1,187 @@
+use super::semaphore::pick;
+use futures::Future;
+use std::{
+    fmt,
+    pin::Pin,
+    sync,
+    task::{Context, Poll, Waker},
+};
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    waker: Waker,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Set if `notify_one` was called while there were no waiters.
+    permit: bool,
+    next_id: u64,
+    waiters: Vec<Waiter>,
+    /// Waiters which have been notified, but have not been polled since, and whether they
+    /// were notified by `notify_one`.
+    notified: Vec<(u64, bool)>,
+}
+
+/// Notifies tasks waiting for an event. Under the deterministic runtime, the waiter woken by
+/// [`notify_one`] is drawn from the seed.
+///
+/// [`notify_one`]:Notify::notify_one
+#[derive(Default)]
+pub struct Notify {
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(
+            f,
+            "Notify {{ permit: {}, waiters: {} }}",
+            state.permit,
+            state.waiters.len()
+        )
+    }
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a future which completes once this task is notified.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wake one waiting task. If no task is waiting, the next call to [`notified`] completes
+    /// immediately.
+    ///
+    /// [`notified`]:Notify::notified
+    pub fn notify_one(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.waiters.is_empty() {
+            state.permit = true;
+            return;
+        }
+        let index = pick(state.waiters.len());
+        let waiter = state.waiters.remove(index);
+        state.notified.push((waiter.id, true));
+        waiter.waker.wake();
+    }
+
+    /// Wake every waiting task.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock().unwrap();
+        for waiter in std::mem::take(&mut state.waiters) {
+            state.notified.push((waiter.id, false));
+            waiter.waker.wake();
+        }
+    }
+}
+
+/// Future returned by [`Notify::notified`].
+///
+/// [`Notify::notified`]:Notify::notified
+#[derive(Debug)]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Set once the future is waiting to be notified.
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.notify.state.lock().unwrap();
+        match self.id {
+            Some(id) => {
+                if let Some(index) = state
+                    .notified
+                    .iter()
+                    .position(|&(notified, _)| notified == id)
+                {
+                    state.notified.remove(index);
+                    drop(state);
+                    self.id.take();
+                    return Poll::Ready(());
+                }
+                if let Some(waiter) = state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+                    waiter.waker = cx.waker().clone();
+                }
+                Poll::Pending
+            }
+            None if state.permit => {
+                state.permit = false;
+                Poll::Ready(())
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push(Waiter {
+                    id,
+                    waker: cx.waker().clone(),
+                });
+                drop(state);
+                self.id.replace(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.notify.state.lock().unwrap();
+        match state
+            .notified
+            .iter()
+            .position(|&(notified, _)| notified == id)
+        {
+            Some(index) => {
+                let (_, one) = state.notified.remove(index);
+                drop(state);
+                // a notification meant for a single waiter is passed on to another one.
+                if one {
+                    self.notify.notify_one();
+                }
+            }
+            None => state.waiters.retain(|waiter| waiter.id != id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor, FutureExt};
+
+    #[test]
+    /// Test that a notification sent without waiters is kept for the next one, and that
+    /// notifying every waiter wakes them all.
+    fn notify() {
+        let notify = Notify::new();
+        notify.notify_one();
+        assert!(notify.notified().now_or_never().is_some());
+        assert!(notify.notified().now_or_never().is_none());
+
+        let mut first = Box::pin(notify.notified());
+        let mut second = Box::pin(notify.notified());
+        assert!(first.as_mut().now_or_never().is_none());
+        assert!(second.as_mut().now_or_never().is_none());
+        notify.notify_waiters();
+        executor::block_on(async {
+            first.await;
+            second.await;
+        });
+    }
+}
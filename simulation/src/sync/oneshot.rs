@@ -0,0 +1,69 @@
+//! Channels for sending a single message.
+use super::{Delivery, Faults};
+use futures::{channel::oneshot, Future, FutureExt};
+use std::{
+    pin::Pin,
+    sync,
+    task::{Context, Poll},
+};
+
+pub use futures::channel::oneshot::Canceled;
+
+/// Creates a channel which delivers its message without faults.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    channel_with_faults(None)
+}
+
+pub(crate) fn channel_with_faults<T>(
+    faults: Option<sync::Arc<dyn Faults>>,
+) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = oneshot::channel();
+    let rx = Receiver {
+        rx: Some(rx),
+        delivery: Delivery::new(faults, false),
+    };
+    (Sender { tx }, rx)
+}
+
+#[derive(Debug)]
+pub struct Sender<T> {
+    tx: oneshot::Sender<T>,
+}
+
+impl<T> Sender<T> {
+    /// Send `message`, returning it if the receiver has been dropped.
+    pub fn send(self, message: T) -> Result<(), T> {
+        self.tx.send(message)
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_canceled(&self) -> bool {
+        self.tx.is_canceled()
+    }
+}
+
+/// A future which completes with the message, or fails if the sender was dropped without
+/// sending one.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    rx: Option<oneshot::Receiver<T>>,
+    delivery: Delivery<T>,
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Self { rx, delivery } = self.get_mut();
+        let message = futures::ready!(delivery.poll_deliver(cx, |cx| {
+            let received = match rx.as_mut() {
+                Some(rx) => futures::ready!(rx.poll_unpin(cx)).ok(),
+                None => None,
+            };
+            // the message has been taken, so the sender can no longer be polled.
+            rx.take();
+            Poll::Ready(received)
+        }));
+        Poll::Ready(message.ok_or(Canceled))
+    }
+}
@@ -0,0 +1,143 @@
+use super::{Semaphore, SemaphorePermit};
+use std::{fmt, ops, sync};
+
+/// The number of readers which may hold the lock at once. Writers acquire all of them.
+const MAX_READS: usize = 1 << 29;
+
+/// An async reader-writer lock, which hands the lock to waiters in an order drawn from the
+/// seed under the deterministic runtime.
+pub struct RwLock<T> {
+    semaphore: Semaphore,
+    /// The value, which is shared by readers and moved into the guard of a writer.
+    value: sync::Mutex<Option<sync::Arc<T>>>,
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RwLock {{ semaphore: {:?} }}", self.semaphore)
+    }
+}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READS),
+            value: sync::Mutex::new(Some(sync::Arc::new(value))),
+        }
+    }
+
+    /// Lock for reading, waiting until there is no writer.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        let value = self.value.lock().unwrap().clone().unwrap();
+        RwLockReadGuard {
+            value,
+            _permit: permit,
+        }
+    }
+
+    /// Lock for writing, waiting until there are no readers or writers.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let permit = self.semaphore.acquire_many(MAX_READS).await;
+        let value = self.value.lock().unwrap().take().unwrap();
+        // every reader has returned their permit, and dropped their reference before it.
+        let value = sync::Arc::try_unwrap(value).ok().unwrap();
+        RwLockWriteGuard {
+            lock: self,
+            value: Some(value),
+            _permit: permit,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        let value = self.value.into_inner().unwrap().unwrap();
+        sync::Arc::try_unwrap(value).ok().unwrap()
+    }
+}
+
+/// Holds a [`RwLock`] for reading until dropped.
+///
+/// [`RwLock`]:RwLock
+pub struct RwLockReadGuard<'a, T> {
+    value: sync::Arc<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T> fmt::Debug for RwLockReadGuard<'_, T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Holds a [`RwLock`] for writing until dropped.
+///
+/// [`RwLock`]:RwLock
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    value: Option<T>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T> fmt::Debug for RwLockWriteGuard<'_, T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> ops::Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> ops::DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let value = self.value.take().map(sync::Arc::new);
+        *self.lock.value.lock().unwrap() = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor, FutureExt};
+
+    #[test]
+    /// Test that readers share the lock, and writers wait for them to release it.
+    fn readers_and_writers() {
+        let lock = RwLock::new(1);
+        executor::block_on(async {
+            let first = lock.read().await;
+            let second = lock.read().await;
+            assert_eq!(*first + *second, 2);
+            let mut write = Box::pin(lock.write());
+            assert!(write.as_mut().now_or_never().is_none());
+            drop((first, second));
+            *write.await += 1;
+            assert_eq!(*lock.read().await, 2);
+        });
+        assert_eq!(lock.into_inner(), 2);
+    }
+}
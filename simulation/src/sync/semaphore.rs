@@ -0,0 +1,236 @@
+use futures::Future;
+use std::{
+    collections, fmt,
+    pin::Pin,
+    sync,
+    task::{Context, Poll, Waker},
+};
+
+/// Returns the index of the waiter to wake out of `candidates`. Under the deterministic
+/// runtime this is drawn from its seed, otherwise waiters are woken in the order they arrived.
+pub(crate) fn pick(candidates: usize) -> usize {
+    if candidates < 2 {
+        return 0;
+    }
+    #[cfg(feature = "sim")]
+    {
+        if let Some(handle) = crate::deterministic::current() {
+            return handle.random_handle().gen_index(candidates);
+        }
+    }
+    0
+}
+
+/// Returns true if called from a task running inside the deterministic runtime.
+fn simulated() -> bool {
+    #[cfg(feature = "sim")]
+    {
+        crate::deterministic::current().is_some()
+    }
+    #[cfg(not(feature = "sim"))]
+    {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    permits: usize,
+    waker: Waker,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    permits: usize,
+    next_id: u64,
+    /// Waiters in the order they arrived.
+    waiters: Vec<Waiter>,
+    /// Waiters which have been granted their permits, but have not been polled since.
+    granted: collections::HashSet<u64>,
+}
+
+impl State {
+    /// Grant permits to waiters until no waiter can be satisfied by the permits left.
+    fn grant(&mut self, deterministic: bool) {
+        loop {
+            let permits = self.permits;
+            let index = if deterministic {
+                // any waiter which fits may be woken, to explore different orderings.
+                let fitting: Vec<usize> = (0..self.waiters.len())
+                    .filter(|&i| self.waiters[i].permits <= permits)
+                    .collect();
+                if fitting.is_empty() {
+                    return;
+                }
+                fitting[pick(fitting.len())]
+            } else {
+                // only the first waiter may be woken, so large acquisitions are not starved.
+                match self.waiters.first() {
+                    Some(waiter) if waiter.permits <= permits => 0,
+                    _ => return,
+                }
+            };
+            let waiter = self.waiters.remove(index);
+            self.permits -= waiter.permits;
+            self.granted.insert(waiter.id);
+            waiter.waker.wake();
+        }
+    }
+}
+
+/// A counting semaphore, which wakes waiters in an order drawn from the seed under the
+/// deterministic runtime.
+pub struct Semaphore {
+    state: sync::Mutex<State>,
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(
+            f,
+            "Semaphore {{ permits: {}, waiters: {} }}",
+            state.permits,
+            state.waiters.len()
+        )
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        let state = State {
+            permits,
+            ..State::default()
+        };
+        Self {
+            state: sync::Mutex::new(state),
+        }
+    }
+
+    /// Returns the number of permits which can be acquired without waiting.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    /// Add `permits` to the semaphore, waking any waiters they satisfy.
+    pub fn add_permits(&self, permits: usize) {
+        let deterministic = simulated();
+        let mut state = self.state.lock().unwrap();
+        state.permits += permits;
+        state.grant(deterministic);
+    }
+
+    /// Acquire a permit, waiting until one is available.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1).await
+    }
+
+    /// Acquire `permits` permits at once, waiting until enough are available.
+    pub async fn acquire_many(&self, permits: usize) -> SemaphorePermit<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+        .await
+    }
+
+    /// Acquire a permit if one is available without waiting.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.permits == 0 || !state.waiters.is_empty() {
+            return None;
+        }
+        state.permits -= 1;
+        Some(SemaphorePermit {
+            semaphore: self,
+            permits: 1,
+        })
+    }
+}
+
+/// Permits acquired from a [`Semaphore`], which are returned to it when dropped.
+///
+/// [`Semaphore`]:Semaphore
+#[derive(Debug)]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Drop the permits without returning them to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Set once the acquisition is waiting for permits.
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let mut state = semaphore.state.lock().unwrap();
+        match self.id {
+            Some(id) if state.granted.remove(&id) => {
+                self.id.take();
+            }
+            Some(id) => {
+                if let Some(waiter) = state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+                    waiter.waker = cx.waker().clone();
+                }
+                return Poll::Pending;
+            }
+            None if state.waiters.is_empty() && state.permits >= permits => {
+                state.permits -= permits;
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push(Waiter {
+                    id,
+                    permits,
+                    waker: cx.waker().clone(),
+                });
+                self.id.replace(id);
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(SemaphorePermit { semaphore, permits })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let deterministic = simulated();
+        let mut state = self.semaphore.state.lock().unwrap();
+        if state.granted.remove(&id) {
+            // the permits were granted but never taken, pass them on to another waiter.
+            state.permits += self.permits;
+        } else {
+            state.waiters.retain(|waiter| waiter.id != id);
+        }
+        state.grant(deterministic);
+    }
+}
@@ -0,0 +1,70 @@
+//! Timeouts which are independent of the runtime providing the timer.
+//!
+//! [`Timeout`] races a future against any delay future, so every [`Environment`] can provide
+//! timeouts from its own timer, whichever version of Tokio that timer belongs to.
+//!
+//! [`Timeout`]:Timeout
+//! [`Environment`]:crate::Environment
+use futures::{Future, FutureExt};
+use std::{
+    error, fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Error returned by [`Timeout`] when its delay completed before the value.
+///
+/// [`Timeout`]:Timeout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl error::Error for Elapsed {}
+
+/// A future which completes with the output of `value`, or fails with [`Elapsed`] once `delay`
+/// completes, whichever happens first.
+///
+/// [`Elapsed`]:Elapsed
+pub struct Timeout<T, D> {
+    value: Pin<Box<T>>,
+    delay: D,
+}
+
+impl<T, D> fmt::Debug for Timeout<T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timeout")
+    }
+}
+
+impl<T, D> Timeout<T, D> {
+    pub fn new(value: T, delay: D) -> Self {
+        Self {
+            value: Box::pin(value),
+            delay,
+        }
+    }
+}
+
+impl<T, D> Future for Timeout<T, D>
+where
+    T: Future,
+    D: Future<Output = ()> + Unpin,
+{
+    type Output = Result<T::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the value is polled first, so it completes even if the delay has also elapsed.
+        if let Poll::Ready(output) = self.value.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match self.delay.poll_unpin(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed(()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
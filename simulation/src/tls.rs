@@ -0,0 +1,436 @@
+//! TLS over simulated sockets using [rustls].
+//!
+//! [`connect`] and [`accept`] perform a TLS handshake over any stream, such as the
+//! [`TcpStream`] of an [`Environment`], and return a [`TlsStream`] which encrypts everything
+//! written to it. A [`TlsStream`] over a [`TcpStream`] is itself a [`TcpStream`], so services
+//! which terminate TLS can run unmodified against the simulated network.
+//!
+//! [`TestCa`] mints a certificate authority and server certificates from the simulation RNG.
+//! Keys are derived from the RNG and certificates are signed with Ed25519, which is
+//! deterministic, so the same seed always produces the same certificates.
+//!
+//! [rustls]: https://docs.rs/rustls
+//! [`Environment`]:crate::Environment
+use crate::{deterministic::DeterministicRandomHandle, Error, TcpStream};
+use futures::future;
+use rustls::{ClientConfig, ClientSession, NoClientAuth, ServerConfig, ServerSession, Session};
+use std::{
+    io, net,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Perform a client handshake over `io`, verifying that the server's certificate is valid for
+/// `domain`.
+pub async fn connect<S>(
+    config: Arc<ClientConfig>,
+    domain: &str,
+    io: S,
+) -> io::Result<TlsStream<S, ClientSession>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let domain = webpki::DNSNameRef::try_from_ascii_str(domain).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a valid domain", domain),
+        )
+    })?;
+    let mut stream = TlsStream::new(io, ClientSession::new(&config, domain));
+    future::poll_fn(|cx| stream.poll_handshake(cx)).await?;
+    Ok(stream)
+}
+
+/// Perform a server handshake over `io`.
+pub async fn accept<S>(config: Arc<ServerConfig>, io: S) -> io::Result<TlsStream<S, ServerSession>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = TlsStream::new(io, ServerSession::new(&config));
+    future::poll_fn(|cx| stream.poll_handshake(cx)).await?;
+    Ok(stream)
+}
+
+/// A stream which encrypts data written to, and decrypts data read from, an underlying stream.
+#[derive(Debug)]
+pub struct TlsStream<S, C> {
+    io: S,
+    session: C,
+    eof: bool,
+    shutdown: bool,
+}
+
+impl<S, C> TlsStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Session,
+{
+    fn new(io: S, session: C) -> Self {
+        Self {
+            io,
+            session,
+            eof: false,
+            shutdown: false,
+        }
+    }
+
+    /// Returns the underlying stream and TLS session.
+    pub fn get_ref(&self) -> (&S, &C) {
+        (&self.io, &self.session)
+    }
+
+    /// Read TLS records from the underlying stream and process them, returning the number of
+    /// bytes read.
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let read = match self.session.read_tls(&mut SyncIo::new(&mut self.io, cx)) {
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if let Err(e) = self.session.process_new_packets() {
+            // try to send the alert describing the failure to the peer.
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(read))
+    }
+
+    /// Write buffered TLS records to the underlying stream until the session has nothing left
+    /// to send.
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut SyncIo::new(&mut self.io, cx)) {
+                Ok(0) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.session.is_handshaking() {
+            futures::ready!(self.poll_write_tls(cx))?;
+            if self.session.is_handshaking()
+                && self.session.wants_read()
+                && futures::ready!(self.poll_read_tls(cx))? == 0
+            {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during the TLS handshake",
+                )));
+            }
+        }
+        self.poll_write_tls(cx)
+    }
+}
+
+impl<S, C> AsyncRead for TlsStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Session + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            match io::Read::read(&mut this.session, buf) {
+                Ok(0) if !this.eof => {}
+                Ok(read) => return Poll::Ready(Ok(read)),
+                // the peer sent close_notify.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    return Poll::Ready(Ok(0))
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if futures::ready!(this.poll_read_tls(cx))? == 0 {
+                this.eof = true;
+            }
+            // the session may need to respond to what it read, but this need not block reads.
+            if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+}
+
+impl<S, C> AsyncWrite for TlsStream<S, C>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Session + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = io::Write::write(&mut this.session, buf)?;
+        match this.poll_write_tls(cx) {
+            Poll::Pending if written == 0 => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(written)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        io::Write::flush(&mut this.session)?;
+        futures::ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.shutdown {
+            this.session.send_close_notify();
+            this.shutdown = true;
+        }
+        futures::ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl<S, C> TcpStream for TlsStream<S, C>
+where
+    S: TcpStream,
+    C: Session + Unpin + 'static,
+{
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.io.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.io.peer_addr()
+    }
+}
+
+/// Adapts an async stream to the blocking `Read` and `Write` traits expected by rustls,
+/// returning `WouldBlock` whenever the stream is not ready.
+struct SyncIo<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, S> SyncIo<'a, 'b, S> {
+    fn new(io: &'a mut S, cx: &'a mut Context<'b>) -> Self {
+        Self { io, cx }
+    }
+}
+
+impl<S> io::Read for SyncIo<'_, '_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S> io::Write for SyncIo<'_, '_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// A certificate authority for tests, whose keys are derived from the simulation RNG.
+pub struct TestCa {
+    certificate: rcgen::Certificate,
+    der: Vec<u8>,
+}
+
+impl std::fmt::Debug for TestCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TestCa")
+    }
+}
+
+impl TestCa {
+    /// Create a self-signed certificate authority.
+    pub fn new(random: &DeterministicRandomHandle) -> Result<Self, Error> {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(key_pair(random)?);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "simulation test CA");
+        let certificate = rcgen::Certificate::from_params(params).map_err(certificate_error)?;
+        let der = certificate.serialize_der().map_err(certificate_error)?;
+        Ok(Self { certificate, der })
+    }
+
+    /// Returns the DER encoded certificate of the authority.
+    pub fn certificate(&self) -> rustls::Certificate {
+        rustls::Certificate(self.der.clone())
+    }
+
+    /// Issue a certificate valid for each of `names`, returning the certificate chain and its
+    /// private key.
+    pub fn issue(
+        &self,
+        random: &DeterministicRandomHandle,
+        names: &[&str],
+    ) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey), Error> {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let mut params = rcgen::CertificateParams::new(names);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(key_pair(random)?);
+        let certificate = rcgen::Certificate::from_params(params).map_err(certificate_error)?;
+        let der = certificate
+            .serialize_der_with_signer(&self.certificate)
+            .map_err(certificate_error)?;
+        let key = certificate.serialize_private_key_der();
+        Ok((
+            vec![rustls::Certificate(der), self.certificate()],
+            rustls::PrivateKey(key),
+        ))
+    }
+
+    /// Returns a client configuration which trusts only this authority.
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>, Error> {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add(&self.certificate())
+            .map_err(|e| certificate_error(format!("{:?}", e)))?;
+        Ok(Arc::new(config))
+    }
+
+    /// Returns a server configuration presenting a newly issued certificate valid for each of
+    /// `names`.
+    pub fn server_config(
+        &self,
+        random: &DeterministicRandomHandle,
+        names: &[&str],
+    ) -> Result<Arc<ServerConfig>, Error> {
+        let (chain, key) = self.issue(random, names)?;
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(chain, key)
+            .map_err(certificate_error)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// Returns an Ed25519 key pair whose seed is drawn from `random`.
+fn key_pair(random: &DeterministicRandomHandle) -> Result<rcgen::KeyPair, Error> {
+    // PKCS#8 v1 encoding of an Ed25519 private key, followed by the 32 byte seed.
+    const PREFIX: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    let mut seed = [0; 32];
+    random.fill_bytes(&mut seed);
+    let mut der = PREFIX.to_vec();
+    der.extend_from_slice(&seed);
+    rcgen::KeyPair::from_der(&der).map_err(certificate_error)
+}
+
+fn certificate_error<E>(source: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::Certificate {
+        source: source.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, SpawnEnv, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    #[cfg_attr(feature = "clock-guard", ignore = "rustls reads the real clock")]
+    /// Test that a client and server can exchange data over TLS, and that the client rejects a
+    /// certificate which is not valid for the domain it expects.
+    fn handshake_and_echo() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        let random = server.random_handle();
+        let ca = TestCa::new(&random).unwrap();
+        let server_config = ca.server_config(&random, &["server.sim"]).unwrap();
+        let client_config = ca.client_config().unwrap();
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 443);
+                let mut listener = server.bind(addr).await.unwrap();
+                server.spawn(async move {
+                    loop {
+                        let (socket, _) = listener.accept().await.unwrap();
+                        if let Ok(mut stream) = accept(Arc::clone(&server_config), socket).await {
+                            let mut buf = [0; 5];
+                            stream.read_exact(&mut buf).await.unwrap();
+                            stream.write_all(&buf).await.unwrap();
+                            stream.flush().await.unwrap();
+                        }
+                    }
+                });
+
+                let socket = client.connect(addr).await.unwrap();
+                let mut stream = connect(Arc::clone(&client_config), "server.sim", socket)
+                    .await
+                    .unwrap();
+                assert_eq!(stream.peer_addr().unwrap(), addr);
+                stream.write_all(b"hello").await.unwrap();
+                stream.flush().await.unwrap();
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                let socket = client.connect(addr).await.unwrap();
+                let err = connect(client_config, "other.sim", socket)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    /// Test that the same seed mints the same certificates.
+    fn deterministic_certificates() {
+        let mint = |seed| {
+            let (runtime, _) = DeterministicRuntime::builder().seed(seed).build().unwrap();
+            let random = runtime
+                .handle(net::Ipv4Addr::new(10, 0, 0, 1).into())
+                .random_handle();
+            let ca = TestCa::new(&random).unwrap();
+            let (chain, _) = ca.issue(&random, &["server.sim"]).unwrap();
+            chain
+        };
+        assert_eq!(mint(1), mint(1));
+        assert_ne!(mint(1), mint(2));
+    }
+}
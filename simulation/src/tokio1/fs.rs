@@ -0,0 +1,43 @@
+use super::Compat;
+use ::tokio1::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use async_trait::async_trait;
+use std::io::{self, SeekFrom};
+
+#[async_trait]
+impl crate::File for Compat<::tokio1::fs::File> {
+    async fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.get_mut().seek(pos).await
+    }
+    async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let file = self.get_mut();
+        let cursor = file.stream_position().await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read(&mut buf[read..]).await? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        file.seek(SeekFrom::Start(cursor)).await?;
+        Ok(read)
+    }
+    async fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let file = self.get_mut();
+        let cursor = file.stream_position().await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(buf).await?;
+        file.flush().await?;
+        file.seek(SeekFrom::Start(cursor)).await?;
+        Ok(())
+    }
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.get_ref().metadata().await?.len())
+    }
+    async fn sync_all(&mut self) -> io::Result<()> {
+        self.get_ref().sync_all().await
+    }
+    async fn sync_data(&mut self) -> io::Result<()> {
+        self.get_ref().sync_data().await
+    }
+}
@@ -0,0 +1,243 @@
+//! An [`Environment`] backed by a Tokio 1.x runtime, enabled with the `tokio1` feature.
+//!
+//! The rest of this crate is built on the Tokio 0.2 alphas, which makes it hard to adopt in
+//! applications which otherwise depend on a current release of Tokio. Applications which are
+//! written against [`Environment`] can be tested in the [`DeterministicRuntime`], and then run
+//! on a Tokio 1.x runtime by passing them a [`TokioRuntimeHandle`].
+//!
+//! Only the production side is provided here. The [`DeterministicRuntime`] itself is still
+//! built on `tokio-executor`, `tokio-timer` and `tokio-net` from the 0.2 alphas, which let it
+//! take over parking the executor and reading the clock. Tokio 1.x exposes neither, so the
+//! simulation still runs on the 0.2 alphas, even in builds with this feature enabled.
+//!
+//! ```ignore
+//! let runtime = tokio1::runtime::Runtime::new()?;
+//! let handle = TokioRuntimeHandle::new(runtime.handle().clone());
+//! runtime.block_on(server(handle, addr))?;
+//! ```
+//!
+//! Sockets and files are wrapped in [`Compat`], which implements the `AsyncRead` and
+//! `AsyncWrite` traits used by [`Environment`]. Futures returned by the handle must be polled
+//! by the runtime it was created from.
+//!
+//! [`Environment`]:crate::Environment
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`TokioRuntimeHandle`]:TokioRuntimeHandle
+//! [`Compat`]:Compat
+use async_trait::async_trait;
+use futures::Future;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path,
+    pin::Pin,
+    time,
+};
+mod compat;
+mod fs;
+mod net;
+pub use compat::Compat;
+
+/// Handle to a Tokio 1.x runtime, which tasks are spawned onto and which provides time,
+/// sockets and files.
+#[derive(Debug, Clone)]
+pub struct TokioRuntimeHandle {
+    handle: ::tokio1::runtime::Handle,
+}
+
+impl TokioRuntimeHandle {
+    pub fn new(handle: ::tokio1::runtime::Handle) -> Self {
+        Self { handle }
+    }
+
+    /// Returns a handle to the runtime the caller is running on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio 1.x runtime.
+    pub fn current() -> Self {
+        Self::new(::tokio1::runtime::Handle::current())
+    }
+}
+
+#[async_trait]
+impl crate::SpawnEnv for TokioRuntimeHandle {
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.handle.spawn(future);
+    }
+    async fn exit(&self, code: i32) {
+        std::process::exit(code)
+    }
+}
+
+impl crate::TimeEnv for TokioRuntimeHandle {
+    type Delay = Pin<Box<::tokio1::time::Sleep>>;
+    fn now(&self) -> time::Instant {
+        ::tokio1::time::Instant::now().into_std()
+    }
+    fn delay(&self, deadline: time::Instant) -> Self::Delay {
+        // the timer is looked up when the delay is created, which may be outside the runtime.
+        let _enter = self.handle.enter();
+        Box::pin(::tokio1::time::sleep_until(deadline.into()))
+    }
+}
+
+#[async_trait]
+impl crate::NetEnv for TokioRuntimeHandle {
+    type TcpStream = Compat<::tokio1::net::TcpStream>;
+    type TcpListener = Compat<::tokio1::net::TcpListener>;
+    async fn bind<A>(&self, addr: A) -> Result<Self::TcpListener, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        let listener = ::tokio1::net::TcpListener::bind(addr.into()).await?;
+        Ok(Compat::new(listener))
+    }
+    async fn connect<A>(&self, addr: A) -> Result<Self::TcpStream, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        let socket = ::tokio1::net::TcpStream::connect(addr.into()).await?;
+        Ok(Compat::new(socket))
+    }
+}
+
+impl crate::RandEnv for TokioRuntimeHandle {}
+
+impl crate::HostEnv for TokioRuntimeHandle {
+    fn hostname(&self) -> String {
+        crate::host::hostname()
+    }
+    fn host_id(&self) -> u64 {
+        crate::host::host_id()
+    }
+    fn local_addrs(&self) -> Vec<IpAddr> {
+        crate::host::local_addrs()
+    }
+}
+
+#[async_trait]
+impl crate::FsEnv for TokioRuntimeHandle {
+    type File = Compat<::tokio1::fs::File>;
+    async fn open<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let file = ::tokio1::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())
+            .await?;
+        Ok(Compat::new(file))
+    }
+    async fn create<P>(&self, path: P) -> Result<Self::File, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let file = ::tokio1::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())
+            .await?;
+        Ok(Compat::new(file))
+    }
+    async fn read<P>(&self, path: P) -> Result<Vec<u8>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        ::tokio1::fs::read(path.as_ref()).await
+    }
+    async fn write<P, C>(&self, path: P, contents: C) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        C: AsRef<[u8]> + Send + Sync,
+    {
+        ::tokio1::fs::write(path.as_ref(), contents.as_ref()).await
+    }
+    async fn rename<P, Q>(&self, from: P, to: Q) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+        Q: AsRef<path::Path> + Send + Sync,
+    {
+        ::tokio1::fs::rename(from.as_ref(), to.as_ref()).await
+    }
+    async fn remove<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        ::tokio1::fs::remove_file(path.as_ref()).await
+    }
+    async fn create_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        ::tokio1::fs::create_dir(path.as_ref()).await
+    }
+    async fn remove_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        ::tokio1::fs::remove_dir(path.as_ref()).await
+    }
+    async fn read_dir<P>(&self, path: P) -> Result<Vec<path::PathBuf>, io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let mut entries = ::tokio1::fs::read_dir(path.as_ref()).await?;
+        let mut paths = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            paths.push(entry.path());
+        }
+        paths.sort();
+        Ok(paths)
+    }
+    async fn sync_dir<P>(&self, path: P) -> Result<(), io::Error>
+    where
+        P: AsRef<path::Path> + Send + Sync,
+    {
+        let dir = ::tokio1::fs::File::open(path.as_ref()).await?;
+        dir.sync_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NetEnv, SpawnEnv, TcpListener, TimeEnv};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that sockets and timers are provided by a Tokio 1.x runtime.
+    fn tokio1_environment() {
+        let runtime = ::tokio1::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = TokioRuntimeHandle::new(runtime.handle().clone());
+        runtime.block_on(async {
+            let localhost = net::SocketAddr::new(net::Ipv4Addr::LOCALHOST.into(), 0);
+            let mut listener = handle.bind(localhost).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut buf = [0; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let start = handle.now();
+            let pending = futures::future::pending::<()>();
+            let timeout = time::Duration::from_millis(10);
+            assert!(handle.timeout(pending, timeout).await.is_err());
+            assert!(handle.now() - start >= timeout);
+        });
+    }
+}
@@ -0,0 +1,37 @@
+use super::Compat;
+use async_trait::async_trait;
+use futures::{stream, Stream};
+use std::{io, net, pin::Pin};
+
+impl crate::TcpStream for Compat<::tokio1::net::TcpStream> {
+    fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.get_ref().local_addr()
+    }
+    fn peer_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.get_ref().peer_addr()
+    }
+}
+
+#[async_trait]
+impl crate::TcpListener for Compat<::tokio1::net::TcpListener> {
+    type Stream = Compat<::tokio1::net::TcpStream>;
+    async fn accept(&mut self) -> Result<(Self::Stream, net::SocketAddr), io::Error> {
+        let (socket, addr) = self.get_ref().accept().await?;
+        Ok((Compat::new(socket), addr))
+    }
+    fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        self.get_ref().local_addr()
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        self.get_ref().ttl()
+    }
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.get_ref().set_ttl(ttl)
+    }
+    fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>> {
+        Box::pin(stream::unfold(self, |listener| async move {
+            let accepted = listener.get_ref().accept().await;
+            Some((accepted.map(|(socket, _)| Compat::new(socket)), listener))
+        }))
+    }
+}
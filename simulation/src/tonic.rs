@@ -0,0 +1,121 @@
+//! Helpers for running [tonic] gRPC services on an [`Environment`].
+//!
+//! Tonic's transport is built on hyper, and accepts both a custom stream of incoming
+//! connections on the server and a custom connector on the client. [`incoming`] turns a
+//! [`TcpListener`] into the stream expected by `Server::serve_with_incoming`, and
+//! [`TonicConnector`] can be passed to `Endpoint::connect_with_connector`, so a service and its
+//! clients can run inside the [`DeterministicRuntime`] with a few lines.
+//!
+//! ```ignore
+//! let listener = server_handle.bind(addr).await?;
+//! server_handle.spawn(async move {
+//!     Server::builder()
+//!         .add_service(GreeterServer::new(greeter))
+//!         .serve_with_incoming(simulation::tonic::incoming(listener))
+//!         .await
+//!         .unwrap()
+//! });
+//!
+//! let channel = Endpoint::from_shared(format!("http://{}", addr))?
+//!     .connect_with_connector(TonicConnector::new(client_handle.clone()))
+//!     .await?;
+//! let mut client = GreeterClient::new(channel);
+//! ```
+//!
+//! Like [`HyperConnect`], the connector only resolves URIs whose host is an IP address.
+//!
+//! [tonic]: https://docs.rs/tonic
+//! [`Environment`]:crate::Environment
+//! [`DeterministicRuntime`]:crate::deterministic::DeterministicRuntime
+//! [`HyperConnect`]:crate::hyper::HyperConnect
+use crate::{NetEnv, TcpListener};
+use ::hyper::Uri;
+use futures::{Future, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Returns the stream of connections accepted by `listener`, for serving a tonic server.
+pub fn incoming<L>(listener: L) -> impl Stream<Item = io::Result<L::Stream>> + Send
+where
+    L: TcpListener,
+{
+    listener.into_stream()
+}
+
+/// A tonic channel connector which opens connections using a [`NetEnv`].
+#[derive(Debug, Clone)]
+pub struct TonicConnector<E> {
+    env: E,
+}
+
+impl<E> TonicConnector<E>
+where
+    E: NetEnv,
+{
+    pub fn new(env: E) -> Self {
+        Self { env }
+    }
+}
+
+impl<E> tower_service::Service<Uri> for TonicConnector<E>
+where
+    E: NetEnv,
+{
+    type Response = E::TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<E::TcpStream>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let env = self.env.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no host", uri))
+            })?;
+            let scheme = uri.scheme_str().unwrap_or("http");
+            let addr = crate::hyper::socket_addr(scheme, host, uri.port_u16())?;
+            env.connect(addr).await
+        })
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{NetEnv, TcpStream};
+    use futures::StreamExt;
+    use std::net;
+    use tower_service::Service;
+
+    #[test]
+    /// Test that connections made by the connector are yielded by the incoming stream.
+    fn connector_and_incoming() {
+        let (mut runtime, _) = DeterministicRuntime::builder().build().unwrap();
+        let server = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 1).into());
+        let client = runtime.handle(net::Ipv4Addr::new(10, 0, 0, 2).into());
+        runtime
+            .block_on(async {
+                let addr = net::SocketAddr::new(server.local_addr(), 50051);
+                let mut incoming = Box::pin(incoming(server.bind(addr).await.unwrap()));
+
+                let mut connector = TonicConnector::new(client.clone());
+                let uri: Uri = format!("http://{}", addr).parse().unwrap();
+                let socket = connector.call(uri).await.unwrap();
+                let accepted = incoming.next().await.unwrap().unwrap();
+                assert_eq!(socket.peer_addr().unwrap(), addr);
+                assert_eq!(accepted.peer_addr().unwrap(), socket.local_addr().unwrap());
+
+                let uri: Uri = "http://localhost:50051".parse().unwrap();
+                let err = connector.call(uri).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            })
+            .unwrap();
+    }
+}